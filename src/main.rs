@@ -1,10 +1,98 @@
+use trin_core::cli::{TrinCommand, TrinConfig};
+use trin_core::portalnet::discovery::Subnetwork;
+use trin_core::portalnet::protocol::{
+    bench_store, build_local_enr, dry_run_migration, verify_store, PortalnetConfig,
+};
+use trin_core::selftest::run_selftest;
+use trin_core::utils::{init_tracing_logger, node_id_hex};
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let trin_config = TrinConfig::new();
+    init_tracing_logger(trin_config.log.as_deref())?;
+
+    if let Some(TrinCommand::SelfTest) = trin_config.command {
+        let report = run_selftest();
+        report.print();
+        return match report.passed() {
+            true => Ok(()),
+            false => Err("Self-test failed".into()),
+        };
+    }
+
+    if let Some(TrinCommand::VerifyStore { repair }) = trin_config.command {
+        let verification = verify_store(
+            &PortalnetConfig {
+                external_addr: trin_config.external_addr,
+                private_key: trin_config.private_key.clone(),
+                listen_port: trin_config.discovery_port,
+                subnetworks: trin_config.enabled_subnetworks(),
+                storage_backend: trin_config.storage,
+                ..Default::default()
+            },
+            repair,
+        )?;
+        verification.print();
+        return Ok(());
+    }
+
+    if let Some(TrinCommand::BenchStore {
+        items,
+        size,
+        memory,
+    }) = trin_config.command
+    {
+        let report = bench_store(
+            &PortalnetConfig {
+                storage_backend: trin_config.storage,
+                store_cache_capacity: trin_config.store_cache_size,
+                write_buffer_size: trin_config.write_buffer_size,
+                compress_content: trin_config.compress_content,
+                ..Default::default()
+            },
+            items,
+            size,
+            memory,
+        )?;
+        report.print();
+        return Ok(());
+    }
+
+    if trin_config.print_enr {
+        let enr = build_local_enr(&PortalnetConfig {
+            external_addr: trin_config.external_addr,
+            private_key: trin_config.private_key.clone(),
+            listen_port: trin_config.discovery_port,
+            subnetworks: trin_config.enabled_subnetworks(),
+            ..Default::default()
+        })?;
+        println!("{}", enr.to_base64());
+        println!("{}", node_id_hex(&enr.node_id()));
+        return Ok(());
+    }
+
+    if trin_config.migrate_dry_run {
+        let migration = dry_run_migration(&PortalnetConfig {
+            external_addr: trin_config.external_addr,
+            private_key: trin_config.private_key.clone(),
+            listen_port: trin_config.discovery_port,
+            subnetworks: trin_config.enabled_subnetworks(),
+            storage_backend: trin_config.storage,
+            ..Default::default()
+        })?;
+        println!(
+            "Store schema v{} -> v{}: {} content keys to migrate",
+            migration.from_version, migration.to_version, migration.migrated_keys
+        );
+        return Ok(());
+    }
+
+    let subnetworks = trin_config.enabled_subnetworks();
     tokio::select! {
-        history = trin_history::main() => {
+        history = trin_history::main(), if subnetworks.contains(&Subnetwork::History) => {
             history
         },
-        state = trin_state::main() => {
+        state = trin_state::main(), if subnetworks.contains(&Subnetwork::State) => {
             state
         },
     }
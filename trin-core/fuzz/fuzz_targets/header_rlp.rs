@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlp::Rlp;
+use trin_core::types::header::{DecodingMode, Header};
+
+// Decoding arbitrary bytes must never panic, in either mode. Whatever does decode must survive
+// a round trip through its own encoding.
+fuzz_target!(|data: &[u8]| {
+    for &mode in &[DecodingMode::Strict, DecodingMode::Lenient] {
+        if let Ok(header) = Header::decode_with_mode(&Rlp::new(data), mode) {
+            let encoded = rlp::encode(&header);
+            let decoded = Header::decode_with_mode(&Rlp::new(&encoded), mode)
+                .expect("re-encoded header doesn't decode");
+            assert_eq!(decoded, header);
+        }
+    }
+});
@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+use crate::portalnet::storage::ContentStore;
+
+/// Timings of one operation over every benchmarked item.
+pub struct BenchPhase {
+    pub operations: usize,
+    pub total: Duration,
+    pub slowest: Duration,
+}
+
+impl BenchPhase {
+    /// Operations per second. A phase too fast for the clock counts as taking a nanosecond.
+    pub fn throughput(&self) -> f64 {
+        self.operations as f64 / self.total.as_secs_f64().max(1e-9)
+    }
+
+    pub fn mean_latency(&self) -> Duration {
+        self.total / self.operations.max(1) as u32
+    }
+
+    fn print(&self, name: &str) {
+        println!(
+            "{}: {:.0} ops/s, mean latency {:?}, slowest {:?}",
+            name,
+            self.throughput(),
+            self.mean_latency(),
+            self.slowest
+        );
+    }
+}
+
+/// Results of writing and then reading back a batch of items.
+pub struct BenchReport {
+    pub items: usize,
+    pub value_size: usize,
+    /// Writes, including the flush that makes them durable
+    pub put: BenchPhase,
+    pub get: BenchPhase,
+}
+
+impl BenchReport {
+    pub fn print(&self) {
+        println!("{} items of {} bytes", self.items, self.value_size);
+        self.put.print("put");
+        self.get.print("get");
+    }
+}
+
+/// Write `items` distinct values of `value_size` bytes to the store, flush them, and read them
+/// all back. Each value differs, so that content-addressed stores can't share their blobs.
+pub fn run_store_bench(
+    store: &dyn ContentStore,
+    items: usize,
+    value_size: usize,
+) -> Result<BenchReport, String> {
+    let started = Instant::now();
+    let mut slowest_put = Duration::default();
+    for i in 0..items {
+        let (content_key, value) = bench_item(i, value_size);
+        let put_started = Instant::now();
+        store.put(&content_key, &value)?;
+        slowest_put = slowest_put.max(put_started.elapsed());
+    }
+    store.flush()?;
+    let put = BenchPhase {
+        operations: items,
+        total: started.elapsed(),
+        slowest: slowest_put,
+    };
+
+    let started = Instant::now();
+    let mut slowest_get = Duration::default();
+    for i in 0..items {
+        let (content_key, value) = bench_item(i, value_size);
+        let get_started = Instant::now();
+        let stored = store.get(&content_key)?;
+        slowest_get = slowest_get.max(get_started.elapsed());
+        if stored != Some(value) {
+            return Err(format!("Benchmark item {} didn't read back as written", i));
+        }
+    }
+    let get = BenchPhase {
+        operations: items,
+        total: started.elapsed(),
+        slowest: slowest_get,
+    };
+
+    Ok(BenchReport {
+        items,
+        value_size,
+        put,
+        get,
+    })
+}
+
+fn bench_item(i: usize, value_size: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut content_key = vec![0xbe];
+    content_key.extend_from_slice(&(i as u64).to_be_bytes());
+    let value = (i as u64)
+        .to_be_bytes()
+        .iter()
+        .copied()
+        .cycle()
+        .enumerate()
+        .map(|(offset, byte)| byte ^ offset as u8)
+        .take(value_size)
+        .collect();
+    (content_key, value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::storage::{ContentAddressedStore, MemoryBackend};
+
+    #[test]
+    fn test_tiny_bench_reports_nonzero_throughput() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        let report = run_store_bench(&store, 20, 64).unwrap();
+
+        assert_eq!(report.items, 20);
+        assert_eq!(report.put.operations, 20);
+        assert!(report.put.throughput() > 0.0);
+        assert!(report.get.throughput() > 0.0);
+        assert!(report.get.slowest <= report.get.total);
+        assert_eq!(store.content_keys().unwrap().len(), 20);
+        report.print();
+    }
+}
@@ -1,14 +1,46 @@
-use crate::portalnet::types::HexData;
-use log::info;
+use crate::portalnet::chain::TrustedCheckpoint;
+use crate::portalnet::discovery::Subnetwork;
+use crate::portalnet::gossip::GossipConfig;
+use crate::portalnet::lookup::LookupOverflowPolicy;
+use crate::portalnet::peer_filter::{Cidr, PeerFilter};
+use crate::portalnet::protocol::PortalnetConfig;
+use crate::portalnet::storage::{OfferAcceptancePolicy, StorageBackend, StoragePolicy};
+use crate::portalnet::types::{ContentType, HexData, SerdeEnr};
+use crate::socket::SourcePortStrategy;
+use crate::types::rlp_limits::RlpLimits;
 
 use std::env;
 use std::ffi::OsString;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_WEB3_IPC_PATH: &str = "/tmp/trin-jsonrpc.ipc";
+const DEFAULT_ACCEPT_OFFERS: &str = "all";
 const DEFAULT_WEB3_HTTP_PORT: &str = "8545";
 const DEFAULT_DISCOVERY_PORT: &str = "9000";
+const DEFAULT_GOSSIP_FANOUT: &str = "8";
+const DEFAULT_GOSSIP_REDUNDANCY: &str = "4";
+const DEFAULT_GOSSIP_REANNOUNCE_LIMIT: &str = "32";
+const DEFAULT_GOSSIP_SEED_LIMIT: &str = "16";
+const DEFAULT_STORE_CACHE_SIZE: &str = "1024";
+const DEFAULT_MAX_HEADER_FUTURE_DRIFT: &str = "15";
+const DEFAULT_LOOKUP_RETRIES: &str = "2";
+const DEFAULT_LOOKUP_HOP_LIMIT: &str = "16";
+const DEFAULT_MAX_CONCURRENT_LOOKUPS: &str = "16";
+const DEFAULT_NOT_FOUND_TTL: &str = "10";
+const DEFAULT_FOUND_CONTENT_CACHE_SIZE: &str = "256";
+const DEFAULT_FOUND_CONTENT_TTL: &str = "30";
+const DEFAULT_COMPACTION_INTERVAL: &str = "86400";
+const DEFAULT_BOOTSTRAP_TIMEOUT: &str = "30";
+const DEFAULT_MAX_RLP_SIZE: &str = "4194304";
+const DEFAULT_MAX_CONCURRENT_REQUESTS: &str = "32";
+const DEFAULT_WRITE_BUFFER_SIZE: &str = "0";
+const DEFAULT_WRITE_BUFFER_FLUSH_INTERVAL: &str = "1";
+const DEFAULT_REQUEST_LOG_MAX_SIZE: &str = "67108864";
+const DEFAULT_BENCH_ITEMS: &str = "10000";
+const DEFAULT_BENCH_SIZE: &str = "1024";
 
 #[derive(StructOpt, Debug, PartialEq)]
 #[structopt(
@@ -46,6 +78,13 @@ pub struct TrinConfig {
     )]
     pub pool_size: u32,
 
+    #[structopt(
+        default_value(DEFAULT_MAX_CONCURRENT_REQUESTS),
+        long = "max-concurrent-requests",
+        help = "Most json-rpc requests handled at once; more wait until one finishes"
+    )]
+    pub max_concurrent_requests: usize,
+
     #[structopt(
         default_value(DEFAULT_DISCOVERY_PORT),
         long = "discovery-port",
@@ -58,7 +97,105 @@ pub struct TrinConfig {
         long = "bootnodes",
         help = "One or more comma-delimited base64-encoded ENR's or multiaddr strings of peers to initially add to the local routing table"
     )]
-    pub bootnodes: Vec<String>,
+    pub bootnodes: Vec<SerdeEnr>,
+
+    #[structopt(
+        use_delimiter = true,
+        long = "allow-cidrs",
+        help = "Comma-delimited IP ranges, like 10.0.0.0/8; if set, only peers in these ranges are added or answered"
+    )]
+    pub allow_cidrs: Vec<Cidr>,
+
+    #[structopt(
+        use_delimiter = true,
+        long = "deny-cidrs",
+        help = "Comma-delimited IP ranges, like 10.0.0.0/8, whose peers are never added or answered"
+    )]
+    pub deny_cidrs: Vec<Cidr>,
+
+    #[structopt(
+        long = "max-peers",
+        help = "Most peers to keep in the routing table, not counting bootnodes; the farthest are evicted for closer ones"
+    )]
+    pub max_peers: Option<usize>,
+
+    #[structopt(
+        long = "max-peer-idle",
+        help = "Seconds a peer may go without answering a request before it's dropped from the routing table; bootnodes are kept"
+    )]
+    pub max_peer_idle: Option<u64>,
+
+    #[structopt(
+        long = "max-requests-per-peer",
+        help = "Most requests outstanding to any one peer at once; further requests to it wait for earlier ones to finish"
+    )]
+    pub max_requests_per_peer: Option<usize>,
+
+    #[structopt(
+        default_value(DEFAULT_COMPACTION_INTERVAL),
+        long = "compaction-interval",
+        help = "Seconds between compactions of the store's database, which also follow large eviction batches; 0 disables the schedule"
+    )]
+    pub compaction_interval: u64,
+
+    #[structopt(
+        long = "ping-interval",
+        help = "Base seconds between maintenance pings of each overlay peer; reliable peers are pinged less often, flaky ones more"
+    )]
+    pub ping_interval: Option<u64>,
+
+    #[structopt(
+        default_value(DEFAULT_BOOTSTRAP_TIMEOUT),
+        long = "bootstrap-timeout",
+        help = "Seconds after startup the node may have no peers before bootstrap is reported as stalled, with hints on what to fix"
+    )]
+    pub bootstrap_timeout: u64,
+
+    #[structopt(
+        long = "max-transfers",
+        help = "Most content transfers in flight at once, inbound and outbound; more are refused until one finishes"
+    )]
+    pub max_transfers: Option<usize>,
+
+    #[structopt(
+        default_value = "0",
+        long = "min-lookup-peers",
+        help = "Fewest connected peers a recursive lookup is attempted with; lookups before then fail as not ready"
+    )]
+    pub min_lookup_peers: usize,
+
+    #[structopt(
+        long = "routing-table-snapshot",
+        help = "File to periodically save the routing table to, and preload peers from on startup"
+    )]
+    pub routing_table_snapshot: Option<PathBuf>,
+
+    #[structopt(
+        long = "request-log",
+        help = "File to record every overlay request and response to, as JSON lines, for inspecting a session offline"
+    )]
+    pub request_log: Option<PathBuf>,
+
+    #[structopt(
+        default_value(DEFAULT_REQUEST_LOG_MAX_SIZE),
+        long = "request-log-max-size",
+        help = "Bytes the request log may grow to before it's rotated to <request-log>.1"
+    )]
+    pub request_log_max_size: u64,
+
+    #[structopt(
+        long = "bind-address",
+        help = "Local IP address to bind the discovery socket to, like one interface's address (defaults to every interface)"
+    )]
+    pub bind_address: Option<IpAddr>,
+
+    #[structopt(
+        default_value = "listen-port",
+        possible_values(&["listen-port", "ephemeral"]),
+        long = "source-port-strategy",
+        help = "Whether discovery traffic is sent from --discovery-port or a random free port, for NATs that handle one better"
+    )]
+    pub source_port_strategy: SourcePortStrategy,
 
     #[structopt(
         long = "external-address",
@@ -72,6 +209,253 @@ pub struct TrinConfig {
         help = "Hex encoded 32 byte private key (considered unsafe to pass in pk as cli arg, as it's stored in terminal history - keyfile support coming soon)"
     )]
     pub private_key: Option<HexData>,
+
+    #[structopt(
+        long = "log",
+        help = "Log filter directive, like trin_core=debug,discv5=info (defaults to the RUST_LOG env var)"
+    )]
+    pub log: Option<String>,
+
+    #[structopt(
+        long = "offline",
+        help = "Never start discv5 or proxy to Infura; answer json-rpc purely from the local store"
+    )]
+    pub offline: bool,
+
+    #[structopt(
+        long = "print-enr",
+        help = "Print the node's ENR and node id, then exit without starting networking; needs --unsafe-private-key"
+    )]
+    pub print_enr: bool,
+
+    #[structopt(
+        long = "migrate-dry-run",
+        help = "Print the store schema migration that startup would run, then exit without changing anything"
+    )]
+    pub migrate_dry_run: bool,
+
+    #[structopt(
+        long = "enable-history",
+        help = "Run the history subnetwork; without any --enable-* flag, every subnetwork runs"
+    )]
+    pub enable_history: bool,
+
+    #[structopt(
+        long = "enable-state",
+        help = "Run the state subnetwork; without any --enable-* flag, every subnetwork runs"
+    )]
+    pub enable_state: bool,
+
+    #[structopt(
+        long = "strict-radius",
+        help = "Never serve content outside the advertised data radius, even when it's stored"
+    )]
+    pub strict_radius: bool,
+
+    #[structopt(
+        long = "filter-private-ips",
+        help = "Never add peers advertising private, loopback or link-local addresses to the routing table, as on a public node"
+    )]
+    pub filter_private_ips: bool,
+
+    #[structopt(
+        long = "compress-content",
+        help = "Snappy-compress content as it's stored; content stored either way reads back, so this can be turned on and off"
+    )]
+    pub compress_content: bool,
+
+    #[structopt(
+        use_delimiter = true,
+        long = "store-content-types",
+        help = "Comma-delimited content types to store: header, body, receipts, accumulator (defaults to all)"
+    )]
+    pub store_content_types: Vec<ContentType>,
+
+    #[structopt(
+        default_value(DEFAULT_ACCEPT_OFFERS),
+        long = "accept-offers",
+        help = "Whose content offers to accept: all, none, or trusted:<node ids> for only the comma-delimited hex node ids, like bridges"
+    )]
+    pub accept_offers: OfferAcceptancePolicy,
+
+    #[structopt(
+        default_value(DEFAULT_STORE_CACHE_SIZE),
+        long = "store-cache-size",
+        help = "Number of recently read content values, and decoded headers, to keep in memory"
+    )]
+    pub store_cache_size: usize,
+
+    #[structopt(
+        default_value(DEFAULT_WRITE_BUFFER_SIZE),
+        long = "write-buffer-size",
+        help = "Content writes to buffer in memory and flush to the database in batches; 0 writes straight through"
+    )]
+    pub write_buffer_size: usize,
+
+    #[structopt(
+        default_value(DEFAULT_WRITE_BUFFER_FLUSH_INTERVAL),
+        long = "write-buffer-flush-interval",
+        help = "Seconds between flushes of buffered content writes, which are also flushed when the buffer fills and on shutdown"
+    )]
+    pub write_buffer_flush_interval: u64,
+
+    #[structopt(
+        default_value = "rocksdb",
+        possible_values(&["rocksdb", "sqlite"]),
+        long = "storage",
+        help = "Database to keep content in; sqlite stores everything in a single file"
+    )]
+    pub storage: StorageBackend,
+
+    #[structopt(
+        long = "storage-capacity",
+        help = "Most bytes of content the sqlite store keeps; the content farthest from the node is evicted first"
+    )]
+    pub storage_capacity: Option<u64>,
+
+    #[structopt(
+        default_value(DEFAULT_GOSSIP_FANOUT),
+        long = "gossip-fanout",
+        help = "Maximum number of peers offered new content per propagation"
+    )]
+    pub gossip_fanout: usize,
+
+    #[structopt(
+        default_value(DEFAULT_GOSSIP_REDUNDANCY),
+        long = "gossip-redundancy",
+        help = "Stop propagating new content once this many peers accepted it"
+    )]
+    pub gossip_redundancy: usize,
+
+    #[structopt(
+        default_value(DEFAULT_GOSSIP_REANNOUNCE_LIMIT),
+        long = "gossip-reannounce-limit",
+        help = "Most held content keys gossiped again when the data radius grows to cover them"
+    )]
+    pub gossip_reannounce_limit: usize,
+
+    #[structopt(
+        default_value(DEFAULT_GOSSIP_SEED_LIMIT),
+        long = "gossip-seed-limit",
+        help = "Most held content keys offered to a peer new to the routing table, closest to it first; 0 offers none"
+    )]
+    pub gossip_seed_limit: usize,
+
+    #[structopt(
+        default_value(DEFAULT_LOOKUP_RETRIES),
+        long = "lookup-retries",
+        help = "Times a content lookup is retried without the sending peer when the content fails validation"
+    )]
+    pub lookup_retries: usize,
+
+    #[structopt(
+        default_value(DEFAULT_LOOKUP_HOP_LIMIT),
+        long = "lookup-hop-limit",
+        help = "Most peers a content lookup queries before giving up with a hop limit error"
+    )]
+    pub lookup_hop_limit: usize,
+
+    #[structopt(
+        default_value(DEFAULT_MAX_CONCURRENT_LOOKUPS),
+        long = "max-concurrent-lookups",
+        help = "Most recursive content lookups running at once, across all json-rpc requests"
+    )]
+    pub max_concurrent_lookups: usize,
+
+    #[structopt(
+        default_value = "queue",
+        possible_values(&["queue", "reject"]),
+        long = "lookup-overflow-policy",
+        help = "Whether lookups beyond --max-concurrent-lookups wait for a slot or fail as busy"
+    )]
+    pub lookup_overflow_policy: LookupOverflowPolicy,
+
+    #[structopt(
+        default_value(DEFAULT_NOT_FOUND_TTL),
+        long = "not-found-ttl",
+        help = "Seconds a content key that no peer had is answered as not found without another lookup; 0 disables"
+    )]
+    pub not_found_ttl: u64,
+
+    #[structopt(
+        default_value(DEFAULT_FOUND_CONTENT_CACHE_SIZE),
+        long = "found-content-cache-size",
+        help = "Most recently found content values kept in memory, whether or not they're stored, to serve repeat requests without another lookup"
+    )]
+    pub found_content_cache_size: usize,
+
+    #[structopt(
+        default_value(DEFAULT_FOUND_CONTENT_TTL),
+        long = "found-content-ttl",
+        help = "Seconds found content is served from memory without another lookup; 0 disables"
+    )]
+    pub found_content_ttl: u64,
+
+    #[structopt(
+        long = "trusted-checkpoint",
+        help = "Block to trust as the anchor of the canonical chain, as <number>:<hash>; headers that don't chain back to it are rejected"
+    )]
+    pub trusted_checkpoint: Option<TrustedCheckpoint>,
+
+    #[structopt(
+        default_value(DEFAULT_MAX_HEADER_FUTURE_DRIFT),
+        long = "max-header-future-drift",
+        help = "Seconds a live header's timestamp may be ahead of the local clock; historical headers aren't checked"
+    )]
+    pub max_header_future_drift: u64,
+
+    #[structopt(
+        default_value(DEFAULT_MAX_RLP_SIZE),
+        long = "max-rlp-size",
+        help = "Largest RLP payload from a peer, in bytes, that is decoded; bigger payloads are rejected unread"
+    )]
+    pub max_rlp_size: usize,
+
+    #[structopt(subcommand)]
+    pub command: Option<TrinCommand>,
+}
+
+#[derive(StructOpt, Debug, PartialEq)]
+pub enum TrinCommand {
+    #[structopt(
+        name = "selftest",
+        about = "Run local RLP/SSZ round-trips against built-in fixtures, then exit"
+    )]
+    SelfTest,
+    #[structopt(
+        name = "verify-store",
+        about = "Check every stored value against its content hash, report mismatches, then exit"
+    )]
+    VerifyStore {
+        #[structopt(
+            long = "repair",
+            help = "Delete the content whose stored value is corrupted"
+        )]
+        repair: bool,
+    },
+    #[structopt(
+        name = "bench-store",
+        about = "Measure put and get throughput of the configured store backend on scratch data, then exit"
+    )]
+    BenchStore {
+        #[structopt(
+            default_value(DEFAULT_BENCH_ITEMS),
+            long = "items",
+            help = "Number of values to write and read back"
+        )]
+        items: usize,
+        #[structopt(
+            default_value(DEFAULT_BENCH_SIZE),
+            long = "size",
+            help = "Size of each value, in bytes"
+        )]
+        size: usize,
+        #[structopt(
+            long = "memory",
+            help = "Benchmark an in-memory store instead of the configured backend, as a baseline"
+        )]
+        memory: bool,
+    },
 }
 
 impl Default for TrinConfig {
@@ -106,28 +490,96 @@ impl TrinConfig {
         Ok(config)
     }
 
-    pub fn display_config(&self) {
-        match self.web3_transport.as_str() {
-            "http" => {
-                info!(
-                    "Protocol: {}\nWEB3 HTTP port: {}",
-                    self.web3_transport, self.web3_http_port
-                )
-            }
-            "ipc" => {
-                info!(
-                    "Protocol: {}\nIPC path: {}",
-                    self.web3_transport, self.web3_ipc_path
-                )
-            }
-            val => panic!("Unsupported json-rpc protocol: {}", val),
+    /// Returns the subnetworks to run. Without any `--enable-*` flag, every subnetwork runs.
+    pub fn enabled_subnetworks(&self) -> Vec<Subnetwork> {
+        let all = !self.enable_history && !self.enable_state;
+        let mut subnetworks = vec![];
+        if all || self.enable_history {
+            subnetworks.push(Subnetwork::History);
+        }
+        if all || self.enable_state {
+            subnetworks.push(Subnetwork::State);
         }
+        subnetworks
+    }
 
-        info!("Pool Size: {}", self.pool_size);
+    /// Returns how json-rpc is served, like `http port 8545`, for the startup summary.
+    pub fn json_rpc_endpoint(&self) -> String {
+        match self.web3_transport.as_str() {
+            "http" => format!("http port {}", self.web3_http_port),
+            "ipc" => format!("ipc at {}", self.web3_ipc_path),
+            val => format!("unsupported transport {}", val),
+        }
+    }
+}
 
-        match self.bootnodes.is_empty() {
-            true => info!("Bootnodes: None"),
-            _ => info!("Bootnodes: {:?}", self.bootnodes),
+/// The overlay config for the parsed options, serving the enabled subnetworks. Each subnetwork's
+/// node overrides the subnetworks with its own.
+impl From<&TrinConfig> for PortalnetConfig {
+    fn from(trin_config: &TrinConfig) -> Self {
+        PortalnetConfig {
+            external_addr: trin_config.external_addr,
+            bind_address: trin_config.bind_address,
+            source_port_strategy: trin_config.source_port_strategy,
+            private_key: trin_config.private_key.clone(),
+            listen_port: trin_config.discovery_port,
+            bootnode_enrs: trin_config
+                .bootnodes
+                .iter()
+                .map(|enr| enr.0.clone())
+                .collect(),
+            subnetworks: trin_config.enabled_subnetworks(),
+            storage_policy: StoragePolicy::from_allowlist(trin_config.store_content_types.clone()),
+            offer_acceptance_policy: trin_config.accept_offers.clone(),
+            store_cache_capacity: trin_config.store_cache_size,
+            write_buffer_size: trin_config.write_buffer_size,
+            compress_content: trin_config.compress_content,
+            write_buffer_flush_interval: Duration::from_secs(
+                trin_config.write_buffer_flush_interval,
+            ),
+            storage_backend: trin_config.storage,
+            storage_capacity: trin_config.storage_capacity,
+            trusted_checkpoint: trin_config.trusted_checkpoint,
+            max_header_future_drift: Duration::from_secs(trin_config.max_header_future_drift),
+            lookup_retries: trin_config.lookup_retries,
+            lookup_hop_limit: trin_config.lookup_hop_limit,
+            max_concurrent_lookups: trin_config.max_concurrent_lookups,
+            lookup_overflow_policy: trin_config.lookup_overflow_policy,
+            not_found_ttl: Duration::from_secs(trin_config.not_found_ttl),
+            found_content_cache_capacity: trin_config.found_content_cache_size,
+            found_content_ttl: Duration::from_secs(trin_config.found_content_ttl),
+            routing_table_snapshot: trin_config.routing_table_snapshot.clone(),
+            request_log: trin_config.request_log.clone(),
+            request_log_max_size: trin_config.request_log_max_size,
+            rlp_limits: RlpLimits {
+                max_size: trin_config.max_rlp_size,
+                ..Default::default()
+            },
+            max_concurrent_requests: trin_config.max_concurrent_requests,
+            max_peers: trin_config.max_peers,
+            max_peer_idle: trin_config.max_peer_idle.map(Duration::from_secs),
+            max_requests_per_peer: trin_config.max_requests_per_peer,
+            ping_interval: trin_config.ping_interval.map(Duration::from_secs),
+            bootstrap_timeout: Duration::from_secs(trin_config.bootstrap_timeout),
+            compaction_interval: match trin_config.compaction_interval {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            max_transfers: trin_config.max_transfers,
+            min_lookup_peers: trin_config.min_lookup_peers,
+            strict_radius: trin_config.strict_radius,
+            filter_private_ips: trin_config.filter_private_ips,
+            peer_filter: PeerFilter {
+                allow: trin_config.allow_cidrs.clone(),
+                deny: trin_config.deny_cidrs.clone(),
+            },
+            gossip: GossipConfig {
+                fanout: trin_config.gossip_fanout,
+                redundancy: trin_config.gossip_redundancy,
+                reannounce_limit: trin_config.gossip_reannounce_limit,
+                seed_limit: trin_config.gossip_seed_limit,
+            },
+            ..Default::default()
         }
     }
 }
@@ -145,6 +597,9 @@ fn check_private_key_length(private_key: String) -> Result<(), String> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::portalnet::discovery::enr_serves_subnetwork;
+    use crate::portalnet::protocol::build_local_enr;
+    use discv5::enr::{CombinedKey, EnrBuilder, NodeId};
     use std::env;
 
     fn env_is_set() -> bool {
@@ -157,36 +612,19 @@ mod test {
     #[test]
     fn test_default_args() {
         assert!(env_is_set());
-        let expected_config = TrinConfig {
-            web3_http_port: DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap(),
-            web3_ipc_path: DEFAULT_WEB3_IPC_PATH.to_string(),
-            pool_size: 2,
-            web3_transport: "ipc".to_string(),
-            discovery_port: DEFAULT_DISCOVERY_PORT.parse().unwrap(),
-            bootnodes: vec![],
-            external_addr: None,
-            private_key: None,
-        };
         let actual_config = TrinConfig::new_from(["trin"].iter()).unwrap();
-        assert_eq!(actual_config.web3_transport, expected_config.web3_transport);
-        assert_eq!(actual_config.web3_http_port, expected_config.web3_http_port);
-        assert_eq!(actual_config.pool_size, expected_config.pool_size);
-        assert_eq!(actual_config.external_addr, expected_config.external_addr);
+        assert_eq!(actual_config.web3_transport, "ipc".to_string());
+        assert_eq!(
+            actual_config.web3_http_port,
+            DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap()
+        );
+        assert_eq!(actual_config.pool_size, 2);
+        assert_eq!(actual_config.external_addr, None);
     }
 
     #[test]
     fn test_custom_http_args() {
         assert!(env_is_set());
-        let expected_config = TrinConfig {
-            external_addr: None,
-            private_key: None,
-            web3_http_port: 8080,
-            web3_ipc_path: DEFAULT_WEB3_IPC_PATH.to_string(),
-            pool_size: 3,
-            web3_transport: "http".to_string(),
-            discovery_port: DEFAULT_DISCOVERY_PORT.parse().unwrap(),
-            bootnodes: vec![],
-        };
         let actual_config = TrinConfig::new_from(
             [
                 "trin",
@@ -200,9 +638,9 @@ mod test {
             .iter(),
         )
         .unwrap();
-        assert_eq!(actual_config.web3_transport, expected_config.web3_transport);
-        assert_eq!(actual_config.web3_http_port, expected_config.web3_http_port);
-        assert_eq!(actual_config.pool_size, expected_config.pool_size);
+        assert_eq!(actual_config.web3_transport, "http".to_string());
+        assert_eq!(actual_config.web3_http_port, 8080);
+        assert_eq!(actual_config.pool_size, 3);
     }
 
     #[test]
@@ -210,19 +648,12 @@ mod test {
         assert!(env_is_set());
         let actual_config =
             TrinConfig::new_from(["trin", "--web3-transport", "ipc"].iter()).unwrap();
-        let expected_config = TrinConfig {
-            external_addr: None,
-            private_key: None,
-            web3_http_port: DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap(),
-            web3_ipc_path: DEFAULT_WEB3_IPC_PATH.to_string(),
-            pool_size: 2,
-            web3_transport: "ipc".to_string(),
-            discovery_port: DEFAULT_DISCOVERY_PORT.parse().unwrap(),
-            bootnodes: vec![],
-        };
-        assert_eq!(actual_config.web3_transport, expected_config.web3_transport);
-        assert_eq!(actual_config.web3_http_port, expected_config.web3_http_port);
-        assert_eq!(actual_config.pool_size, expected_config.pool_size);
+        assert_eq!(actual_config.web3_transport, "ipc".to_string());
+        assert_eq!(
+            actual_config.web3_http_port,
+            DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap()
+        );
+        assert_eq!(actual_config.pool_size, 2);
     }
 
     #[test]
@@ -239,20 +670,13 @@ mod test {
             .iter(),
         )
         .unwrap();
-        let expected_config = TrinConfig {
-            private_key: None,
-            external_addr: None,
-            web3_http_port: DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap(),
-            web3_ipc_path: "/path/test.ipc".to_string(),
-            pool_size: 2,
-            web3_transport: "ipc".to_string(),
-            discovery_port: DEFAULT_DISCOVERY_PORT.parse().unwrap(),
-            bootnodes: vec![],
-        };
-        assert_eq!(actual_config.web3_transport, expected_config.web3_transport);
-        assert_eq!(actual_config.web3_http_port, expected_config.web3_http_port);
-        assert_eq!(actual_config.pool_size, expected_config.pool_size);
-        assert_eq!(actual_config.web3_ipc_path, expected_config.web3_ipc_path);
+        assert_eq!(actual_config.web3_transport, "ipc".to_string());
+        assert_eq!(
+            actual_config.web3_http_port,
+            DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap()
+        );
+        assert_eq!(actual_config.pool_size, 2);
+        assert_eq!(actual_config.web3_ipc_path, "/path/test.ipc".to_string());
     }
 
     #[test]
@@ -292,37 +716,29 @@ mod test {
     #[test]
     fn test_custom_discovery_port() {
         assert!(env_is_set());
-        let expected_config = TrinConfig {
-            external_addr: None,
-            private_key: None,
-            web3_http_port: DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap(),
-            web3_ipc_path: DEFAULT_WEB3_IPC_PATH.to_string(),
-            pool_size: 2,
-            web3_transport: "ipc".to_string(),
-            discovery_port: 999,
-            bootnodes: vec![],
-        };
         let actual_config =
             TrinConfig::new_from(["trin", "--discovery-port", "999"].iter()).unwrap();
-        assert_eq!(actual_config.discovery_port, expected_config.discovery_port);
+        assert_eq!(actual_config.discovery_port, 999);
     }
 
     #[test]
     fn test_custom_bootnodes() {
         assert!(env_is_set());
-        let expected_config = TrinConfig {
-            external_addr: None,
-            private_key: None,
-            web3_http_port: DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap(),
-            web3_ipc_path: DEFAULT_WEB3_IPC_PATH.to_string(),
-            pool_size: 2,
-            web3_transport: "ipc".to_string(),
-            discovery_port: DEFAULT_DISCOVERY_PORT.parse().unwrap(),
-            bootnodes: vec!["enr:-aoeu".to_string(), "enr:-htns".to_string()],
-        };
+        let bootnodes: Vec<SerdeEnr> = (1..=2)
+            .map(|key_byte| {
+                let enr_key =
+                    CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+                SerdeEnr(EnrBuilder::new("v4").udp(9000).build(&enr_key).unwrap())
+            })
+            .collect();
+        let bootnodes_arg = bootnodes
+            .iter()
+            .map(|enr| enr.to_base64())
+            .collect::<Vec<String>>()
+            .join(",");
         let actual_config =
-            TrinConfig::new_from(["trin", "--bootnodes", "enr:-aoeu,enr:-htns"].iter()).unwrap();
-        assert_eq!(actual_config.bootnodes, expected_config.bootnodes);
+            TrinConfig::new_from(["trin", "--bootnodes", &bootnodes_arg].iter()).unwrap();
+        assert_eq!(actual_config.bootnodes, bootnodes);
     }
 
     #[test]
@@ -336,6 +752,17 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_bind_address() {
+        assert!(env_is_set());
+        let actual_config =
+            TrinConfig::new_from(["trin", "--bind-address", "10.0.0.7"].iter()).unwrap();
+        assert_eq!(
+            actual_config.bind_address,
+            Some(IpAddr::from([10, 0, 0, 7]))
+        );
+    }
+
     #[test]
     fn test_manual_external_addr_v6() {
         assert!(env_is_set());
@@ -350,16 +777,6 @@ mod test {
     #[test]
     fn test_custom_private_key() {
         assert!(env_is_set());
-        let expected_config = TrinConfig {
-            external_addr: None,
-            private_key: Some(HexData(vec![1; 32])),
-            web3_http_port: DEFAULT_WEB3_HTTP_PORT.parse::<u16>().unwrap(),
-            web3_ipc_path: DEFAULT_WEB3_IPC_PATH.to_string(),
-            pool_size: 2,
-            web3_transport: "ipc".to_string(),
-            discovery_port: DEFAULT_DISCOVERY_PORT.parse().unwrap(),
-            bootnodes: vec![],
-        };
         let actual_config = TrinConfig::new_from(
             [
                 "trin",
@@ -369,7 +786,7 @@ mod test {
             .iter(),
         )
         .unwrap();
-        assert_eq!(actual_config.private_key, expected_config.private_key);
+        assert_eq!(actual_config.private_key, Some(HexData(vec![1; 32])));
     }
 
     #[test]
@@ -387,6 +804,483 @@ mod test {
         .unwrap_err();
     }
 
+    #[test]
+    fn test_filter_private_ips_flag() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert!(!default_config.filter_private_ips);
+        let actual_config = TrinConfig::new_from(["trin", "--filter-private-ips"].iter()).unwrap();
+        assert!(actual_config.filter_private_ips);
+    }
+
+    #[test]
+    fn test_offline_flag() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert!(!default_config.offline);
+        let actual_config = TrinConfig::new_from(["trin", "--offline"].iter()).unwrap();
+        assert!(actual_config.offline);
+    }
+
+    #[test]
+    fn test_compress_content_flag() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert!(!default_config.compress_content);
+        let actual_config = TrinConfig::new_from(["trin", "--compress-content"].iter()).unwrap();
+        assert!(actual_config.compress_content);
+    }
+
+    #[test]
+    fn test_store_content_types() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert!(default_config.store_content_types.is_empty());
+        let actual_config =
+            TrinConfig::new_from(["trin", "--store-content-types", "header,receipts"].iter())
+                .unwrap();
+        assert_eq!(
+            actual_config.store_content_types,
+            vec![ContentType::BlockHeader, ContentType::Receipts]
+        );
+    }
+
+    #[test]
+    fn test_accept_offers() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(default_config.accept_offers, OfferAcceptancePolicy::All);
+        let policy = format!("trusted:0x{}", "07".repeat(32));
+        let actual_config =
+            TrinConfig::new_from(["trin", "--accept-offers", policy.as_str()].iter()).unwrap();
+        assert_eq!(
+            actual_config.accept_offers,
+            OfferAcceptancePolicy::TrustedPeers(vec![NodeId::new(&[7; 32])])
+        );
+    }
+
+    #[test]
+    fn test_store_cache_size() {
+        assert!(env_is_set());
+        let actual_config =
+            TrinConfig::new_from(["trin", "--store-cache-size", "64"].iter()).unwrap();
+        assert_eq!(actual_config.store_cache_size, 64);
+    }
+
+    #[test]
+    fn test_write_buffer() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(
+            [
+                "trin",
+                "--write-buffer-size",
+                "256",
+                "--write-buffer-flush-interval",
+                "5",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(actual_config.write_buffer_size, 256);
+        assert_eq!(actual_config.write_buffer_flush_interval, 5);
+    }
+
+    #[test]
+    fn test_request_log() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(
+            [
+                "trin",
+                "--request-log",
+                "/tmp/trin-requests.jsonl",
+                "--request-log-max-size",
+                "1024",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(
+            actual_config.request_log,
+            Some(PathBuf::from("/tmp/trin-requests.jsonl"))
+        );
+        assert_eq!(actual_config.request_log_max_size, 1024);
+    }
+
+    #[test]
+    fn test_trusted_checkpoint() {
+        assert!(env_is_set());
+        let hash = format!("0x{}", "ab".repeat(32));
+        let checkpoint = format!("100:{}", hash);
+        let actual_config =
+            TrinConfig::new_from(["trin", "--trusted-checkpoint", checkpoint.as_str()].iter())
+                .unwrap();
+        assert_eq!(
+            actual_config.trusted_checkpoint,
+            Some(TrustedCheckpoint {
+                number: 100,
+                hash: ethereum_types::H256::repeat_byte(0xab),
+            })
+        );
+    }
+
+    #[test]
+    fn test_max_header_future_drift() {
+        assert!(env_is_set());
+        let actual_config =
+            TrinConfig::new_from(["trin", "--max-header-future-drift", "60"].iter()).unwrap();
+        assert_eq!(actual_config.max_header_future_drift, 60);
+    }
+
+    #[test]
+    fn test_lookup_retries() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(["trin", "--lookup-retries", "5"].iter()).unwrap();
+        assert_eq!(actual_config.lookup_retries, 5);
+    }
+
+    #[test]
+    fn test_compaction_interval() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(default_config.compaction_interval, 86400);
+        let actual_config =
+            TrinConfig::new_from(["trin", "--compaction-interval", "0"].iter()).unwrap();
+        assert_eq!(actual_config.compaction_interval, 0);
+    }
+
+    #[test]
+    fn test_ping_interval() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(default_config.ping_interval, None);
+        let actual_config =
+            TrinConfig::new_from(["trin", "--ping-interval", "300"].iter()).unwrap();
+        assert_eq!(actual_config.ping_interval, Some(300));
+    }
+
+    #[test]
+    fn test_max_requests_per_peer() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(default_config.max_requests_per_peer, None);
+        let actual_config =
+            TrinConfig::new_from(["trin", "--max-requests-per-peer", "4"].iter()).unwrap();
+        assert_eq!(actual_config.max_requests_per_peer, Some(4));
+    }
+
+    #[test]
+    fn test_bootstrap_timeout() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(default_config.bootstrap_timeout, 30);
+        let actual_config =
+            TrinConfig::new_from(["trin", "--bootstrap-timeout", "120"].iter()).unwrap();
+        assert_eq!(actual_config.bootstrap_timeout, 120);
+    }
+
+    #[test]
+    fn test_source_port_strategy() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(
+            default_config.source_port_strategy,
+            SourcePortStrategy::ListenPort
+        );
+        let actual_config =
+            TrinConfig::new_from(["trin", "--source-port-strategy", "ephemeral"].iter()).unwrap();
+        assert_eq!(
+            actual_config.source_port_strategy,
+            SourcePortStrategy::Ephemeral
+        );
+    }
+
+    #[test]
+    fn test_lookup_concurrency() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(
+            [
+                "trin",
+                "--max-concurrent-lookups",
+                "4",
+                "--lookup-overflow-policy",
+                "reject",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(actual_config.max_concurrent_lookups, 4);
+        assert_eq!(
+            actual_config.lookup_overflow_policy,
+            LookupOverflowPolicy::Reject
+        );
+    }
+
+    #[test]
+    fn test_lookup_hop_limit() {
+        assert!(env_is_set());
+        let actual_config =
+            TrinConfig::new_from(["trin", "--lookup-hop-limit", "4"].iter()).unwrap();
+        assert_eq!(actual_config.lookup_hop_limit, 4);
+    }
+
+    #[test]
+    fn test_found_content_cache() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(default_config.found_content_cache_size, 256);
+        assert_eq!(default_config.found_content_ttl, 30);
+        let actual_config = TrinConfig::new_from(
+            [
+                "trin",
+                "--found-content-cache-size",
+                "8",
+                "--found-content-ttl",
+                "0",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(actual_config.found_content_cache_size, 8);
+        assert_eq!(actual_config.found_content_ttl, 0);
+    }
+
+    #[test]
+    fn test_peer_cidrs() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(
+            [
+                "trin",
+                "--allow-cidrs",
+                "10.0.0.0/8,192.168.0.0/16",
+                "--deny-cidrs",
+                "10.1.0.0/16",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(actual_config.allow_cidrs.len(), 2);
+        assert_eq!(
+            actual_config.deny_cidrs,
+            vec!["10.1.0.0/16".parse::<Cidr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_storage_capacity() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(default_config.storage_capacity, None);
+        let actual_config =
+            TrinConfig::new_from(["trin", "--storage-capacity", "1000000"].iter()).unwrap();
+        assert_eq!(actual_config.storage_capacity, Some(1_000_000));
+    }
+
+    #[test]
+    fn test_storage_backend() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(["trin", "--storage", "sqlite"].iter()).unwrap();
+        assert_eq!(actual_config.storage, StorageBackend::Sqlite);
+        assert!(TrinConfig::new_from(["trin", "--storage", "leveldb"].iter()).is_err());
+    }
+
+    #[test]
+    fn test_gossip_options() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(
+            ["trin", "--gossip-fanout", "16", "--gossip-redundancy", "2"].iter(),
+        )
+        .unwrap();
+        assert_eq!(actual_config.gossip_fanout, 16);
+        assert_eq!(actual_config.gossip_redundancy, 2);
+        assert_eq!(actual_config.gossip_seed_limit, 16);
+
+        let actual_config =
+            TrinConfig::new_from(["trin", "--gossip-seed-limit", "0"].iter()).unwrap();
+        assert_eq!(actual_config.gossip_seed_limit, 0);
+    }
+
+    #[test]
+    fn test_portalnet_config_from_options() {
+        assert!(env_is_set());
+        let trin_config = TrinConfig::new_from(
+            [
+                "trin",
+                "--enable-state",
+                "--discovery-port",
+                "9009",
+                "--max-peer-idle",
+                "120",
+                "--compaction-interval",
+                "0",
+                "--max-rlp-size",
+                "1024",
+                "--deny-cidrs",
+                "10.0.0.0/8",
+                "--gossip-fanout",
+                "3",
+                "--store-cache-size",
+                "64",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        let portal_config = PortalnetConfig::from(&trin_config);
+        assert_eq!(portal_config.subnetworks, vec![Subnetwork::State]);
+        assert_eq!(portal_config.listen_port, 9009);
+        assert_eq!(portal_config.max_peer_idle, Some(Duration::from_secs(120)));
+        assert_eq!(portal_config.compaction_interval, None);
+        assert_eq!(portal_config.rlp_limits.max_size, 1024);
+        assert_eq!(
+            portal_config.peer_filter.deny,
+            vec!["10.0.0.0/8".parse::<Cidr>().unwrap()]
+        );
+        assert_eq!(portal_config.gossip.fanout, 3);
+        assert_eq!(portal_config.gossip.seed_limit, 16);
+        assert_eq!(portal_config.store_cache_capacity, 64);
+        assert_eq!(
+            portal_config.not_found_ttl,
+            Duration::from_secs(trin_config.not_found_ttl)
+        );
+        assert!(portal_config.bootnode_enrs.is_empty());
+    }
+
+    #[test]
+    fn test_migrate_dry_run_flag() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert!(!default_config.migrate_dry_run);
+        let actual_config = TrinConfig::new_from(["trin", "--migrate-dry-run"].iter()).unwrap();
+        assert!(actual_config.migrate_dry_run);
+    }
+
+    #[test]
+    fn test_print_enr_flag() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert!(!default_config.print_enr);
+        let actual_config = TrinConfig::new_from(["trin", "--print-enr"].iter()).unwrap();
+        assert!(actual_config.print_enr);
+    }
+
+    #[test]
+    fn test_only_enabled_subnetworks_run_and_are_advertised() {
+        assert!(env_is_set());
+        let default_config = TrinConfig::new_from(["trin"].iter()).unwrap();
+        assert_eq!(
+            default_config.enabled_subnetworks(),
+            vec![Subnetwork::History, Subnetwork::State]
+        );
+
+        let history_config = TrinConfig::new_from(["trin", "--enable-history"].iter()).unwrap();
+        let subnetworks = history_config.enabled_subnetworks();
+        assert_eq!(subnetworks, vec![Subnetwork::History]);
+        let enr = build_local_enr(&PortalnetConfig {
+            subnetworks,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(enr_serves_subnetwork(&enr, Subnetwork::History));
+        assert!(!enr_serves_subnetwork(&enr, Subnetwork::State));
+    }
+
+    #[test]
+    fn test_json_rpc_endpoint() {
+        assert!(env_is_set());
+        let http_config = TrinConfig::new_from(
+            [
+                "trin",
+                "--web3-transport",
+                "http",
+                "--web3-http-port",
+                "8080",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(http_config.json_rpc_endpoint(), "http port 8080");
+        let ipc_config = TrinConfig::new_from(
+            [
+                "trin",
+                "--web3-transport",
+                "ipc",
+                "--web3-ipc-path",
+                "/tmp/trin.ipc",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(ipc_config.json_rpc_endpoint(), "ipc at /tmp/trin.ipc");
+    }
+
+    #[test]
+    fn test_selftest_subcommand() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(["trin", "selftest"].iter()).unwrap();
+        assert_eq!(actual_config.command, Some(TrinCommand::SelfTest));
+    }
+
+    #[test]
+    fn test_verify_store_subcommand() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(["trin", "verify-store"].iter()).unwrap();
+        assert_eq!(
+            actual_config.command,
+            Some(TrinCommand::VerifyStore { repair: false })
+        );
+        let actual_config =
+            TrinConfig::new_from(["trin", "verify-store", "--repair"].iter()).unwrap();
+        assert_eq!(
+            actual_config.command,
+            Some(TrinCommand::VerifyStore { repair: true })
+        );
+    }
+
+    #[test]
+    fn test_bench_store_subcommand() {
+        assert!(env_is_set());
+        let actual_config = TrinConfig::new_from(["trin", "bench-store"].iter()).unwrap();
+        assert_eq!(
+            actual_config.command,
+            Some(TrinCommand::BenchStore {
+                items: 10000,
+                size: 1024,
+                memory: false
+            })
+        );
+        let actual_config = TrinConfig::new_from(
+            [
+                "trin",
+                "bench-store",
+                "--items",
+                "50",
+                "--size",
+                "32",
+                "--memory",
+            ]
+            .iter(),
+        )
+        .unwrap();
+        assert_eq!(
+            actual_config.command,
+            Some(TrinCommand::BenchStore {
+                items: 50,
+                size: 32,
+                memory: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_custom_log_directive() {
+        assert!(env_is_set());
+        let actual_config =
+            TrinConfig::new_from(["trin", "--log", "trin_core=debug,discv5=info"].iter()).unwrap();
+        assert_eq!(
+            actual_config.log,
+            Some("trin_core=debug,discv5=info".to_string())
+        );
+    }
+
     #[test]
     #[should_panic(expected = "Invalid private key length")]
     fn test_custom_private_key_requires_32_bytes() {
@@ -1,5 +1,8 @@
 use crate::cli::TrinConfig;
 use crate::portalnet::protocol::{PortalEndpoint, PortalEndpointKind};
+use crate::portalnet::types::{validate_content_key, SerdeEnr};
+use crate::portalnet::Enr;
+use discv5::enr::NodeId;
 use reqwest::blocking as reqwest;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -22,7 +25,20 @@ struct JsonRequest {
     #[validate(custom = "validate_jsonrpc_version")]
     pub jsonrpc: String,
     pub method: String,
-    pub id: u32,
+    /// Missing for a notification, which is handled without a response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+/// What a client sends at once: a single request, or a batch of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonPayload {
+    /// Kept raw, so that an invalid request only fails its own part of the batch
+    Batch(Vec<Value>),
+    Single(JsonRequest),
 }
 
 fn validate_jsonrpc_version(jsonrpc: &str) -> Result<(), ValidationError> {
@@ -36,9 +52,11 @@ lazy_static! {
     static ref IPC_PATH: Mutex<String> = Mutex::new(String::new());
 }
 
+/// Serve json-rpc requests. Without an Infura project id (offline mode), requests that would be
+/// proxied to Infura return an error instead.
 pub fn launch_trin(
     trin_config: TrinConfig,
-    infura_project_id: String,
+    infura_project_id: Option<String>,
     portal_tx: UnboundedSender<PortalEndpoint>,
 ) {
     let pool = ThreadPool::new(trin_config.pool_size as usize);
@@ -87,7 +105,7 @@ fn get_listener_result(ipc_path: &str) -> tokio::io::Result<uds_windows::UnixLis
 
 fn launch_ipc_client(
     pool: ThreadPool,
-    infura_project_id: String,
+    infura_project_id: Option<String>,
     ipc_path: &str,
     portal_tx: UnboundedSender<PortalEndpoint>,
 ) {
@@ -107,10 +125,10 @@ fn launch_ipc_client(
         let infura_project_id = infura_project_id.clone();
         let portal_tx = portal_tx.clone();
         pool.execute(move || {
-            let infura_url = get_infura_url(&infura_project_id);
+            let infura_url = infura_project_id.as_deref().map(get_infura_url);
             let mut rx = stream.try_clone().unwrap();
             let mut tx = stream;
-            serve_ipc_client(&mut rx, &mut tx, &infura_url, portal_tx);
+            serve_ipc_client(&mut rx, &mut tx, infura_url.as_deref(), portal_tx);
         });
     }
     println!("Clean exit");
@@ -118,7 +136,7 @@ fn launch_ipc_client(
 
 fn launch_http_client(
     pool: ThreadPool,
-    infura_project_id: String,
+    infura_project_id: Option<String>,
     trin_config: TrinConfig,
     portal_tx: UnboundedSender<PortalEndpoint>,
 ) {
@@ -130,8 +148,8 @@ fn launch_http_client(
                 let infura_project_id = infura_project_id.clone();
                 let portal_tx = portal_tx.clone();
                 pool.execute(move || {
-                    let infura_url = get_infura_url(&infura_project_id);
-                    serve_http_client(stream, &infura_url, portal_tx);
+                    let infura_url = infura_project_id.as_deref().map(get_infura_url);
+                    serve_http_client(stream, infura_url.as_deref(), portal_tx);
                 });
             }
             Err(e) => {
@@ -144,12 +162,21 @@ fn launch_http_client(
 fn serve_ipc_client(
     rx: &mut impl Read,
     tx: &mut impl Write,
-    infura_url: &str,
+    infura_url: Option<&str>,
     portal_tx: UnboundedSender<PortalEndpoint>,
 ) {
     let deser = serde_json::Deserializer::from_reader(rx);
-    for obj in deser.into_iter::<JsonRequest>() {
-        let obj = obj.unwrap();
+    for payload in deser.into_iter::<JsonPayload>() {
+        let obj = match payload.unwrap() {
+            JsonPayload::Single(obj) => obj,
+            JsonPayload::Batch(batch) => {
+                if let Some(responses) = handle_batch(batch, infura_url, &portal_tx) {
+                    tx.write_all(responses.as_bytes()).unwrap();
+                }
+                continue;
+            }
+        };
+        let is_notification = obj.id.is_none();
         let formatted_response = match obj.validate() {
             Ok(_) => {
                 let result = handle_request(obj, infura_url, portal_tx.clone());
@@ -160,13 +187,15 @@ fn serve_ipc_client(
             }
             Err(e) => format!("Unsupported trin request: {}", e).into_bytes(),
         };
-        tx.write_all(&formatted_response).unwrap();
+        if !is_notification {
+            tx.write_all(&formatted_response).unwrap();
+        }
     }
 }
 
 fn serve_http_client(
     mut stream: TcpStream,
-    infura_url: &str,
+    infura_url: Option<&str>,
     portal_tx: UnboundedSender<PortalEndpoint>,
 ) {
     let mut buffer = Vec::new();
@@ -174,11 +203,26 @@ fn serve_http_client(
 
     let json_request = String::from_utf8_lossy(&buffer);
     let deser = serde_json::Deserializer::from_str(&json_request);
-    for obj in deser.into_iter::<JsonRequest>() {
-        let obj = obj.unwrap();
-        let formatted_response = match obj.validate() {
-            Ok(_) => process_http_request(obj, infura_url, portal_tx.clone()),
-            Err(e) => format!("HTTP/1.1 400 BAD REQUEST\r\n\r\n{}", e).into_bytes(),
+    for payload in deser.into_iter::<JsonPayload>() {
+        let formatted_response = match payload.unwrap() {
+            JsonPayload::Single(obj) => match obj.validate() {
+                Ok(_) if obj.id.is_none() => {
+                    // Notifications get no response body.
+                    let _ = handle_request(obj, infura_url, portal_tx.clone());
+                    b"HTTP/1.1 204 NO CONTENT\r\n\r\n".to_vec()
+                }
+                Ok(_) => process_http_request(obj, infura_url, portal_tx.clone()),
+                Err(e) => format!("HTTP/1.1 400 BAD REQUEST\r\n\r\n{}", e).into_bytes(),
+            },
+            JsonPayload::Batch(batch) => match handle_batch(batch, infura_url, &portal_tx) {
+                Some(contents) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    contents.len(),
+                    contents,
+                )
+                .into_bytes(),
+                None => b"HTTP/1.1 204 NO CONTENT\r\n\r\n".to_vec(),
+            },
         };
         stream.write_all(&formatted_response).unwrap();
         stream.flush().unwrap();
@@ -187,7 +231,7 @@ fn serve_http_client(
 
 fn process_http_request(
     obj: JsonRequest,
-    infura_url: &str,
+    infura_url: Option<&str>,
     portal_tx: UnboundedSender<PortalEndpoint>,
 ) -> Vec<u8> {
     let result = handle_request(obj, infura_url, portal_tx);
@@ -207,9 +251,63 @@ fn process_http_request(
     }
 }
 
+/// Handle each request of a batch in turn, so that the batch is held to the same concurrency
+/// limits as single requests. Returns the array of responses in request order, leaving out
+/// notifications, or `None` if every request was a notification.
+fn handle_batch(
+    batch: Vec<Value>,
+    infura_url: Option<&str>,
+    portal_tx: &UnboundedSender<PortalEndpoint>,
+) -> Option<String> {
+    if batch.is_empty() {
+        return Some(
+            json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": "Invalid request: empty batch",
+            })
+            .to_string(),
+        );
+    }
+    let mut responses = vec![];
+    for request in batch {
+        let obj: JsonRequest = match serde_json::from_value(request) {
+            Ok(obj) => obj,
+            Err(e) => {
+                responses.push(json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": format!("Invalid request: {}", e),
+                }));
+                continue;
+            }
+        };
+        let id = obj.id;
+        let response = match obj.validate() {
+            Ok(_) => match handle_request(obj, infura_url, portal_tx.clone()) {
+                Ok(contents) | Err(contents) => {
+                    serde_json::from_str(&contents).unwrap_or(Value::String(contents))
+                }
+            },
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": format!("Unsupported trin request: {}", e),
+            }),
+        };
+        if id.is_some() {
+            responses.push(response);
+        }
+    }
+    match responses.is_empty() {
+        true => None,
+        false => Some(Value::Array(responses).to_string()),
+    }
+}
+
 fn handle_request(
     obj: JsonRequest,
-    infura_url: &str,
+    infura_url: Option<&str>,
     portal_tx: UnboundedSender<PortalEndpoint>,
 ) -> Result<String, String> {
     match obj.method.as_str() {
@@ -219,12 +317,26 @@ fn handle_request(
             "result": "trin 0.0.1-alpha",
         })
         .to_string()),
+        "eth_blockNumber" => dispatch_portal_request(obj, portal_tx),
         _ if obj.method.as_str().starts_with("discv5") => dispatch_portal_request(obj, portal_tx),
+        _ if obj.method.as_str().starts_with("portal") => dispatch_portal_request(obj, portal_tx),
+        _ if obj.method.as_str().starts_with("trin") => dispatch_portal_request(obj, portal_tx),
         _ => dispatch_infura_request(obj, infura_url),
     }
 }
 
-fn dispatch_infura_request(obj: JsonRequest, infura_url: &str) -> Result<String, String> {
+fn dispatch_infura_request(obj: JsonRequest, infura_url: Option<&str>) -> Result<String, String> {
+    let infura_url = match infura_url {
+        Some(url) => url,
+        None => {
+            return Err(json!({
+                "jsonrpc": "2.0",
+                "id": obj.id,
+                "error": format!("Unavailable in offline mode: {}", obj.method),
+            })
+            .to_string())
+        }
+    };
     //Re-encode json to proxy to Infura
     let request = serde_json::to_string(&obj).unwrap();
     match proxy_to_url(request, infura_url) {
@@ -253,6 +365,58 @@ fn dispatch_portal_request(
             kind: PortalEndpointKind::RoutingTableInfo,
             resp: resp_tx,
         },
+        "discv5_peerClients" => PortalEndpoint {
+            kind: PortalEndpointKind::PeerClients,
+            resp: resp_tx,
+        },
+        "eth_blockNumber" => PortalEndpoint {
+            kind: PortalEndpointKind::BlockNumber,
+            resp: resp_tx,
+        },
+        "discv5_addEnr" => PortalEndpoint {
+            kind: PortalEndpointKind::AddEnr(enr_param(&obj, 0)?),
+            resp: resp_tx,
+        },
+        "discv5_recursiveFindNode" => PortalEndpoint {
+            kind: PortalEndpointKind::RecursiveFindNode(node_id_param(&obj, 0)?),
+            resp: resp_tx,
+        },
+        "trin_refreshRoutingTable" => PortalEndpoint {
+            kind: PortalEndpointKind::RecursiveFindNode(NodeId::random()),
+            resp: resp_tx,
+        },
+        "portal_historyRadius" => PortalEndpoint {
+            kind: PortalEndpointKind::PeerRadius(enr_param(&obj, 0)?),
+            resp: resp_tx,
+        },
+        "portal_historyAvailability" => PortalEndpoint {
+            kind: PortalEndpointKind::Availability(hex_list_param(&obj, 0)?),
+            resp: resp_tx,
+        },
+        "portal_localContent" => PortalEndpoint {
+            kind: PortalEndpointKind::LocalContent(hex_param(&obj, 0)?),
+            resp: resp_tx,
+        },
+        "trin_deleteContent" => PortalEndpoint {
+            kind: PortalEndpointKind::DeleteContent(content_key_param(&obj, 0)?),
+            resp: resp_tx,
+        },
+        "portal_historyTraceRecursiveFindContent" => PortalEndpoint {
+            kind: PortalEndpointKind::TraceRecursiveFindContent(hex_param(&obj, 0)?),
+            resp: resp_tx,
+        },
+        "portal_historyRecursiveFindContent" => PortalEndpoint {
+            kind: PortalEndpointKind::RecursiveFindContent(hex_param(&obj, 0)?),
+            resp: resp_tx,
+        },
+        "trin_health" => PortalEndpoint {
+            kind: PortalEndpointKind::Health,
+            resp: resp_tx,
+        },
+        "trin_activeTransfers" => PortalEndpoint {
+            kind: PortalEndpointKind::ActiveTransfers,
+            resp: resp_tx,
+        },
         _ => {
             return Err(json!({
                 "jsonrpc": "2.0",
@@ -283,6 +447,78 @@ fn dispatch_portal_request(
     .to_string())
 }
 
+fn invalid_params(obj: &JsonRequest, msg: String) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": obj.id,
+        "error": format!("Invalid params for {}: {}", obj.method, msg),
+    })
+    .to_string()
+}
+
+fn string_param(obj: &JsonRequest, index: usize) -> Result<&str, String> {
+    match obj.params.get(index) {
+        Some(Value::String(param)) => Ok(param),
+        Some(_) => Err(invalid_params(
+            obj,
+            format!("param {} must be a string", index),
+        )),
+        None => Err(invalid_params(obj, format!("missing param {}", index))),
+    }
+}
+
+/// Decode a 0x-prefixed hex string from the positional params.
+fn hex_param(obj: &JsonRequest, index: usize) -> Result<Vec<u8>, String> {
+    hex::decode(string_param(obj, index)?.trim_start_matches("0x"))
+        .map_err(|e| invalid_params(obj, e.to_string()))
+}
+
+/// Decode a 0x-prefixed hex content key from the positional params, and check it's well-formed.
+fn content_key_param(obj: &JsonRequest, index: usize) -> Result<Vec<u8>, String> {
+    let content_key = hex_param(obj, index)?;
+    validate_content_key(&content_key).map_err(|e| invalid_params(obj, e))?;
+    Ok(content_key)
+}
+
+/// Decode a list of 0x-prefixed hex strings from the positional params.
+fn hex_list_param(obj: &JsonRequest, index: usize) -> Result<Vec<Vec<u8>>, String> {
+    let items = match obj.params.get(index) {
+        Some(Value::Array(items)) => items,
+        Some(_) => {
+            return Err(invalid_params(
+                obj,
+                format!("param {} must be a list", index),
+            ))
+        }
+        None => return Err(invalid_params(obj, format!("missing param {}", index))),
+    };
+    items
+        .iter()
+        .map(|item| match item {
+            Value::String(item) => hex::decode(item.trim_start_matches("0x"))
+                .map_err(|e| invalid_params(obj, e.to_string())),
+            _ => Err(invalid_params(
+                obj,
+                format!("param {} must be a list of strings", index),
+            )),
+        })
+        .collect()
+}
+
+/// Decode a base64 ENR from the positional params.
+fn enr_param(obj: &JsonRequest, index: usize) -> Result<Enr, String> {
+    string_param(obj, index)?;
+    serde_json::from_value::<SerdeEnr>(obj.params[index].clone())
+        .map(Enr::from)
+        .map_err(|e| invalid_params(obj, e.to_string()))
+}
+
+/// Decode a 0x-prefixed hex node id from the positional params.
+fn node_id_param(obj: &JsonRequest, index: usize) -> Result<NodeId, String> {
+    let raw = hex_param(obj, index)?;
+    NodeId::parse(&raw).map_err(|e| invalid_params(obj, e.to_string()))
+}
+
 fn proxy_to_url(request: String, url: &str) -> io::Result<Vec<u8>> {
     let client = reqwest::Client::new();
     match client.post(url).body(request).send() {
@@ -324,8 +560,9 @@ mod test {
     fn test_json_validator_accepts_valid_json() {
         let request = JsonRequest {
             jsonrpc: "2.0".to_string(),
-            id: 1,
+            id: Some(1),
             method: "eth_blockNumber".to_string(),
+            params: vec![],
         };
         assert_eq!(request.validate(), Ok(()));
     }
@@ -334,10 +571,155 @@ mod test {
     fn test_json_validator_with_invalid_jsonrpc_field() {
         let request = JsonRequest {
             jsonrpc: "1.0".to_string(),
-            id: 1,
+            id: Some(1),
             method: "eth_blockNumber".to_string(),
+            params: vec![],
         };
         let errors = request.validate();
         assert!(ValidationErrors::has_error(&errors, "jsonrpc"));
     }
+
+    #[test]
+    fn test_offline_mode_does_not_proxy_to_infura() {
+        let request = JsonRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(1),
+            method: "eth_blockNumber".to_string(),
+            params: vec![],
+        };
+        let error = dispatch_infura_request(request, None).unwrap_err();
+        assert!(error.contains("Unavailable in offline mode: eth_blockNumber"));
+    }
+
+    #[test]
+    fn test_hex_param() {
+        let request = JsonRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(1),
+            method: "portal_localContent".to_string(),
+            params: vec![json!("0x0102")],
+        };
+        assert_eq!(hex_param(&request, 0).unwrap(), vec![1, 2]);
+        assert!(hex_param(&request, 1).is_err());
+    }
+
+    #[test]
+    fn test_content_key_param() {
+        let header_key = format!("0x00{}", "ab".repeat(34));
+        let request = JsonRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(1),
+            method: "trin_deleteContent".to_string(),
+            params: vec![json!(header_key), json!("0x0001"), json!("0x09")],
+        };
+        assert_eq!(content_key_param(&request, 0).unwrap().len(), 35);
+        assert!(content_key_param(&request, 1)
+            .unwrap_err()
+            .contains("BlockHeader content key has 2 bytes, expected 35"));
+        assert!(content_key_param(&request, 2)
+            .unwrap_err()
+            .contains("Unknown content key type: 0x09"));
+    }
+
+    #[test]
+    fn test_hex_list_param() {
+        let request = JsonRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(1),
+            method: "portal_historyAvailability".to_string(),
+            params: vec![json!(["0x0102", "0x03"]), json!("0x04"), json!(["0x0z"])],
+        };
+        assert_eq!(
+            hex_list_param(&request, 0).unwrap(),
+            vec![vec![1, 2], vec![3]]
+        );
+        assert!(hex_list_param(&request, 1).is_err());
+        assert!(hex_list_param(&request, 2).is_err());
+        assert!(hex_list_param(&request, 3).is_err());
+    }
+
+    #[test]
+    fn test_node_id_param() {
+        let node_id = NodeId::random();
+        let request = JsonRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(1),
+            method: "discv5_recursiveFindNode".to_string(),
+            params: vec![
+                json!(format!("0x{}", hex::encode(node_id.raw()))),
+                json!("0x01"),
+            ],
+        };
+        assert_eq!(node_id_param(&request, 0).unwrap(), node_id);
+        assert!(node_id_param(&request, 1).is_err());
+    }
+
+    #[test]
+    fn test_enr_param_rejects_invalid_enr() {
+        let request = JsonRequest {
+            jsonrpc: "2.0".to_string(),
+            id: Some(1),
+            method: "discv5_addEnr".to_string(),
+            params: vec![json!("enr:-not-an-enr")],
+        };
+        let error = enr_param(&request, 0).unwrap_err();
+        assert!(error.contains("Invalid params for discv5_addEnr"));
+        assert!(error.contains("Invalid ENR enr:-not-an-enr"));
+    }
+
+    #[test]
+    fn test_batch_responses_are_ordered_without_notifications() {
+        let (portal_tx, mut portal_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        std::thread::spawn(move || {
+            while let Some(endpoint) = portal_rx.blocking_recv() {
+                let result = match endpoint.kind {
+                    PortalEndpointKind::BlockNumber => Ok(json!("0x2a")),
+                    _ => Err("unexpected endpoint".to_owned()),
+                };
+                endpoint.resp.send(result).unwrap();
+            }
+        });
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []},
+            {"jsonrpc": "2.0", "method": "web3_clientVersion"},
+            {"jsonrpc": "2.0", "id": 2, "method": "web3_clientVersion"},
+            {"jsonrpc": "2.0", "id": 3, "method": "eth_getBalance", "params": []},
+            {"jsonrpc": "2.0", "id": 4, "method": "discv5_unknown"},
+            {"id": 5},
+        ])
+        .to_string();
+
+        let mut response = vec![];
+        serve_ipc_client(&mut batch.as_bytes(), &mut response, None, portal_tx);
+
+        let responses: Vec<Value> = serde_json::from_slice(&response).unwrap();
+        let ids: Vec<Value> = responses.iter().map(|r| r["id"].clone()).collect();
+        assert_eq!(
+            ids,
+            vec![json!(1), json!(2), json!(3), json!(4), json!(null)]
+        );
+        assert_eq!(responses[0]["result"], "0x2a");
+        assert_eq!(responses[1]["result"], "trin 0.0.1-alpha");
+        assert!(responses[2]["error"]
+            .as_str()
+            .unwrap()
+            .contains("Unavailable in offline mode"));
+        assert!(responses[3]["error"]
+            .as_str()
+            .unwrap()
+            .contains("Unsupported discv5 endpoint"));
+        assert!(responses[4]["error"]
+            .as_str()
+            .unwrap()
+            .contains("Invalid request"));
+    }
+
+    #[test]
+    fn test_batch_of_notifications_gets_no_response() {
+        let (portal_tx, _portal_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let batch = json!([{"jsonrpc": "2.0", "method": "web3_clientVersion"}]).to_string();
+        let mut response = vec![];
+        serve_ipc_client(&mut batch.as_bytes(), &mut response, None, portal_tx);
+        assert!(response.is_empty());
+    }
 }
@@ -1,8 +1,11 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod bench;
 pub mod cli;
 pub mod jsonrpc;
 pub mod portalnet;
+pub mod selftest;
 pub mod socket;
+pub mod types;
 pub mod utils;
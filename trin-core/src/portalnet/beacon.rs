@@ -0,0 +1,214 @@
+//! Content types of the beacon chain light client subnetwork. Only their structure is in place:
+//! content is keyed, encoded and routed, but not yet verified against the beacon chain.
+
+use ssz::{Decode, Encode};
+use ssz_derive::{Decode, Encode};
+
+use super::validation::ContentValidator;
+
+/// Talk protocol id of the beacon light client subnetwork, from the portal wire spec.
+pub const BEACON_PROTOCOL_ID: [u8; 2] = [0x50, 0x1a];
+
+const BOOTSTRAP_SELECTOR: u8 = 0x10;
+const UPDATES_BY_RANGE_SELECTOR: u8 = 0x11;
+
+/// Beacon content key: a selector byte, then the SSZ encoding of what identifies the content.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BeaconContentKey {
+    /// The bootstrap for the block with the given root
+    LightClientBootstrap(BootstrapKey),
+    /// `count` consecutive sync committee period updates, from `start_period` on
+    LightClientUpdatesByRange(UpdatesByRangeKey),
+}
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct BootstrapKey {
+    pub block_root: [u8; 32],
+}
+
+#[derive(Clone, Debug, PartialEq, Encode, Decode)]
+pub struct UpdatesByRangeKey {
+    pub start_period: u64,
+    pub count: u64,
+}
+
+impl BeaconContentKey {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (selector, mut body) = match self {
+            BeaconContentKey::LightClientBootstrap(key) => (BOOTSTRAP_SELECTOR, key.as_ssz_bytes()),
+            BeaconContentKey::LightClientUpdatesByRange(key) => {
+                (UPDATES_BY_RANGE_SELECTOR, key.as_ssz_bytes())
+            }
+        };
+        let mut bytes = vec![selector];
+        bytes.append(&mut body);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let (selector, body) = bytes
+            .split_first()
+            .ok_or_else(|| "Empty beacon content key".to_owned())?;
+        let invalid = |e| format!("Invalid beacon content key: {:?}", e);
+        match *selector {
+            BOOTSTRAP_SELECTOR => Ok(BeaconContentKey::LightClientBootstrap(
+                BootstrapKey::from_ssz_bytes(body).map_err(invalid)?,
+            )),
+            UPDATES_BY_RANGE_SELECTOR => Ok(BeaconContentKey::LightClientUpdatesByRange(
+                UpdatesByRangeKey::from_ssz_bytes(body).map_err(invalid)?,
+            )),
+            selector => Err(format!("Unknown beacon content selector: {}", selector)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Encode, Decode)]
+pub struct BeaconBlockHeader {
+    pub slot: u64,
+    pub proposer_index: u64,
+    pub parent_root: [u8; 32],
+    pub state_root: [u8; 32],
+    pub body_root: [u8; 32],
+}
+
+/// Placeholder for the light client bootstrap. The sync committee is kept as its raw SSZ until
+/// it can be verified.
+#[derive(Clone, Debug, Default, PartialEq, Encode, Decode)]
+pub struct LightClientBootstrap {
+    pub header: BeaconBlockHeader,
+    pub current_sync_committee: Vec<u8>,
+    pub current_sync_committee_branch: Vec<[u8; 32]>,
+}
+
+/// Placeholder for a light client update. The sync committee and aggregate are kept as their raw
+/// SSZ until they can be verified.
+#[derive(Clone, Debug, Default, PartialEq, Encode, Decode)]
+pub struct LightClientUpdate {
+    pub attested_header: BeaconBlockHeader,
+    pub next_sync_committee: Vec<u8>,
+    pub next_sync_committee_branch: Vec<[u8; 32]>,
+    pub finalized_header: BeaconBlockHeader,
+    pub finality_branch: Vec<[u8; 32]>,
+    pub sync_aggregate: Vec<u8>,
+    pub signature_slot: u64,
+}
+
+/// How thoroughly beacon content was checked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verification {
+    /// The content decodes as the type its key names, but its proofs and signatures weren't
+    /// checked.
+    Unverified,
+}
+
+/// Checks that the content decodes as the type its key names.
+pub fn validate_beacon_content(content_key: &[u8], content: &[u8]) -> Result<Verification, String> {
+    let invalid = |e| format!("Invalid beacon content: {:?}", e);
+    match BeaconContentKey::from_bytes(content_key)? {
+        BeaconContentKey::LightClientBootstrap(_) => {
+            LightClientBootstrap::from_ssz_bytes(content).map_err(invalid)?;
+        }
+        BeaconContentKey::LightClientUpdatesByRange(_) => {
+            Vec::<LightClientUpdate>::from_ssz_bytes(content).map_err(invalid)?;
+        }
+    }
+    // TODO: verify the Merkle branches and sync committee signatures against a trusted block
+    // root. Until then, well-formed content is only ever unverified.
+    Ok(Verification::Unverified)
+}
+
+/// Accepts well-formed beacon content, unverified.
+#[derive(Clone, Debug, Default)]
+pub struct BeaconValidator;
+
+impl ContentValidator for BeaconValidator {
+    fn validate(&self, content_key: &[u8], content: &[u8]) -> Result<(), String> {
+        validate_beacon_content(content_key, content).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(slot: u64) -> BeaconBlockHeader {
+        BeaconBlockHeader {
+            slot,
+            proposer_index: 3,
+            parent_root: [1; 32],
+            state_root: [2; 32],
+            body_root: [3; 32],
+        }
+    }
+
+    #[test]
+    fn test_content_keys_round_trip() {
+        let bootstrap = BeaconContentKey::LightClientBootstrap(BootstrapKey {
+            block_root: [0xab; 32],
+        });
+        let bytes = bootstrap.to_bytes();
+        assert_eq!(bytes[0], 0x10);
+        assert_eq!(&bytes[1..], &[0xab; 32][..]);
+        assert_eq!(BeaconContentKey::from_bytes(&bytes), Ok(bootstrap));
+
+        let updates = BeaconContentKey::LightClientUpdatesByRange(UpdatesByRangeKey {
+            start_period: 0x0102,
+            count: 4,
+        });
+        let bytes = updates.to_bytes();
+        assert_eq!(
+            bytes,
+            vec![0x11, 0x02, 0x01, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(BeaconContentKey::from_bytes(&bytes), Ok(updates));
+
+        assert!(BeaconContentKey::from_bytes(&[]).is_err());
+        assert!(BeaconContentKey::from_bytes(&[0x12]).is_err());
+        assert!(BeaconContentKey::from_bytes(&[0x10, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_containers_round_trip() {
+        let bootstrap = LightClientBootstrap {
+            header: header(7),
+            current_sync_committee: vec![9; 100],
+            current_sync_committee_branch: vec![[4; 32], [5; 32]],
+        };
+        let bytes = bootstrap.as_ssz_bytes();
+        assert_eq!(LightClientBootstrap::from_ssz_bytes(&bytes), Ok(bootstrap));
+
+        let updates = vec![
+            LightClientUpdate {
+                attested_header: header(8),
+                next_sync_committee: vec![6; 10],
+                next_sync_committee_branch: vec![[7; 32]],
+                finalized_header: header(4),
+                finality_branch: vec![[8; 32], [9; 32]],
+                sync_aggregate: vec![1, 2, 3],
+                signature_slot: 9,
+            },
+            LightClientUpdate::default(),
+        ];
+        let bytes = updates.as_ssz_bytes();
+        assert_eq!(
+            Vec::<LightClientUpdate>::from_ssz_bytes(&bytes),
+            Ok(updates)
+        );
+    }
+
+    #[test]
+    fn test_well_formed_content_is_unverified() {
+        let key = BeaconContentKey::LightClientBootstrap(BootstrapKey {
+            block_root: [0xab; 32],
+        })
+        .to_bytes();
+        let content = LightClientBootstrap::default().as_ssz_bytes();
+
+        assert_eq!(
+            validate_beacon_content(&key, &content),
+            Ok(Verification::Unverified)
+        );
+        assert!(validate_beacon_content(&key, &[1, 2, 3]).is_err());
+        assert!(BeaconValidator.validate(&key, &content).is_ok());
+    }
+}
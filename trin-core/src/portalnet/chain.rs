@@ -0,0 +1,330 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ethereum_types::H256;
+use parking_lot::Mutex;
+
+use super::storage::CanonicalIndex;
+use crate::types::header::Header;
+
+/// A block the node trusts without validation, used as the anchor of its canonical chain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrustedCheckpoint {
+    pub number: u64,
+    pub hash: H256,
+}
+
+impl FromStr for TrustedCheckpoint {
+    type Err = String;
+
+    /// Parse a checkpoint written as `<number>:<hash>`, with the hash in hex.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, hash) = match s.split_once(':') {
+            Some(parts) => parts,
+            None => return Err(format!("Expected <number>:<hash>, got {}", s)),
+        };
+        let number = number
+            .parse()
+            .map_err(|e| format!("Invalid checkpoint block number {}: {}", number, e))?;
+        let hash = hex::decode(hash.trim_start_matches("0x"))
+            .map_err(|e| format!("Invalid checkpoint hash {}: {}", hash, e))?;
+        if hash.len() != 32 {
+            return Err(format!(
+                "Invalid checkpoint hash length: {}, expected 32 bytes",
+                hash.len()
+            ));
+        }
+        Ok(Self {
+            number,
+            hash: H256::from_slice(&hash),
+        })
+    }
+}
+
+/// Where a run of headers comes from, which decides whether it's checked against the clock.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderSource {
+    /// Headers at the tip of the chain. The newest may not be too far in the future.
+    Live,
+    /// Historical headers synced from the checkpoint, which aren't checked against the clock.
+    Backfill,
+}
+
+/// Accepts headers into the canonical index only when they chain back to the indexed headers,
+/// anchored at the trusted checkpoint if one is configured.
+pub struct HeaderChain {
+    canonical_index: Arc<CanonicalIndex>,
+    checkpoint: Option<TrustedCheckpoint>,
+    /// How far ahead of the local clock a live header's timestamp may be
+    max_future_drift: Duration,
+    // Serializes validating a segment and indexing it.
+    write_lock: Mutex<()>,
+}
+
+impl HeaderChain {
+    pub fn new(
+        canonical_index: Arc<CanonicalIndex>,
+        checkpoint: Option<TrustedCheckpoint>,
+        max_future_drift: Duration,
+    ) -> Result<Self, String> {
+        if let Some(checkpoint) = checkpoint {
+            match canonical_index.block_hash(checkpoint.number)? {
+                Some(hash) if hash != checkpoint.hash => {
+                    return Err(format!(
+                        "Trusted checkpoint {:?} conflicts with indexed block hash {:?}",
+                        checkpoint, hash
+                    ))
+                }
+                Some(_) => {}
+                None => canonical_index.insert(checkpoint.number, checkpoint.hash)?,
+            }
+        }
+        Ok(Self {
+            canonical_index,
+            checkpoint,
+            max_future_drift,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    pub fn checkpoint(&self) -> Option<TrustedCheckpoint> {
+        self.checkpoint
+    }
+
+    /// Validate a run of consecutive headers, in ascending order, and index them as canonical.
+    /// The run must agree with every block it overlaps in the index, and connect to it: either
+    /// by overlapping, or by its first header being the child of an indexed block. Without a
+    /// checkpoint, the first run is only checked to be linked. Timestamps must strictly
+    /// increase, no header may use more gas than its limit, and for live headers the newest may
+    /// be at most `max_future_drift` ahead.
+    pub fn insert_headers(&self, headers: &[Header], source: HeaderSource) -> Result<(), String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        self.insert_headers_at(headers, source, now)
+    }
+
+    fn insert_headers_at(
+        &self,
+        headers: &[Header],
+        source: HeaderSource,
+        now: u64,
+    ) -> Result<(), String> {
+        let _guard = self.write_lock.lock();
+        let (first, last) = match (headers.first(), headers.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(()),
+        };
+        for header in headers {
+            header.check_gas_used()?;
+        }
+        for pair in headers.windows(2) {
+            if pair[1].number != pair[0].number + 1 || pair[1].parent_hash != pair[0].hash() {
+                return Err(format!(
+                    "Header {} is not the child of header {}",
+                    pair[1].number, pair[0].number
+                ));
+            }
+            if pair[1].timestamp <= pair[0].timestamp {
+                return Err(format!(
+                    "Header {} has timestamp {}, not after its parent's {}",
+                    pair[1].number, pair[1].timestamp, pair[0].timestamp
+                ));
+            }
+        }
+        let latest_allowed = now.saturating_add(self.max_future_drift.as_secs());
+        if source == HeaderSource::Live && last.timestamp > latest_allowed {
+            return Err(format!(
+                "Header {} has timestamp {}, more than {}s in the future",
+                last.number,
+                last.timestamp,
+                self.max_future_drift.as_secs()
+            ));
+        }
+
+        let mut connected = match first.number.checked_sub(1) {
+            Some(parent_number) => {
+                self.canonical_index.block_hash(parent_number)? == Some(first.parent_hash)
+            }
+            None => false,
+        };
+        let hashes: Vec<H256> = headers.iter().map(Header::hash).collect();
+        for (header, hash) in headers.iter().zip(hashes.iter()) {
+            match self.canonical_index.block_hash(header.number)? {
+                Some(indexed) if indexed == *hash => connected = true,
+                Some(indexed) => {
+                    return Err(format!(
+                        "Header {} has hash {:?}, but the canonical chain has {:?}",
+                        header.number, hash, indexed
+                    ))
+                }
+                None => {}
+            }
+        }
+        let anchored = connected
+            || (self.checkpoint.is_none()
+                && self.canonical_index.highest_block_number()?.is_none());
+        if !anchored {
+            return Err(format!(
+                "Headers {}..={} don't chain back to the canonical chain",
+                first.number,
+                first.number + headers.len() as u64 - 1
+            ));
+        }
+
+        for (header, hash) in headers.iter().zip(hashes) {
+            self.canonical_index.insert(header.number, hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::storage::MemoryBackend;
+
+    /// Returns `length` linked headers starting at block `start`, distinguished by `extra_data`.
+    fn chain(parent_hash: H256, start: u64, length: u64, extra_data: &[u8]) -> Vec<Header> {
+        let mut headers: Vec<Header> = vec![];
+        for number in start..start + length {
+            let parent_hash = headers.last().map_or(parent_hash, Header::hash);
+            headers.push(Header {
+                parent_hash,
+                number,
+                timestamp: number * 12,
+                extra_data: extra_data.to_vec(),
+                ..Default::default()
+            });
+        }
+        headers
+    }
+
+    fn header_chain(checkpoint: &Header) -> HeaderChain {
+        let canonical_index = Arc::new(CanonicalIndex::new(Arc::new(MemoryBackend::default())));
+        let checkpoint = TrustedCheckpoint {
+            number: checkpoint.number,
+            hash: checkpoint.hash(),
+        };
+        HeaderChain::new(canonical_index, Some(checkpoint), Duration::from_secs(15)).unwrap()
+    }
+
+    #[test]
+    fn test_chain_reaching_checkpoint_is_accepted() {
+        let canonical = chain(H256::zero(), 90, 20, b"canonical");
+        let headers = header_chain(&canonical[10]);
+
+        headers
+            .insert_headers(&canonical[..11], HeaderSource::Backfill)
+            .unwrap();
+        headers
+            .insert_headers(&canonical[11..], HeaderSource::Live)
+            .unwrap();
+
+        assert_eq!(
+            headers.canonical_index.block_hash(95).unwrap(),
+            Some(canonical[5].hash())
+        );
+        assert_eq!(
+            headers.canonical_index.highest_block_number().unwrap(),
+            Some(109)
+        );
+    }
+
+    #[test]
+    fn test_fork_before_checkpoint_is_rejected() {
+        let canonical = chain(H256::zero(), 90, 20, b"canonical");
+        let headers = header_chain(&canonical[10]);
+        let fork = chain(canonical[4].hash(), 95, 15, b"fork");
+
+        assert!(headers
+            .insert_headers(&fork, HeaderSource::Backfill)
+            .is_err());
+        assert!(headers
+            .insert_headers(&fork[6..], HeaderSource::Live)
+            .is_err());
+        assert_eq!(headers.canonical_index.block_hash(95).unwrap(), None);
+        assert_eq!(
+            headers.canonical_index.highest_block_number().unwrap(),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn test_unlinked_headers_are_rejected() {
+        let canonical = chain(H256::zero(), 90, 20, b"canonical");
+        let headers = header_chain(&canonical[10]);
+
+        let mut unlinked = canonical[10..].to_vec();
+        unlinked.remove(3);
+        assert!(headers
+            .insert_headers(&unlinked, HeaderSource::Backfill)
+            .is_err());
+    }
+
+    #[test]
+    fn test_non_increasing_timestamp_is_rejected() {
+        let mut canonical = chain(H256::zero(), 90, 20, b"canonical");
+        canonical[12].timestamp = canonical[11].timestamp;
+        let headers = header_chain(&canonical[10]);
+
+        assert!(headers
+            .insert_headers(&canonical[10..], HeaderSource::Backfill)
+            .is_err());
+    }
+
+    #[test]
+    fn test_header_over_gas_limit_is_rejected() {
+        let mut canonical = chain(H256::zero(), 90, 20, b"canonical");
+        canonical[19].gas_used = canonical[19].gas_limit + 1;
+        let headers = header_chain(&canonical[10]);
+
+        let error = headers
+            .insert_headers(&canonical[10..], HeaderSource::Backfill)
+            .unwrap_err();
+        assert_eq!(error, "Header 109 uses 1 gas, over its gas limit of 0");
+        assert_eq!(
+            headers.canonical_index.highest_block_number().unwrap(),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn test_live_header_too_far_in_future_is_rejected() {
+        let canonical = chain(H256::zero(), 90, 20, b"canonical");
+        let headers = header_chain(&canonical[10]);
+        let newest = canonical[19].timestamp;
+
+        assert!(headers
+            .insert_headers_at(&canonical[10..], HeaderSource::Live, newest - 16)
+            .is_err());
+        headers
+            .insert_headers_at(&canonical[10..], HeaderSource::Live, newest - 15)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_historical_header_skips_future_check() {
+        let canonical = chain(H256::zero(), 90, 20, b"canonical");
+        let headers = header_chain(&canonical[10]);
+
+        // A local clock far behind the chain doesn't stop a backfill.
+        headers
+            .insert_headers_at(&canonical[..11], HeaderSource::Backfill, 0)
+            .unwrap();
+        assert_eq!(
+            headers.canonical_index.block_hash(90).unwrap(),
+            Some(canonical[0].hash())
+        );
+    }
+
+    #[test]
+    fn test_parse_checkpoint() {
+        let hash = H256::repeat_byte(0xab);
+        let checkpoint: TrustedCheckpoint = format!("100:0x{}", hex::encode(hash)).parse().unwrap();
+        assert_eq!(checkpoint, TrustedCheckpoint { number: 100, hash });
+        assert!("100".parse::<TrustedCheckpoint>().is_err());
+        assert!("100:0xabcd".parse::<TrustedCheckpoint>().is_err());
+    }
+}
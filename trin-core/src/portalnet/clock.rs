@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+#[cfg(test)]
+use parking_lot::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+
+/// Source of the current time for TTL, cooldown and reaper logic, so that tests can move time
+/// forward instead of sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The wall clock, which production uses.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that stands still until it's advanced, starting at the time it's created.
+#[cfg(test)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+}
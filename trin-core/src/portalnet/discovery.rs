@@ -1,11 +1,322 @@
 #![allow(dead_code)]
 
+use super::clock::{Clock, SystemClock};
+use super::metrics::{
+    peers_in_bucket, peers_with_client, Metrics, PEERS_EVICTED_IDLE, PEERS_EVICTED_PEER_CAP,
+};
+use super::peer_filter::PeerFilter;
 use super::types::HexData;
 use super::Enr;
+use crate::utils::{log2_distance, xor_two_values};
 use discv5::enr::{CombinedKey, EnrBuilder, NodeId};
-use discv5::{Discv5, Discv5Config};
-use log::info;
-use std::net::{IpAddr, SocketAddr};
+use discv5::{Discv5, Discv5Config, RequestError};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// ENR key holding a bitmap of the portal subnetworks a node serves.
+pub const PORTAL_SUBNETWORKS_ENR_KEY: &str = "pk";
+
+/// ENR key holding the name and version of the client a node runs, like `trin/v0.1.0`.
+pub const CLIENT_ENR_KEY: &str = "c";
+
+/// The client this node advertises under `CLIENT_ENR_KEY`.
+pub const CLIENT_VERSION: &str = concat!("trin/v", env!("CARGO_PKG_VERSION"));
+
+/// How peers without a readable client entry are counted.
+pub const UNKNOWN_CLIENT: &str = "unknown";
+
+/// A portal subnetwork, as advertised in the ENR.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Subnetwork {
+    History,
+    State,
+}
+
+impl Subnetwork {
+    fn bit(&self) -> u8 {
+        match self {
+            Subnetwork::History => 0b0000_0001,
+            Subnetwork::State => 0b0000_0010,
+        }
+    }
+}
+
+pub fn subnetwork_bitmap(subnetworks: &[Subnetwork]) -> u8 {
+    subnetworks
+        .iter()
+        .fold(0, |bitmap, subnetwork| bitmap | subnetwork.bit())
+}
+
+/// Returns whether the ENR advertises serving the subnetwork. ENRs without the portal key don't.
+pub fn enr_serves_subnetwork(enr: &Enr, subnetwork: Subnetwork) -> bool {
+    match enr.get(PORTAL_SUBNETWORKS_ENR_KEY) {
+        Some(bitmap) => bitmap
+            .first()
+            .map_or(false, |bitmap| bitmap & subnetwork.bit() != 0),
+        None => false,
+    }
+}
+
+/// Returns the client the ENR advertises, if it has a client entry holding UTF-8 text.
+pub fn enr_client(enr: &Enr) -> Option<String> {
+    let value = enr.get(CLIENT_ENR_KEY)?;
+    // The entry may come RLP-encoded as a byte string, whose header byte is never printable text.
+    let text = match value.first() {
+        Some(header) if *header >= 0x80 => rlp::Rlp::new(value).data().ok()?,
+        _ => value,
+    };
+    String::from_utf8(text.to_vec()).ok()
+}
+
+/// Returns whether the ENR advertises a UDP endpoint requests can be sent to. ENRs without one
+/// may still be kept in the routing table, in case the peer later updates its record. An
+/// IPv4-mapped address in the `ip6` field mixes the two address families, and isn't dialed.
+pub fn is_dialable(enr: &Enr) -> bool {
+    let usable = |ip: Option<IpAddr>, port: Option<u16>| match (ip, port) {
+        (Some(ip), Some(port)) => !ip.is_unspecified() && port != 0,
+        _ => false,
+    };
+    let ip6 = enr.ip6().filter(|ip| ipv4_mapped(ip).is_none());
+    usable(enr.ip().map(IpAddr::V4), enr.udp()) || usable(ip6.map(IpAddr::V6), enr.udp6())
+}
+
+/// Returns whether the address is only reachable on a private network or the local host:
+/// RFC 1918 and unique local ranges, loopback and link-local addresses.
+pub fn is_private_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ipv4_mapped(ip) {
+            Some(ip) => is_private_ip(&IpAddr::V4(ip)),
+            None => {
+                let first_segment = ip.segments()[0];
+                ip.is_loopback()
+                    || first_segment & 0xfe00 == 0xfc00
+                    || first_segment & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Returns whether none of the addresses the ENR advertises is private. Matches the signature of
+/// the discv5 table filter.
+pub fn has_no_private_ips(enr: &Enr) -> bool {
+    !enr.ip()
+        .map(IpAddr::V4)
+        .into_iter()
+        .chain(enr.ip6().map(IpAddr::V6))
+        .any(|ip| is_private_ip(&ip))
+}
+
+/// Returns the IPv4 address an IPv4-mapped IPv6 address, like `::ffff:10.0.0.1`, stands for.
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+/// Returns the address in the family it belongs in the ENR: an IPv4-mapped IPv6 address, as a
+/// dual-stack socket reports IPv4 peers and interfaces, is converted to plain IPv4.
+pub fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip6) => ipv4_mapped(&ip6).map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Leading bytes of a versioned routing table snapshot. Older snapshots, from before the format
+/// was versioned, are one base64 ENR per line.
+const SNAPSHOT_MAGIC: &[u8] = b"trrt";
+
+/// Version of the snapshot format written. Later versions only append: fields at the end of each
+/// record, and data after the records, both of which earlier versions skip.
+const SNAPSHOT_VERSION: u16 = 1;
+
+/// Read the ENRs saved in a routing table snapshot. A missing snapshot holds no ENRs, a corrupt
+/// one is skipped with a warning, and ENRs that don't parse are skipped with a warning.
+///
+/// The snapshot is `SNAPSHOT_MAGIC`, the format version as a big-endian u16 and the record count
+/// as a big-endian u32, followed by the records. Each record is its length as a big-endian u32,
+/// then the base64 ENR prefixed with its length as a big-endian u16.
+pub fn read_snapshot(path: &Path) -> Result<Vec<Enr>, String> {
+    let snapshot = match fs::read(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("Failed to read routing table snapshot: {}", e)),
+    };
+    let enrs = match snapshot.strip_prefix(SNAPSHOT_MAGIC) {
+        Some(versioned) => parse_snapshot(versioned),
+        None => String::from_utf8(snapshot)
+            .map(|snapshot| parse_unversioned_snapshot(&snapshot))
+            .map_err(|_| "neither a versioned snapshot nor text".to_string()),
+    };
+    match enrs {
+        Ok(enrs) => Ok(enrs),
+        Err(e) => {
+            warn!(
+                "Skipping corrupt routing table snapshot {}: {}",
+                path.display(),
+                e
+            );
+            Ok(vec![])
+        }
+    }
+}
+
+fn parse_snapshot(mut snapshot: &[u8]) -> Result<Vec<Enr>, String> {
+    let version = take_u16(&mut snapshot)?;
+    if version == 0 {
+        return Err("unknown format version 0".to_string());
+    }
+    let count = take_u32(&mut snapshot)?;
+    let mut enrs = vec![];
+    for _ in 0..count {
+        let length = take_u32(&mut snapshot)? as usize;
+        let mut record = take(&mut snapshot, length)?;
+        let enr_length = take_u16(&mut record)? as usize;
+        let enr = take(&mut record, enr_length)?;
+        // Whatever is left of the record holds fields of later versions.
+        match std::str::from_utf8(enr)
+            .map_err(|e| e.to_string())
+            .and_then(str::parse::<Enr>)
+        {
+            Ok(enr) => enrs.push(enr),
+            Err(e) => warn!("Skipping invalid ENR in routing table snapshot: {}", e),
+        }
+    }
+    // Anything after the records is data of later versions.
+    Ok(enrs)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8], String> {
+    if bytes.len() < length {
+        return Err(format!(
+            "truncated, {} bytes left where {} are needed",
+            bytes.len(),
+            length
+        ));
+    }
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn take_u16(bytes: &mut &[u8]) -> Result<u16, String> {
+    let taken = take(bytes, 2)?;
+    Ok(u16::from_be_bytes([taken[0], taken[1]]))
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Result<u32, String> {
+    let taken = take(bytes, 4)?;
+    Ok(u32::from_be_bytes([taken[0], taken[1], taken[2], taken[3]]))
+}
+
+fn parse_unversioned_snapshot(snapshot: &str) -> Vec<Enr> {
+    let mut enrs = vec![];
+    for line in snapshot
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        match line.parse::<Enr>() {
+            Ok(enr) => enrs.push(enr),
+            Err(e) => warn!("Skipping invalid ENR in routing table snapshot: {}", e),
+        }
+    }
+    enrs
+}
+
+/// Save ENRs as a routing table snapshot. The snapshot is written to a temporary file first, so
+/// a crash mid-write leaves the previous snapshot intact.
+pub fn write_snapshot(path: &Path, enrs: &[Enr]) -> Result<(), String> {
+    let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+    snapshot.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+    snapshot.extend_from_slice(&(enrs.len() as u32).to_be_bytes());
+    for enr in enrs {
+        let enr = enr.to_base64();
+        snapshot.extend_from_slice(&(2 + enr.len() as u32).to_be_bytes());
+        snapshot.extend_from_slice(&(enr.len() as u16).to_be_bytes());
+        snapshot.extend_from_slice(enr.as_bytes());
+    }
+    let partial = path.with_extension("partial");
+    fs::write(&partial, snapshot)
+        .and_then(|_| fs::rename(&partial, path))
+        .map_err(|e| format!("Failed to write routing table snapshot: {}", e))
+}
+
+/// Drop ENRs that share a node id, keeping the one with the highest sequence number. The order
+/// in which node ids first appear is preserved.
+pub fn dedupe_enrs(enrs: Vec<Enr>) -> Vec<Enr> {
+    let mut deduped: Vec<Enr> = vec![];
+    let mut positions: HashMap<NodeId, usize> = HashMap::new();
+    for enr in enrs {
+        match positions.get(&enr.node_id()) {
+            Some(&position) => {
+                debug!("Skipping duplicate bootnode {}", enr.node_id());
+                if enr.seq() > deduped[position].seq() {
+                    deduped[position] = enr;
+                }
+            }
+            None => {
+                positions.insert(enr.node_id(), deduped.len());
+                deduped.push(enr);
+            }
+        }
+    }
+    deduped
+}
+
+/// Caps how many requests are outstanding to each peer at once, so that one slow or stuck peer
+/// can't take up every request a lookup makes. Requests beyond a peer's cap wait for one of its
+/// earlier requests to finish; other peers' requests go ahead.
+#[derive(Clone)]
+pub struct PeerRequestLimiter {
+    max_requests: usize,
+    /// Each peer with requests outstanding or waiting
+    peers: Arc<Mutex<HashMap<NodeId, Arc<Semaphore>>>>,
+}
+
+impl PeerRequestLimiter {
+    /// Allow up to `max_requests` requests to each peer at once. At least one is allowed.
+    pub fn new(max_requests: usize) -> Self {
+        Self {
+            max_requests: max_requests.max(1),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Wait for a request slot for the peer, held until the permit is dropped.
+    pub async fn acquire(&self, node_id: &NodeId) -> OwnedSemaphorePermit {
+        let permits = {
+            let mut peers = self.peers.lock();
+            // Permits and waiters hold the semaphore too, so a peer whose semaphore only the map
+            // holds has nothing outstanding and no longer needs tracking.
+            peers.retain(|_, permits| Arc::strong_count(permits) > 1);
+            peers
+                .entry(*node_id)
+                .or_insert_with(|| Arc::new(Semaphore::new(self.max_requests)))
+                .clone()
+        };
+        if permits.available_permits() == 0 {
+            debug!(
+                "Request to {} waits behind {} outstanding requests",
+                node_id, self.max_requests
+            );
+        }
+        permits
+            .acquire_owned()
+            .await
+            .expect("peer request semaphores are never closed")
+    }
+}
 
 #[derive(Clone)]
 pub struct Config {
@@ -14,6 +325,26 @@ pub struct Config {
     pub discv5_config: Discv5Config,
     pub bootnode_enrs: Vec<Enr>,
     pub private_key: Option<HexData>,
+    /// Subnetworks to advertise in the local ENR
+    pub subnetworks: Vec<Subnetwork>,
+    /// Which peers, by IP address, may be added to the routing table and send requests
+    pub peer_filter: PeerFilter,
+    /// File the routing table is saved to, and preloaded from on startup
+    pub routing_table_snapshot: Option<PathBuf>,
+    /// Most peers to keep in the routing table, not counting bootnodes
+    pub max_peers: Option<usize>,
+    /// How long a peer may go without answering a request before it's reaped from the routing
+    /// table. Bootnodes are never reaped.
+    pub max_peer_idle: Option<Duration>,
+    /// Where peer evictions are counted, by reason
+    pub metrics: Arc<Metrics>,
+    /// Never add peers advertising private, loopback or link-local addresses to the routing
+    /// table, whether they're discovered or added directly
+    pub filter_private_ips: bool,
+    /// What peers' idle time is measured against
+    pub clock: Arc<dyn Clock>,
+    /// Most requests outstanding to any one peer at once; unlimited without it
+    pub max_requests_per_peer: Option<usize>,
 }
 
 impl Default for Config {
@@ -24,16 +355,99 @@ impl Default for Config {
             discv5_config: Discv5Config::default(),
             bootnode_enrs: vec![],
             private_key: None,
+            subnetworks: vec![],
+            peer_filter: PeerFilter::default(),
+            routing_table_snapshot: None,
+            max_peers: None,
+            max_peer_idle: None,
+            metrics: Arc::new(Metrics::default()),
+            filter_private_ips: false,
+            clock: Arc::new(SystemClock),
+            max_requests_per_peer: None,
+        }
+    }
+}
+
+/// Why a peer was removed from the routing table.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionReason {
+    /// It went too long without answering a request.
+    Idle,
+    /// A closer peer needed its place under the peer cap.
+    PeerCap,
+}
+
+impl EvictionReason {
+    /// Returns the metric counting evictions for this reason.
+    pub fn metric(&self) -> &'static str {
+        match self {
+            EvictionReason::Idle => PEERS_EVICTED_IDLE,
+            EvictionReason::PeerCap => PEERS_EVICTED_PEER_CAP,
+        }
+    }
+}
+
+impl fmt::Display for EvictionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvictionReason::Idle => write!(f, "idle"),
+            EvictionReason::PeerCap => write!(f, "peer_cap"),
         }
     }
 }
 
 pub type ProtocolRequest = Vec<u8>;
 
+/// Why a request to a peer failed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiscoveryError {
+    /// The peer didn't answer in time. It may only be busy, so the request is worth retrying.
+    NoResponse,
+    /// The request couldn't be made, or the peer's answer broke the protocol. Retrying won't
+    /// help, and the peer may be penalized.
+    ProtocolError(String),
+}
+
+impl From<RequestError> for DiscoveryError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::Timeout => DiscoveryError::NoResponse,
+            error => DiscoveryError::ProtocolError(format!("{:?}", error)),
+        }
+    }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::NoResponse => write!(f, "no response"),
+            DiscoveryError::ProtocolError(e) => write!(f, "protocol error: {}", e),
+        }
+    }
+}
+
+impl From<DiscoveryError> for String {
+    fn from(error: DiscoveryError) -> Self {
+        error.to_string()
+    }
+}
+
 pub struct Discovery {
     pub discv5: Discv5,
     /// Indicates if the discv5 service has been started
     pub started: bool,
+    peer_filter: PeerFilter,
+    routing_table_snapshot: Option<PathBuf>,
+    max_peers: Option<usize>,
+    /// Bootnodes are never evicted to make room under `max_peers`, nor reaped when idle
+    bootnode_ids: HashSet<NodeId>,
+    max_peer_idle: Option<Duration>,
+    /// When each peer last answered a request, or was added if it hasn't answered yet
+    last_response: Mutex<HashMap<NodeId, Instant>>,
+    metrics: Arc<Metrics>,
+    filter_private_ips: bool,
+    clock: Arc<dyn Clock>,
+    request_limiter: Option<PeerRequestLimiter>,
 }
 
 impl Discovery {
@@ -45,8 +459,15 @@ impl Discovery {
 
         let enr = {
             let mut builder = EnrBuilder::new("v4");
-            builder.ip(config.listen_address);
-            builder.udp(config.listen_port);
+            match normalize_ip(config.listen_address) {
+                ip @ IpAddr::V4(_) => builder.ip(ip).udp(config.listen_port),
+                ip @ IpAddr::V6(_) => builder.ip(ip).udp6(config.listen_port),
+            };
+            builder.add_value(
+                PORTAL_SUBNETWORKS_ENR_KEY,
+                &vec![subnetwork_bitmap(&config.subnetworks)],
+            );
+            builder.add_value(CLIENT_ENR_KEY, &CLIENT_VERSION.as_bytes().to_vec());
             builder.build(&enr_key).unwrap()
         };
 
@@ -55,20 +476,229 @@ impl Discovery {
             enr, enr
         );
 
-        let mut discv5 = Discv5::new(enr, enr_key, config.discv5_config)
+        let mut discv5_config = config.discv5_config;
+        if config.filter_private_ips {
+            discv5_config.table_filter = has_no_private_ips;
+        }
+        let discv5 = Discv5::new(enr, enr_key, discv5_config)
             .map_err(|e| format!("Failed to create discv5 instance: {}", e))?;
+        let discovery = Self {
+            discv5,
+            started: false,
+            peer_filter: config.peer_filter,
+            routing_table_snapshot: config.routing_table_snapshot,
+            max_peers: config.max_peers,
+            max_peer_idle: config.max_peer_idle,
+            last_response: Mutex::new(HashMap::new()),
+            metrics: config.metrics,
+            filter_private_ips: config.filter_private_ips,
+            clock: config.clock,
+            request_limiter: config.max_requests_per_peer.map(PeerRequestLimiter::new),
+            bootnode_ids: config
+                .bootnode_enrs
+                .iter()
+                .map(|enr| enr.node_id())
+                .collect(),
+        };
 
-        for enr in config.bootnode_enrs {
+        for enr in dedupe_enrs(config.bootnode_enrs) {
             info!("Adding bootnode {}", enr);
-            discv5
-                .add_enr(enr)
-                .map_err(|e| format!("Failed to add enr: {}", e))?;
+            discovery.add_enr(enr)?;
         }
 
-        Ok(Self {
-            discv5,
-            started: false,
-        })
+        if let Some(path) = &discovery.routing_table_snapshot {
+            let mut added = 0;
+            for enr in dedupe_enrs(read_snapshot(path)?) {
+                if discovery.add_enr(enr)? {
+                    added += 1;
+                }
+            }
+            info!("Preloaded {} peers from {}", added, path.display());
+        }
+
+        Ok(discovery)
+    }
+
+    /// Add a peer to the routing table. The local node's own ENR is skipped with a warning, and
+    /// peers refused by the peer filter or advertising private addresses under the private IP
+    /// filter are skipped; all return `false`. Once the table holds
+    /// `max_peers` peers besides the bootnodes, the farthest of them and the new peer is dropped.
+    pub fn add_enr(&self, enr: Enr) -> Result<bool, String> {
+        if enr.node_id() == self.local_enr().node_id() {
+            warn!("Skipping ENR with the local node id: {}", enr);
+            return Ok(false);
+        }
+        if !self.peer_filter.allows_enr(&enr) {
+            debug!("Skipping ENR refused by the peer filter: {}", enr);
+            return Ok(false);
+        }
+        if self.filter_private_ips && !has_no_private_ips(&enr) {
+            debug!("Skipping ENR with a private address: {}", enr);
+            return Ok(false);
+        }
+        if !self.make_room_for(&enr.node_id()) {
+            debug!(
+                "Skipping ENR farther than every peer at the peer cap: {}",
+                enr
+            );
+            return Ok(false);
+        }
+        let node_id = enr.node_id();
+        let client = enr_client(&enr);
+        self.discv5
+            .add_enr(enr)
+            .map_err(|e| format!("Failed to add enr: {}", e))?;
+        self.last_response
+            .lock()
+            .entry(node_id)
+            .or_insert_with(|| self.clock.now());
+        let bucket = self.bucket_index_for(&node_id);
+        debug!(
+            "Added peer node_id={} bucket={} client={}",
+            node_id,
+            bucket,
+            client.as_deref().unwrap_or(UNKNOWN_CLIENT)
+        );
+        self.update_bucket_gauge(bucket);
+        self.update_client_gauge(client.as_deref().unwrap_or(UNKNOWN_CLIENT));
+        Ok(true)
+    }
+
+    /// Returns how many routing table peers advertise each client; those without a readable
+    /// client entry are counted as `unknown`.
+    pub fn peer_clients(&self) -> BTreeMap<String, usize> {
+        let mut clients = BTreeMap::new();
+        for enr in self.discv5.table_entries_enr() {
+            let client = enr_client(&enr).unwrap_or_else(|| UNKNOWN_CLIENT.to_owned());
+            *clients.entry(client).or_insert(0) += 1;
+        }
+        clients
+    }
+
+    /// Set the gauge of how many peers run the client.
+    fn update_client_gauge(&self, client: &str) {
+        let peers = self.peer_clients().get(client).copied().unwrap_or(0);
+        self.metrics.set(&peers_with_client(client), peers as u64);
+    }
+
+    /// Returns the routing table bucket the node falls in: its log2 distance from the local node,
+    /// minus one, so that peers differing in the highest bit land in bucket 255. The local node id
+    /// itself, at distance zero, would share bucket 0 with the closest peers.
+    pub fn bucket_index_for(&self, node_id: &NodeId) -> u8 {
+        let distance = xor_two_values(&node_id.raw(), &self.local_enr().node_id().raw());
+        log2_distance(&distance).saturating_sub(1) as u8
+    }
+
+    /// Set the gauge of how many peers the bucket holds.
+    fn update_bucket_gauge(&self, bucket: u8) {
+        let peers = self
+            .discv5
+            .table_entries_id()
+            .iter()
+            .filter(|node_id| self.bucket_index_for(node_id) == bucket)
+            .count();
+        self.metrics.set(&peers_in_bucket(bucket), peers as u64);
+    }
+
+    /// Remove the peers that haven't answered a request for longer than `max_peer_idle`,
+    /// returning their node ids. Bootnodes are kept. Does nothing without an idle limit.
+    pub fn reap_idle_peers(&self) -> Vec<NodeId> {
+        let now = self.clock.now();
+        let max_idle = match self.max_peer_idle {
+            Some(max_idle) => max_idle,
+            None => return vec![],
+        };
+        let entries: HashSet<NodeId> = self.discv5.table_entries_id().into_iter().collect();
+        let mut last_response = self.last_response.lock();
+        // Peers that left the table some other way, like eviction, no longer need tracking.
+        last_response.retain(|node_id, _| entries.contains(node_id));
+
+        let mut idle = vec![];
+        for node_id in entries {
+            if self.bootnode_ids.contains(&node_id) {
+                continue;
+            }
+            // discv5 inserts some peers itself; their idle time starts when they're first seen.
+            let last = *last_response.entry(node_id).or_insert(now);
+            if now.saturating_duration_since(last) > max_idle {
+                debug!("Reaping {}, idle for {}s", node_id, (now - last).as_secs());
+                idle.push(node_id);
+            }
+        }
+        drop(last_response);
+        idle.into_iter()
+            .filter(|node_id| self.evict_peer(node_id, EvictionReason::Idle))
+            .collect()
+    }
+
+    /// Remove a peer from the routing table, logging and counting why. Every eviction goes
+    /// through here, so that changes to the peer set can be explained. Returns whether the peer
+    /// was in the table.
+    pub fn evict_peer(&self, node_id: &NodeId, reason: EvictionReason) -> bool {
+        let client = self
+            .discv5
+            .find_enr(node_id)
+            .and_then(|enr| enr_client(&enr));
+        if !self.discv5.remove_node(node_id) {
+            return false;
+        }
+        self.last_response.lock().remove(node_id);
+        self.metrics.increment(reason.metric());
+        self.update_bucket_gauge(self.bucket_index_for(node_id));
+        self.update_client_gauge(client.as_deref().unwrap_or(UNKNOWN_CLIENT));
+        info!("Evicted peer node_id={} reason={}", node_id, reason);
+        true
+    }
+
+    /// Evict the farthest evictable peer if the table is at the peer cap, unless the new peer is
+    /// farther itself. Returns whether the new peer may be added.
+    fn make_room_for(&self, node_id: &NodeId) -> bool {
+        let max_peers = match self.max_peers {
+            Some(max_peers) => max_peers,
+            None => return true,
+        };
+        if self.bootnode_ids.contains(node_id) || self.discv5.find_enr(node_id).is_some() {
+            return true;
+        }
+        let evictable: Vec<NodeId> = self
+            .discv5
+            .table_entries_id()
+            .into_iter()
+            .filter(|peer| !self.bootnode_ids.contains(peer))
+            .collect();
+        if evictable.len() < max_peers {
+            return true;
+        }
+        let local_id = self.local_enr().node_id();
+        let distance = |peer: &NodeId| xor_two_values(&peer.raw(), &local_id.raw());
+        let farthest = match evictable.into_iter().max_by_key(distance) {
+            Some(farthest) => farthest,
+            None => return false,
+        };
+        if distance(node_id) > distance(&farthest) {
+            return false;
+        }
+        self.evict_peer(&farthest, EvictionReason::PeerCap)
+    }
+
+    /// Returns whether the peer filter accepts requests from the node, judged by its known ENR.
+    pub fn allows_peer(&self, node_id: &NodeId) -> bool {
+        match self.discv5.find_enr(node_id) {
+            Some(enr) => self.peer_filter.allows_enr(&enr),
+            None => self.peer_filter.allows_unknown(),
+        }
+    }
+
+    /// Save the ENRs in the routing table to the configured snapshot, returning how many were
+    /// saved. Does nothing without a snapshot path.
+    pub fn save_routing_table_snapshot(&self) -> Result<usize, String> {
+        let path = match &self.routing_table_snapshot {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let enrs = self.discv5.table_entries_enr();
+        write_snapshot(path, &enrs)?;
+        Ok(enrs.len())
     }
 
     pub async fn start(&mut self, listen_socket: SocketAddr) -> Result<(), String> {
@@ -86,6 +716,19 @@ impl Discovery {
         self.discv5.connected_peers()
     }
 
+    /// Err unless at least `min_peers` peers are connected. With fewer, a lookup finding nothing
+    /// can't be told apart from the node still bootstrapping.
+    pub fn ensure_connected_peers(&self, min_peers: usize) -> Result<(), String> {
+        let connected = self.connected_peers_len();
+        if connected < min_peers {
+            return Err(format!(
+                "Not ready: {} connected peers, lookups need {}",
+                connected, min_peers
+            ));
+        }
+        Ok(())
+    }
+
     pub fn connected_peers(&mut self) -> Vec<NodeId> {
         self.discv5.table_entries_id()
     }
@@ -94,23 +737,44 @@ impl Discovery {
         self.discv5.local_enr()
     }
 
-    /// Do a FindNode query and add the discovered peers to the dht
-    pub async fn discover_nodes(&mut self) -> Result<(), String> {
-        let random_node = NodeId::random();
+    /// Returns the ENRs of routing table peers that advertise serving the subnetwork. Lookups for
+    /// a subnetwork should only select targets from these peers.
+    pub fn peers_serving(&self, subnetwork: Subnetwork) -> Vec<Enr> {
+        self.discv5
+            .table_entries_enr()
+            .into_iter()
+            .filter(|enr| enr_serves_subnetwork(enr, subnetwork))
+            .collect()
+    }
+
+    /// Do a FindNode query for a random node id and add the discovered peers to the dht
+    pub async fn discover_nodes(&self) -> Result<usize, String> {
+        self.discover_nodes_near(NodeId::random()).await
+    }
+
+    /// Do a FindNode query for the target and add the discovered peers to the dht, returning how
+    /// many peers the routing table gained.
+    pub async fn discover_nodes_near(&self, target: NodeId) -> Result<usize, String> {
+        let known: HashSet<NodeId> = self.discv5.table_entries_id().into_iter().collect();
         let nodes = self
             .discv5
-            .find_node(random_node)
+            .find_node(target)
             .await
             .map_err(|e| format!("FindNode query failed: {:?}", e))?;
 
         info!("FindNode query found {} nodes", nodes.len());
 
         for node in nodes {
-            self.discv5
-                .add_enr(node)
-                .map_err(|e| format!("Failed to add node to dht: {}", e))?;
+            self.add_enr(node)?;
         }
-        Ok(())
+        // discv5 inserts the peers that answered the query itself, so count the table's growth
+        // rather than the ENRs added here.
+        Ok(self
+            .discv5
+            .table_entries_id()
+            .iter()
+            .filter(|node_id| !known.contains(node_id))
+            .count())
     }
 
     pub async fn send_talkreq(
@@ -118,12 +782,526 @@ impl Discovery {
         enr: Enr,
         protocol: String,
         request: ProtocolRequest,
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Vec<u8>, DiscoveryError> {
+        let node_id = enr.node_id();
+        let _permit = match &self.request_limiter {
+            Some(limiter) => Some(limiter.acquire(&node_id).await),
+            None => None,
+        };
         let response = self
             .discv5
             .talk_req(enr, protocol.into_bytes(), request)
-            .await
-            .map_err(|e| format!("TalkReq query failed: {:?}", e))?;
+            .await?;
+        self.last_response.lock().insert(node_id, self.clock.now());
         Ok(response)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::clock::MockClock;
+
+    fn peer_enr(key_byte: u8, subnetworks: Option<&[Subnetwork]>) -> Enr {
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("127.0.0.1".parse().unwrap());
+        builder.udp(9000 + key_byte as u16);
+        if let Some(subnetworks) = subnetworks {
+            builder.add_value(
+                PORTAL_SUBNETWORKS_ENR_KEY,
+                &vec![subnetwork_bitmap(subnetworks)],
+            );
+        }
+        builder.build(&enr_key).unwrap()
+    }
+
+    #[test]
+    fn test_duplicate_bootnodes_keep_highest_seq() {
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![2; 32].as_mut_slice()).unwrap();
+        let stale = peer_enr(2, None);
+        let mut fresh = stale.clone();
+        fresh.set_udp(9100, &enr_key).unwrap();
+        assert!(fresh.seq() > stale.seq());
+        let other = peer_enr(3, None);
+
+        let deduped = dedupe_enrs(vec![stale.clone(), other.clone(), fresh.clone(), stale]);
+        assert_eq!(deduped, vec![fresh, other]);
+    }
+
+    #[test]
+    fn test_local_enr_is_not_added() {
+        let local_key = vec![1; 32];
+        let discovery = Discovery::new(Config {
+            private_key: Some(HexData(local_key)),
+            bootnode_enrs: vec![peer_enr(1, None), peer_enr(2, None)],
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            discovery.discv5.table_entries_enr(),
+            vec![peer_enr(2, None)]
+        );
+        assert_eq!(discovery.add_enr(discovery.local_enr()), Ok(false));
+        assert_eq!(discovery.discv5.table_entries_id().len(), 1);
+    }
+
+    fn enr_at(key_byte: u8, ip: &str) -> Enr {
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+        let mut builder = EnrBuilder::new("v4");
+        match ip.parse().unwrap() {
+            ip @ IpAddr::V4(_) => builder.ip(ip).udp(9000),
+            ip @ IpAddr::V6(_) => builder.ip(ip).udp6(9000),
+        };
+        builder.build(&enr_key).unwrap()
+    }
+
+    #[test]
+    fn test_private_ip_filter_applies_to_added_enrs() {
+        let private = vec![
+            enr_at(3, "10.1.2.3"),
+            enr_at(4, "192.168.0.7"),
+            enr_at(5, "127.0.0.1"),
+            enr_at(6, "169.254.0.1"),
+            enr_at(7, "fd12::1"),
+            enr_at(8, "fe80::1"),
+            enr_at(9, "::ffff:10.0.0.1"),
+        ];
+        let public = enr_at(10, "8.8.8.8");
+
+        let filtered = Discovery::new(Config {
+            filter_private_ips: true,
+            ..Default::default()
+        })
+        .unwrap();
+        for enr in &private {
+            assert_eq!(filtered.add_enr(enr.clone()), Ok(false), "{}", enr);
+        }
+        assert_eq!(filtered.add_enr(public.clone()), Ok(true));
+        assert_eq!(filtered.discv5.table_entries_id(), vec![public.node_id()]);
+
+        let unfiltered = Discovery::new(Config::default()).unwrap();
+        assert_eq!(unfiltered.add_enr(private[0].clone()), Ok(true));
+        assert_eq!(unfiltered.discv5.table_entries_id().len(), 1);
+    }
+
+    #[test]
+    fn test_peer_filter_applies_to_added_enrs() {
+        let discovery = Discovery::new(Config {
+            peer_filter: PeerFilter {
+                allow: vec![],
+                deny: vec!["127.0.0.0/8".parse().unwrap()],
+            },
+            bootnode_enrs: vec![peer_enr(2, None)],
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(discovery.discv5.table_entries_id().is_empty());
+        assert_eq!(discovery.add_enr(peer_enr(3, None)), Ok(false));
+        assert!(!discovery.allows_peer(&peer_enr(3, None).node_id()));
+    }
+
+    #[test]
+    fn test_snapshot_is_preloaded_into_routing_table() {
+        let path = std::env::temp_dir().join(format!(
+            "trin-routing-table-{}.enrs",
+            hex::encode(NodeId::random().raw())
+        ));
+        let peers = vec![peer_enr(2, None), peer_enr(3, None)];
+        write_snapshot(&path, &peers).unwrap();
+
+        let discovery = Discovery::new(Config {
+            routing_table_snapshot: Some(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        let entries = discovery.discv5.table_entries_enr();
+        assert_eq!(entries.len(), 2);
+        assert!(peers.iter().all(|peer| entries.contains(peer)));
+
+        // Saving the table round-trips through the snapshot.
+        fs::remove_file(&path).unwrap();
+        assert_eq!(discovery.save_routing_table_snapshot(), Ok(2));
+        assert_eq!(read_snapshot(&path).unwrap().len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_snapshot_is_empty() {
+        let path = std::env::temp_dir().join("trin-routing-table-missing.enrs");
+        assert_eq!(read_snapshot(&path), Ok(vec![]));
+    }
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "trin-routing-table-{}-{}.enrs",
+            name,
+            hex::encode(NodeId::random().raw())
+        ))
+    }
+
+    /// A snapshot written by format version `version`, whose records carry `extra` after the ENR
+    /// and which ends in `trailer`.
+    fn versioned_snapshot(version: u16, enrs: &[Enr], extra: &[u8], trailer: &[u8]) -> Vec<u8> {
+        let mut snapshot = b"trrt".to_vec();
+        snapshot.extend_from_slice(&version.to_be_bytes());
+        snapshot.extend_from_slice(&(enrs.len() as u32).to_be_bytes());
+        for enr in enrs {
+            let enr = enr.to_base64();
+            snapshot.extend_from_slice(&((2 + enr.len() + extra.len()) as u32).to_be_bytes());
+            snapshot.extend_from_slice(&(enr.len() as u16).to_be_bytes());
+            snapshot.extend_from_slice(enr.as_bytes());
+            snapshot.extend_from_slice(extra);
+        }
+        snapshot.extend_from_slice(trailer);
+        snapshot
+    }
+
+    #[test]
+    fn test_snapshots_of_other_versions_are_read() {
+        let peers = vec![peer_enr(2, None), peer_enr(3, None)];
+        let path = snapshot_path("versions");
+
+        // A v1 snapshot, as this build writes it.
+        fs::write(&path, versioned_snapshot(1, &peers, &[], &[])).unwrap();
+        assert_eq!(read_snapshot(&path), Ok(peers.clone()));
+        write_snapshot(&path, &peers).unwrap();
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            versioned_snapshot(1, &peers, &[], &[])
+        );
+
+        // A snapshot of a later version, with fields this build doesn't know.
+        fs::write(&path, versioned_snapshot(2, &peers, &[7; 12], &[8; 5])).unwrap();
+        assert_eq!(read_snapshot(&path), Ok(peers.clone()));
+
+        // A snapshot from before the format was versioned.
+        let unversioned: String = peers
+            .iter()
+            .map(|enr| format!("{}\n", enr.to_base64()))
+            .collect();
+        fs::write(&path, unversioned).unwrap();
+        assert_eq!(read_snapshot(&path), Ok(peers));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_snapshot_is_skipped() {
+        let peers = vec![peer_enr(2, None), peer_enr(3, None)];
+        let path = snapshot_path("corrupt");
+        let snapshot = versioned_snapshot(1, &peers, &[], &[]);
+        for corrupt in vec![
+            snapshot[..snapshot.len() - 10].to_vec(),
+            snapshot[..5].to_vec(),
+            versioned_snapshot(0, &peers, &[], &[]),
+            [&b"trrt"[..], &[0xff; 64][..]].concat(),
+            vec![0xff, 0xfe, 0x00, 0x80],
+        ] {
+            fs::write(&path, corrupt).unwrap();
+            assert_eq!(read_snapshot(&path), Ok(vec![]));
+        }
+
+        // Startup goes ahead with an empty table.
+        let discovery = Discovery::new(Config {
+            routing_table_snapshot: Some(path.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(discovery.discv5.table_entries_id().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_peer_cap_evicts_farthest_peer_and_keeps_bootnodes() {
+        let local_key = vec![1; 32];
+        let bootnode = peer_enr(2, None);
+        let metrics = Arc::new(Metrics::default());
+        let discovery = Discovery::new(Config {
+            private_key: Some(HexData(local_key)),
+            bootnode_enrs: vec![bootnode.clone()],
+            max_peers: Some(2),
+            metrics: metrics.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        let local_id = discovery.local_enr().node_id();
+        let mut peers: Vec<Enr> = (3..6).map(|key_byte| peer_enr(key_byte, None)).collect();
+        peers.sort_by_key(|peer| xor_two_values(&peer.node_id().raw(), &local_id.raw()));
+        let (closest, middle, farthest) = (&peers[0], &peers[1], &peers[2]);
+
+        assert_eq!(discovery.add_enr(farthest.clone()), Ok(true));
+        assert_eq!(discovery.add_enr(middle.clone()), Ok(true));
+        // At the cap, a closer peer evicts the farthest, and a farther one is skipped.
+        assert_eq!(discovery.add_enr(closest.clone()), Ok(true));
+        assert_eq!(discovery.add_enr(farthest.clone()), Ok(false));
+
+        let entries = discovery.discv5.table_entries_id();
+        assert_eq!(entries.len(), 3);
+        assert!(entries.contains(&bootnode.node_id()));
+        assert!(entries.contains(&closest.node_id()));
+        assert!(entries.contains(&middle.node_id()));
+        assert_eq!(metrics.get(PEERS_EVICTED_PEER_CAP), 1);
+        assert_eq!(metrics.get(PEERS_EVICTED_IDLE), 0);
+    }
+
+    #[test]
+    fn test_timeout_is_no_response() {
+        assert_eq!(
+            DiscoveryError::from(RequestError::Timeout),
+            DiscoveryError::NoResponse
+        );
+        assert!(matches!(
+            DiscoveryError::from(RequestError::ServiceNotStarted),
+            DiscoveryError::ProtocolError(_)
+        ));
+    }
+
+    #[test]
+    fn test_local_enr_advertises_subnetworks() {
+        let discovery = Discovery::new(Config {
+            subnetworks: vec![Subnetwork::History],
+            ..Default::default()
+        })
+        .unwrap();
+        let local_enr = discovery.local_enr();
+        assert!(enr_serves_subnetwork(&local_enr, Subnetwork::History));
+        assert!(!enr_serves_subnetwork(&local_enr, Subnetwork::State));
+    }
+
+    #[test]
+    fn test_peer_not_serving_history_is_skipped() {
+        let history_peer = peer_enr(2, Some(&[Subnetwork::History, Subnetwork::State]));
+        let state_peer = peer_enr(3, Some(&[Subnetwork::State]));
+        let unadvertised_peer = peer_enr(4, None);
+
+        let discovery = Discovery::new(Config {
+            bootnode_enrs: vec![history_peer.clone(), state_peer.clone(), unadvertised_peer],
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            discovery.peers_serving(Subnetwork::History),
+            vec![history_peer.clone()]
+        );
+        let state_peers = discovery.peers_serving(Subnetwork::State);
+        assert_eq!(state_peers.len(), 2);
+        assert!(state_peers.contains(&history_peer));
+        assert!(state_peers.contains(&state_peer));
+    }
+
+    #[test]
+    fn test_idle_peer_is_reaped_and_active_peer_is_kept() {
+        let bootnode = peer_enr(2, None);
+        let (idle, active) = (peer_enr(3, None), peer_enr(4, None));
+        let metrics = Arc::new(Metrics::default());
+        let clock = Arc::new(MockClock::default());
+        let discovery = Discovery::new(Config {
+            bootnode_enrs: vec![bootnode.clone()],
+            max_peer_idle: Some(Duration::from_secs(60)),
+            metrics: metrics.clone(),
+            clock: clock.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(discovery.add_enr(idle.clone()), Ok(true));
+        assert_eq!(discovery.add_enr(active.clone()), Ok(true));
+        clock.advance(Duration::from_secs(50));
+        discovery
+            .last_response
+            .lock()
+            .insert(active.node_id(), clock.now());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(discovery.reap_idle_peers().is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(discovery.reap_idle_peers(), vec![idle.node_id()]);
+
+        let entries = discovery.discv5.table_entries_id();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains(&bootnode.node_id()));
+        assert!(entries.contains(&active.node_id()));
+        assert_eq!(metrics.get(PEERS_EVICTED_IDLE), 1);
+        assert_eq!(metrics.get(PEERS_EVICTED_PEER_CAP), 0);
+
+        // Evicting a peer that's already gone records nothing.
+        assert!(!discovery.evict_peer(&idle.node_id(), EvictionReason::Idle));
+        assert_eq!(metrics.get(PEERS_EVICTED_IDLE), 1);
+    }
+
+    #[test]
+    fn test_enr_without_udp_endpoint_is_not_dialable() {
+        assert!(is_dialable(&peer_enr(2, None)));
+
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![3; 32].as_mut_slice()).unwrap();
+        assert!(!is_dialable(
+            &EnrBuilder::new("v4").build(&enr_key).unwrap()
+        ));
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("127.0.0.1".parse().unwrap());
+        assert!(!is_dialable(&builder.build(&enr_key).unwrap()));
+        builder.udp(0);
+        assert!(!is_dialable(&builder.build(&enr_key).unwrap()));
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("0.0.0.0".parse().unwrap()).udp(9003);
+        assert!(!is_dialable(&builder.build(&enr_key).unwrap()));
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("::ffff:127.0.0.1".parse().unwrap()).udp6(9003);
+        assert!(!is_dialable(&builder.build(&enr_key).unwrap()));
+    }
+
+    #[test]
+    fn test_ipv4_mapped_address_is_advertised_as_ipv4() {
+        let discovery = Discovery::new(Config {
+            listen_address: "::ffff:10.0.0.1".parse().unwrap(),
+            listen_port: 9004,
+            ..Default::default()
+        })
+        .unwrap();
+        let enr = discovery.local_enr();
+        assert_eq!(enr.ip(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(enr.udp(), Some(9004));
+        assert_eq!(enr.ip6(), None);
+        assert_eq!(enr.udp6(), None);
+
+        let discovery = Discovery::new(Config {
+            listen_address: "2001:db8::1".parse().unwrap(),
+            listen_port: 9004,
+            ..Default::default()
+        })
+        .unwrap();
+        let enr = discovery.local_enr();
+        assert_eq!(enr.ip(), None);
+        assert_eq!(enr.ip6(), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(enr.udp6(), Some(9004));
+        assert_eq!(enr.udp(), None);
+    }
+
+    #[test]
+    fn test_bucket_index_matches_log2_distance() {
+        let metrics = Arc::new(Metrics::default());
+        let discovery = Discovery::new(Config {
+            metrics: metrics.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        let local_id = discovery.local_enr().node_id().raw();
+        let at_distance = |flip: &[(usize, u8)]| {
+            let mut raw = local_id;
+            for (byte, bits) in flip {
+                raw[*byte] ^= bits;
+            }
+            NodeId::new(&raw)
+        };
+
+        assert_eq!(discovery.bucket_index_for(&at_distance(&[(0, 0x80)])), 255);
+        assert_eq!(discovery.bucket_index_for(&at_distance(&[(0, 0x01)])), 248);
+        assert_eq!(
+            discovery.bucket_index_for(&at_distance(&[(0, 0x01), (31, 0xff)])),
+            248
+        );
+        assert_eq!(discovery.bucket_index_for(&at_distance(&[(30, 0x02)])), 9);
+        assert_eq!(discovery.bucket_index_for(&at_distance(&[(31, 0x01)])), 0);
+
+        // Adding peers keeps the gauge of their bucket up to date.
+        let peers = vec![peer_enr(2, None), peer_enr(3, None)];
+        for peer in &peers {
+            assert_eq!(discovery.add_enr(peer.clone()), Ok(true));
+        }
+        let buckets: Vec<u8> = peers
+            .iter()
+            .map(|peer| discovery.bucket_index_for(&peer.node_id()))
+            .collect();
+        for bucket in &buckets {
+            let expected = buckets.iter().filter(|other| *other == bucket).count();
+            assert_eq!(metrics.get(&peers_in_bucket(*bucket)), expected as u64);
+        }
+    }
+
+    #[test]
+    fn test_client_entry_is_advertised_and_peer_clients_are_counted() {
+        let metrics = Arc::new(Metrics::default());
+        let discovery = Discovery::new(Config {
+            metrics: metrics.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            enr_client(&discovery.local_enr()),
+            Some(CLIENT_VERSION.to_owned())
+        );
+        assert!(CLIENT_VERSION.starts_with("trin/v"));
+
+        let with_client = |key_byte: u8, client: &str| {
+            let enr_key =
+                CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+            let mut builder = EnrBuilder::new("v4");
+            builder.ip("127.0.0.1".parse().unwrap());
+            builder.udp(9000 + key_byte as u16);
+            builder.add_value(CLIENT_ENR_KEY, &client.as_bytes().to_vec());
+            builder.build(&enr_key).unwrap()
+        };
+        let peers = vec![
+            with_client(2, "fluffy/v0.2.0"),
+            with_client(3, "fluffy/v0.2.0"),
+            with_client(4, "t"),
+            peer_enr(5, None),
+        ];
+        assert_eq!(enr_client(&peers[0]), Some("fluffy/v0.2.0".to_owned()));
+        for peer in peers {
+            assert_eq!(discovery.add_enr(peer), Ok(true));
+        }
+
+        let expected: BTreeMap<String, usize> = vec![
+            ("fluffy/v0.2.0".to_owned(), 2),
+            ("t".to_owned(), 1),
+            (UNKNOWN_CLIENT.to_owned(), 1),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(discovery.peer_clients(), expected);
+        assert_eq!(metrics.get(&peers_with_client("fluffy/v0.2.0")), 2);
+        assert_eq!(metrics.get(&peers_with_client(UNKNOWN_CLIENT)), 1);
+
+        let fluffy = with_client(2, "fluffy/v0.2.0").node_id();
+        assert!(discovery.evict_peer(&fluffy, EvictionReason::PeerCap));
+        assert_eq!(metrics.get(&peers_with_client("fluffy/v0.2.0")), 1);
+    }
+
+    #[tokio::test]
+    async fn test_requests_beyond_a_peers_cap_wait_while_other_peers_proceed() {
+        let limiter = PeerRequestLimiter::new(2);
+        let (busy, other) = (peer_enr(2, None).node_id(), peer_enr(3, None).node_id());
+        let first = limiter.acquire(&busy).await;
+        let _second = limiter.acquire(&busy).await;
+
+        let wait = Duration::from_millis(20);
+        assert!(tokio::time::timeout(wait, limiter.acquire(&busy))
+            .await
+            .is_err());
+        let other_permit = tokio::time::timeout(wait, limiter.acquire(&other))
+            .await
+            .unwrap();
+
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                let _permit = limiter.acquire(&busy).await;
+            }
+        });
+        // The queued request goes ahead once an earlier one finishes.
+        tokio::time::sleep(wait).await;
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), queued)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Peers stop being tracked once nothing is outstanding to them.
+        drop(other_permit);
+        let _busy_permit = limiter.acquire(&busy).await;
+        assert_eq!(limiter.peers.lock().len(), 1);
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use discv5::enr::NodeId;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+
+use super::discovery::DiscoveryError;
+use super::gossip::OfferTransport;
+use super::lookup::FindContentTransport;
+use super::types::{Accept, FindContentResult};
+use super::Enr;
+
+/// Content returned by a peer injected with `Fault::Malformed`, which no validator accepts.
+pub const MALFORMED_CONTENT: &[u8] = &[0xde, 0xad, 0xbe, 0xef];
+
+/// How a faulty peer misbehaves.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// The peer never responds.
+    Timeout,
+    /// The peer responds with garbage: content that fails validation, or an accept that doesn't
+    /// match the offer.
+    Malformed,
+    /// The request fails with the given protocol error.
+    Error(String),
+}
+
+/// Wraps a transport so that chosen peers fail on demand, to exercise retry and exclusion paths
+/// deterministically. Requests to other peers go to the inner transport.
+pub struct FaultyTransport<T> {
+    inner: T,
+    faults: Mutex<HashMap<NodeId, Fault>>,
+    /// How many requests to each peer were answered with its fault
+    injected: Mutex<HashMap<NodeId, usize>>,
+}
+
+impl<T> FaultyTransport<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(HashMap::new()),
+            injected: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Make every later request to the peer fail with the fault.
+    pub fn inject(&self, node_id: NodeId, fault: Fault) {
+        self.faults.lock().insert(node_id, fault);
+    }
+
+    /// Let requests to the peer reach the inner transport again.
+    pub fn heal(&self, node_id: &NodeId) {
+        self.faults.lock().remove(node_id);
+    }
+
+    /// Returns how many requests to the peer were answered with its fault.
+    pub fn injected(&self, node_id: &NodeId) -> usize {
+        self.injected.lock().get(node_id).copied().unwrap_or(0)
+    }
+
+    fn fault_for(&self, node_id: NodeId) -> Option<Fault> {
+        let fault = self.faults.lock().get(&node_id).cloned()?;
+        *self.injected.lock().entry(node_id).or_insert(0) += 1;
+        Some(fault)
+    }
+}
+
+impl<T: FindContentTransport> FindContentTransport for FaultyTransport<T> {
+    fn find_content(
+        &self,
+        enr: Enr,
+        content_key: Vec<u8>,
+    ) -> BoxFuture<'_, Result<FindContentResult, DiscoveryError>> {
+        let result = match self.fault_for(enr.node_id()) {
+            Some(Fault::Timeout) => Err(DiscoveryError::NoResponse),
+            Some(Fault::Malformed) => Ok(FindContentResult::Content(MALFORMED_CONTENT.to_vec())),
+            Some(Fault::Error(e)) => Err(DiscoveryError::ProtocolError(e)),
+            None => return self.inner.find_content(enr, content_key),
+        };
+        Box::pin(async move { result })
+    }
+}
+
+impl<T: OfferTransport> OfferTransport for FaultyTransport<T> {
+    fn offer(
+        &self,
+        enr: Enr,
+        content_keys: Vec<Vec<u8>>,
+    ) -> BoxFuture<'_, Result<Accept, DiscoveryError>> {
+        let result = match self.fault_for(enr.node_id()) {
+            Some(Fault::Timeout) => Err(DiscoveryError::NoResponse),
+            Some(Fault::Malformed) => Ok(Accept {
+                connection_id: 0,
+                content_keys: vec![],
+            }),
+            Some(Fault::Error(e)) => Err(DiscoveryError::ProtocolError(e)),
+            None => return self.inner.offer(enr, content_keys),
+        };
+        Box::pin(async move { result })
+    }
+}
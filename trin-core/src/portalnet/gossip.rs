@@ -0,0 +1,398 @@
+use futures::future::BoxFuture;
+use log::debug;
+
+use super::discovery::{is_dialable, DiscoveryError};
+use super::lookup::content_id;
+use super::overlay::{Node, Overlay};
+use super::storage::ContentStore;
+use super::types::Accept;
+use super::{Enr, U256};
+use crate::utils::content_distance;
+
+/// Sends OFFER requests on behalf of gossip, so propagation can run against a mock network.
+pub trait OfferTransport: Send + Sync {
+    fn offer(
+        &self,
+        enr: Enr,
+        content_keys: Vec<Vec<u8>>,
+    ) -> BoxFuture<'_, Result<Accept, DiscoveryError>>;
+}
+
+/// How widely new content is gossiped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GossipConfig {
+    /// Maximum number of peers offered the content per propagation
+    pub fanout: usize,
+    /// Stop offering once this many peers accepted the content
+    pub redundancy: usize,
+    /// Most held content keys re-announced after the data radius grows, so that an expansion
+    /// doesn't set off a burst of offers
+    pub reannounce_limit: usize,
+    /// Most held content keys offered to a peer newly added to the routing table; 0 offers none
+    pub seed_limit: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            fanout: 8,
+            redundancy: 4,
+            reannounce_limit: 32,
+            seed_limit: 16,
+        }
+    }
+}
+
+/// Outcome of propagating one content key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Propagation {
+    pub offered: usize,
+    pub accepted: usize,
+}
+
+/// Returns whether the content falls within the node's data radius.
+pub fn is_interested(node: &Node, content_key: &[u8]) -> bool {
+    content_distance(&node.enr().node_id(), &content_id(content_key)) <= node.data_radius()
+}
+
+/// Offer the content to the interested peers, in the order given, until `fanout` peers were
+/// offered it or `redundancy` peers accepted it. Peers that didn't respond are offered it once
+/// more after the others; peers that broke the protocol, or can't be dialed, aren't offered it.
+pub async fn propagate_content<T: OfferTransport + ?Sized>(
+    transport: &T,
+    config: &GossipConfig,
+    content_key: Vec<u8>,
+    peers: Vec<Node>,
+) -> Propagation {
+    let mut propagation = Propagation::default();
+    let mut unanswered = vec![];
+    for peer in peers
+        .into_iter()
+        .filter(|peer| is_interested(peer, &content_key) && is_dialable(&peer.enr()))
+    {
+        if propagation.offered >= config.fanout || propagation.accepted >= config.redundancy {
+            break;
+        }
+        propagation.offered += 1;
+        match offer(transport, &peer, &content_key).await {
+            Ok(accepted) => propagation.accepted += accepted as usize,
+            Err(DiscoveryError::NoResponse) => unanswered.push(peer),
+            Err(e) => debug!("Failed to offer content to {}: {}", peer.enr().node_id(), e),
+        }
+    }
+    for peer in unanswered {
+        if propagation.accepted >= config.redundancy {
+            break;
+        }
+        match offer(transport, &peer, &content_key).await {
+            Ok(accepted) => propagation.accepted += accepted as usize,
+            Err(e) => debug!("Failed to offer content to {}: {}", peer.enr().node_id(), e),
+        }
+    }
+    propagation
+}
+
+/// Gossip the held content that a data radius expansion from `old_radius` brought within the
+/// overlay's radius, closest to the local node first, and at most `reannounce_limit` keys.
+/// Returns how many keys were re-announced. A radius that didn't grow announces nothing.
+pub async fn reannounce_newly_covered<T: OfferTransport + ?Sized>(
+    transport: &T,
+    config: &GossipConfig,
+    store: &dyn ContentStore,
+    overlay: &Overlay,
+    old_radius: U256,
+) -> Result<usize, String> {
+    let new_radius = overlay.data_radius();
+    if new_radius <= old_radius {
+        return Ok(0);
+    }
+    let local_id = overlay.local_enr().node_id();
+    let mut newly_covered: Vec<(U256, Vec<u8>)> = store
+        .content_keys()?
+        .into_iter()
+        .map(|content_key| {
+            let distance = content_distance(&local_id, &content_id(&content_key));
+            (distance, content_key)
+        })
+        .filter(|(distance, _)| *distance > old_radius && *distance <= new_radius)
+        .collect();
+    newly_covered.sort();
+    newly_covered.truncate(config.reannounce_limit);
+
+    let reannounced = newly_covered.len();
+    for (_, content_key) in newly_covered {
+        let peers = overlay.closest_nodes(&content_id(&content_key), usize::MAX);
+        let propagation = propagate_content(transport, config, content_key, peers).await;
+        debug!(
+            "Re-announced content after the radius grew: {:?}",
+            propagation
+        );
+    }
+    Ok(reannounced)
+}
+
+/// Offer a peer newly added to the routing table the held content closest to it within its data
+/// radius, at most `seed_limit` keys, in a single OFFER. Returns how many keys it accepted.
+pub async fn seed_new_peer<T: OfferTransport + ?Sized>(
+    transport: &T,
+    config: &GossipConfig,
+    store: &dyn ContentStore,
+    peer: &Node,
+) -> Result<usize, String> {
+    if config.seed_limit == 0 || !is_dialable(&peer.enr()) {
+        return Ok(0);
+    }
+    let content_keys: Vec<Vec<u8>> = store
+        .content_closest_to(&peer.enr().node_id(), config.seed_limit)?
+        .into_iter()
+        .filter(|content_key| is_interested(peer, content_key))
+        .collect();
+    if content_keys.is_empty() {
+        return Ok(0);
+    }
+    let accept = transport
+        .offer(peer.enr(), content_keys)
+        .await
+        .map_err(|e| format!("Failed to seed {}: {}", peer.enr().node_id(), e))?;
+    Ok(accept
+        .content_keys
+        .iter()
+        .filter(|accepted| **accepted)
+        .count())
+}
+
+/// Offer the content to a peer, returning whether it was accepted.
+async fn offer<T: OfferTransport + ?Sized>(
+    transport: &T,
+    peer: &Node,
+    content_key: &[u8],
+) -> Result<bool, DiscoveryError> {
+    let accept = transport
+        .offer(peer.enr(), vec![content_key.to_vec()])
+        .await?;
+    Ok(accept.content_keys.first() == Some(&true))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::faults::{Fault, FaultyTransport};
+    use crate::portalnet::overlay::Config as OverlayConfig;
+    use crate::portalnet::storage::{ContentAddressedStore, MemoryBackend};
+    use discv5::enr::{CombinedKey, EnrBuilder};
+    use parking_lot::Mutex;
+
+    fn node(key_byte: u8, data_radius: U256) -> Node {
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("127.0.0.1".parse().unwrap());
+        builder.udp(9000 + key_byte as u16);
+        Node::new(builder.build(&enr_key).unwrap(), data_radius)
+    }
+
+    /// Accepts every offer, and records who was offered what.
+    #[derive(Default)]
+    struct MockNetwork {
+        offered: Mutex<Vec<Enr>>,
+        offered_keys: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl OfferTransport for MockNetwork {
+        fn offer(
+            &self,
+            enr: Enr,
+            content_keys: Vec<Vec<u8>>,
+        ) -> BoxFuture<'_, Result<Accept, DiscoveryError>> {
+            let result = Ok(Accept {
+                connection_id: 0,
+                content_keys: vec![true; content_keys.len()],
+            });
+            self.offered.lock().push(enr);
+            self.offered_keys.lock().extend(content_keys);
+            Box::pin(async move { result })
+        }
+    }
+
+    fn peers() -> Vec<Node> {
+        (2..12).map(|i| node(i, U256::max_value())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_fanout_bounds_offers() {
+        let network = MockNetwork::default();
+        let config = GossipConfig {
+            fanout: 3,
+            redundancy: 10,
+            ..Default::default()
+        };
+
+        let propagation = propagate_content(&network, &config, vec![1, 2], peers()).await;
+
+        assert_eq!(
+            propagation,
+            Propagation {
+                offered: 3,
+                accepted: 3
+            }
+        );
+        assert_eq!(network.offered.lock().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_redundancy_stops_propagation_early() {
+        let network = MockNetwork::default();
+        let config = GossipConfig {
+            fanout: 8,
+            redundancy: 2,
+            ..Default::default()
+        };
+
+        let propagation = propagate_content(&network, &config, vec![1, 2], peers()).await;
+
+        assert_eq!(propagation.offered, 2);
+        assert_eq!(propagation.accepted, 2);
+    }
+
+    #[tokio::test]
+    async fn test_uninterested_peers_are_not_offered() {
+        let network = MockNetwork::default();
+        let peers = vec![node(2, U256::zero()), node(3, U256::max_value())];
+
+        let propagation = propagate_content(
+            &network,
+            &GossipConfig::default(),
+            vec![1, 2],
+            peers.clone(),
+        )
+        .await;
+
+        assert_eq!(propagation.offered, 1);
+        assert_eq!(*network.offered.lock(), vec![peers[1].enr()]);
+    }
+
+    #[tokio::test]
+    async fn test_peers_without_ip_are_not_offered() {
+        let network = MockNetwork::default();
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![2; 32].as_mut_slice()).unwrap();
+        let ip_less = Node::new(
+            EnrBuilder::new("v4").build(&enr_key).unwrap(),
+            U256::max_value(),
+        );
+        let peers = vec![ip_less, node(3, U256::max_value())];
+
+        let propagation = propagate_content(
+            &network,
+            &GossipConfig::default(),
+            vec![1, 2],
+            peers.clone(),
+        )
+        .await;
+
+        assert_eq!(propagation.offered, 1);
+        assert_eq!(*network.offered.lock(), vec![peers[1].enr()]);
+    }
+
+    #[tokio::test]
+    async fn test_only_unanswered_offers_are_retried() {
+        let peers = peers();
+        let network = FaultyTransport::new(MockNetwork::default());
+        network.inject(peers[0].enr().node_id(), Fault::Timeout);
+        network.inject(
+            peers[1].enr().node_id(),
+            Fault::Error("malformed".to_owned()),
+        );
+        let config = GossipConfig {
+            fanout: 3,
+            redundancy: 10,
+            ..Default::default()
+        };
+
+        let propagation = propagate_content(&network, &config, vec![1, 2], peers.clone()).await;
+
+        assert_eq!(
+            propagation,
+            Propagation {
+                offered: 3,
+                accepted: 1
+            }
+        );
+        assert_eq!(network.injected(&peers[0].enr().node_id()), 2);
+        assert_eq!(network.injected(&peers[1].enr().node_id()), 1);
+        assert_eq!(*network.inner().offered.lock(), vec![peers[2].enr()]);
+    }
+
+    #[tokio::test]
+    async fn test_radius_expansion_reannounces_bounded_held_content() {
+        let local = node(1, U256::zero());
+        let overlay = Overlay::new(local.enr(), U256::zero(), OverlayConfig::default());
+        for peer in peers() {
+            overlay.insert_node(peer.enr(), peer.data_radius());
+        }
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        for i in 0..100u32 {
+            store.put(&i.to_be_bytes(), b"held").unwrap();
+        }
+        let network = MockNetwork::default();
+        let config = GossipConfig {
+            reannounce_limit: 5,
+            ..Default::default()
+        };
+
+        let old_radius = overlay.set_data_radius(U256::max_value());
+        let reannounced = reannounce_newly_covered(&network, &config, &store, &overlay, old_radius)
+            .await
+            .unwrap();
+
+        assert_eq!(reannounced, 5);
+        let mut offered_keys = network.offered_keys.lock().clone();
+        offered_keys.dedup();
+        assert_eq!(offered_keys.len(), 5);
+        // The keys closest to the local node are announced first.
+        let local_id = local.enr().node_id();
+        let distance = |key: &Vec<u8>| content_distance(&local_id, &content_id(key));
+        let mut closest = store.content_keys().unwrap();
+        closest.sort_by_key(distance);
+        offered_keys.sort_by_key(distance);
+        assert_eq!(offered_keys, closest[..5].to_vec());
+
+        // Shrinking the radius announces nothing.
+        let old_radius = overlay.set_data_radius(U256::zero());
+        assert_eq!(
+            reannounce_newly_covered(&network, &config, &store, &overlay, old_radius).await,
+            Ok(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_peer_is_seeded_with_closest_held_content() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        for i in 0..50u32 {
+            store.put(&i.to_be_bytes(), b"held").unwrap();
+        }
+        let peer = node(2, U256::max_value());
+        let network = MockNetwork::default();
+        let config = GossipConfig {
+            seed_limit: 4,
+            ..Default::default()
+        };
+
+        let accepted = seed_new_peer(&network, &config, &store, &peer)
+            .await
+            .unwrap();
+
+        assert_eq!(accepted, 4);
+        assert_eq!(*network.offered.lock(), vec![peer.enr()]);
+        let peer_id = peer.enr().node_id();
+        let mut closest = store.content_keys().unwrap();
+        closest.sort_by_key(|key| content_distance(&peer_id, &content_id(key)));
+        assert_eq!(*network.offered_keys.lock(), closest[..4].to_vec());
+
+        // A peer whose radius covers none of it is offered nothing.
+        let uninterested = node(3, U256::zero());
+        assert_eq!(
+            seed_new_peer(&network, &config, &store, &uninterested).await,
+            Ok(0)
+        );
+        assert_eq!(network.offered.lock().len(), 1);
+    }
+}
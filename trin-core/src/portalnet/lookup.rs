@@ -0,0 +1,1222 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use discv5::enr::NodeId;
+use futures::future::BoxFuture;
+use log::debug;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::clock::{Clock, SystemClock};
+use super::discovery::{is_dialable, DiscoveryError};
+use super::types::{FindContentResult, FindNodesResult};
+use super::validation::ContentValidator;
+use super::Enr;
+use crate::utils::{log2_distance, node_id_hex, xor_two_values};
+
+/// Default maximum number of peers queried by a single recursive lookup: its hop limit.
+pub const LOOKUP_MAX_QUERIES: usize = 16;
+
+/// Sends FINDCONTENT requests on behalf of a lookup, so lookups can run against a mock network.
+pub trait FindContentTransport: Send + Sync {
+    fn find_content(
+        &self,
+        enr: Enr,
+        content_key: Vec<u8>,
+    ) -> BoxFuture<'_, Result<FindContentResult, DiscoveryError>>;
+}
+
+/// Number of peers closest to the target that a recursive FINDNODES converges on.
+pub const FIND_NODES_RESULTS: usize = 16;
+
+/// Most candidates a recursive FINDNODES keeps at once, to bound its memory; the farthest from
+/// the target are dropped first.
+pub const FIND_NODES_MAX_CANDIDATES: usize = 64;
+
+/// Sends FINDNODES requests on behalf of a lookup, so lookups can run against a mock network.
+pub trait FindNodesTransport: Send + Sync {
+    fn find_nodes(
+        &self,
+        enr: Enr,
+        distances: Vec<u16>,
+    ) -> BoxFuture<'_, Result<FindNodesResult, DiscoveryError>>;
+}
+
+/// Returns the content id of a content key: its position in the node id space.
+pub fn content_id(content_key: &[u8]) -> [u8; 32] {
+    let mut id = [0u8; 32];
+    id.copy_from_slice(&Sha256::digest(content_key));
+    id
+}
+
+/// What to do with a recursive lookup started while the most allowed are already running.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LookupOverflowPolicy {
+    /// Wait until a running lookup finishes.
+    Queue,
+    /// Fail right away with a busy error.
+    Reject,
+}
+
+impl Default for LookupOverflowPolicy {
+    fn default() -> Self {
+        LookupOverflowPolicy::Queue
+    }
+}
+
+impl FromStr for LookupOverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(LookupOverflowPolicy::Queue),
+            "reject" => Ok(LookupOverflowPolicy::Reject),
+            _ => Err(format!("Unknown lookup overflow policy: {}", s)),
+        }
+    }
+}
+
+/// Caps how many recursive lookups run at once across the node, however they were requested.
+#[derive(Clone)]
+pub struct LookupLimiter {
+    permits: Arc<Semaphore>,
+    max_lookups: usize,
+    policy: LookupOverflowPolicy,
+}
+
+impl LookupLimiter {
+    /// Allow up to `max_lookups` lookups at once. At least one is allowed.
+    pub fn new(max_lookups: usize, policy: LookupOverflowPolicy) -> Self {
+        let max_lookups = max_lookups.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(max_lookups)),
+            max_lookups,
+            policy,
+        }
+    }
+
+    /// Wait for a lookup slot, held until the permit is dropped. Under the reject policy, errs
+    /// instead of waiting when every slot is taken.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, String> {
+        let permit = match self.policy {
+            LookupOverflowPolicy::Queue => self.permits.clone().acquire_owned().await.ok(),
+            LookupOverflowPolicy::Reject => self.permits.clone().try_acquire_owned().ok(),
+        };
+        permit.ok_or_else(|| {
+            format!(
+                "Node is busy: {} recursive lookups are already running",
+                self.max_lookups
+            )
+        })
+    }
+}
+
+/// Content ids whose recent lookups found nothing, so that repeat lookups are answered as not
+/// found without querying the network until the entry expires. A zero TTL caches nothing.
+pub struct NotFoundCache {
+    ttl: Duration,
+    /// When each content id was last found missing
+    entries: Mutex<HashMap<[u8; 32], Instant>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl NotFoundCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_clock(ttl, Arc::new(SystemClock))
+    }
+
+    /// A cache whose entries expire by the given clock.
+    pub fn with_clock(ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Returns whether a lookup for the content recently found nothing.
+    pub fn contains(&self, content_key: &[u8]) -> bool {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock();
+        let content_id = content_id(content_key);
+        match entries.get(&content_id) {
+            Some(missed) if now.saturating_duration_since(*missed) < self.ttl => true,
+            Some(_) => {
+                entries.remove(&content_id);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remember that a lookup for the content found nothing.
+    pub fn insert(&self, content_key: &[u8]) {
+        if self.ttl > Duration::from_secs(0) {
+            self.entries
+                .lock()
+                .insert(content_id(content_key), self.clock.now());
+        }
+    }
+
+    /// Forget a miss, because the content arrived after all.
+    pub fn remove(&self, content_key: &[u8]) {
+        self.entries.lock().remove(&content_id(content_key));
+    }
+}
+
+/// Content that recent lookups found and validated, by content id, so that bursts of requests for
+/// the same content are served from memory until the entry expires, whether or not the content is
+/// stored. Holds up to `capacity` entries, evicting the least recently used; a zero capacity or
+/// TTL caches nothing.
+pub struct FoundContentCache {
+    ttl: Duration,
+    /// Content id -> when it was found, and the content
+    entries: Option<Mutex<LruCache<[u8; 32], (Instant, Vec<u8>)>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FoundContentCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self::with_clock(capacity, ttl, Arc::new(SystemClock))
+    }
+
+    /// A cache whose entries expire by the given clock.
+    pub fn with_clock(capacity: usize, ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        let entries = if capacity > 0 && ttl > Duration::from_secs(0) {
+            Some(Mutex::new(LruCache::new(capacity)))
+        } else {
+            None
+        };
+        Self {
+            ttl,
+            entries,
+            clock,
+        }
+    }
+
+    /// Returns the content if a lookup found it within the TTL.
+    pub fn get(&self, content_key: &[u8]) -> Option<Vec<u8>> {
+        let now = self.clock.now();
+        let mut entries = self.entries.as_ref()?.lock();
+        let content_id = content_id(content_key);
+        match entries.get(&content_id) {
+            Some((found, content)) if now.saturating_duration_since(*found) < self.ttl => {
+                Some(content.clone())
+            }
+            Some(_) => {
+                entries.pop(&content_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remember content a lookup found.
+    pub fn insert(&self, content_key: &[u8], content: Vec<u8>) {
+        if let Some(entries) = &self.entries {
+            entries
+                .lock()
+                .put(content_id(content_key), (self.clock.now(), content));
+        }
+    }
+}
+
+/// How a queried peer responded during a lookup.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceResponse {
+    /// The peer returned the content.
+    Content,
+    /// The peer returned the node ids of peers it considers closer to the content.
+    Enrs(Vec<NodeId>),
+    /// The peer has neither the content nor any closer peers.
+    NotFound,
+    /// The request failed or timed out.
+    Failed(String),
+}
+
+impl TraceResponse {
+    fn kind(&self) -> &'static str {
+        match self {
+            TraceResponse::Content => "content",
+            TraceResponse::Enrs(_) => "enrs",
+            TraceResponse::NotFound => "notFound",
+            TraceResponse::Failed(_) => "error",
+        }
+    }
+}
+
+/// Why a lookup ended without the content.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LookupTermination {
+    /// The hop limit was reached with peers still left to query.
+    HopLimitReached,
+    /// Every peer was queried, and none answered that it lacks the content; they only failed, or
+    /// referred to peers already queried.
+    NoCloserPeers,
+    /// The closest peers reached answered that they have neither the content nor closer peers.
+    ContentNotFound,
+}
+
+impl LookupTermination {
+    fn kind(&self) -> &'static str {
+        match self {
+            LookupTermination::HopLimitReached => "hopLimitReached",
+            LookupTermination::NoCloserPeers => "noCloserPeers",
+            LookupTermination::ContentNotFound => "contentNotFound",
+        }
+    }
+}
+
+impl fmt::Display for LookupTermination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupTermination::HopLimitReached => write!(f, "hop limit reached"),
+            LookupTermination::NoCloserPeers => write!(f, "no closer peers"),
+            LookupTermination::ContentNotFound => write!(f, "content not found"),
+        }
+    }
+}
+
+/// A single peer queried during a lookup.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceHop {
+    pub node_id: NodeId,
+    pub duration: Duration,
+    pub response: TraceResponse,
+}
+
+/// Record of a recursive content lookup, in the order peers were queried.
+#[derive(Clone, Debug)]
+pub struct LookupTrace {
+    pub origin: NodeId,
+    pub target_id: [u8; 32],
+    /// The peer that returned the content, if any did.
+    pub received_from: Option<NodeId>,
+    /// Why the lookup ended, if it didn't find the content.
+    pub termination: Option<LookupTermination>,
+    pub hops: Vec<TraceHop>,
+    /// ENRs of every node seen during the lookup.
+    pub metadata: HashMap<NodeId, Enr>,
+    pub started_at_ms: u64,
+}
+
+impl LookupTrace {
+    /// Returns the trace in the json format used by other portal clients, extended with each
+    /// peer's response type.
+    pub fn to_json(&self) -> Value {
+        let responses: Map<String, Value> = self
+            .hops
+            .iter()
+            .map(|hop| {
+                let responded_with = match &hop.response {
+                    TraceResponse::Enrs(node_ids) => node_ids.iter().map(node_id_hex).collect(),
+                    _ => vec![],
+                };
+                (
+                    node_id_hex(&hop.node_id),
+                    json!({
+                        "durationMs": hop.duration.as_millis() as u64,
+                        "respondedWith": responded_with,
+                        "responseType": hop.response.kind(),
+                    }),
+                )
+            })
+            .collect();
+        let metadata: Map<String, Value> = self
+            .metadata
+            .iter()
+            .map(|(node_id, enr)| {
+                let distance = xor_two_values(&self.target_id, &node_id.raw());
+                (
+                    node_id_hex(node_id),
+                    json!({
+                        "enr": enr.to_base64(),
+                        "distance": format!("0x{}", hex::encode(distance)),
+                    }),
+                )
+            })
+            .collect();
+
+        json!({
+            "origin": node_id_hex(&self.origin),
+            "targetId": format!("0x{}", hex::encode(self.target_id)),
+            "receivedFrom": self.received_from.as_ref().map(node_id_hex),
+            "termination": self.termination.as_ref().map(LookupTermination::kind),
+            "responses": responses,
+            "metadata": metadata,
+            "startedAtMs": self.started_at_ms,
+        })
+    }
+}
+
+/// Look up content by repeatedly querying the closest known peer that hasn't been queried yet,
+/// starting from the seeds, until one returns the content or `max_queries` peers were queried. A
+/// peer that has no closer peers ends that branch, and the lookup moves on to the next candidate.
+/// A peer that didn't respond is queried once more when no candidates are left, while one that
+/// broke the protocol isn't. Peers without a dialable endpoint are never queried.
+pub async fn trace_recursive_find_content<T: FindContentTransport + ?Sized>(
+    transport: &T,
+    local_enr: &Enr,
+    content_key: Vec<u8>,
+    seeds: Vec<Enr>,
+    max_queries: usize,
+) -> (Option<Vec<u8>>, LookupTrace) {
+    trace_lookup_excluding(
+        transport,
+        local_enr,
+        content_key,
+        seeds,
+        max_queries,
+        &HashSet::new(),
+    )
+    .await
+}
+
+/// Look up content, retrying without the peer that served it whenever it fails validation, up to
+/// `retries` times. Returns `None` if the closest peers reached don't have the content, or if a
+/// lookup recently found that, in which case the network isn't queried; nor is it for content a
+/// lookup recently found, which is returned from `found`. Errs, naming the reason, if the lookup
+/// hit the hop limit or ran out of peers before reaching them.
+#[allow(clippy::too_many_arguments)]
+pub async fn find_validated_content<T: FindContentTransport + ?Sized>(
+    transport: &T,
+    validator: &dyn ContentValidator,
+    not_found: &NotFoundCache,
+    found: &FoundContentCache,
+    local_enr: &Enr,
+    content_key: Vec<u8>,
+    seeds: Vec<Enr>,
+    max_queries: usize,
+    retries: usize,
+) -> Result<Option<Vec<u8>>, String> {
+    if not_found.contains(&content_key) {
+        debug!("Content was recently not found, skipping the lookup");
+        return Ok(None);
+    }
+    if let Some(content) = found.get(&content_key) {
+        debug!("Content was recently found, skipping the lookup");
+        return Ok(Some(content));
+    }
+    let mut excluded = HashSet::new();
+    loop {
+        let (content, trace) = trace_lookup_excluding(
+            transport,
+            local_enr,
+            content_key.clone(),
+            seeds.clone(),
+            max_queries,
+            &excluded,
+        )
+        .await;
+        let content = match (content, trace.termination) {
+            (Some(content), _) => content,
+            (None, Some(LookupTermination::ContentNotFound)) => {
+                not_found.insert(&content_key);
+                return Ok(None);
+            }
+            (None, termination) => {
+                return Err(format!(
+                    "Content lookup failed after {} queries: {}",
+                    trace.hops.len(),
+                    termination.unwrap_or(LookupTermination::NoCloserPeers)
+                ))
+            }
+        };
+        match validator.validate(&content_key, &content) {
+            Ok(()) => {
+                found.insert(&content_key, content.clone());
+                return Ok(Some(content));
+            }
+            Err(e) if excluded.len() >= retries => {
+                return Err(format!(
+                    "Content failed validation after {} retries: {}",
+                    retries, e
+                ))
+            }
+            Err(e) => {
+                let offender = trace
+                    .received_from
+                    .expect("a lookup that returned content records its sender");
+                debug!(
+                    "Retrying lookup without {}, whose content failed validation: {}",
+                    offender, e
+                );
+                excluded.insert(offender);
+            }
+        }
+    }
+}
+
+/// Run a lookup that never queries the `excluded` peers.
+async fn trace_lookup_excluding<T: FindContentTransport + ?Sized>(
+    transport: &T,
+    local_enr: &Enr,
+    content_key: Vec<u8>,
+    seeds: Vec<Enr>,
+    max_queries: usize,
+    excluded: &HashSet<NodeId>,
+) -> (Option<Vec<u8>>, LookupTrace) {
+    let target_id = content_id(&content_key);
+    let started_at_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let mut trace = LookupTrace {
+        origin: local_enr.node_id(),
+        target_id,
+        received_from: None,
+        termination: None,
+        hops: vec![],
+        metadata: HashMap::new(),
+        started_at_ms,
+    };
+    trace
+        .metadata
+        .insert(local_enr.node_id(), local_enr.clone());
+
+    // Unqueried peers, closest to the content first.
+    let mut candidates: BTreeMap<Vec<u8>, Enr> = BTreeMap::new();
+    // Peers that didn't respond, to query again once the candidates run out.
+    let mut unanswered: Vec<Enr> = vec![];
+    let mut retried: HashSet<NodeId> = HashSet::new();
+    let mut seen: HashSet<NodeId> = excluded.clone();
+    seen.insert(local_enr.node_id());
+    for enr in seeds {
+        if seen.insert(enr.node_id()) {
+            trace.metadata.insert(enr.node_id(), enr.clone());
+            add_candidate(&mut candidates, &target_id, enr);
+        }
+    }
+
+    while trace.hops.len() < max_queries {
+        let enr = match candidates.keys().next().cloned() {
+            Some(distance) => candidates
+                .remove(&distance)
+                .expect("candidate was just found"),
+            None => match unanswered.pop() {
+                Some(enr) => enr,
+                None => break,
+            },
+        };
+        let node_id = enr.node_id();
+
+        let started = Instant::now();
+        let result = transport
+            .find_content(enr.clone(), content_key.clone())
+            .await;
+        let duration = started.elapsed();
+
+        let response = match result {
+            Ok(FindContentResult::Content(content)) => {
+                trace.hops.push(TraceHop {
+                    node_id,
+                    duration,
+                    response: TraceResponse::Content,
+                });
+                trace.received_from = Some(node_id);
+                return (Some(content), trace);
+            }
+            Ok(FindContentResult::Enrs(enrs)) => {
+                let mut responded_with = vec![];
+                for enr in enrs {
+                    responded_with.push(enr.node_id());
+                    if seen.insert(enr.node_id()) {
+                        trace.metadata.insert(enr.node_id(), enr.clone());
+                        add_candidate(&mut candidates, &target_id, enr);
+                    }
+                }
+                TraceResponse::Enrs(responded_with)
+            }
+            Ok(FindContentResult::NotFound) => TraceResponse::NotFound,
+            Err(DiscoveryError::NoResponse) => {
+                if retried.insert(node_id) {
+                    unanswered.push(enr);
+                }
+                TraceResponse::Failed("no response".to_string())
+            }
+            Err(DiscoveryError::ProtocolError(e)) => TraceResponse::Failed(e),
+        };
+        trace.hops.push(TraceHop {
+            node_id,
+            duration,
+            response,
+        });
+    }
+
+    trace.termination = Some(if !candidates.is_empty() || !unanswered.is_empty() {
+        LookupTermination::HopLimitReached
+    } else if trace
+        .hops
+        .iter()
+        .any(|hop| hop.response == TraceResponse::NotFound)
+    {
+        LookupTermination::ContentNotFound
+    } else {
+        LookupTermination::NoCloserPeers
+    });
+    (None, trace)
+}
+
+/// Find the peers closest to a target node id, by repeatedly asking the closest known peer that
+/// hasn't been asked yet for the nodes around the target. The lookup has converged once the
+/// `FIND_NODES_RESULTS` closest candidates have all answered, and then returns them, closest
+/// first. Peers that don't answer are dropped. At most `FIND_NODES_MAX_CANDIDATES` candidates are
+/// kept, and at most `max_queries` peers are asked; a lookup cut short returns the closest peers
+/// it knows of.
+pub async fn recursive_find_nodes<T: FindNodesTransport + ?Sized>(
+    transport: &T,
+    local_enr: &Enr,
+    target: NodeId,
+    seeds: Vec<Enr>,
+    max_queries: usize,
+) -> Vec<Enr> {
+    let target_id = target.raw();
+    // Candidates closest to the target first, with whether each has answered a query.
+    let mut candidates: BTreeMap<Vec<u8>, (Enr, bool)> = BTreeMap::new();
+    // Peers skipped when they're returned again: the local node, and those that didn't answer.
+    let mut skipped: HashSet<NodeId> = HashSet::new();
+    skipped.insert(local_enr.node_id());
+    for enr in seeds {
+        if !skipped.contains(&enr.node_id()) {
+            add_node_candidate(&mut candidates, &target_id, enr);
+        }
+    }
+
+    let mut queries = 0;
+    while queries < max_queries {
+        let next = candidates
+            .iter()
+            .take(FIND_NODES_RESULTS)
+            .find(|(_, (_, queried))| !queried)
+            .map(|(distance, (enr, _))| (distance.clone(), enr.clone()));
+        let (distance, enr) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        queries += 1;
+
+        // Ask for the peer's bucket holding the target, and its neighbours, which between them
+        // hold every node closer to the target than the peer.
+        let bucket = log2_distance(&distance) as u16;
+        let distances = vec![bucket, bucket.saturating_add(1), bucket.saturating_sub(1)]
+            .into_iter()
+            .filter(|distance| (1..=256).contains(distance))
+            .collect();
+        match transport.find_nodes(enr.clone(), distances).await {
+            Ok(result) => {
+                if let Some(candidate) = candidates.get_mut(&distance) {
+                    candidate.1 = true;
+                }
+                if let FindNodesResult::Enrs(enrs) = result {
+                    for enr in enrs {
+                        if !skipped.contains(&enr.node_id()) {
+                            add_node_candidate(&mut candidates, &target_id, enr);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("Dropping lookup candidate {}: {}", enr.node_id(), e);
+                candidates.remove(&distance);
+                skipped.insert(enr.node_id());
+            }
+        }
+    }
+
+    candidates
+        .into_iter()
+        .take(FIND_NODES_RESULTS)
+        .map(|(_, (enr, _))| enr)
+        .collect()
+}
+
+/// Queue a peer to be queried, by its distance to the content, unless it can't be dialed.
+fn add_candidate(candidates: &mut BTreeMap<Vec<u8>, Enr>, target_id: &[u8; 32], enr: Enr) {
+    if !is_dialable(&enr) {
+        debug!("Skipping lookup candidate without a UDP endpoint: {}", enr);
+        return;
+    }
+    candidates.insert(xor_two_values(target_id, &enr.node_id().raw()), enr);
+}
+
+/// Add a peer to a recursive FINDNODES's candidates, unless it can't be dialed, then drop the
+/// farthest candidates beyond the cap.
+fn add_node_candidate(
+    candidates: &mut BTreeMap<Vec<u8>, (Enr, bool)>,
+    target_id: &[u8; 32],
+    enr: Enr,
+) {
+    if !is_dialable(&enr) {
+        debug!("Skipping lookup candidate without a UDP endpoint: {}", enr);
+        return;
+    }
+    candidates
+        .entry(xor_two_values(target_id, &enr.node_id().raw()))
+        .or_insert((enr, false));
+    while candidates.len() > FIND_NODES_MAX_CANDIDATES {
+        let farthest = candidates.keys().next_back().cloned().expect("not empty");
+        candidates.remove(&farthest);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::clock::MockClock;
+    use crate::portalnet::faults::{Fault, FaultyTransport};
+    use crate::portalnet::types::{FoundContent, SszEnr};
+    use discv5::enr::{CombinedKey, EnrBuilder};
+
+    fn enr(key_byte: u8) -> Enr {
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("127.0.0.1".parse().unwrap());
+        builder.udp(9000 + key_byte as u16);
+        builder.build(&enr_key).unwrap()
+    }
+
+    /// Peers answer with a fixed response. Peers missing from the network are unreachable, and
+    /// silent peers never respond. Every query is recorded.
+    #[derive(Default)]
+    struct MockNetwork {
+        responses: HashMap<NodeId, FoundContent>,
+        silent: HashSet<NodeId>,
+        queried: Mutex<Vec<NodeId>>,
+    }
+
+    impl MockNetwork {
+        fn refer(&mut self, peer: &Enr, closer: &[&Enr]) {
+            self.responses.insert(
+                peer.node_id(),
+                FoundContent {
+                    enrs: closer
+                        .iter()
+                        .map(|enr| SszEnr::new((*enr).clone()))
+                        .collect(),
+                    payload: vec![],
+                },
+            );
+        }
+
+        fn serve(&mut self, peer: &Enr, content: Vec<u8>) {
+            self.responses.insert(
+                peer.node_id(),
+                FoundContent {
+                    enrs: vec![],
+                    payload: content,
+                },
+            );
+        }
+    }
+
+    impl FindContentTransport for MockNetwork {
+        fn find_content(
+            &self,
+            enr: Enr,
+            _content_key: Vec<u8>,
+        ) -> BoxFuture<'_, Result<FindContentResult, DiscoveryError>> {
+            self.queried.lock().push(enr.node_id());
+            let response = match self.silent.contains(&enr.node_id()) {
+                true => Err(DiscoveryError::NoResponse),
+                false => self
+                    .responses
+                    .get(&enr.node_id())
+                    .cloned()
+                    .map(FindContentResult::from)
+                    .ok_or_else(|| DiscoveryError::ProtocolError("unreachable".to_string())),
+            };
+            Box::pin(async move { response })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_lists_hops_in_order() {
+        let local = enr(1);
+        let (a, b, c) = (enr(2), enr(3), enr(4));
+        let mut network = MockNetwork::default();
+        network.refer(&a, &[&b]);
+        network.refer(&b, &[&a, &c, &local]);
+        network.serve(&c, vec![0xaa; 8]);
+
+        let (content, trace) =
+            trace_recursive_find_content(&network, &local, vec![1, 2, 3], vec![a.clone()], 16)
+                .await;
+
+        assert_eq!(content, Some(vec![0xaa; 8]));
+        let hops: Vec<(NodeId, TraceResponse)> = trace
+            .hops
+            .iter()
+            .map(|hop| (hop.node_id, hop.response.clone()))
+            .collect();
+        assert_eq!(
+            hops,
+            vec![
+                (a.node_id(), TraceResponse::Enrs(vec![b.node_id()])),
+                (
+                    b.node_id(),
+                    TraceResponse::Enrs(vec![a.node_id(), c.node_id(), local.node_id()])
+                ),
+                (c.node_id(), TraceResponse::Content),
+            ]
+        );
+        assert_eq!(trace.origin, local.node_id());
+        assert_eq!(trace.received_from, Some(c.node_id()));
+        assert_eq!(trace.metadata.len(), 4);
+
+        let json = trace.to_json();
+        assert_eq!(
+            json["receivedFrom"],
+            Value::String(node_id_hex(&c.node_id()))
+        );
+        assert_eq!(
+            json["responses"][node_id_hex(&b.node_id())]["responseType"],
+            Value::String("enrs".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trace_records_failed_lookup() {
+        let local = enr(1);
+        let (a, unreachable) = (enr(2), enr(3));
+        let mut network = MockNetwork::default();
+        network.refer(&a, &[&unreachable]);
+
+        let (content, trace) =
+            trace_recursive_find_content(&network, &local, vec![1, 2, 3], vec![a.clone()], 16)
+                .await;
+
+        assert_eq!(content, None);
+        assert_eq!(trace.received_from, None);
+        assert_eq!(trace.termination, Some(LookupTermination::NoCloserPeers));
+        assert_eq!(trace.hops.len(), 2);
+        assert_eq!(
+            trace.hops[1].response,
+            TraceResponse::Failed("unreachable".to_string())
+        );
+        assert_eq!(trace.to_json()["receivedFrom"], Value::Null);
+        assert_eq!(
+            trace.to_json()["termination"],
+            Value::String("noCloserPeers".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_without_closer_peers_ends_its_branch() {
+        let local = enr(1);
+        let (a, b, c) = (enr(2), enr(3), enr(4));
+        let mut network = MockNetwork::default();
+        network.refer(&a, &[]);
+        network.refer(&b, &[&c]);
+        network.serve(&c, vec![]);
+
+        let (content, trace) = trace_recursive_find_content(
+            &network,
+            &local,
+            vec![1, 2, 3],
+            vec![a.clone(), b.clone()],
+            16,
+        )
+        .await;
+
+        assert_eq!(content, None);
+        assert_eq!(trace.termination, Some(LookupTermination::ContentNotFound));
+        assert_eq!(trace.hops.len(), 3);
+        for hop in trace.hops.iter() {
+            if hop.node_id != b.node_id() {
+                assert_eq!(hop.response, TraceResponse::NotFound);
+            }
+        }
+        assert_eq!(
+            trace.to_json()["responses"][node_id_hex(&c.node_id())]["responseType"],
+            Value::String("notFound".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_is_retried_once_after_other_candidates() {
+        let local = enr(1);
+        let (a, silent, b) = (enr(2), enr(3), enr(4));
+        let mut network = MockNetwork::default();
+        network.refer(&a, &[&silent, &b]);
+        network.refer(&b, &[]);
+        network.silent.insert(silent.node_id());
+
+        let (content, trace) =
+            trace_recursive_find_content(&network, &local, vec![1, 2, 3], vec![a.clone()], 16)
+                .await;
+
+        assert_eq!(content, None);
+        let queried: Vec<NodeId> = trace.hops.iter().map(|hop| hop.node_id).collect();
+        assert_eq!(queried.len(), 4);
+        assert_eq!(queried[0], a.node_id());
+        assert_eq!(queried[3], silent.node_id());
+        assert_eq!(
+            queried
+                .iter()
+                .filter(|node_id| **node_id == silent.node_id())
+                .count(),
+            2
+        );
+        assert_eq!(
+            trace.hops[3].response,
+            TraceResponse::Failed("no response".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enr_without_ip_is_not_queried() {
+        let local = enr(1);
+        let a = enr(2);
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![3; 32].as_mut_slice()).unwrap();
+        let ip_less = EnrBuilder::new("v4").build(&enr_key).unwrap();
+        let mut network = MockNetwork::default();
+        network.refer(&a, &[&ip_less]);
+        network.serve(&ip_less, vec![0xaa; 8]);
+
+        let (content, trace) = trace_recursive_find_content(
+            &network,
+            &local,
+            vec![1, 2, 3],
+            vec![a.clone(), ip_less.clone()],
+            16,
+        )
+        .await;
+
+        assert_eq!(content, None);
+        let queried: Vec<NodeId> = trace.hops.iter().map(|hop| hop.node_id).collect();
+        assert_eq!(queried, vec![a.node_id()]);
+        assert!(trace.metadata.contains_key(&ip_less.node_id()));
+    }
+
+    /// Accepts only content equal to the given value.
+    struct ExpectContent(Vec<u8>);
+
+    impl ContentValidator for ExpectContent {
+        fn validate(&self, _content_key: &[u8], content: &[u8]) -> Result<(), String> {
+            match content == self.0.as_slice() {
+                true => Ok(()),
+                false => Err("bad content".to_string()),
+            }
+        }
+    }
+
+    /// Returns two peers serving the content, the first one queried before the second.
+    fn two_servers(content_key: &[u8]) -> (Enr, Enr) {
+        let target_id = content_id(content_key);
+        let mut peers = vec![enr(2), enr(3)];
+        peers.sort_by_key(|peer| xor_two_values(&target_id, &peer.node_id().raw()));
+        (peers[0].clone(), peers[1].clone())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_content_is_retried_without_its_sender() {
+        let local = enr(1);
+        let content_key = vec![1, 2, 3];
+        let (poisoned, honest) = two_servers(&content_key);
+        let mut network = MockNetwork::default();
+        network.serve(&poisoned, vec![0xbb; 8]);
+        network.serve(&honest, vec![0xaa; 8]);
+
+        let content = find_validated_content(
+            &network,
+            &ExpectContent(vec![0xaa; 8]),
+            &NotFoundCache::new(Duration::from_secs(0)),
+            &FoundContentCache::new(0, Duration::from_secs(0)),
+            &local,
+            content_key,
+            vec![poisoned, honest],
+            16,
+            2,
+        )
+        .await;
+
+        assert_eq!(content, Ok(Some(vec![0xaa; 8])));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_content_fails_when_retries_run_out() {
+        let local = enr(1);
+        let content_key = vec![1, 2, 3];
+        let (poisoned, honest) = two_servers(&content_key);
+        let mut network = MockNetwork::default();
+        network.serve(&poisoned, vec![0xbb; 8]);
+        network.serve(&honest, vec![0xaa; 8]);
+
+        let content = find_validated_content(
+            &network,
+            &ExpectContent(vec![0xaa; 8]),
+            &NotFoundCache::new(Duration::from_secs(0)),
+            &FoundContentCache::new(0, Duration::from_secs(0)),
+            &local,
+            content_key,
+            vec![poisoned, honest],
+            16,
+            0,
+        )
+        .await;
+
+        assert!(content.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_repeat_lookup_within_ttl_skips_the_network() {
+        let local = enr(1);
+        let a = enr(2);
+        let mut network = MockNetwork::default();
+        network.refer(&a, &[]);
+        let not_found = NotFoundCache::new(Duration::from_secs(60));
+        let found = FoundContentCache::new(0, Duration::from_secs(0));
+        let lookup = || {
+            find_validated_content(
+                &network,
+                &ExpectContent(vec![]),
+                &not_found,
+                &found,
+                &local,
+                vec![1, 2, 3],
+                vec![a.clone()],
+                16,
+                0,
+            )
+        };
+
+        assert_eq!(lookup().await, Ok(None));
+        assert_eq!(network.queried.lock().len(), 1);
+        assert_eq!(lookup().await, Ok(None));
+        assert_eq!(network.queried.lock().len(), 1);
+
+        // Content arriving later clears the miss.
+        not_found.remove(&[1, 2, 3]);
+        assert_eq!(lookup().await, Ok(None));
+        assert_eq!(network.queried.lock().len(), 2);
+    }
+
+    #[test]
+    fn test_not_found_entries_expire() {
+        let clock = Arc::new(MockClock::default());
+        let not_found = NotFoundCache::with_clock(Duration::from_secs(10), clock.clone());
+        not_found.insert(&[1]);
+        clock.advance(Duration::from_secs(9));
+        assert!(not_found.contains(&[1]));
+        clock.advance(Duration::from_secs(1));
+        assert!(!not_found.contains(&[1]));
+        assert!(not_found.entries.lock().is_empty());
+
+        let disabled = NotFoundCache::new(Duration::from_secs(0));
+        disabled.insert(&[1]);
+        assert!(!disabled.contains(&[1]));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_exceeding_the_hop_limit_reports_it() {
+        let local = enr(1);
+        let (a, b, c) = (enr(2), enr(3), enr(4));
+        let mut network = MockNetwork::default();
+        network.refer(&a, &[&b]);
+        network.refer(&b, &[&c]);
+        network.serve(&c, vec![0xaa; 8]);
+
+        let (content, trace) =
+            trace_recursive_find_content(&network, &local, vec![1, 2, 3], vec![a.clone()], 2).await;
+        assert_eq!(content, None);
+        assert_eq!(trace.termination, Some(LookupTermination::HopLimitReached));
+
+        let result = find_validated_content(
+            &network,
+            &ExpectContent(vec![0xaa; 8]),
+            &NotFoundCache::new(Duration::from_secs(60)),
+            &FoundContentCache::new(0, Duration::from_secs(0)),
+            &local,
+            vec![1, 2, 3],
+            vec![a],
+            2,
+            0,
+        )
+        .await;
+        assert_eq!(
+            result,
+            Err("Content lookup failed after 2 queries: hop limit reached".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_serving_malformed_content_is_excluded() {
+        let local = enr(1);
+        let content_key = vec![1, 2, 3];
+        let (poisoned, honest) = two_servers(&content_key);
+        let mut network = MockNetwork::default();
+        network.serve(&poisoned, vec![0xaa; 8]);
+        network.serve(&honest, vec![0xaa; 8]);
+        let network = FaultyTransport::new(network);
+        network.inject(poisoned.node_id(), Fault::Malformed);
+
+        let content = find_validated_content(
+            &network,
+            &ExpectContent(vec![0xaa; 8]),
+            &NotFoundCache::new(Duration::from_secs(0)),
+            &FoundContentCache::new(0, Duration::from_secs(0)),
+            &local,
+            content_key,
+            vec![poisoned.clone(), honest.clone()],
+            16,
+            2,
+        )
+        .await;
+
+        assert_eq!(content, Ok(Some(vec![0xaa; 8])));
+        // The retry never went back to the poisoned peer.
+        assert_eq!(network.injected(&poisoned.node_id()), 1);
+        assert_eq!(*network.inner().queried.lock(), vec![honest.node_id()]);
+    }
+
+    #[tokio::test]
+    async fn test_lookups_beyond_the_cap_queue_or_are_rejected() {
+        let limiter = LookupLimiter::new(2, LookupOverflowPolicy::Queue);
+        let running = Arc::new(Mutex::new((0, 0)));
+        let lookups = (0..5).map(|_| {
+            let limiter = limiter.clone();
+            let running = running.clone();
+            tokio::spawn(async move {
+                let _permit = limiter.acquire().await?;
+                {
+                    let mut running = running.lock();
+                    running.0 += 1;
+                    running.1 = running.1.max(running.0);
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.lock().0 -= 1;
+                Ok::<(), String>(())
+            })
+        });
+        for result in futures::future::join_all(lookups).await {
+            assert_eq!(result.unwrap(), Ok(()));
+        }
+        // No more than two ran at once.
+        assert_eq!(*running.lock(), (0, 2));
+
+        let limiter = LookupLimiter::new(2, LookupOverflowPolicy::Reject);
+        let first = limiter.acquire().await.unwrap();
+        let _second = limiter.acquire().await.unwrap();
+        assert_eq!(
+            limiter.acquire().await.err(),
+            Some("Node is busy: 2 recursive lookups are already running".to_string())
+        );
+        drop(first);
+        assert!(limiter.acquire().await.is_ok());
+    }
+
+    /// Every peer knows every other peer, and answers FINDNODES with all of them at the requested
+    /// distances from itself.
+    struct MockNodesNetwork {
+        peers: Vec<Enr>,
+        queried: Mutex<Vec<NodeId>>,
+    }
+
+    impl FindNodesTransport for MockNodesNetwork {
+        fn find_nodes(
+            &self,
+            enr: Enr,
+            distances: Vec<u16>,
+        ) -> BoxFuture<'_, Result<FindNodesResult, DiscoveryError>> {
+            self.queried.lock().push(enr.node_id());
+            let enrs: Vec<Enr> = self
+                .peers
+                .iter()
+                .filter(|peer| {
+                    let distance = xor_two_values(&enr.node_id().raw(), &peer.node_id().raw());
+                    distances.contains(&(log2_distance(&distance) as u16))
+                })
+                .cloned()
+                .collect();
+            let response = match enrs.is_empty() {
+                true => FindNodesResult::Empty,
+                false => FindNodesResult::Enrs(enrs),
+            };
+            Box::pin(async move { Ok(response) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recursive_find_nodes_converges_on_closest_peers() {
+        let local = enr(1);
+        let peers: Vec<Enr> = (2..130).map(enr).collect();
+        let target = NodeId::new(&[0x5a; 32]);
+        let mut by_distance = peers.clone();
+        by_distance.sort_by_key(|peer| xor_two_values(&target.raw(), &peer.node_id().raw()));
+        let closest: Vec<Enr> = by_distance[..FIND_NODES_RESULTS].to_vec();
+        // Start from the peers farthest from the target.
+        let seeds = by_distance[by_distance.len() - 3..].to_vec();
+
+        let network = MockNodesNetwork {
+            peers: peers.clone(),
+            queried: Mutex::new(vec![]),
+        };
+        let found =
+            recursive_find_nodes(&network, &local, target, seeds, LOOKUP_MAX_QUERIES * 4).await;
+
+        assert_eq!(found, closest);
+        // It got there without asking the whole network.
+        let queried = network.queried.lock().len();
+        assert!(queried < peers.len(), "queried {} peers", queried);
+    }
+
+    #[tokio::test]
+    async fn test_repeat_lookup_within_ttl_is_served_from_the_found_cache() {
+        let local = enr(1);
+        let a = enr(2);
+        let mut network = MockNetwork::default();
+        network.serve(&a, vec![0xaa; 8]);
+        let not_found = NotFoundCache::new(Duration::from_secs(0));
+        let found = FoundContentCache::new(16, Duration::from_secs(60));
+        let lookup = || {
+            find_validated_content(
+                &network,
+                &ExpectContent(vec![0xaa; 8]),
+                &not_found,
+                &found,
+                &local,
+                vec![1, 2, 3],
+                vec![a.clone()],
+                16,
+                0,
+            )
+        };
+
+        assert_eq!(lookup().await, Ok(Some(vec![0xaa; 8])));
+        assert_eq!(network.queried.lock().len(), 1);
+        assert_eq!(lookup().await, Ok(Some(vec![0xaa; 8])));
+        assert_eq!(network.queried.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_found_content_expires_and_is_bounded() {
+        let clock = Arc::new(MockClock::default());
+        let found = FoundContentCache::with_clock(2, Duration::from_secs(10), clock.clone());
+        found.insert(&[1], vec![1]);
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(found.get(&[1]), Some(vec![1]));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(found.get(&[1]), None);
+
+        // The least recently used entry is evicted beyond the capacity.
+        for i in 2..=4 {
+            found.insert(&[i], vec![i]);
+        }
+        assert_eq!(found.get(&[2]), None);
+        assert_eq!(found.get(&[4]), Some(vec![4]));
+
+        // A zero capacity or TTL caches nothing.
+        for disabled in vec![
+            FoundContentCache::new(0, Duration::from_secs(10)),
+            FoundContentCache::new(2, Duration::from_secs(0)),
+        ] {
+            disabled.insert(&[1], vec![1]);
+            assert_eq!(disabled.get(&[1]), None);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::{BTreeMap, HashMap};
+
+use parking_lot::RwLock;
+
+use super::types::ContentType;
+
+/// Talk requests dropped because the inbound queue was full.
+pub const TALK_REQUESTS_DROPPED: &str = "talk_requests_dropped";
+/// Inbound content transfers aborted because they stalled.
+pub const TRANSFERS_TIMED_OUT: &str = "transfers_timed_out";
+/// Overlay responses dropped because they didn't match the request they arrived for.
+pub const MISMATCHED_RESPONSES: &str = "mismatched_responses";
+/// Talk requests answered with the default reply because they named a protocol other than portal.
+pub const UNKNOWN_PROTOCOL_REQUESTS: &str = "unknown_protocol_requests";
+/// Offers received that listed the same content key more than once.
+pub const DUPLICATE_KEY_OFFERS: &str = "duplicate_key_offers";
+/// Peers dropped from the routing table for going too long without answering a request.
+pub const PEERS_EVICTED_IDLE: &str = "peers_evicted_idle";
+/// Peers dropped from the routing table to make room for a closer peer under the peer cap.
+pub const PEERS_EVICTED_PEER_CAP: &str = "peers_evicted_peer_cap";
+/// Prefix of the gauges holding how many peers each routing table bucket has, like
+/// `peers_in_bucket_255`.
+pub const PEERS_IN_BUCKET: &str = "peers_in_bucket_";
+/// Prefix of the gauges holding how many routing table peers advertise each client, like
+/// `peers_with_client_trin/v0.1.0`.
+pub const PEERS_WITH_CLIENT: &str = "peers_with_client_";
+/// Manual compactions of the store's database.
+pub const STORE_COMPACTIONS: &str = "store_compactions";
+/// How long the latest compaction of the store took, in milliseconds.
+pub const STORE_COMPACTION_DURATION_MS: &str = "store_compaction_duration_ms";
+/// Bytes of disk compactions of the store have reclaimed.
+pub const STORE_COMPACTION_RECLAIMED_BYTES: &str = "store_compaction_reclaimed_bytes";
+/// Prefix of the counters of content writes to the store, by content type, like
+/// `content_stored_header`.
+pub const CONTENT_STORED: &str = "content_stored_";
+/// Prefix of the counters of content served to peers, by content type.
+pub const CONTENT_SERVED: &str = "content_served_";
+/// Prefix of the counters of content bytes sent to and received from peers, by content type.
+pub const CONTENT_TRANSFER_BYTES: &str = "content_transfer_bytes_";
+/// Prefix of the counters of content from peers that failed validation, by content type.
+pub const CONTENT_VALIDATION_FAILURES: &str = "content_validation_failures_";
+/// 1 once the bootstrap timeout passed without the node joining the network, 0 otherwise.
+pub const BOOTSTRAP_STALLED: &str = "bootstrap_stalled";
+/// 1 while discv5 has been quiet for longer than the watchdog allows, 0 otherwise.
+pub const DISCV5_DEGRADED: &str = "discv5_degraded";
+/// Times discv5 went quiet for longer than the watchdog allows.
+pub const DISCV5_STALLS: &str = "discv5_stalls";
+
+/// Named counters and gauges, shared between the overlay components of a node.
+#[derive(Default)]
+pub struct Metrics {
+    values: RwLock<HashMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn increment(&self, name: &str) {
+        self.increment_by(name, 1);
+    }
+
+    pub fn increment_by(&self, name: &str, amount: u64) {
+        let mut values = self.values.write();
+        let value = values.entry(name.to_owned()).or_insert(0);
+        *value = value.saturating_add(amount);
+    }
+
+    /// Set a gauge to an absolute value.
+    pub fn set(&self, name: &str, value: u64) {
+        self.values.write().insert(name.to_owned(), value);
+    }
+
+    /// Returns the current value, or zero if it was never recorded.
+    pub fn get(&self, name: &str) -> u64 {
+        self.values.read().get(name).copied().unwrap_or(0)
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.values
+            .read()
+            .iter()
+            .map(|(name, value)| (name.clone(), *value))
+            .collect()
+    }
+}
+
+/// Returns the name of the gauge holding how many peers the routing table bucket has.
+pub fn peers_in_bucket(bucket: u8) -> String {
+    format!("{}{}", PEERS_IN_BUCKET, bucket)
+}
+
+/// Returns the name of the gauge holding how many routing table peers run the client.
+pub fn peers_with_client(client: &str) -> String {
+    format!("{}{}", PEERS_WITH_CLIENT, client)
+}
+
+/// Returns the name of the per-content-type counter with the prefix, for the type of the content
+/// key. Keys of an unknown type share an `unknown` counter.
+pub fn for_content(prefix: &str, content_key: &[u8]) -> String {
+    let content_type = ContentType::from_content_key(content_key)
+        .map_or("unknown", |content_type| content_type.name());
+    format!("{}{}", prefix, content_type)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_counters_and_gauges() {
+        let metrics = Metrics::default();
+        metrics.increment("requests");
+        metrics.increment_by("requests", 2);
+        metrics.set("peers", 7);
+        metrics.set("peers", 5);
+
+        assert_eq!(metrics.get("requests"), 3);
+        assert_eq!(metrics.get("peers"), 5);
+        assert_eq!(metrics.get("missing"), 0);
+        assert_eq!(metrics.snapshot().len(), 2);
+    }
+}
@@ -4,10 +4,28 @@ use discv5::enr::CombinedKey;
 use ssz::DecodeError;
 use uint::construct_uint;
 
+pub mod beacon;
+pub mod chain;
+pub mod clock;
 pub mod discovery;
+#[cfg(test)]
+pub mod faults;
+pub mod gossip;
+pub mod lookup;
+pub mod metrics;
 pub mod overlay;
+pub mod peer_filter;
+pub mod pinger;
 pub mod protocol;
+pub mod queue;
+pub mod recorder;
+pub mod sqlite;
+pub mod storage;
+pub mod summary;
+pub mod transfers;
 pub mod types;
+pub mod validation;
+pub mod watchdog;
 
 pub type Enr = discv5::enr::Enr<CombinedKey>;
 
@@ -1,25 +1,48 @@
-use crate::portalnet::types::SszEnr;
+use crate::portalnet::types::{Nodes, SszEnr};
 use crate::portalnet::{Enr, U256};
-use crate::utils::xor_two_values;
+use crate::utils::{log2_distance, xor_two_values};
 
 use discv5::enr::NodeId;
-use discv5::kbucket::{Filter, KBucketsTable};
+use discv5::kbucket::{
+    ConnectionDirection, ConnectionState, Entry, Filter, InsertResult, KBucketsTable, Key,
+    NodeStatus,
+};
 use parking_lot::RwLock;
+use rlp::Encodable;
 use std::sync::Arc;
 use std::time::Duration;
 
-/// Maximum number of ENRs in response to FindNodes.
-const FIND_NODES_MAX_NODES: usize = 32;
 /// Maximum number of ENRs in response to FindContent.
 const FIND_CONTENT_MAX_NODES: usize = 32;
+/// Bytes of an encoded NODES message besides its ENRs: message id, total, and the ENR list offset.
+const NODES_MESSAGE_OVERHEAD: usize = 1 + 1 + 4;
+/// Bytes per ENR in an encoded NODES message besides the ENR itself: its offset in the list.
+const NODES_ENR_OVERHEAD: usize = 4;
+/// Weight of the newest sample in a peer's average response time.
+const RESPONSE_TIME_WEIGHT: f64 = 0.25;
+/// Weight of the newest request in a peer's reliability.
+const RELIABILITY_WEIGHT: f64 = 0.25;
 
 #[derive(Clone)]
 pub struct Node {
     enr: Enr,
     data_radius: U256,
+    /// Exponentially-weighted average of the peer's response times, if it ever responded.
+    response_time: Option<Duration>,
+    /// Exponentially-weighted share of requests the peer answered, if it was ever sent one.
+    reliability: Option<f64>,
 }
 
 impl Node {
+    pub fn new(enr: Enr, data_radius: U256) -> Self {
+        Self {
+            enr,
+            data_radius,
+            response_time: None,
+            reliability: None,
+        }
+    }
+
     pub fn enr(&self) -> Enr {
         self.enr.clone()
     }
@@ -27,6 +50,38 @@ impl Node {
     pub fn data_radius(&self) -> U256 {
         self.data_radius.clone()
     }
+
+    pub fn response_time(&self) -> Option<Duration> {
+        self.response_time
+    }
+
+    /// Returns the weighted share of requests the peer answered, from 0 to 1.
+    pub fn reliability(&self) -> Option<f64> {
+        self.reliability
+    }
+
+    /// Fold whether the peer answered a request into its reliability.
+    pub fn record_outcome(&mut self, responded: bool) {
+        let sample = match responded {
+            true => 1.0,
+            false => 0.0,
+        };
+        self.reliability = Some(match self.reliability {
+            Some(average) => RELIABILITY_WEIGHT * sample + (1.0 - RELIABILITY_WEIGHT) * average,
+            None => sample,
+        });
+    }
+
+    /// Fold a new response time sample into the average.
+    pub fn record_response_time(&mut self, sample: Duration) {
+        self.response_time = Some(match self.response_time {
+            Some(average) => Duration::from_secs_f64(
+                RESPONSE_TIME_WEIGHT * sample.as_secs_f64()
+                    + (1.0 - RESPONSE_TIME_WEIGHT) * average.as_secs_f64(),
+            ),
+            None => sample,
+        });
+    }
 }
 
 impl std::cmp::Eq for Node {}
@@ -44,6 +99,10 @@ pub struct Config {
     max_incoming_per_bucket: usize,
     table_filter: Option<Box<dyn Filter<Node>>>,
     bucket_filter: Option<Box<dyn Filter<Node>>>,
+    /// Maximum number of ENRs in response to FindNodes.
+    pub find_nodes_max_nodes: usize,
+    /// Maximum encoded size of a NODES message, to stay under the discv5 packet size.
+    pub find_nodes_max_payload_bytes: usize,
 }
 
 impl Default for Config {
@@ -53,6 +112,8 @@ impl Default for Config {
             max_incoming_per_bucket: 16,
             table_filter: None,
             bucket_filter: None,
+            find_nodes_max_nodes: 32,
+            find_nodes_max_payload_bytes: 1000,
         }
     }
 }
@@ -66,6 +127,10 @@ pub struct Overlay {
     data_radius: Arc<RwLock<U256>>,
     // The routing table of the local node.
     kbuckets: Arc<RwLock<KBucketsTable<NodeId, Node>>>,
+    // Maximum number of ENRs in response to FindNodes.
+    find_nodes_max_nodes: usize,
+    // Maximum encoded size of a NODES message.
+    find_nodes_max_payload_bytes: usize,
 }
 
 impl Overlay {
@@ -84,6 +149,8 @@ impl Overlay {
             local_enr,
             data_radius,
             kbuckets,
+            find_nodes_max_nodes: config.find_nodes_max_nodes,
+            find_nodes_max_payload_bytes: config.find_nodes_max_payload_bytes,
         }
     }
 
@@ -97,6 +164,11 @@ impl Overlay {
         self.data_radius.read().clone()
     }
 
+    /// Set the data radius of the node, returning the previous one.
+    pub fn set_data_radius(&self, data_radius: U256) -> U256 {
+        std::mem::replace(&mut *self.data_radius.write(), data_radius)
+    }
+
     /// Returns a vector of the ENRs of the closest nodes by the given log2 distances.
     pub fn nodes_by_distance(&self, mut log2_distances: Vec<u64>) -> Vec<Enr> {
         let mut nodes_to_send = Vec::new();
@@ -112,42 +184,117 @@ impl Overlay {
         if !log2_distances.is_empty() {
             let mut kbuckets = self.kbuckets.write();
             for node in kbuckets
-                .nodes_by_distances(log2_distances, FIND_NODES_MAX_NODES)
+                .nodes_by_distances(log2_distances, self.find_nodes_max_nodes)
                 .into_iter()
                 .map(|entry| entry.node.value.clone())
             {
                 nodes_to_send.push(node.enr());
             }
         }
+        nodes_to_send.truncate(self.find_nodes_max_nodes);
         nodes_to_send
     }
 
+    /// Returns the NODES response for the given log2 distances. A talk request gets a single
+    /// response, so only the first page of ENRs that fits the payload budget is sent, and its
+    /// `total` is the one message actually sent.
+    pub fn find_nodes_response(&self, log2_distances: Vec<u64>) -> Nodes {
+        let enrs = self.nodes_by_distance(log2_distances);
+        let enrs = paginate_nodes(enrs, self.find_nodes_max_payload_bytes)
+            .into_iter()
+            .next()
+            .map(|page| page.enrs)
+            .unwrap_or_default();
+        Nodes { total: 1, enrs }
+    }
+
+    /// Add a node to the routing table, or refresh its ENR and data radius if already present.
+    /// A known node keeps its response time history. Returns whether the node was newly added.
+    pub fn insert_node(&self, enr: Enr, data_radius: U256) -> bool {
+        let key = Key::from(enr.node_id());
+        let mut kbuckets = self.kbuckets.write();
+        if let Entry::Present(mut entry, _) = kbuckets.entry(&key) {
+            let node = entry.value();
+            node.enr = enr;
+            node.data_radius = data_radius;
+            return false;
+        }
+        let status = NodeStatus {
+            state: ConnectionState::Connected,
+            direction: ConnectionDirection::Outgoing,
+        };
+        matches!(
+            kbuckets.insert_or_update(&key, Node::new(enr, data_radius), status),
+            InsertResult::Inserted
+        )
+    }
+
+    /// Record how long a peer took to respond. Peers not in the routing table are ignored.
+    pub fn record_response_time(&self, node_id: &NodeId, response_time: Duration) {
+        let key = Key::from(*node_id);
+        if let Entry::Present(mut entry, _) = self.kbuckets.write().entry(&key) {
+            entry.value().record_response_time(response_time);
+        }
+    }
+
+    /// Record whether a peer answered a request. Peers not in the routing table are ignored.
+    pub fn record_outcome(&self, node_id: &NodeId, responded: bool) {
+        let key = Key::from(*node_id);
+        if let Entry::Present(mut entry, _) = self.kbuckets.write().entry(&key) {
+            entry.value().record_outcome(responded);
+        }
+    }
+
+    /// Returns up to `n` routing table nodes closest to the target, by log2 distance. Among
+    /// nodes at the same log2 distance, faster responders come first, and nodes that never
+    /// responded come last.
+    pub fn closest_nodes(&self, target: &[u8], n: usize) -> Vec<Node> {
+        let mut nodes: Vec<(u64, Vec<u8>, Node)> = self
+            .kbuckets
+            .write()
+            .iter()
+            .map(|entry| {
+                let node = entry.node.value.clone();
+                let distance = xor_two_values(target, &node.enr.node_id().raw().to_vec());
+                (log2_distance(&distance), distance, node)
+            })
+            .collect();
+
+        nodes.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then_with(|| match (a.2.response_time, b.2.response_time) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    (Some(_), None) => std::cmp::Ordering::Less,
+                    (None, Some(_)) => std::cmp::Ordering::Greater,
+                    (None, None) => std::cmp::Ordering::Equal,
+                })
+                .then_with(|| a.1.cmp(&b.1))
+        });
+
+        nodes.into_iter().take(n).map(|node| node.2).collect()
+    }
+
     /// Returns list of nodes closer to content than self, sorted by distance.
     pub fn find_nodes_close_to_content(&self, content_key: Vec<u8>) -> Vec<SszEnr> {
         let self_node_id = self.local_enr().node_id();
         let self_distance = xor_two_values(&content_key, &self_node_id.raw().to_vec());
 
-        let mut nodes_with_distance: Vec<(Vec<u8>, Enr)> = self
-            .table_entries_enr()
+        self.closest_nodes(&content_key, FIND_CONTENT_MAX_NODES)
             .into_iter()
-            .map(|enr| {
-                (
-                    xor_two_values(&content_key, &enr.node_id().raw().to_vec()),
-                    enr,
-                )
+            .filter(|node| {
+                xor_two_values(&content_key, &node.enr.node_id().raw().to_vec()) < self_distance
             })
-            .collect();
-
-        nodes_with_distance.sort_by(|a, b| a.0.cmp(&b.0));
-
-        let closest_nodes = nodes_with_distance
-            .into_iter()
-            .take(FIND_CONTENT_MAX_NODES)
-            .filter(|node_record| &node_record.0 < &self_distance)
-            .map(|node_record| SszEnr::new(node_record.1))
-            .collect();
+            .map(|node| SszEnr::new(node.enr))
+            .collect()
+    }
 
-        closest_nodes
+    /// Returns all nodes currently contained in the routing table.
+    pub fn nodes(&self) -> Vec<Node> {
+        self.kbuckets
+            .write()
+            .iter()
+            .map(|entry| entry.node.value.clone())
+            .collect()
     }
 
     /// Returns a vector of all ENR node IDs of nodes currently contained in the routing table.
@@ -168,3 +315,190 @@ impl Overlay {
             .collect()
     }
 }
+
+/// Splits ENRs into NODES messages whose encoding fits in `max_payload_bytes`, each carrying the
+/// total number of messages. ENRs too large to fit in any message are skipped.
+pub fn paginate_nodes(enrs: Vec<Enr>, max_payload_bytes: usize) -> Vec<Nodes> {
+    let mut pages: Vec<Vec<Enr>> = vec![];
+    let mut page_bytes = NODES_MESSAGE_OVERHEAD;
+
+    for enr in enrs {
+        let enr_bytes = enr.rlp_bytes().len() + NODES_ENR_OVERHEAD;
+        if NODES_MESSAGE_OVERHEAD + enr_bytes > max_payload_bytes {
+            continue;
+        }
+
+        match pages.last_mut() {
+            Some(page) if page_bytes + enr_bytes <= max_payload_bytes => {
+                page.push(enr);
+                page_bytes += enr_bytes;
+            }
+            _ => {
+                pages.push(vec![enr]);
+                page_bytes = NODES_MESSAGE_OVERHEAD + enr_bytes;
+            }
+        }
+    }
+
+    let total = pages.len() as u8;
+    pages
+        .into_iter()
+        .map(|enrs| Nodes { total, enrs })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::types::{Message, Response};
+    use discv5::enr::{CombinedKey, EnrBuilder};
+
+    fn enr(key_byte: u8, padding: usize) -> Enr {
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("127.0.0.1".parse().unwrap());
+        builder.udp(9000);
+        if padding > 0 {
+            builder.add_value("padding", &vec![0u8; padding]);
+        }
+        builder.build(&enr_key).unwrap()
+    }
+
+    fn encoded_len(nodes: &Nodes) -> usize {
+        Message::Response(Response::Nodes(nodes.clone()))
+            .to_bytes()
+            .len()
+    }
+
+    #[test]
+    fn test_many_enrs_are_paginated_within_budget() {
+        let enrs: Vec<Enr> = (1..=40).map(|i| enr(i, 0)).collect();
+        let pages = paginate_nodes(enrs, 1000);
+
+        assert!(pages.len() > 1);
+        for page in pages.iter() {
+            assert_eq!(page.total as usize, pages.len());
+            assert!(!page.enrs.is_empty());
+            assert!(encoded_len(page) <= 1000);
+        }
+        let paged_enrs: usize = pages.iter().map(|page| page.enrs.len()).sum();
+        assert_eq!(paged_enrs, 40);
+    }
+
+    #[test]
+    fn test_page_size_accounting_is_exact() {
+        let enrs: Vec<Enr> = (1..=3).map(|i| enr(i, 0)).collect();
+        let pages = paginate_nodes(enrs.clone(), usize::MAX);
+        assert_eq!(pages.len(), 1);
+
+        let expected_len = NODES_MESSAGE_OVERHEAD
+            + enrs
+                .iter()
+                .map(|enr| enr.rlp_bytes().len() + NODES_ENR_OVERHEAD)
+                .sum::<usize>();
+        assert_eq!(encoded_len(&pages[0]), expected_len);
+    }
+
+    #[test]
+    fn test_oversized_enr_is_skipped() {
+        let small = enr(1, 0);
+        let oversized = enr(2, 150);
+        let budget = NODES_MESSAGE_OVERHEAD + NODES_ENR_OVERHEAD + small.rlp_bytes().len();
+
+        let pages = paginate_nodes(vec![small.clone(), oversized, small.clone()], budget);
+        assert_eq!(pages.len(), 2);
+        for page in pages.iter() {
+            assert_eq!(page.total, 2);
+            assert_eq!(page.enrs, vec![small.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_no_enrs() {
+        assert!(paginate_nodes(vec![], 1000).is_empty());
+    }
+
+    #[test]
+    fn test_find_nodes_response_is_one_message_within_budget() {
+        let budget =
+            NODES_MESSAGE_OVERHEAD + 3 * (NODES_ENR_OVERHEAD + enr(2, 0).rlp_bytes().len());
+        let overlay = Overlay::new(
+            enr(1, 0),
+            U256::from(u64::MAX),
+            Config {
+                find_nodes_max_payload_bytes: budget,
+                ..Default::default()
+            },
+        );
+        for i in 2..=20 {
+            overlay.insert_node(enr(i, 0), U256::from(u64::MAX));
+        }
+
+        let response = overlay.find_nodes_response((1..=256).collect());
+        assert_eq!(response.total, 1);
+        assert!(!response.enrs.is_empty());
+        assert!(response.enrs.len() < 19);
+        assert!(encoded_len(&response) <= budget);
+    }
+
+    #[test]
+    fn test_lower_latency_peer_is_selected_first_among_equal_distance() {
+        let overlay = Overlay::new(enr(1, 0), U256::from(u64::MAX), Config::default());
+        let target = [0u8; 32];
+        let peers: Vec<Enr> = (2..=20).map(|i| enr(i, 0)).collect();
+        for peer in peers.iter() {
+            overlay.insert_node(peer.clone(), U256::from(u64::MAX));
+        }
+
+        let distance = |enr: &Enr| log2_distance(&xor_two_values(&target, &enr.node_id().raw()));
+        let (slow, fast) = peers
+            .iter()
+            .enumerate()
+            .find_map(|(i, a)| {
+                peers[i + 1..]
+                    .iter()
+                    .find(|b| distance(a) == distance(b))
+                    .map(|b| (a.clone(), b.clone()))
+            })
+            .expect("two peers at the same log2 distance");
+
+        // Whichever is ordered first by raw distance is made slower.
+        let (slow, fast) = if xor_two_values(&target, &slow.node_id().raw())
+            < xor_two_values(&target, &fast.node_id().raw())
+        {
+            (slow, fast)
+        } else {
+            (fast, slow)
+        };
+        overlay.record_response_time(&slow.node_id(), Duration::from_millis(900));
+        overlay.record_response_time(&fast.node_id(), Duration::from_millis(50));
+
+        let selected: Vec<Enr> = overlay
+            .closest_nodes(&target, peers.len())
+            .into_iter()
+            .map(|node| node.enr())
+            .filter(|enr| *enr == slow || *enr == fast)
+            .collect();
+        assert_eq!(selected, vec![fast, slow]);
+    }
+
+    #[test]
+    fn test_response_time_is_averaged() {
+        let mut node = Node::new(enr(1, 0), U256::from(1));
+        assert_eq!(node.response_time(), None);
+        node.record_response_time(Duration::from_secs(1));
+        assert_eq!(node.response_time(), Some(Duration::from_secs(1)));
+        node.record_response_time(Duration::from_secs(5));
+        assert_eq!(node.response_time(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_reliability_is_averaged() {
+        let mut node = Node::new(enr(1, 0), U256::zero());
+        assert_eq!(node.reliability(), None);
+        node.record_outcome(false);
+        assert_eq!(node.reliability(), Some(0.0));
+        node.record_outcome(true);
+        assert_eq!(node.reliability(), Some(0.25));
+    }
+}
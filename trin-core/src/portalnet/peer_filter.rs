@@ -0,0 +1,166 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use super::Enr;
+
+/// A block of IP addresses, like `10.0.0.0/8` or `fd00::/8`. A bare address is a block of one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = match s.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = network
+            .parse()
+            .map_err(|e| format!("Invalid CIDR address {}: {}", s, e))?;
+        let max_prefix_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .map_err(|e| format!("Invalid CIDR prefix length {}: {}", s, e))?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(format!(
+                "Invalid CIDR prefix length {}: at most {} for this address",
+                s, max_prefix_len
+            ));
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Which peers the node talks to, by IP address. Denied peers are always refused. If an
+/// allowlist is set, only peers matching it are accepted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl PeerFilter {
+    pub fn allows_ip(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Returns whether the peer is accepted by the IP addresses in its ENR. A peer that doesn't
+    /// advertise an address is only accepted without an allowlist.
+    pub fn allows_enr(&self, enr: &Enr) -> bool {
+        let ips: Vec<IpAddr> = enr
+            .ip()
+            .map(IpAddr::V4)
+            .into_iter()
+            .chain(enr.ip6().map(IpAddr::V6))
+            .collect();
+        if ips.is_empty() {
+            return self.allows_unknown();
+        }
+        ips.iter().all(|ip| self.allows_ip(ip))
+    }
+
+    /// Returns whether a peer whose address isn't known is accepted.
+    pub fn allows_unknown(&self) -> bool {
+        self.allow.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use discv5::enr::{CombinedKey, EnrBuilder};
+
+    fn enr(ip: &str) -> Enr {
+        let enr_key = CombinedKey::generate_secp256k1();
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip(ip.parse().unwrap());
+        builder.udp(9000);
+        builder.build(&enr_key).unwrap()
+    }
+
+    fn cidrs(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_denied_cidr_peer_is_rejected() {
+        let filter = PeerFilter {
+            allow: vec![],
+            deny: cidrs(&["10.0.0.0/8"]),
+        };
+        assert!(!filter.allows_enr(&enr("10.1.2.3")));
+        assert!(filter.allows_enr(&enr("11.1.2.3")));
+        assert!(filter.allows_unknown());
+    }
+
+    #[test]
+    fn test_allowlist_excludes_non_matching_peers() {
+        let filter = PeerFilter {
+            allow: cidrs(&["192.168.0.0/16", "fd00::/8"]),
+            deny: cidrs(&["192.168.1.0/24"]),
+        };
+        assert!(filter.allows_enr(&enr("192.168.0.7")));
+        assert!(!filter.allows_enr(&enr("192.168.1.7")));
+        assert!(!filter.allows_enr(&enr("127.0.0.1")));
+        assert!(filter.allows_ip(&"fd12::1".parse().unwrap()));
+        assert!(!filter.allows_unknown());
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        let cidr: Cidr = "1.2.3.4".parse().unwrap();
+        assert_eq!(cidr.to_string(), "1.2.3.4/32");
+        assert!(cidr.contains(&"1.2.3.4".parse().unwrap()));
+        assert!(!cidr.contains(&"1.2.3.5".parse().unwrap()));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&"8.8.8.8".parse().unwrap()));
+        assert!(!everything.contains(&"::1".parse().unwrap()));
+
+        assert!("1.2.3.4/33".parse::<Cidr>().is_err());
+        assert!("1.2.3/8".parse::<Cidr>().is_err());
+    }
+}
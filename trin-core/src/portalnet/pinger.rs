@@ -0,0 +1,149 @@
+//! Schedules the maintenance pings that keep overlay peers' radii and liveness current. Each
+//! peer's interval follows how it has behaved: reliable, fast peers are pinged less often than the
+//! base interval, and flaky ones more often.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use discv5::enr::NodeId;
+use parking_lot::Mutex;
+
+use super::clock::{Clock, SystemClock};
+use super::overlay::Node;
+
+/// Most a peer's interval is stretched, for a fully reliable peer, or shrunk, for one that never
+/// answers, relative to the base interval.
+pub const MAX_INTERVAL_FACTOR: f64 = 4.0;
+
+/// Peers slower than this on average are pinged at least as often as the base interval, however
+/// reliable they are.
+const SLOW_RESPONSE_TIME: Duration = Duration::from_millis(500);
+
+pub struct PingScheduler {
+    base_interval: Duration,
+    /// When each peer was last pinged, or first seen if it hasn't been pinged yet
+    last_ping: Mutex<HashMap<NodeId, Instant>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl PingScheduler {
+    pub fn new(base_interval: Duration) -> Self {
+        Self::with_clock(base_interval, Arc::new(SystemClock))
+    }
+
+    /// A scheduler whose intervals are measured by the given clock.
+    pub fn with_clock(base_interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            base_interval,
+            last_ping: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// How often the scheduler should be asked for due peers, to ping the most flaky peers on time.
+    pub fn tick_interval(&self) -> Duration {
+        self.base_interval
+            .div_f64(MAX_INTERVAL_FACTOR)
+            .max(Duration::from_secs(1))
+    }
+
+    /// Returns how long to wait between pings of the peer. A peer without a track record gets the
+    /// base interval. Otherwise the interval scales geometrically with reliability, from a
+    /// `MAX_INTERVAL_FACTOR` times shorter for a peer that never answers, up to that many times
+    /// longer for one that always does, unless it's slow.
+    pub fn interval_for(&self, node: &Node) -> Duration {
+        let reliability = match node.reliability() {
+            Some(reliability) => reliability,
+            None => return self.base_interval,
+        };
+        let mut factor = MAX_INTERVAL_FACTOR.powf(2.0 * reliability - 1.0);
+        if node
+            .response_time()
+            .map_or(false, |response_time| response_time > SLOW_RESPONSE_TIME)
+        {
+            factor = factor.min(1.0);
+        }
+        self.base_interval.mul_f64(factor)
+    }
+
+    /// Returns the peers whose interval has passed since they were last pinged, and marks them
+    /// pinged now. Peers seen for the first time start their interval now.
+    pub fn due_nodes(&self, nodes: Vec<Node>) -> Vec<Node> {
+        let now = self.clock.now();
+        let mut last_ping = self.last_ping.lock();
+        // Peers that left the routing table no longer need tracking.
+        let present: HashSet<NodeId> = nodes.iter().map(|node| node.enr().node_id()).collect();
+        last_ping.retain(|node_id, _| present.contains(node_id));
+
+        nodes
+            .into_iter()
+            .filter(|node| {
+                let last = last_ping.entry(node.enr().node_id()).or_insert(now);
+                if now.saturating_duration_since(*last) < self.interval_for(node) {
+                    return false;
+                }
+                *last = now;
+                true
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::clock::MockClock;
+    use crate::portalnet::{Enr, U256};
+    use discv5::enr::{CombinedKey, EnrBuilder};
+
+    fn node(key_byte: u8) -> Node {
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("127.0.0.1".parse().unwrap());
+        builder.udp(9000 + key_byte as u16);
+        let enr: Enr = builder.build(&enr_key).unwrap();
+        Node::new(enr, U256::from(u64::MAX))
+    }
+
+    #[test]
+    fn test_reliable_peer_is_pinged_less_often_than_unreliable_one() {
+        let base_interval = Duration::from_secs(60);
+        let clock = Arc::new(MockClock::default());
+        let scheduler = PingScheduler::with_clock(base_interval, clock.clone());
+        let mut reliable = node(1);
+        let mut flaky = node(2);
+        let mut slow = node(3);
+        for _ in 0..8 {
+            reliable.record_outcome(true);
+            reliable.record_response_time(Duration::from_millis(20));
+            flaky.record_outcome(false);
+            slow.record_outcome(true);
+            slow.record_response_time(Duration::from_secs(2));
+        }
+        let unknown = node(4);
+        assert!(scheduler.interval_for(&reliable) > base_interval);
+        assert!(scheduler.interval_for(&flaky) < base_interval);
+        assert_eq!(scheduler.interval_for(&slow), base_interval);
+        assert_eq!(scheduler.interval_for(&unknown), base_interval);
+
+        // Over an hour of ticks, count how often each peer comes due.
+        let nodes = vec![reliable.clone(), flaky.clone()];
+        let mut pings: HashMap<NodeId, usize> = HashMap::new();
+        let ticks = Duration::from_secs(3600).as_secs() / scheduler.tick_interval().as_secs();
+        for _ in 0..=ticks {
+            for due in scheduler.due_nodes(nodes.clone()) {
+                *pings.entry(due.enr().node_id()).or_insert(0) += 1;
+            }
+            clock.advance(scheduler.tick_interval());
+        }
+        let reliable_pings = pings[&reliable.enr().node_id()];
+        let flaky_pings = pings[&flaky.enr().node_id()];
+        assert!(
+            reliable_pings < flaky_pings,
+            "reliable peer pinged {} times, flaky peer {} times",
+            reliable_pings,
+            flaky_pings
+        );
+    }
+}
@@ -1,26 +1,64 @@
 #![allow(dead_code)]
 
-use std::net::SocketAddr;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use discv5::enr::NodeId;
 use discv5::{Discv5ConfigBuilder, Discv5Event, TalkRequest};
-use log::{debug, error, warn};
+use futures::future::BoxFuture;
+use log::{debug, error, info, warn};
 use rocksdb::{Options, DB};
-use serde_json::Value;
-use tokio::sync::mpsc;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, Semaphore};
 
-use crate::utils::get_data_dir;
+use crate::bench::{run_store_bench, BenchReport};
+use crate::types::rlp_limits::RlpLimits;
+use crate::utils::{content_distance, data_dir_override, get_data_dir, get_default_data_dir};
 
 use super::{
-    discovery::{Config as DiscoveryConfig, Discovery},
-    overlay::{Config as OverlayConfig, Overlay},
+    chain::{HeaderChain, TrustedCheckpoint},
+    discovery::{Config as DiscoveryConfig, Discovery, DiscoveryError, Subnetwork},
+    gossip::{
+        is_interested, propagate_content, reannounce_newly_covered, seed_new_peer, GossipConfig,
+        OfferTransport, Propagation,
+    },
+    lookup::{
+        content_id, find_validated_content, recursive_find_nodes, trace_recursive_find_content,
+        FindContentTransport, FindNodesTransport, FoundContentCache, LookupLimiter,
+        LookupOverflowPolicy, NotFoundCache, LOOKUP_MAX_QUERIES,
+    },
+    metrics::{
+        self, Metrics, BOOTSTRAP_STALLED, CONTENT_SERVED, CONTENT_TRANSFER_BYTES,
+        DUPLICATE_KEY_OFFERS, MISMATCHED_RESPONSES, TALK_REQUESTS_DROPPED, TRANSFERS_TIMED_OUT,
+        UNKNOWN_PROTOCOL_REQUESTS,
+    },
+    overlay::{Config as OverlayConfig, Node, Overlay},
+    peer_filter::PeerFilter,
+    pinger::PingScheduler,
+    queue::{BoundedQueue, OverflowPolicy},
+    recorder::{Direction, RequestRecorder},
+    sqlite::{self, SqliteBackend, SqliteContentStore},
+    storage::{
+        migrate, BufferedStore, CachedStore, CanonicalIndex, Compactor, CompressedStore,
+        ContentAddressedStore, ContentStore, KeyValueBackend, MemoryBackend, MeteredStore,
+        Migration, OfferAcceptancePolicy, StorageBackend, StoragePolicy, StoreVerification,
+    },
+    summary::StartupSummary,
+    transfers::{TransferError, TransferRegistry},
     types::{
-        FindContent, FindNodes, FoundContent, HexData, Nodes, Ping, Pong, Request, Response, SszEnr,
+        describe_content_key, Accept, Envelope, FindContent, FindContentResult, FindNodes,
+        FindNodesResult, FoundContent, HexData, Offer, Ping, Pong, Request, Response, SszEnr,
     },
+    validation::{ContentSizeLimits, ContentValidator, MeteredValidator, ValidatorRegistry},
+    watchdog::{watch_discv5_activity, ActivityWatchdog},
     U256,
 };
 use super::{types::Message, Enr};
-use crate::socket;
+use crate::socket::{self, SourcePortStrategy};
 
 type Responder<T, E> = mpsc::UnboundedSender<Result<T, E>>;
 
@@ -28,6 +66,32 @@ type Responder<T, E> = mpsc::UnboundedSender<Result<T, E>>;
 pub enum PortalEndpointKind {
     NodeInfo,
     RoutingTableInfo,
+    /// How many routing table peers advertise each client in their ENR.
+    PeerClients,
+    /// Insert a peer into the discv5 routing table.
+    AddEnr(Enr),
+    /// Do a discv5 FindNode query for the node id right away, returning how many peers the
+    /// routing table gained.
+    RecursiveFindNode(NodeId),
+    /// Ping a peer, returning only the data radius it advertises.
+    PeerRadius(Enr),
+    /// Read a content key from the local store only, without any network lookup.
+    LocalContent(Vec<u8>),
+    /// Remove a content key from the local store, returning whether it was stored.
+    DeleteContent(Vec<u8>),
+    /// For each content key, whether it's stored locally and, if not, how many routing table
+    /// peers advertise a radius covering it.
+    Availability(Vec<Vec<u8>>),
+    /// Highest block number in the canonical header index.
+    BlockNumber,
+    /// Progress of the content transfers currently in flight.
+    ActiveTransfers,
+    /// Whether the node is healthy, and the problems it has if not.
+    Health,
+    /// Recursively look up a content key on the network, returning the lookup trace with it.
+    TraceRecursiveFindContent(Vec<u8>),
+    /// Recursively look up a content key on the network, retrying if it fails validation.
+    RecursiveFindContent(Vec<u8>),
 }
 
 #[derive(Debug)]
@@ -39,82 +103,487 @@ pub struct PortalEndpoint {
 #[derive(Clone)]
 pub struct PortalnetConfig {
     pub external_addr: Option<SocketAddr>,
+    /// Local address to bind the discv5 socket to, instead of every interface
+    pub bind_address: Option<IpAddr>,
+    /// Whether discv5 sends from the listen port or a random one
+    pub source_port_strategy: SourcePortStrategy,
     pub private_key: Option<HexData>,
     pub listen_port: u16,
     pub bootnode_enrs: Vec<Enr>,
     pub data_radius: U256,
+    pub subnetworks: Vec<Subnetwork>,
+    /// Maximum number of inbound talk requests waiting to be handled
+    pub talk_request_queue_capacity: usize,
+    /// Which talk requests to drop when the queue is full
+    pub talk_request_overflow_policy: OverflowPolicy,
+    /// Which content types to store and accept in offers
+    pub storage_policy: StoragePolicy,
+    /// Which peers' offers are accepted at all
+    pub offer_acceptance_policy: OfferAcceptancePolicy,
+    /// How long an inbound content transfer may go without progress before it's aborted
+    pub transfer_timeout: Duration,
+    /// Most content transfers in flight at once, inbound and outbound together
+    pub max_transfers: Option<usize>,
+    pub gossip: GossipConfig,
+    /// Number of recently read values, and decoded headers, kept in memory
+    pub store_cache_capacity: usize,
+    /// Content writes buffered in memory before they're flushed to the database; 0 writes
+    /// straight through. Offline nodes don't buffer.
+    pub write_buffer_size: usize,
+    /// How often buffered writes are flushed, besides when the buffer fills
+    pub write_buffer_flush_interval: Duration,
+    /// Whether content is snappy-compressed as it's stored. Compressed content reads back either
+    /// way.
+    pub compress_content: bool,
+    pub storage_backend: StorageBackend,
+    /// Most bytes of content the SQLite store keeps before it evicts the content farthest from the
+    /// node; unlimited without it. RocksDB stores don't evict.
+    pub storage_capacity: Option<u64>,
+    /// Block that anchors the canonical chain; only headers chaining back to it are indexed
+    pub trusted_checkpoint: Option<TrustedCheckpoint>,
+    /// How far ahead of the local clock a live header's timestamp may be
+    pub max_header_future_drift: Duration,
+    /// How many times a lookup is retried, without the sending peer, when content fails validation
+    pub lookup_retries: usize,
+    /// Most peers a lookup queries before it gives up
+    pub lookup_hop_limit: usize,
+    /// Most recursive lookups running at once, across all requests
+    pub max_concurrent_lookups: usize,
+    /// Whether lookups beyond `max_concurrent_lookups` wait or fail as busy
+    pub lookup_overflow_policy: LookupOverflowPolicy,
+    /// How long content no peer had is answered as not found without another lookup
+    pub not_found_ttl: Duration,
+    /// Recently found content kept in memory, whether or not it's stored, to serve bursts of
+    /// requests for it without another lookup
+    pub found_content_cache_capacity: usize,
+    /// How long found content is served from memory; zero disables the cache
+    pub found_content_ttl: Duration,
+    /// Which peers, by IP address, the node talks to
+    pub peer_filter: PeerFilter,
+    /// File the routing table is periodically saved to, and preloaded from on startup
+    pub routing_table_snapshot: Option<PathBuf>,
+    /// Bounds on RLP payloads decoded from peers
+    pub rlp_limits: RlpLimits,
+    /// Most json-rpc requests handled at once
+    pub max_concurrent_requests: usize,
+    /// Most peers to keep in the routing table, not counting bootnodes
+    pub max_peers: Option<usize>,
+    /// How long a peer may go without answering a request before it's dropped from the routing
+    /// table, bootnodes excepted
+    pub max_peer_idle: Option<Duration>,
+    /// Most requests outstanding to any one peer at once, so one slow peer can't hold up a
+    /// lookup's other requests; further requests to the peer wait
+    pub max_requests_per_peer: Option<usize>,
+    /// How often the store's database is compacted; without it, only large eviction batches
+    /// are followed by a compaction
+    pub compaction_interval: Option<Duration>,
+    /// Base interval between maintenance pings of each overlay peer, stretched for reliable peers
+    /// and shrunk for flaky ones; no maintenance pings without it
+    pub ping_interval: Option<Duration>,
+    /// How long discv5 may go without any traffic before it's reported as degraded
+    pub discv5_stall_timeout: Duration,
+    /// How long after startup the node may still have no peers before bootstrap is reported as
+    /// stalled
+    pub bootstrap_timeout: Duration,
+    /// Never serve content outside the data radius, even when it's stored
+    pub strict_radius: bool,
+    /// Never add peers advertising private, loopback or link-local addresses to the routing table
+    pub filter_private_ips: bool,
+    /// Fewest connected peers a recursive lookup is attempted with
+    pub min_lookup_peers: usize,
+    /// Reply to talk requests for protocols other than the portal protocol, so that peers
+    /// speaking several protocols get an answer instead of a timeout
+    pub unknown_protocol_response: Vec<u8>,
+    /// File every overlay request and response is recorded to, if any
+    pub request_log: Option<PathBuf>,
+    /// Size the request log may grow to before it's rotated
+    pub request_log_max_size: u64,
 }
 
 impl Default for PortalnetConfig {
     fn default() -> Self {
         Self {
             external_addr: None,
+            bind_address: None,
+            source_port_strategy: SourcePortStrategy::ListenPort,
             private_key: None,
             listen_port: 4242,
             bootnode_enrs: Vec::<Enr>::new(),
             data_radius: U256::from(u64::MAX), //TODO better data_radius default?
+            subnetworks: vec![],
+            talk_request_queue_capacity: 256,
+            talk_request_overflow_policy: OverflowPolicy::DropOldest,
+            storage_policy: StoragePolicy::default(),
+            offer_acceptance_policy: OfferAcceptancePolicy::default(),
+            transfer_timeout: Duration::from_secs(30),
+            max_transfers: None,
+            gossip: GossipConfig::default(),
+            store_cache_capacity: 1024,
+            write_buffer_size: 0,
+            write_buffer_flush_interval: Duration::from_secs(1),
+            compress_content: false,
+            storage_backend: StorageBackend::default(),
+            storage_capacity: None,
+            trusted_checkpoint: None,
+            max_header_future_drift: Duration::from_secs(15),
+            lookup_retries: 2,
+            lookup_hop_limit: LOOKUP_MAX_QUERIES,
+            max_concurrent_lookups: 16,
+            lookup_overflow_policy: LookupOverflowPolicy::Queue,
+            not_found_ttl: Duration::from_secs(10),
+            found_content_cache_capacity: 256,
+            found_content_ttl: Duration::from_secs(30),
+            peer_filter: PeerFilter::default(),
+            routing_table_snapshot: None,
+            rlp_limits: RlpLimits::default(),
+            max_concurrent_requests: 32,
+            max_peers: None,
+            max_peer_idle: None,
+            max_requests_per_peer: None,
+            compaction_interval: None,
+            ping_interval: None,
+            discv5_stall_timeout: Duration::from_secs(600),
+            bootstrap_timeout: Duration::from_secs(30),
+            strict_radius: false,
+            filter_private_ips: false,
+            min_lookup_peers: 0,
+            unknown_protocol_response: vec![],
+            request_log: None,
+            request_log_max_size: 64 * 1024 * 1024,
         }
     }
 }
 
+impl PortalnetConfig {
+    /// Returns the local socket discv5 binds to: the bind address if one is set, otherwise every
+    /// interface.
+    pub fn listen_socket(&self) -> SocketAddr {
+        let ip = self
+            .bind_address
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        SocketAddr::new(ip, self.listen_port)
+    }
+
+    /// Returns the local socket discv5 actually binds to, which the source port strategy
+    /// decides: the listen socket, or the same address on a random free port.
+    pub fn bind_socket(&self) -> Result<SocketAddr, String> {
+        let listen_socket = self.listen_socket();
+        match self.source_port_strategy {
+            SourcePortStrategy::ListenPort => Ok(listen_socket),
+            SourcePortStrategy::Ephemeral => socket::ephemeral_socket(listen_socket.ip()),
+        }
+    }
+
+    /// Returns the address to advertise in the ENR for the bound socket. An explicit external
+    /// address wins, then what `stun` reports for the socket, then the bind address, and finally
+    /// a guess at the address of the local network interface.
+    fn enr_address(
+        &self,
+        bind_socket: SocketAddr,
+        stun: impl FnOnce(&SocketAddr) -> Option<SocketAddr>,
+    ) -> SocketAddr {
+        if let Some(external_addr) = self.external_addr {
+            return external_addr;
+        }
+        stun(&bind_socket).unwrap_or_else(|| match self.bind_address {
+            Some(ip) => SocketAddr::new(ip, bind_socket.port()),
+            None => socket::default_local_address(bind_socket.port()),
+        })
+    }
+}
+
 pub const PROTOCOL: &str = "portal";
+/// Name of the database file in the data directory, when storing content in SQLite.
+const SQLITE_FILE_NAME: &str = "trin.sqlite";
+/// How often the routing table is saved to its snapshot, when one is configured.
+const ROUTING_TABLE_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Clone)]
 pub struct PortalnetProtocol {
     pub discovery: Arc<Discovery>,
     pub overlay: Overlay,
+    pub store: Arc<dyn ContentStore>,
+    pub canonical_index: Arc<CanonicalIndex>,
+    pub header_chain: Arc<HeaderChain>,
+    pub transfers: TransferRegistry,
+    pub metrics: Arc<Metrics>,
+    pub watchdog: Arc<ActivityWatchdog>,
+    /// Local socket discv5 is bound to
+    listen_socket: SocketAddr,
+    transfer_timeout: Duration,
+    gossip: GossipConfig,
+    lookup_retries: usize,
+    lookup_hop_limit: usize,
+    /// Bounds the recursive lookups running at once
+    lookups: LookupLimiter,
+    /// Content that recent lookups found nowhere, cleared as the content arrives
+    not_found: Arc<NotFoundCache>,
+    /// Content that recent lookups found, served again without a lookup until it expires
+    found: Arc<FoundContentCache>,
+    min_lookup_peers: usize,
+    /// Checks content from peers, by content type, before it's stored or returned
+    validators: Arc<dyn ContentValidator>,
+    next_request_id: Arc<AtomicU32>,
+    /// Where overlay requests and responses are recorded, if anywhere
+    recorder: Option<Arc<RequestRecorder>>,
 }
 
 pub struct PortalnetEvents {
     discovery: Arc<Discovery>,
     overlay: Overlay,
-    protocol_receiver: mpsc::Receiver<Discv5Event>,
-    db: DB,
+    talk_requests: BoundedQueue<TalkRequest>,
+    store: Arc<dyn ContentStore>,
+    storage_policy: StoragePolicy,
+    offer_acceptance_policy: OfferAcceptancePolicy,
+    metrics: Arc<Metrics>,
+    /// Whether held content outside the data radius is withheld from FindContent
+    strict_radius: bool,
+    /// Reply to talk requests for protocols other than the portal protocol
+    unknown_protocol_response: Vec<u8>,
+    /// Where overlay requests and responses are recorded, if anywhere
+    recorder: Option<Arc<RequestRecorder>>,
 }
 
 pub struct JsonRpcHandler {
     pub discovery: Arc<Discovery>,
+    pub store: Arc<dyn ContentStore>,
+    pub canonical_index: Arc<CanonicalIndex>,
+    pub transfers: TransferRegistry,
+    /// The running overlay, used for network lookups. `None` when offline.
+    pub protocol: Option<PortalnetProtocol>,
+    /// Most requests handled at once
+    pub max_concurrent_requests: usize,
     pub jsonrpc_rx: mpsc::UnboundedReceiver<PortalEndpoint>,
 }
 
 impl JsonRpcHandler {
-    pub async fn process_jsonrpc_requests(mut self) {
-        while let Some(cmd) = self.jsonrpc_rx.recv().await {
-            use PortalEndpointKind::*;
-
-            match cmd.kind {
-                NodeInfo => {
-                    let node_id = self.discovery.local_enr().to_base64();
-                    let _ = cmd.resp.send(Ok(Value::String(node_id)));
-                }
-                RoutingTableInfo => {
-                    let routing_table_info = self
-                        .discovery
-                        .discv5
-                        .table_entries_id()
-                        .iter()
-                        .map(|node_id| Value::String(node_id.to_string()))
-                        .collect();
-                    let _ = cmd.resp.send(Ok(Value::Array(routing_table_info)));
+    /// Set up a handler that answers purely from the local store. discv5 is only used to derive
+    /// the node identity, and is never started.
+    pub fn new_offline(
+        portal_config: PortalnetConfig,
+        jsonrpc_rx: mpsc::UnboundedReceiver<PortalEndpoint>,
+    ) -> Result<Self, String> {
+        let discovery = Arc::new(unstarted_discovery(&portal_config)?);
+        let (store, canonical_index, _) = open_storage(
+            discovery.local_enr(),
+            portal_config.storage_backend,
+            portal_config.storage_capacity,
+            portal_config.store_cache_capacity,
+            0,
+            portal_config.compress_content,
+        )?;
+
+        Ok(Self {
+            discovery,
+            store,
+            canonical_index,
+            transfers: TransferRegistry::default(),
+            protocol: None,
+            max_concurrent_requests: portal_config.max_concurrent_requests,
+            jsonrpc_rx,
+        })
+    }
+
+    /// Answer json-rpc requests, each in its own task so that a slow network lookup doesn't hold
+    /// up the requests behind it. At most `max_concurrent_requests` are handled at once; beyond
+    /// that, requests wait in the channel.
+    pub async fn process_jsonrpc_requests(self) {
+        let JsonRpcHandler {
+            discovery,
+            store,
+            canonical_index,
+            transfers,
+            protocol,
+            max_concurrent_requests,
+            mut jsonrpc_rx,
+        } = self;
+        let queries = Arc::new(ClientQueries {
+            discovery,
+            store,
+            canonical_index,
+            transfers,
+            protocol,
+        });
+        let permits = Arc::new(Semaphore::new(max_concurrent_requests.max(1)));
+
+        while let Some(cmd) = jsonrpc_rx.recv().await {
+            let permit = match permits.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            let queries = queries.clone();
+            tokio::spawn(async move {
+                let response = queries.handle(cmd.kind).await;
+                let _ = cmd.resp.send(response);
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// The state json-rpc requests are answered from, shared by the tasks handling them.
+struct ClientQueries {
+    discovery: Arc<Discovery>,
+    store: Arc<dyn ContentStore>,
+    canonical_index: Arc<CanonicalIndex>,
+    transfers: TransferRegistry,
+    protocol: Option<PortalnetProtocol>,
+}
+
+impl ClientQueries {
+    async fn handle(&self, kind: PortalEndpointKind) -> Result<Value, String> {
+        use PortalEndpointKind::*;
+
+        match kind {
+            NodeInfo => Ok(Value::String(self.discovery.local_enr().to_base64())),
+            RoutingTableInfo => Ok(Value::Array(
+                self.discovery
+                    .discv5
+                    .table_entries_id()
+                    .iter()
+                    .map(|node_id| Value::String(node_id.to_string()))
+                    .collect(),
+            )),
+            PeerClients => Ok(json!(self.discovery.peer_clients())),
+            AddEnr(enr) => self.discovery.add_enr(enr).map(Value::Bool),
+            RecursiveFindNode(target) => {
+                if !self.discovery.started {
+                    return Err("Unavailable in offline mode".to_string());
                 }
+                let added = self.discovery.discover_nodes_near(target).await?;
+                Ok(Value::from(added))
+            }
+            PeerRadius(enr) => {
+                let radius = self.online()?.peer_radius(enr).await?;
+                Ok(Value::String(format!("0x{:x}", radius)))
+            }
+            LocalContent(content_key) => match self.store.get(&content_key)? {
+                Some(value) => Ok(Value::String(format!("0x{}", hex::encode(value)))),
+                None => Ok(Value::Null),
+            },
+            DeleteContent(content_key) => self.store.delete(&content_key).map(Value::Bool),
+            Availability(content_keys) => content_keys
+                .iter()
+                .map(|content_key| self.availability(content_key))
+                .collect::<Result<Vec<Value>, String>>()
+                .map(Value::Array),
+            BlockNumber => self
+                .canonical_index
+                .highest_block_number()
+                .map(|number| Value::String(format!("0x{:x}", number.unwrap_or(0)))),
+            ActiveTransfers => Ok(Value::Array(
+                self.transfers
+                    .active()
+                    .iter()
+                    .map(|transfer| transfer.to_json())
+                    .collect(),
+            )),
+            Health => Ok(self.health()),
+            TraceRecursiveFindContent(content_key) => {
+                self.online()?
+                    .trace_recursive_find_content(content_key)
+                    .await
+            }
+            RecursiveFindContent(content_key) => {
+                self.online()?.recursive_find_content(content_key).await
+            }
+        }
+    }
+
+    /// Reports the problems the node has: a bootstrap that stalled and still has no peers, or
+    /// discv5 gone quiet. An offline node never networks, so it has neither.
+    fn health(&self) -> Value {
+        let mut problems = vec![];
+        if let Some(protocol) = &self.protocol {
+            if protocol.metrics.get(BOOTSTRAP_STALLED) == 1
+                && protocol.discovery.connected_peers_len() == 0
+            {
+                problems.push("bootstrap_stalled");
+            }
+            if protocol.watchdog.is_degraded() {
+                problems.push("discv5_degraded");
             }
         }
+        json!({
+            "healthy": problems.is_empty(),
+            "problems": problems,
+        })
+    }
+
+    /// Reports whether the content key is stored and, if not, how many overlay peers advertise a
+    /// radius covering it. Offline, no peers are known, so none do.
+    fn availability(&self, content_key: &[u8]) -> Result<Value, String> {
+        let stored = self.store.get(content_key)?.is_some();
+        let covering_peers = match (stored, &self.protocol) {
+            (true, _) => None,
+            (false, Some(protocol)) => Some(
+                protocol
+                    .overlay
+                    .nodes()
+                    .iter()
+                    .filter(|node| is_interested(node, content_key))
+                    .count(),
+            ),
+            (false, None) => Some(0),
+        };
+        Ok(json!({
+            "contentKey": format!("0x{}", hex::encode(content_key)),
+            "content": describe_content_key(content_key),
+            "stored": stored,
+            "coveringPeers": covering_peers,
+        }))
+    }
+
+    fn online(&self) -> Result<&PortalnetProtocol, String> {
+        self.protocol
+            .as_ref()
+            .ok_or_else(|| "Unavailable in offline mode".to_string())
     }
 }
 
 impl PortalnetEvents {
     /// Receives a request from the talkreq handler and sends a response back
-    pub async fn process_discv5_requests(mut self) {
-        while let Some(event) = self.protocol_receiver.recv().await {
-            debug!("Got discv5 event {:?}", event);
-
-            let request = match event {
-                Discv5Event::TalkRequest(r) => r,
-                _ => continue,
-            };
-
-            let reply = match self.process_one_request(&request).await {
-                Ok(r) => Message::Response(r).to_bytes(),
+    pub async fn process_discv5_requests(self) {
+        while let Some(request) = self.talk_requests.pop().await {
+            if !self.discovery.allows_peer(request.node_id()) {
+                debug!(
+                    "Dropping talk request from {}, refused by the peer filter",
+                    request.node_id()
+                );
+                continue;
+            }
+            if request.protocol() != PROTOCOL.as_bytes() {
+                debug!(
+                    "Answering talk request for unknown protocol {} from {}",
+                    String::from_utf8_lossy(request.protocol()),
+                    request.node_id()
+                );
+                self.metrics.increment(UNKNOWN_PROTOCOL_REQUESTS);
+                if let Err(e) = request.respond(self.unknown_protocol_response.clone()) {
+                    warn!("failed to send reply: {}", e);
+                }
+                continue;
+            }
+            let result = self
+                .process_one_request(&request)
+                .await
+                .map(|(request_id, r)| {
+                    Envelope {
+                        request_id,
+                        message: Message::Response(r),
+                    }
+                    .to_bytes()
+                });
+            if let Some(recorder) = &self.recorder {
+                recorder.record(
+                    Direction::Inbound,
+                    request.node_id(),
+                    request.body(),
+                    &result,
+                );
+            }
+            let reply = match result {
+                Ok(reply) => reply,
                 Err(e) => {
                     error!("failed to process portal event: {}", e);
                     e.into_bytes()
@@ -127,16 +596,16 @@ impl PortalnetEvents {
         }
     }
 
-    async fn process_one_request(&self, talk_request: &TalkRequest) -> Result<Response, String> {
-        let protocol = std::str::from_utf8(talk_request.protocol())
-            .map_err(|_| "Invalid protocol".to_owned())?;
-
-        if protocol != PROTOCOL {
-            return Err("Invalid protocol".to_owned());
-        }
-
-        let request = match Message::from_bytes(talk_request.body()) {
-            Ok(Message::Request(r)) => r,
+    /// Handle a request, returning the response along with the request id it must carry.
+    async fn process_one_request(
+        &self,
+        talk_request: &TalkRequest,
+    ) -> Result<(u32, Response), String> {
+        let (request_id, request) = match Envelope::from_bytes(talk_request.body()) {
+            Ok(Envelope {
+                request_id,
+                message: Message::Request(r),
+            }) => (request_id, r),
             Ok(_) => return Err("Invalid message".to_owned()),
             Err(e) => return Err(format!("Invalid request: {}", e)),
         };
@@ -152,47 +621,236 @@ impl PortalnetEvents {
             }
             Request::FindNodes(FindNodes { distances }) => {
                 let distances64: Vec<u64> = distances.iter().map(|x| (*x).into()).collect();
-                let enrs = self.overlay.nodes_by_distance(distances64);
-                Response::Nodes(Nodes {
-                    // from spec: total = The total number of Nodes response messages being sent.
-                    // TODO: support returning multiple messages
-                    total: 1 as u8,
-                    enrs,
+                // from spec: total = The total number of Nodes response messages being sent.
+                // A talk request gets one response, so that's always a single message.
+                Response::Nodes(self.overlay.find_nodes_response(distances64))
+            }
+            Request::FindContent(FindContent { content_key }) => {
+                debug!(
+                    "Got FindContent request for {}",
+                    describe_content_key(&content_key)
+                );
+                self.find_content_response(content_key)
+            }
+            Request::Offer(Offer { content_keys }) => {
+                debug!(
+                    "Got Offer of {}",
+                    content_keys
+                        .iter()
+                        .map(|content_key| describe_content_key(content_key))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                );
+                self.offer_response(talk_request.node_id(), content_keys)?
+            }
+        };
+
+        Ok((request_id, response))
+    }
+
+    /// Accept the offered content that's wanted, if the acceptance policy takes offers from the
+    /// peer at all. An offer listing a content key more than once breaks the protocol, since each
+    /// accepted key gets its own transfer: only the first copy can be accepted, and the offer is
+    /// counted against the peer.
+    fn offer_response(
+        &self,
+        peer: &NodeId,
+        content_keys: Vec<Vec<u8>>,
+    ) -> Result<Response, String> {
+        let mut offered = HashSet::new();
+        let first_offers: Vec<bool> = content_keys
+            .iter()
+            .map(|content_key| offered.insert(content_key.as_slice()))
+            .collect();
+        if offered.len() < content_keys.len() {
+            warn!(
+                "Offer from {} repeats {} content keys",
+                peer,
+                content_keys.len() - offered.len()
+            );
+            self.metrics.increment(DUPLICATE_KEY_OFFERS);
+        }
+        let wanted = match self.offer_acceptance_policy.accepts_from(peer) {
+            true => self
+                .storage_policy
+                .wanted_content(self.store.as_ref(), &content_keys)?,
+            false => vec![false; content_keys.len()],
+        };
+        // TODO: open a uTP connection to receive the accepted content
+        Ok(Response::Accept(Accept {
+            connection_id: 0,
+            content_keys: wanted
+                .into_iter()
+                .zip(first_offers)
+                .map(|(wanted, first)| wanted && first)
+                .collect(),
+        }))
+    }
+
+    /// Serve stored content, or point to closer nodes if it isn't stored. In strict radius mode,
+    /// content outside the data radius is treated as not stored, so the node only serves what its
+    /// advertised radius claims. Served content is counted by type.
+    fn find_content_response(&self, content_key: Vec<u8>) -> Response {
+        let value = match self.store.get(&content_key) {
+            Ok(value) => value,
+            Err(e) => panic!("Unable to respond to FindContent: {}", e),
+        };
+        match value {
+            Some(value) if !self.strict_radius || self.within_radius(&content_key) => {
+                self.metrics
+                    .increment(&metrics::for_content(CONTENT_SERVED, &content_key));
+                self.metrics.increment_by(
+                    &metrics::for_content(CONTENT_TRANSFER_BYTES, &content_key),
+                    value.len() as u64,
+                );
+                let empty_enrs: Vec<SszEnr> = vec![];
+                Response::FoundContent(FoundContent {
+                    enrs: empty_enrs,
+                    payload: value,
                 })
             }
-            Request::FindContent(FindContent { content_key }) => match self.db.get(&content_key) {
-                Ok(Some(value)) => {
-                    let empty_enrs: Vec<SszEnr> = vec![];
-                    Response::FoundContent(FoundContent {
-                        enrs: empty_enrs,
-                        payload: value,
-                    })
-                }
-                Ok(None) => {
-                    let enrs = self.overlay.find_nodes_close_to_content(content_key);
-                    let empty_payload: Vec<u8> = vec![];
-                    Response::FoundContent(FoundContent {
-                        enrs: enrs,
-                        payload: empty_payload,
-                    })
+            _ => {
+                let enrs = self.overlay.find_nodes_close_to_content(content_key);
+                let empty_payload: Vec<u8> = vec![];
+                Response::FoundContent(FoundContent {
+                    enrs: enrs,
+                    payload: empty_payload,
+                })
+            }
+        }
+    }
+
+    fn within_radius(&self, content_key: &[u8]) -> bool {
+        let distance = content_distance(
+            &self.discovery.local_enr().node_id(),
+            &content_id(content_key),
+        );
+        distance <= self.overlay.data_radius()
+    }
+}
+
+/// Moves talk requests from the discv5 event stream into the bounded queue, so a flood of requests
+/// is dropped per the overflow policy instead of growing memory without bound. Every event counts
+/// as discv5 activity for the watchdog.
+async fn forward_talk_requests(
+    mut protocol_receiver: mpsc::Receiver<Discv5Event>,
+    talk_requests: BoundedQueue<TalkRequest>,
+    watchdog: Arc<ActivityWatchdog>,
+    external_addr: Option<SocketAddr>,
+) {
+    while let Some(event) = protocol_receiver.recv().await {
+        debug!("Got discv5 event {:?}", event);
+        watchdog.record_activity();
+
+        match event {
+            Discv5Event::TalkRequest(request) => talk_requests.push(request),
+            Discv5Event::SocketUpdated(observed) => {
+                if let Some(warning) = external_addr_mismatch(external_addr, observed) {
+                    warn!("{}", warning);
                 }
-                Err(e) => panic!("Unable to respond to FindContent: {}", e),
-            },
-        };
+            }
+            _ => {}
+        }
+    }
+    talk_requests.close();
+}
 
-        Ok(response)
+/// Returns a warning when peers, in their pongs, agree on a different address for this node than
+/// the configured external address. Other nodes then can't reach the node at the address its ENR
+/// advertises, usually because the address is wrong or the port isn't forwarded.
+fn external_addr_mismatch(configured: Option<SocketAddr>, observed: SocketAddr) -> Option<String> {
+    let configured = configured?;
+    if configured == observed {
+        return None;
+    }
+    Some(format!(
+        "Peers see this node at {}, not at the configured external address {}. Nodes may be \
+         unable to reach it: check --external-address and that the port is forwarded.",
+        observed, configured
+    ))
+}
+
+/// Save the routing table to its snapshot on an interval, so a restarted node can rejoin from the
+/// peers it last knew.
+async fn snapshot_routing_table(discovery: Arc<Discovery>) {
+    let mut interval = tokio::time::interval(ROUTING_TABLE_SNAPSHOT_INTERVAL);
+    loop {
+        interval.tick().await;
+        match discovery.save_routing_table_snapshot() {
+            Ok(saved) => debug!("Saved {} peers to the routing table snapshot", saved),
+            Err(e) => warn!("{}", e),
+        }
+    }
+}
+
+/// Flush buffered content writes to the database on an interval, so that they don't wait for the
+/// buffer to fill.
+async fn flush_store(store: Arc<dyn ContentStore>, flush_interval: Duration) {
+    let mut interval = tokio::time::interval(flush_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = store.flush() {
+            warn!("Failed to flush buffered content: {}", e);
+        }
+    }
+}
+
+/// Compact the store's database on a schedule, starting one interval after startup, to keep reads
+/// fast as content comes and goes.
+async fn compact_store(compactor: Arc<Compactor>, compaction_interval: Duration) {
+    let mut interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + compaction_interval,
+        compaction_interval,
+    );
+    loop {
+        interval.tick().await;
+        if let Err(e) = compactor.compact() {
+            warn!("Failed to compact the store: {}", e);
+        }
+    }
+}
+
+/// Drop idle peers from the routing table as they pass the idle limit, checking a few times per
+/// limit period.
+async fn reap_idle_peers(discovery: Arc<Discovery>, max_peer_idle: Duration) {
+    let mut interval = tokio::time::interval((max_peer_idle / 4).max(Duration::from_secs(1)));
+    loop {
+        interval.tick().await;
+        let reaped = discovery.reap_idle_peers();
+        if !reaped.is_empty() {
+            info!(
+                "Dropped {} peers idle for over {}s",
+                reaped.len(),
+                max_peer_idle.as_secs()
+            );
+        }
+    }
+}
+
+/// Ping overlay peers as their schedule comes due, which refreshes their radii and tracks how
+/// reliably they answer.
+async fn ping_peers(protocol: PortalnetProtocol, scheduler: PingScheduler) {
+    let mut interval = tokio::time::interval(scheduler.tick_interval());
+    loop {
+        interval.tick().await;
+        for node in scheduler.due_nodes(protocol.overlay.nodes()) {
+            let enr = node.enr();
+            if let Err(e) = protocol
+                .send_ping(protocol.overlay.data_radius(), enr)
+                .await
+            {
+                debug!("Maintenance ping of {} failed: {}", node.enr().node_id(), e);
+            }
+        }
     }
 }
 
 impl PortalnetProtocol {
     pub async fn new(portal_config: PortalnetConfig) -> Result<(Self, PortalnetEvents), String> {
-        let listen_all_ips = SocketAddr::new("0.0.0.0".parse().unwrap(), portal_config.listen_port);
-
-        let external_addr = portal_config
-            .external_addr
-            .or_else(|| socket::stun_for_external(&listen_all_ips))
-            .unwrap_or_else(|| socket::default_local_address(portal_config.listen_port));
+        let listen_socket = portal_config.bind_socket()?;
+        let external_addr = portal_config.enr_address(listen_socket, socket::stun_for_external);
 
+        let metrics = Arc::new(Metrics::default());
         let config = DiscoveryConfig {
             discv5_config: Discv5ConfigBuilder::default().build(),
             // This is for defining the ENR:
@@ -200,11 +858,19 @@ impl PortalnetProtocol {
             listen_address: external_addr.ip(),
             bootnode_enrs: portal_config.bootnode_enrs,
             private_key: portal_config.private_key,
+            subnetworks: portal_config.subnetworks,
+            peer_filter: portal_config.peer_filter,
+            routing_table_snapshot: portal_config.routing_table_snapshot.clone(),
+            max_peers: portal_config.max_peers,
+            max_peer_idle: portal_config.max_peer_idle,
+            max_requests_per_peer: portal_config.max_requests_per_peer,
+            metrics: metrics.clone(),
+            filter_private_ips: portal_config.filter_private_ips,
             ..Default::default()
         };
 
         let mut discovery = Discovery::new(config).unwrap();
-        discovery.start(listen_all_ips).await?;
+        discovery.start(listen_socket).await?;
 
         let protocol_receiver = discovery
             .discv5
@@ -219,68 +885,451 @@ impl PortalnetProtocol {
         );
 
         let discovery = Arc::new(discovery);
-        let data_path = get_data_dir(discovery.local_enr());
-
-        let mut db_opts = Options::default();
-        db_opts.create_if_missing(true);
-        let db = DB::open(&db_opts, data_path).unwrap();
+        if portal_config.routing_table_snapshot.is_some() {
+            tokio::spawn(snapshot_routing_table(discovery.clone()));
+        }
+        if let Some(max_peer_idle) = portal_config.max_peer_idle {
+            tokio::spawn(reap_idle_peers(discovery.clone(), max_peer_idle));
+        }
+        let (store, canonical_index, backend) = open_storage(
+            discovery.local_enr(),
+            portal_config.storage_backend,
+            portal_config.storage_capacity,
+            portal_config.store_cache_capacity,
+            portal_config.write_buffer_size,
+            portal_config.compress_content,
+        )?;
+        let store: Arc<dyn ContentStore> = Arc::new(MeteredStore::new(store, metrics.clone()));
+        let compactor = Arc::new(Compactor::new(backend, metrics.clone()));
+        if let Some(compaction_interval) = portal_config.compaction_interval {
+            tokio::spawn(compact_store(compactor.clone(), compaction_interval));
+        }
+        if portal_config.write_buffer_size > 0 {
+            tokio::spawn(flush_store(
+                store.clone(),
+                portal_config.write_buffer_flush_interval,
+            ));
+        }
+        let header_chain = Arc::new(HeaderChain::new(
+            canonical_index.clone(),
+            portal_config.trusted_checkpoint,
+            portal_config.max_header_future_drift,
+        )?);
+        let pruned = portal_config.storage_policy.prune(store.as_ref())?;
+        if pruned > 0 {
+            info!(
+                "Pruned {} stored content keys not allowed by the storage policy",
+                pruned
+            );
+            store.flush()?;
+            compactor.after_deletes(pruned)?;
+        }
+        let recorder = match portal_config.request_log {
+            Some(path) => Some(Arc::new(RequestRecorder::new(
+                path,
+                portal_config.request_log_max_size,
+            )?)),
+            None => None,
+        };
+        let talk_requests = BoundedQueue::new(
+            portal_config.talk_request_queue_capacity,
+            portal_config.talk_request_overflow_policy,
+            metrics.clone(),
+            TALK_REQUESTS_DROPPED,
+        );
+        let watchdog = Arc::new(ActivityWatchdog::new(
+            portal_config.discv5_stall_timeout,
+            metrics.clone(),
+        ));
+        tokio::spawn(watch_discv5_activity(watchdog.clone()));
+        tokio::spawn(forward_talk_requests(
+            protocol_receiver,
+            talk_requests.clone(),
+            watchdog.clone(),
+            portal_config.external_addr,
+        ));
 
         let events = PortalnetEvents {
             discovery: discovery.clone(),
             overlay: overlay.clone(),
-            protocol_receiver,
-            db,
+            talk_requests,
+            store: store.clone(),
+            storage_policy: portal_config.storage_policy,
+            offer_acceptance_policy: portal_config.offer_acceptance_policy,
+            metrics: metrics.clone(),
+            strict_radius: portal_config.strict_radius,
+            unknown_protocol_response: portal_config.unknown_protocol_response.clone(),
+            recorder: recorder.clone(),
         };
 
+        let validators = Arc::new(MeteredValidator::new(
+            ValidatorRegistry::history(portal_config.rlp_limits, ContentSizeLimits::default()),
+            metrics.clone(),
+        ));
         let proto = Self {
             discovery: discovery.clone(),
             overlay: overlay.clone(),
+            store,
+            canonical_index,
+            header_chain,
+            transfers: TransferRegistry::new(portal_config.max_transfers),
+            metrics,
+            watchdog,
+            listen_socket,
+            transfer_timeout: portal_config.transfer_timeout,
+            gossip: portal_config.gossip,
+            lookup_retries: portal_config.lookup_retries,
+            lookup_hop_limit: portal_config.lookup_hop_limit,
+            lookups: LookupLimiter::new(
+                portal_config.max_concurrent_lookups,
+                portal_config.lookup_overflow_policy,
+            ),
+            not_found: Arc::new(NotFoundCache::new(portal_config.not_found_ttl)),
+            found: Arc::new(FoundContentCache::new(
+                portal_config.found_content_cache_capacity,
+                portal_config.found_content_ttl,
+            )),
+            min_lookup_peers: portal_config.min_lookup_peers,
+            validators,
+            next_request_id: Arc::new(AtomicU32::new(0)),
+            recorder,
         };
+        if let Some(ping_interval) = portal_config.ping_interval {
+            tokio::spawn(ping_peers(proto.clone(), PingScheduler::new(ping_interval)));
+        }
 
         Ok((proto, events))
     }
 
-    pub async fn send_ping(&self, data_radius: U256, enr: Enr) -> Result<Vec<u8>, String> {
+    /// Returns a summary of the running node's configuration, the one given to `new`, to log once
+    /// its services are up.
+    pub fn startup_summary(
+        &self,
+        portal_config: &PortalnetConfig,
+        json_rpc: String,
+    ) -> StartupSummary {
+        let enr = self.discovery.local_enr();
+        StartupSummary {
+            data_dir: get_data_dir(enr.clone()),
+            enr,
+            listen_addresses: vec![self.listen_socket],
+            json_rpc,
+            subnetworks: portal_config.subnetworks.clone(),
+            storage_backend: portal_config.storage_backend,
+            store_cache_capacity: portal_config.store_cache_capacity,
+            write_buffer_size: portal_config.write_buffer_size,
+            compress_content: portal_config.compress_content,
+            max_peers: portal_config.max_peers,
+            data_radius: self.overlay.data_radius(),
+        }
+    }
+
+    /// Ping a peer. A pong adds the peer to the overlay routing table with the radius it
+    /// advertises, and a peer new to the table is offered the held content closest to it. If the
+    /// pong reports a newer ENR than the one the peer was pinged with, the newer ENR is fetched
+    /// and stored instead, so that the peer isn't dialed at a stale address.
+    pub async fn send_ping(&self, data_radius: U256, enr: Enr) -> Result<Response, DiscoveryError> {
         let enr_seq = self.discovery.local_enr().seq();
         let msg = Ping {
             enr_seq,
             data_radius,
         };
-        self.discovery
-            .send_talkreq(
-                enr,
-                PROTOCOL.to_string(),
-                Message::Request(Request::Ping(msg)).to_bytes(),
-            )
-            .await
+        let response = self
+            .send_overlay_request(enr.clone(), Request::Ping(msg))
+            .await?;
+        if let Response::Pong(pong) = &response {
+            let enr = match pong.enr_seq > enr.seq() {
+                true => self.fetch_updated_enr(&enr).await.unwrap_or(enr),
+                false => enr,
+            };
+            if self.overlay.insert_node(enr.clone(), pong.data_radius) {
+                let protocol = self.clone();
+                let peer = Node::new(enr, pong.data_radius);
+                tokio::spawn(async move {
+                    match seed_new_peer(&protocol, &protocol.gossip, protocol.store.as_ref(), &peer)
+                        .await
+                    {
+                        Ok(accepted) if accepted > 0 => {
+                            debug!("Seeded new peer with {} content keys", accepted)
+                        }
+                        Ok(_) => (),
+                        Err(e) => debug!("{}", e),
+                    }
+                });
+            }
+        }
+        Ok(response)
+    }
+
+    /// Ask a peer for its own ENR, with a FINDNODES at distance 0. Returns it only if it's a newer
+    /// record of the same node.
+    async fn fetch_updated_enr(&self, enr: &Enr) -> Option<Enr> {
+        match self.find_nodes(vec![0], enr.clone()).await {
+            Ok(FindNodesResult::Enrs(enrs)) => enrs
+                .into_iter()
+                .find(|updated| updated.node_id() == enr.node_id() && updated.seq() > enr.seq()),
+            Ok(FindNodesResult::Empty) => None,
+            Err(e) => {
+                debug!(
+                    "Failed to fetch the updated ENR of {}: {}",
+                    enr.node_id(),
+                    e
+                );
+                None
+            }
+        }
     }
 
-    pub async fn send_find_nodes(&self, distances: Vec<u16>, enr: Enr) -> Result<Vec<u8>, String> {
+    pub async fn send_find_nodes(
+        &self,
+        distances: Vec<u16>,
+        enr: Enr,
+    ) -> Result<Response, DiscoveryError> {
         let msg = FindNodes { distances };
-        self.discovery
-            .send_talkreq(
-                enr,
-                PROTOCOL.to_string(),
-                Message::Request(Request::FindNodes(msg)).to_bytes(),
-            )
+        self.send_overlay_request(enr, Request::FindNodes(msg))
             .await
     }
 
+    /// Ask a peer for the nodes at the given distances from it.
+    pub async fn find_nodes(
+        &self,
+        distances: Vec<u16>,
+        enr: Enr,
+    ) -> Result<FindNodesResult, String> {
+        match self.send_find_nodes(distances, enr).await? {
+            Response::Nodes(nodes) => Ok(nodes.into()),
+            _ => Err("Unexpected response to FindNodes".to_owned()),
+        }
+    }
+
     pub async fn send_find_content(
         &self,
         content_key: Vec<u8>,
         enr: Enr,
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Response, DiscoveryError> {
         let msg = FindContent { content_key };
-        self.discovery
-            .send_talkreq(
-                enr,
-                PROTOCOL.to_string(),
-                Message::Request(Request::FindContent(msg)).to_bytes(),
-            )
+        self.send_overlay_request(enr, Request::FindContent(msg))
             .await
     }
 
+    pub async fn send_offer(
+        &self,
+        content_keys: Vec<Vec<u8>>,
+        enr: Enr,
+    ) -> Result<Response, DiscoveryError> {
+        let msg = Offer { content_keys };
+        self.send_overlay_request(enr, Request::Offer(msg)).await
+    }
+
+    /// Gossip content to the overlay peers closest to it, within the configured fanout.
+    pub async fn propagate_content(&self, content_key: Vec<u8>) -> Propagation {
+        let peers = self
+            .overlay
+            .closest_nodes(&content_id(&content_key), usize::MAX);
+        propagate_content(self, &self.gossip, content_key, peers).await
+    }
+
+    /// Set the data radius. When it grows, the held content that just came within it is gossiped
+    /// again, up to the re-announce limit. Returns how many content keys were re-announced.
+    pub async fn set_data_radius(&self, data_radius: U256) -> Result<usize, String> {
+        let old_radius = self.overlay.set_data_radius(data_radius);
+        reannounce_newly_covered(
+            self,
+            &self.gossip,
+            self.store.as_ref(),
+            &self.overlay,
+            old_radius,
+        )
+        .await
+    }
+
+    /// Receive content from a peer into the store. A peer that stalls the transfer is penalized
+    /// as if it took the whole timeout to respond.
+    pub async fn receive_content(
+        &self,
+        peer: &NodeId,
+        content_key: Vec<u8>,
+        total: u64,
+        packets: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+    ) -> Result<(), String> {
+        let result = self
+            .transfers
+            .receive(
+                content_key.clone(),
+                total,
+                packets,
+                self.transfer_timeout,
+                self.store.as_ref(),
+                self.validators.as_ref(),
+            )
+            .await;
+        if let Err(TransferError::TimedOut) = result {
+            self.metrics.increment(TRANSFERS_TIMED_OUT);
+            self.overlay
+                .record_response_time(peer, self.transfer_timeout);
+        }
+        if result.is_ok() {
+            self.not_found.remove(&content_key);
+            self.metrics.increment_by(
+                &metrics::for_content(CONTENT_TRANSFER_BYTES, &content_key),
+                total,
+            );
+        }
+        result.map_err(|e| e.to_string())
+    }
+
+    /// Look up content on the network, starting from the discv5 routing table. Returns the
+    /// content, or null, alongside the trace of the lookup. Errs if fewer than
+    /// `min_lookup_peers` peers are connected, or if the node is busy with other lookups under
+    /// the reject policy.
+    pub async fn trace_recursive_find_content(
+        &self,
+        content_key: Vec<u8>,
+    ) -> Result<Value, String> {
+        self.discovery
+            .ensure_connected_peers(self.min_lookup_peers)?;
+        let _permit = self.lookups.acquire().await?;
+        let seeds = self.discovery.discv5.table_entries_enr();
+        let (content, trace) = trace_recursive_find_content(
+            self,
+            &self.discovery.local_enr(),
+            content_key,
+            seeds,
+            self.lookup_hop_limit,
+        )
+        .await;
+        Ok(json!({
+            "content": content.map(|content| format!("0x{}", hex::encode(content))),
+            "trace": trace.to_json(),
+        }))
+    }
+
+    /// Look up content on the network, retrying without the sending peer when it fails
+    /// validation. Returns the content as hex, or null if the closest peers don't have it, now or
+    /// within the not-found TTL. Content found within the found-content TTL is returned without
+    /// another lookup. Errs if fewer than `min_lookup_peers` peers are connected, or if
+    /// the lookup hit the hop limit or ran out of peers first, or if the node is busy with other
+    /// lookups under the reject policy.
+    pub async fn recursive_find_content(&self, content_key: Vec<u8>) -> Result<Value, String> {
+        self.discovery
+            .ensure_connected_peers(self.min_lookup_peers)?;
+        let _permit = self.lookups.acquire().await?;
+        let seeds = self.discovery.discv5.table_entries_enr();
+        let content = find_validated_content(
+            self,
+            self.validators.as_ref(),
+            &self.not_found,
+            &self.found,
+            &self.discovery.local_enr(),
+            content_key,
+            seeds,
+            self.lookup_hop_limit,
+            self.lookup_retries,
+        )
+        .await?;
+        Ok(match content {
+            Some(content) => Value::String(format!("0x{}", hex::encode(content))),
+            None => Value::Null,
+        })
+    }
+
+    /// Send a request to a peer, recording its response time in the overlay routing table. The
+    /// request is tagged with a fresh request id, and a response carrying another id is dropped
+    /// as a protocol error. With a request log, the exchange is recorded as sent and received.
+    async fn send_overlay_request(
+        &self,
+        enr: Enr,
+        request: Request,
+    ) -> Result<Response, DiscoveryError> {
+        let node_id = enr.node_id();
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let envelope = Envelope {
+            request_id,
+            message: Message::Request(request),
+        };
+        let request = envelope.to_bytes();
+        let started = Instant::now();
+        let response = self
+            .discovery
+            .send_talkreq(enr, PROTOCOL.to_string(), request.clone())
+            .await;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Direction::Outbound, &node_id, &request, &response);
+        }
+        self.overlay.record_outcome(
+            &node_id,
+            !matches!(response, Err(DiscoveryError::NoResponse)),
+        );
+        let response = response?;
+        self.watchdog.record_activity();
+        self.overlay
+            .record_response_time(&node_id, started.elapsed());
+        open_response(request_id, &response, &self.metrics).map_err(DiscoveryError::ProtocolError)
+    }
+
+    /// Find the peers closest to a target node id, like the neighbours content is placed with,
+    /// starting from the discv5 routing table. Returns them closest first.
+    pub async fn recursive_find_nodes(&self, target: NodeId) -> Vec<Enr> {
+        recursive_find_nodes(
+            self,
+            &self.discovery.local_enr(),
+            target,
+            self.discovery.discv5.table_entries_enr(),
+            self.lookup_hop_limit,
+        )
+        .await
+    }
+
+    /// Ping a peer, returning the data radius from its pong.
+    pub async fn peer_radius(&self, enr: Enr) -> Result<U256, String> {
+        match self.send_ping(self.overlay.data_radius(), enr).await? {
+            Response::Pong(pong) => Ok(pong.data_radius),
+            _ => Err("Unexpected response to Ping".to_owned()),
+        }
+    }
+
+    /// Ping the bootnodes, then check that the node joined the network once `timeout` passed
+    /// since the check started. A node with no connected peers, whose bootnodes all failed to
+    /// answer, idles without any traffic, so it gets a single error saying what to check, and is
+    /// reported unhealthy. It keeps running, in case the network comes back. Returns how many
+    /// peers are connected.
+    pub async fn bootstrap(&self, timeout: Duration) -> Result<usize, String> {
+        let started = Instant::now();
+        let bootnodes = self.discovery.discv5.table_entries_enr();
+        let mut answered = 0;
+        for enr in bootnodes.iter().cloned() {
+            match self.send_ping(self.overlay.data_radius(), enr).await {
+                Ok(_) => answered += 1,
+                Err(e) => debug!("Bootnode ping failed: {}", e),
+            }
+        }
+        tokio::time::sleep(timeout.checked_sub(started.elapsed()).unwrap_or_default()).await;
+
+        let connected = self.discovery.connected_peers_len();
+        if connected > 0 || answered > 0 {
+            self.metrics.set(BOOTSTRAP_STALLED, 0);
+            return Ok(connected);
+        }
+        let local_enr = self.discovery.local_enr();
+        let enr_address = match (local_enr.ip(), local_enr.udp()) {
+            (Some(ip), Some(port)) => format!("{}:{}", ip, port),
+            _ => "none".to_owned(),
+        };
+        let error = format!(
+            "Bootstrap stalled: no connected peers after {}s, and none of the {} bootnodes \
+             answered a ping. Check that a firewall isn't blocking inbound UDP to the discovery \
+             port and that any NAT forwards it, that the address in the node's ENR ({}) is \
+             reachable from the internet, or set it with --external-address, and that the \
+             --bootnodes ENRs are current.",
+            timeout.as_secs(),
+            bootnodes.len(),
+            enr_address,
+        );
+        error!("{}", error);
+        self.metrics.set(BOOTSTRAP_STALLED, 1);
+        Err(error)
+    }
+
     /// Convenience call for testing, quick way to ping bootnodes
     pub async fn ping_bootnodes(&mut self) -> Result<(), String> {
         // Trigger bonding with bootnodes, at both the base layer and portal overlay.
@@ -294,3 +1343,1319 @@ impl PortalnetProtocol {
         Ok(())
     }
 }
+
+/// Decode a response envelope, checking that it answers the request with `request_id`.
+fn open_response(request_id: u32, bytes: &[u8], metrics: &Metrics) -> Result<Response, String> {
+    let envelope = Envelope::from_bytes(bytes)?;
+    if envelope.request_id != request_id {
+        metrics.increment(MISMATCHED_RESPONSES);
+        return Err(format!(
+            "Dropped response with request id {}, expected {}",
+            envelope.request_id, request_id
+        ));
+    }
+    match envelope.message {
+        Message::Response(response) => Ok(response),
+        Message::Request(_) => Err("Expected a response, got a request".to_owned()),
+    }
+}
+
+impl FindContentTransport for PortalnetProtocol {
+    fn find_content(
+        &self,
+        enr: Enr,
+        content_key: Vec<u8>,
+    ) -> BoxFuture<'_, Result<FindContentResult, DiscoveryError>> {
+        Box::pin(async move {
+            match self.send_find_content(content_key, enr).await? {
+                Response::FoundContent(found) => Ok(found.into()),
+                _ => Err(DiscoveryError::ProtocolError(
+                    "Unexpected response to FindContent".to_owned(),
+                )),
+            }
+        })
+    }
+}
+
+impl FindNodesTransport for PortalnetProtocol {
+    fn find_nodes(
+        &self,
+        enr: Enr,
+        distances: Vec<u16>,
+    ) -> BoxFuture<'_, Result<FindNodesResult, DiscoveryError>> {
+        Box::pin(async move {
+            match self.send_find_nodes(distances, enr).await? {
+                Response::Nodes(nodes) => Ok(nodes.into()),
+                _ => Err(DiscoveryError::ProtocolError(
+                    "Unexpected response to FindNodes".to_owned(),
+                )),
+            }
+        })
+    }
+}
+
+impl OfferTransport for PortalnetProtocol {
+    fn offer(
+        &self,
+        enr: Enr,
+        content_keys: Vec<Vec<u8>>,
+    ) -> BoxFuture<'_, Result<Accept, DiscoveryError>> {
+        Box::pin(async move {
+            match self.send_offer(content_keys, enr).await? {
+                Response::Accept(accept) => Ok(accept),
+                _ => Err(DiscoveryError::ProtocolError(
+                    "Unexpected response to Offer".to_owned(),
+                )),
+            }
+        })
+    }
+}
+
+/// Build the node identity from the config, without starting discv5.
+fn unstarted_discovery(portal_config: &PortalnetConfig) -> Result<Discovery, String> {
+    let local_addr = portal_config
+        .external_addr
+        .unwrap_or_else(|| socket::default_local_address(portal_config.listen_port));
+
+    Discovery::new(DiscoveryConfig {
+        listen_port: local_addr.port(),
+        listen_address: local_addr.ip(),
+        private_key: portal_config.private_key.clone(),
+        subnetworks: portal_config.subnetworks.clone(),
+        ..Default::default()
+    })
+}
+
+/// Returns the ENR the node would advertise with this config. Without a private key, the node
+/// generates a new identity each time it runs, so there is no ENR to return.
+pub fn build_local_enr(portal_config: &PortalnetConfig) -> Result<Enr, String> {
+    if portal_config.private_key.is_none() {
+        return Err("Without --unsafe-private-key, the node has a new ENR every run".to_owned());
+    }
+    Ok(unstarted_discovery(portal_config)?.local_enr())
+}
+
+/// Open the RocksDB store of the node this config runs, which must already exist. The default
+/// data directory is named after the node's ENR, so without `TRIN_DATA_PATH` the node's private
+/// key is needed to find it; a new identity would point at a store no node ever wrote.
+fn open_existing_rocksdb(portal_config: &PortalnetConfig) -> Result<DB, String> {
+    let data_path = match (data_dir_override(), &portal_config.private_key) {
+        (Some(data_path), _) => data_path,
+        (None, Some(_)) => get_default_data_dir(build_local_enr(portal_config)?),
+        (None, None) => {
+            return Err(
+                "Finding the node's store needs its --unsafe-private-key, or TRIN_DATA_PATH"
+                    .to_owned(),
+            )
+        }
+    };
+    let mut db_opts = Options::default();
+    db_opts.create_if_missing(false);
+    DB::open(&db_opts, &data_path)
+        .map_err(|e| format!("Unable to open rocksdb at {}: {}", data_path, e))
+}
+
+/// Report the schema migration the node's store would go through on startup, without writing
+/// anything. Only RocksDB stores are migrated.
+pub fn dry_run_migration(portal_config: &PortalnetConfig) -> Result<Migration, String> {
+    if portal_config.storage_backend != StorageBackend::Rocksdb {
+        return Err("Only RocksDB stores have schema migrations".to_owned());
+    }
+    migrate(Arc::new(open_existing_rocksdb(portal_config)?), true)
+}
+
+/// Scan the node's store for values that no longer match the hash they're stored under, deleting
+/// them with `repair`. Only RocksDB stores are content-addressed, and so can be scanned.
+pub fn verify_store(
+    portal_config: &PortalnetConfig,
+    repair: bool,
+) -> Result<StoreVerification, String> {
+    if portal_config.storage_backend != StorageBackend::Rocksdb {
+        return Err("Only RocksDB stores can be verified".to_owned());
+    }
+    ContentAddressedStore::new(open_existing_rocksdb(portal_config)?).verify(repair)
+}
+
+/// Measure put and get throughput of the configured store backend, with the configured cache and
+/// write buffer, or of an in-memory store with `memory`. The benchmark runs against a scratch
+/// database in the temp directory, removed afterwards, so it never touches the node's content.
+pub fn bench_store(
+    portal_config: &PortalnetConfig,
+    items: usize,
+    value_size: usize,
+    memory: bool,
+) -> Result<BenchReport, String> {
+    if memory {
+        let store = cached_store(
+            ContentAddressedStore::new(MemoryBackend::default()),
+            portal_config.store_cache_capacity,
+            portal_config.write_buffer_size,
+            portal_config.compress_content,
+        );
+        return run_store_bench(store.as_ref(), items, value_size);
+    }
+    let scratch_dir = std::env::temp_dir().join(format!(
+        "trin-bench-{}",
+        hex::encode(NodeId::random().raw())
+    ));
+    std::fs::create_dir_all(&scratch_dir)
+        .map_err(|e| format!("Unable to create benchmark directory: {}", e))?;
+    let report = open_storage_at(
+        &scratch_dir.to_string_lossy(),
+        NodeId::random().raw(),
+        portal_config.storage_backend,
+        portal_config.storage_capacity,
+        portal_config.store_cache_capacity,
+        portal_config.write_buffer_size,
+        portal_config.compress_content,
+    )
+    .and_then(|(store, _, _)| run_store_bench(store.as_ref(), items, value_size));
+    if let Err(e) = std::fs::remove_dir_all(&scratch_dir) {
+        warn!(
+            "Unable to remove benchmark directory {}: {}",
+            scratch_dir.display(),
+            e
+        );
+    }
+    report
+}
+
+/// Open the content store and canonical index, which share a database in the node's data
+/// directory, returned alongside them. A RocksDB database in an older layout is migrated first.
+/// With a nonzero `write_buffer_size`, content writes are buffered in front of the database, and
+/// with `compress_content` they're compressed as they're written to it. A SQLite store evicts the
+/// farthest content once it holds more than `storage_capacity` bytes.
+fn open_storage(
+    local_enr: Enr,
+    storage_backend: StorageBackend,
+    storage_capacity: Option<u64>,
+    cache_capacity: usize,
+    write_buffer_size: usize,
+    compress_content: bool,
+) -> Result<
+    (
+        Arc<dyn ContentStore>,
+        Arc<CanonicalIndex>,
+        Arc<dyn KeyValueBackend>,
+    ),
+    String,
+> {
+    let local_node_id = local_enr.node_id().raw();
+    open_storage_at(
+        &get_data_dir(local_enr),
+        local_node_id,
+        storage_backend,
+        storage_capacity,
+        cache_capacity,
+        write_buffer_size,
+        compress_content,
+    )
+}
+
+/// Open the storage in the given directory, as `open_storage` does.
+fn open_storage_at(
+    data_path: &str,
+    local_node_id: [u8; 32],
+    storage_backend: StorageBackend,
+    storage_capacity: Option<u64>,
+    cache_capacity: usize,
+    write_buffer_size: usize,
+    compress_content: bool,
+) -> Result<
+    (
+        Arc<dyn ContentStore>,
+        Arc<CanonicalIndex>,
+        Arc<dyn KeyValueBackend>,
+    ),
+    String,
+> {
+    match storage_backend {
+        StorageBackend::Rocksdb => {
+            let mut db_opts = Options::default();
+            db_opts.create_if_missing(true);
+            let db: Arc<dyn KeyValueBackend> = Arc::new(
+                DB::open(&db_opts, data_path)
+                    .map_err(|e| format!("Unable to open rocksdb: {}", e))?,
+            );
+            migrate(db.clone(), false)?;
+            Ok((
+                cached_store(
+                    ContentAddressedStore::new(db.clone()),
+                    cache_capacity,
+                    write_buffer_size,
+                    compress_content,
+                ),
+                Arc::new(CanonicalIndex::new(db.clone())),
+                db,
+            ))
+        }
+        StorageBackend::Sqlite => {
+            let conn = sqlite::open_connection(&Path::new(&data_path).join(SQLITE_FILE_NAME))?;
+            let backend: Arc<dyn KeyValueBackend> = Arc::new(SqliteBackend::new(conn.clone()));
+            Ok((
+                cached_store(
+                    SqliteContentStore::new(conn, local_node_id, storage_capacity),
+                    cache_capacity,
+                    write_buffer_size,
+                    compress_content,
+                ),
+                Arc::new(CanonicalIndex::new(backend.clone())),
+                backend,
+            ))
+        }
+    }
+}
+
+/// Put the read cache in front of the store, and the write buffer between them if there is one.
+/// Values are compressed, or not, beneath both, so the cache and buffer hold them uncompressed.
+fn cached_store<S: ContentStore + 'static>(
+    store: S,
+    cache_capacity: usize,
+    write_buffer_size: usize,
+    compress_content: bool,
+) -> Arc<dyn ContentStore> {
+    let store = CompressedStore::new(store, compress_content);
+    match write_buffer_size {
+        0 => Arc::new(CachedStore::new(store, cache_capacity)),
+        size => Arc::new(CachedStore::new(
+            BufferedStore::new(store, size),
+            cache_capacity,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::metrics::{STORE_COMPACTIONS, STORE_COMPACTION_RECLAIMED_BYTES};
+    use crate::portalnet::types::{ContentType, Nodes};
+    use crate::types::header::Header;
+    use discv5::enr::{CombinedKey, EnrBuilder};
+
+    #[tokio::test]
+    async fn test_offline_local_content_query() {
+        let discovery = Arc::new(Discovery::new(DiscoveryConfig::default()).unwrap());
+        let store = Arc::new(ContentAddressedStore::new(MemoryBackend::default()));
+        store.put(&[1, 2], &[3, 4]).unwrap();
+
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery: discovery.clone(),
+            store,
+            canonical_index: Arc::new(CanonicalIndex::new(Arc::new(MemoryBackend::default()))),
+            transfers: TransferRegistry::default(),
+            protocol: None,
+            max_concurrent_requests: 32,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::LocalContent(vec![1, 2]),
+                resp: resp_tx.clone(),
+            })
+            .unwrap();
+        assert_eq!(
+            resp_rx.recv().await.unwrap(),
+            Ok(Value::String("0x0304".to_string()))
+        );
+
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::LocalContent(vec![5, 6]),
+                resp: resp_tx,
+            })
+            .unwrap();
+        assert_eq!(resp_rx.recv().await.unwrap(), Ok(Value::Null));
+
+        assert!(!discovery.started);
+    }
+
+    /// A store whose reads wait until the test sends on the gate.
+    struct GatedStore {
+        gate: parking_lot::Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl ContentStore for GatedStore {
+        fn get(&self, _content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            let _ = self.gate.lock().recv();
+            Ok(None)
+        }
+
+        fn put(&self, _content_key: &[u8], _value: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn delete(&self, _content_key: &[u8]) -> Result<bool, String> {
+            Ok(false)
+        }
+
+        fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+            Ok(vec![])
+        }
+    }
+
+    /// Start a handler whose local content reads wait on the returned gate.
+    fn gated_handler(
+        max_concurrent_requests: usize,
+    ) -> (
+        mpsc::UnboundedSender<PortalEndpoint>,
+        std::sync::mpsc::Sender<()>,
+    ) {
+        let (gate_tx, gate_rx) = std::sync::mpsc::channel();
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery: Arc::new(Discovery::new(DiscoveryConfig::default()).unwrap()),
+            store: Arc::new(GatedStore {
+                gate: parking_lot::Mutex::new(gate_rx),
+            }),
+            canonical_index: Arc::new(CanonicalIndex::new(Arc::new(MemoryBackend::default()))),
+            transfers: TransferRegistry::default(),
+            protocol: None,
+            max_concurrent_requests,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+        (jsonrpc_tx, gate_tx)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_slow_request_does_not_block_later_requests() {
+        let (jsonrpc_tx, gate) = gated_handler(4);
+
+        let (slow_tx, mut slow_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::LocalContent(vec![1, 2]),
+                resp: slow_tx,
+            })
+            .unwrap();
+        let (fast_tx, mut fast_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        for _ in 0..3 {
+            jsonrpc_tx
+                .send(PortalEndpoint {
+                    kind: PortalEndpointKind::NodeInfo,
+                    resp: fast_tx.clone(),
+                })
+                .unwrap();
+        }
+
+        for _ in 0..3 {
+            let response = tokio::time::timeout(Duration::from_secs(5), fast_rx.recv())
+                .await
+                .expect("request was blocked behind the slow one");
+            assert!(response.unwrap().is_ok());
+        }
+        assert!(slow_rx.try_recv().is_err());
+
+        gate.send(()).unwrap();
+        assert_eq!(slow_rx.recv().await.unwrap(), Ok(Value::Null));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_requests_beyond_limit_wait_for_a_slot() {
+        let (jsonrpc_tx, gate) = gated_handler(1);
+
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        for kind in vec![
+            PortalEndpointKind::LocalContent(vec![1, 2]),
+            PortalEndpointKind::BlockNumber,
+        ] {
+            jsonrpc_tx
+                .send(PortalEndpoint {
+                    kind,
+                    resp: resp_tx.clone(),
+                })
+                .unwrap();
+        }
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), resp_rx.recv())
+                .await
+                .is_err()
+        );
+        gate.send(()).unwrap();
+        assert_eq!(resp_rx.recv().await.unwrap(), Ok(Value::Null));
+        assert_eq!(
+            resp_rx.recv().await.unwrap(),
+            Ok(Value::String("0x0".to_string()))
+        );
+    }
+
+    fn events_with_radius(data_radius: U256, strict_radius: bool) -> PortalnetEvents {
+        let discovery = Arc::new(Discovery::new(DiscoveryConfig::default()).unwrap());
+        let metrics = Arc::new(Metrics::default());
+        PortalnetEvents {
+            overlay: Overlay::new(discovery.local_enr(), data_radius, OverlayConfig::default()),
+            discovery,
+            talk_requests: BoundedQueue::new(
+                1,
+                OverflowPolicy::DropOldest,
+                metrics.clone(),
+                TALK_REQUESTS_DROPPED,
+            ),
+            store: Arc::new(ContentAddressedStore::new(MemoryBackend::default())),
+            storage_policy: StoragePolicy::default(),
+            offer_acceptance_policy: OfferAcceptancePolicy::default(),
+            metrics,
+            strict_radius,
+            unknown_protocol_response: vec![],
+            recorder: None,
+        }
+    }
+
+    fn served_payload(events: &PortalnetEvents, content_key: &[u8]) -> Vec<u8> {
+        match events.find_content_response(content_key.to_vec()) {
+            Response::FoundContent(found) => found.payload,
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_compaction_runs_and_is_counted() {
+        let metrics = Arc::new(Metrics::default());
+        let compactor = Arc::new(Compactor::new(
+            Arc::new(MemoryBackend::default()),
+            metrics.clone(),
+        ));
+        let task = tokio::spawn(compact_store(compactor, Duration::from_millis(10)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        task.abort();
+        assert!(metrics.get(STORE_COMPACTIONS) >= 1);
+        assert_eq!(metrics.get(STORE_COMPACTION_RECLAIMED_BYTES), 0);
+    }
+
+    #[test]
+    fn test_duplicate_offered_keys_are_accepted_once_and_counted() {
+        let events = events_with_radius(U256::max_value(), false);
+        let peer = NodeId::random();
+        let (header, body) = (vec![0x00, 0x01], vec![0x01, 0x01]);
+
+        let response = events
+            .offer_response(&peer, vec![header.clone(), body.clone(), header.clone()])
+            .unwrap();
+        assert_eq!(
+            response,
+            Response::Accept(Accept {
+                connection_id: 0,
+                content_keys: vec![true, true, false],
+            })
+        );
+        assert_eq!(events.metrics.get(DUPLICATE_KEY_OFFERS), 1);
+
+        // An offer without repeats isn't counted.
+        events.offer_response(&peer, vec![header, body]).unwrap();
+        assert_eq!(events.metrics.get(DUPLICATE_KEY_OFFERS), 1);
+    }
+
+    #[test]
+    fn test_trusted_peers_policy_accepts_nothing_from_untrusted_peer() {
+        let bridge = NodeId::random();
+        let mut events = events_with_radius(U256::max_value(), false);
+        events.offer_acceptance_policy = OfferAcceptancePolicy::TrustedPeers(vec![bridge]);
+        let content_keys = vec![vec![0x00, 0x01], vec![0x01, 0x01]];
+
+        let response = events
+            .offer_response(&NodeId::random(), content_keys.clone())
+            .unwrap();
+        assert_eq!(
+            response,
+            Response::Accept(Accept {
+                connection_id: 0,
+                content_keys: vec![false, false],
+            })
+        );
+
+        let response = events.offer_response(&bridge, content_keys).unwrap();
+        assert_eq!(
+            response,
+            Response::Accept(Accept {
+                connection_id: 0,
+                content_keys: vec![true, true],
+            })
+        );
+    }
+
+    #[test]
+    fn test_strict_radius_withholds_out_of_radius_content() {
+        let content_key = vec![0, 1, 2];
+        let probe = events_with_radius(U256::zero(), true);
+        let local_id = probe.discovery.local_enr().node_id();
+        let distance = content_distance(&local_id, &content_id(&content_key));
+
+        // Same node identity, with the content just outside the radius.
+        let strict = PortalnetEvents {
+            overlay: Overlay::new(
+                probe.discovery.local_enr(),
+                distance - U256::from(1),
+                OverlayConfig::default(),
+            ),
+            ..probe
+        };
+        strict.store.put(&content_key, b"held").unwrap();
+        assert_eq!(served_payload(&strict, &content_key), Vec::<u8>::new());
+
+        let lenient = PortalnetEvents {
+            strict_radius: false,
+            ..strict
+        };
+        assert_eq!(served_payload(&lenient, &content_key), b"held".to_vec());
+
+        let within = PortalnetEvents {
+            overlay: Overlay::new(
+                lenient.discovery.local_enr(),
+                distance,
+                OverlayConfig::default(),
+            ),
+            strict_radius: true,
+            ..lenient
+        };
+        assert_eq!(served_payload(&within, &content_key), b"held".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_block_number_from_canonical_index() {
+        let canonical_index = Arc::new(CanonicalIndex::new(Arc::new(MemoryBackend::default())));
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery: Arc::new(Discovery::new(DiscoveryConfig::default()).unwrap()),
+            store: Arc::new(ContentAddressedStore::new(MemoryBackend::default())),
+            canonical_index: canonical_index.clone(),
+            transfers: TransferRegistry::default(),
+            protocol: None,
+            max_concurrent_requests: 32,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::BlockNumber,
+                resp: resp_tx.clone(),
+            })
+            .unwrap();
+        assert_eq!(
+            resp_rx.recv().await.unwrap(),
+            Ok(Value::String("0x0".to_string()))
+        );
+
+        for number in 0..=300 {
+            let header = Header {
+                number,
+                ..Default::default()
+            };
+            canonical_index.insert(number, header.hash()).unwrap();
+        }
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::BlockNumber,
+                resp: resp_tx,
+            })
+            .unwrap();
+        assert_eq!(
+            resp_rx.recv().await.unwrap(),
+            Ok(Value::String("0x12c".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_enr_inserts_into_routing_table() {
+        let discovery = Arc::new(Discovery::new(DiscoveryConfig::default()).unwrap());
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery: discovery.clone(),
+            store: Arc::new(ContentAddressedStore::new(MemoryBackend::default())),
+            canonical_index: Arc::new(CanonicalIndex::new(Arc::new(MemoryBackend::default()))),
+            transfers: TransferRegistry::default(),
+            protocol: None,
+            max_concurrent_requests: 32,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![2; 32].as_mut_slice()).unwrap();
+        let peer: Enr = EnrBuilder::new("v4")
+            .ip("127.0.0.1".parse().unwrap())
+            .udp(9002)
+            .build(&enr_key)
+            .unwrap();
+        let peer: Enr = peer.to_base64().parse().unwrap();
+
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::AddEnr(peer.clone()),
+                resp: resp_tx,
+            })
+            .unwrap();
+        assert_eq!(resp_rx.recv().await.unwrap(), Ok(Value::Bool(true)));
+        assert!(discovery.discv5.table_entries_enr().contains(&peer));
+    }
+
+    async fn started_discovery(key_byte: u8, peers: &[Enr]) -> Discovery {
+        let listen_socket = SocketAddr::new("127.0.0.1".parse().unwrap(), 9000 + key_byte as u16);
+        let mut discovery = Discovery::new(DiscoveryConfig {
+            listen_address: listen_socket.ip(),
+            listen_port: listen_socket.port(),
+            private_key: Some(HexData(vec![key_byte; 32])),
+            bootnode_enrs: peers.to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+        discovery.start(listen_socket).await.unwrap();
+        discovery
+    }
+
+    #[tokio::test]
+    async fn test_recursive_find_node_adds_peers_known_to_bootnode() {
+        let others = vec![
+            started_discovery(31, &[]).await,
+            started_discovery(32, &[]).await,
+        ];
+        let other_enrs: Vec<Enr> = others.iter().map(|other| other.local_enr()).collect();
+        let bootnode = started_discovery(30, &other_enrs).await;
+        let discovery = Arc::new(started_discovery(33, &[bootnode.local_enr()]).await);
+        assert_eq!(discovery.discv5.table_entries_id().len(), 1);
+
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery: discovery.clone(),
+            store: Arc::new(ContentAddressedStore::new(MemoryBackend::default())),
+            canonical_index: Arc::new(CanonicalIndex::new(Arc::new(MemoryBackend::default()))),
+            transfers: TransferRegistry::default(),
+            protocol: None,
+            max_concurrent_requests: 32,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::RecursiveFindNode(other_enrs[0].node_id()),
+                resp: resp_tx,
+            })
+            .unwrap();
+        let added = resp_rx.recv().await.unwrap().unwrap().as_u64().unwrap() as usize;
+        assert!(added > 0);
+        let entries = discovery.discv5.table_entries_id();
+        assert_eq!(entries.len(), 1 + added);
+        assert!(entries.contains(&other_enrs[0].node_id()));
+    }
+
+    /// A protocol on the discovery, keeping everything else in memory.
+    fn protocol_with(discovery: Arc<Discovery>) -> PortalnetProtocol {
+        let canonical_index = Arc::new(CanonicalIndex::new(Arc::new(MemoryBackend::default())));
+        let metrics = Arc::new(Metrics::default());
+        let local_enr = discovery.local_enr();
+        let listen_socket =
+            SocketAddr::new(local_enr.ip().unwrap().into(), local_enr.udp().unwrap());
+        PortalnetProtocol {
+            overlay: Overlay::new(
+                discovery.local_enr(),
+                U256::from(u64::MAX),
+                OverlayConfig::default(),
+            ),
+            discovery,
+            store: Arc::new(ContentAddressedStore::new(MemoryBackend::default())),
+            header_chain: Arc::new(
+                HeaderChain::new(canonical_index.clone(), None, Duration::from_secs(15)).unwrap(),
+            ),
+            canonical_index,
+            transfers: TransferRegistry::default(),
+            watchdog: Arc::new(ActivityWatchdog::new(
+                Duration::from_secs(600),
+                metrics.clone(),
+            )),
+            metrics,
+            listen_socket,
+            transfer_timeout: Duration::from_secs(30),
+            gossip: GossipConfig::default(),
+            lookup_retries: 2,
+            lookup_hop_limit: LOOKUP_MAX_QUERIES,
+            lookups: LookupLimiter::new(16, LookupOverflowPolicy::Queue),
+            not_found: Arc::new(NotFoundCache::new(Duration::from_secs(10))),
+            found: Arc::new(FoundContentCache::new(0, Duration::from_secs(0))),
+            min_lookup_peers: 0,
+            validators: Arc::new(ValidatorRegistry::new()),
+            next_request_id: Arc::new(AtomicU32::new(0)),
+            recorder: None,
+        }
+    }
+
+    /// Answer the overlay requests sent to the discovery, advertising the data radius. Returns
+    /// the metrics of the serving node.
+    async fn serve_overlay(discovery: Arc<Discovery>, data_radius: U256) -> Arc<Metrics> {
+        serve_recorded_overlay(discovery, data_radius, None).await
+    }
+
+    /// Like `serve_overlay`, recording the requests served if there's a recorder.
+    async fn serve_recorded_overlay(
+        discovery: Arc<Discovery>,
+        data_radius: U256,
+        recorder: Option<Arc<RequestRecorder>>,
+    ) -> Arc<Metrics> {
+        let protocol_receiver = discovery.discv5.event_stream().await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let talk_requests = BoundedQueue::new(
+            16,
+            OverflowPolicy::DropOldest,
+            metrics.clone(),
+            TALK_REQUESTS_DROPPED,
+        );
+        let events = PortalnetEvents {
+            overlay: Overlay::new(discovery.local_enr(), data_radius, OverlayConfig::default()),
+            discovery,
+            talk_requests: talk_requests.clone(),
+            store: Arc::new(ContentAddressedStore::new(MemoryBackend::default())),
+            storage_policy: StoragePolicy::default(),
+            offer_acceptance_policy: OfferAcceptancePolicy::default(),
+            metrics: metrics.clone(),
+            strict_radius: false,
+            unknown_protocol_response: vec![],
+            recorder,
+        };
+        tokio::spawn(forward_talk_requests(
+            protocol_receiver,
+            talk_requests,
+            Arc::new(ActivityWatchdog::new(
+                Duration::from_secs(600),
+                metrics.clone(),
+            )),
+            None,
+        ));
+        tokio::spawn(events.process_discv5_requests());
+        metrics
+    }
+
+    #[tokio::test]
+    async fn test_peer_radius_matches_what_the_peer_advertises() {
+        let radius = U256::from(0xabcdef_u64) << 200;
+        let peer = Arc::new(started_discovery(50, &[]).await);
+        serve_overlay(peer.clone(), radius).await;
+        let protocol = protocol_with(Arc::new(started_discovery(51, &[]).await));
+
+        assert_eq!(protocol.peer_radius(peer.local_enr()).await, Ok(radius));
+        let known: Vec<U256> = protocol
+            .overlay
+            .nodes()
+            .iter()
+            .map(|node| node.data_radius())
+            .collect();
+        assert_eq!(known, vec![radius]);
+
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery: protocol.discovery.clone(),
+            store: protocol.store.clone(),
+            canonical_index: protocol.canonical_index.clone(),
+            transfers: protocol.transfers.clone(),
+            protocol: Some(protocol),
+            max_concurrent_requests: 32,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::PeerRadius(peer.local_enr()),
+                resp: resp_tx,
+            })
+            .unwrap();
+        assert_eq!(
+            resp_rx.recv().await.unwrap(),
+            Ok(Value::String(format!("0x{:x}", radius)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookups_need_minimum_connected_peers() {
+        let bootnode = started_discovery(40, &[]).await;
+        let discovery = started_discovery(41, &[bootnode.local_enr()]).await;
+        let error = discovery.ensure_connected_peers(1).unwrap_err();
+        assert!(error.starts_with("Not ready"), "{}", error);
+        assert_eq!(discovery.ensure_connected_peers(0), Ok(()));
+
+        // Querying the bootnode connects to it.
+        discovery
+            .discover_nodes_near(bootnode.local_enr().node_id())
+            .await
+            .unwrap();
+        assert_eq!(discovery.ensure_connected_peers(1), Ok(()));
+        assert!(discovery.ensure_connected_peers(2).is_err());
+    }
+
+    #[test]
+    fn test_local_enr_for_fixed_key() {
+        let private_key = vec![7; 32];
+        let portal_config = PortalnetConfig {
+            private_key: Some(HexData(private_key.clone())),
+            ..Default::default()
+        };
+        let enr = build_local_enr(&portal_config).unwrap();
+
+        let enr_key =
+            CombinedKey::secp256k1_from_bytes(private_key.clone().as_mut_slice()).unwrap();
+        let expected_node_id = EnrBuilder::new("v4").build(&enr_key).unwrap().node_id();
+        let decoded: Enr = enr.to_base64().parse().unwrap();
+        assert_eq!(decoded.node_id(), expected_node_id);
+        assert_eq!(
+            build_local_enr(&portal_config).unwrap().node_id(),
+            expected_node_id
+        );
+        assert!(build_local_enr(&PortalnetConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_dry_run_migration_needs_an_existing_store() {
+        if data_dir_override().is_some() {
+            // The store is then found from the environment, whatever the config.
+            return;
+        }
+        assert!(dry_run_migration(&PortalnetConfig::default()).is_err());
+        // A node key no node ever ran with has no store to migrate.
+        let unused_key = PortalnetConfig {
+            private_key: Some(HexData(NodeId::random().raw().to_vec())),
+            ..Default::default()
+        };
+        assert!(dry_run_migration(&unused_key).is_err());
+    }
+
+    #[test]
+    fn test_verify_store_needs_an_existing_store() {
+        if data_dir_override().is_some() {
+            return;
+        }
+        assert!(verify_store(&PortalnetConfig::default(), false).is_err());
+        let unused_key = PortalnetConfig {
+            private_key: Some(HexData(NodeId::random().raw().to_vec())),
+            ..Default::default()
+        };
+        assert!(verify_store(&unused_key, false).is_err());
+    }
+
+    #[test]
+    fn test_response_with_wrong_request_id_is_dropped() {
+        let pong = Response::Pong(Pong {
+            enr_seq: 1,
+            data_radius: U256::from(1),
+        });
+        let bytes = Envelope {
+            request_id: 7,
+            message: Message::Response(pong.clone()),
+        }
+        .to_bytes();
+
+        let metrics = Metrics::default();
+
+        assert_eq!(open_response(7, &bytes, &metrics), Ok(pong));
+        assert_eq!(metrics.get(MISMATCHED_RESPONSES), 0);
+        assert!(open_response(8, &bytes, &metrics).is_err());
+        assert_eq!(metrics.get(MISMATCHED_RESPONSES), 1);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_response_is_a_protocol_error() {
+        let peer = started_discovery(52, &[]).await;
+        let mut peer_events = peer.discv5.event_stream().await.unwrap();
+        tokio::spawn(async move {
+            while let Some(event) = peer_events.recv().await {
+                if let Discv5Event::TalkRequest(request) = event {
+                    let _ = request.respond(b"not an envelope".to_vec());
+                }
+            }
+        });
+        let protocol = protocol_with(Arc::new(started_discovery(53, &[]).await));
+
+        match protocol.send_ping(U256::from(1), peer.local_enr()).await {
+            Err(DiscoveryError::ProtocolError(_)) => {}
+            result => panic!("Expected a protocol error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_bind_address_is_used_for_socket_and_enr() {
+        let config = PortalnetConfig {
+            bind_address: Some("127.0.0.1".parse().unwrap()),
+            listen_port: 9060,
+            ..Default::default()
+        };
+        let listen_socket = config.listen_socket();
+        assert_eq!(listen_socket, SocketAddr::from(([127, 0, 0, 1], 9060)));
+
+        let enr_address = config.enr_address(listen_socket, |stunned| {
+            assert_eq!(*stunned, listen_socket);
+            None
+        });
+        assert_eq!(enr_address, listen_socket);
+        let discovery = Discovery::new(DiscoveryConfig {
+            listen_address: enr_address.ip(),
+            listen_port: enr_address.port(),
+            ..Default::default()
+        })
+        .unwrap();
+        let local_enr = discovery.local_enr();
+        assert_eq!(local_enr.ip(), Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(local_enr.udp(), Some(9060));
+
+        // An explicit external address still wins for the ENR, without asking STUN.
+        let external_addr = SocketAddr::from(([1, 2, 3, 4], 4242));
+        let config = PortalnetConfig {
+            external_addr: Some(external_addr),
+            ..config
+        };
+        assert_eq!(
+            config.enr_address(listen_socket, |_| panic!("STUN was queried")),
+            external_addr
+        );
+        assert_eq!(
+            PortalnetConfig::default().listen_socket(),
+            SocketAddr::from(([0, 0, 0, 0], 4242))
+        );
+    }
+
+    #[test]
+    fn test_source_port_strategy_decides_the_bound_port() {
+        let config = PortalnetConfig {
+            bind_address: Some("127.0.0.1".parse().unwrap()),
+            listen_port: 9061,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.bind_socket(),
+            Ok(SocketAddr::from(([127, 0, 0, 1], 9061)))
+        );
+
+        let config = PortalnetConfig {
+            source_port_strategy: SourcePortStrategy::Ephemeral,
+            ..config
+        };
+        let bind_socket = config.bind_socket().unwrap();
+        assert_eq!(bind_socket.ip(), config.listen_socket().ip());
+        assert_ne!(bind_socket.port(), 9061);
+        assert_ne!(bind_socket.port(), 0);
+        // The port is free to bind, and is the one the ENR advertises.
+        std::net::UdpSocket::bind(bind_socket).unwrap();
+        assert_eq!(config.enr_address(bind_socket, |_| None), bind_socket);
+    }
+
+    #[tokio::test]
+    async fn test_availability_reports_stored_and_covered_keys() {
+        let protocol = protocol_with(Arc::new(
+            Discovery::new(DiscoveryConfig::default()).unwrap(),
+        ));
+        let stored_key = vec![ContentType::BlockHeader.selector(), 1];
+        let remote_key = vec![ContentType::BlockHeader.selector(), 2];
+        protocol.store.put(&stored_key, b"header").unwrap();
+        for (key_byte, radius) in vec![
+            (2u8, U256::max_value()),
+            (3, U256::max_value()),
+            (4, U256::zero()),
+        ] {
+            let enr_key =
+                CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+            let peer: Enr = EnrBuilder::new("v4").build(&enr_key).unwrap();
+            protocol.overlay.insert_node(peer, radius);
+        }
+
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery: protocol.discovery.clone(),
+            store: protocol.store.clone(),
+            canonical_index: protocol.canonical_index.clone(),
+            transfers: protocol.transfers.clone(),
+            protocol: Some(protocol),
+            max_concurrent_requests: 32,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::Availability(vec![stored_key, remote_key]),
+                resp: resp_tx,
+            })
+            .unwrap();
+
+        let statuses = resp_rx.recv().await.unwrap().unwrap();
+        assert_eq!(statuses[0]["contentKey"], json!("0x0001"));
+        assert_eq!(statuses[0]["stored"], json!(true));
+        assert_eq!(statuses[0]["coveringPeers"], Value::Null);
+        assert_eq!(statuses[1]["contentKey"], json!("0x0002"));
+        assert_eq!(statuses[1]["stored"], json!(false));
+        assert_eq!(statuses[1]["coveringPeers"], json!(2));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_protocol_gets_an_empty_response() {
+        let peer = Arc::new(started_discovery(54, &[]).await);
+        let metrics = serve_overlay(peer.clone(), U256::from(1)).await;
+        let sender = started_discovery(55, &[]).await;
+
+        let response = sender
+            .send_talkreq(peer.local_enr(), "bzz".to_string(), b"hello".to_vec())
+            .await;
+
+        assert_eq!(response, Ok(vec![]));
+        assert_eq!(metrics.get(UNKNOWN_PROTOCOL_REQUESTS), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pong_with_newer_enr_seq_refreshes_the_cached_enr() {
+        let peer = started_discovery(56, &[]).await;
+        let stale = peer.local_enr();
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![56; 32].as_mut_slice()).unwrap();
+        let mut fresh = stale.clone();
+        fresh.set_udp(stale.udp().unwrap(), &enr_key).unwrap();
+        assert!(fresh.seq() > stale.seq());
+
+        // The peer answers pings with its newer seq, and hands out the newer ENR at distance 0.
+        let mut peer_events = peer.discv5.event_stream().await.unwrap();
+        let served = fresh.clone();
+        let find_nodes_distances = Arc::new(parking_lot::Mutex::new(vec![]));
+        let recorded = find_nodes_distances.clone();
+        tokio::spawn(async move {
+            while let Some(event) = peer_events.recv().await {
+                if let Discv5Event::TalkRequest(request) = event {
+                    let envelope = Envelope::from_bytes(request.body()).unwrap();
+                    let response = match envelope.message {
+                        Message::Request(Request::Ping(_)) => Response::Pong(Pong {
+                            enr_seq: served.seq(),
+                            data_radius: U256::max_value(),
+                        }),
+                        Message::Request(Request::FindNodes(FindNodes { distances })) => {
+                            recorded.lock().push(distances);
+                            Response::Nodes(Nodes {
+                                total: 1,
+                                enrs: vec![served.clone()],
+                            })
+                        }
+                        message => panic!("Unexpected message {:?}", message),
+                    };
+                    let reply = Envelope {
+                        request_id: envelope.request_id,
+                        message: Message::Response(response),
+                    };
+                    let _ = request.respond(reply.to_bytes());
+                }
+            }
+        });
+        let protocol = protocol_with(Arc::new(started_discovery(57, &[]).await));
+
+        protocol
+            .send_ping(U256::max_value(), stale.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(*find_nodes_distances.lock(), vec![vec![0]]);
+        let cached: Vec<Enr> = protocol
+            .overlay
+            .nodes()
+            .iter()
+            .map(|node| node.enr())
+            .filter(|enr| enr.node_id() == stale.node_id())
+            .collect();
+        assert_eq!(cached, vec![fresh.clone()]);
+
+        // A pong that isn't newer than the cached ENR doesn't trigger another fetch.
+        protocol.send_ping(U256::max_value(), fresh).await.unwrap();
+        assert_eq!(find_nodes_distances.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recorded_ping_is_a_well_formed_log_entry() {
+        let log_path = |name: &str| {
+            std::env::temp_dir().join(format!(
+                "trin-requests-{}-{}.jsonl",
+                name,
+                hex::encode(NodeId::random().raw())
+            ))
+        };
+        let (served_log, sent_log) = (log_path("served"), log_path("sent"));
+        let peer = Arc::new(started_discovery(58, &[]).await);
+        let served = Arc::new(RequestRecorder::new(served_log.clone(), 1 << 20).unwrap());
+        serve_recorded_overlay(peer.clone(), U256::from(1), Some(served)).await;
+        let mut protocol = protocol_with(Arc::new(started_discovery(59, &[]).await));
+        protocol.recorder = Some(Arc::new(
+            RequestRecorder::new(sent_log.clone(), 1 << 20).unwrap(),
+        ));
+
+        protocol
+            .send_ping(U256::from(1), peer.local_enr())
+            .await
+            .unwrap();
+
+        let sender = protocol.discovery.local_enr().node_id();
+        for (path, direction, remote) in vec![
+            (&served_log, "inbound", sender),
+            (&sent_log, "outbound", peer.local_enr().node_id()),
+        ] {
+            let log = std::fs::read_to_string(path).unwrap();
+            let entries: Vec<Value> = log
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            assert_eq!(entries.len(), 1);
+            let entry = &entries[0];
+            assert_eq!(entry["direction"], direction);
+            assert_eq!(entry["peer"], format!("0x{}", hex::encode(remote.raw())));
+            assert_eq!(entry["error"], Value::Null);
+            let decode = |field: &str| {
+                let hex_bytes = entry[field].as_str().unwrap().trim_start_matches("0x");
+                Envelope::from_bytes(&hex::decode(hex_bytes).unwrap()).unwrap()
+            };
+            assert!(matches!(
+                decode("request").message,
+                Message::Request(Request::Ping(_))
+            ));
+            assert!(matches!(
+                decode("response").message,
+                Message::Response(Response::Pong(_))
+            ));
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unreachable_external_addr_is_warned_about() {
+        let configured: SocketAddr = "203.0.113.7:9000".parse().unwrap();
+        let observed: SocketAddr = "198.51.100.2:41234".parse().unwrap();
+        let warning = external_addr_mismatch(Some(configured), observed).unwrap();
+        assert!(warning.contains("198.51.100.2:41234"));
+        assert!(warning.contains("203.0.113.7:9000"));
+
+        // Nothing to warn about when peers agree with the config, or nothing was configured.
+        assert_eq!(external_addr_mismatch(Some(configured), configured), None);
+        assert_eq!(external_addr_mismatch(None, observed), None);
+    }
+
+    #[test]
+    fn test_header_traffic_is_counted_under_header_metrics_only() {
+        let mut events = events_with_radius(U256::max_value(), false);
+        events.store = Arc::new(MeteredStore::new(
+            events.store.clone(),
+            events.metrics.clone(),
+        ));
+        let header_key = vec![ContentType::BlockHeader.selector(), 1];
+        events.store.put(&header_key, b"header").unwrap();
+        assert_eq!(served_payload(&events, &header_key), b"header");
+
+        let metrics = &events.metrics;
+        assert_eq!(metrics.get("content_stored_header"), 1);
+        assert_eq!(metrics.get("content_served_header"), 1);
+        assert_eq!(metrics.get("content_transfer_bytes_header"), 6);
+        for body_metric in &[
+            "content_stored_body",
+            "content_served_body",
+            "content_transfer_bytes_body",
+        ] {
+            assert_eq!(metrics.get(body_metric), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_content_reports_whether_it_was_stored() {
+        let discovery = Arc::new(Discovery::new(DiscoveryConfig::default()).unwrap());
+        let store = Arc::new(ContentAddressedStore::new(MemoryBackend::default()));
+        let content_key = vec![ContentType::BlockHeader.selector(); 35];
+        store.put(&content_key, b"header").unwrap();
+
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery,
+            store: store.clone(),
+            canonical_index: Arc::new(CanonicalIndex::new(Arc::new(MemoryBackend::default()))),
+            transfers: TransferRegistry::default(),
+            protocol: None,
+            max_concurrent_requests: 32,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        for expected in vec![true, false] {
+            jsonrpc_tx
+                .send(PortalEndpoint {
+                    kind: PortalEndpointKind::DeleteContent(content_key.clone()),
+                    resp: resp_tx.clone(),
+                })
+                .unwrap();
+            assert_eq!(resp_rx.recv().await.unwrap(), Ok(Value::Bool(expected)));
+        }
+        assert_eq!(store.get(&content_key).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_total_bootstrap_failure_is_reported_and_unhealthy() {
+        // A bootnode that was never started, so no ping reaches it.
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![61; 32].as_mut_slice()).unwrap();
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("127.0.0.1".parse().unwrap());
+        builder.udp(9061);
+        let dead_bootnode: Enr = builder.build(&enr_key).unwrap();
+        let discovery = Arc::new(started_discovery(60, &[dead_bootnode]).await);
+        let protocol = protocol_with(discovery.clone());
+
+        let error = protocol
+            .bootstrap(Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert!(error.contains("none of the 1 bootnodes answered"));
+        assert!(error.contains("firewall"));
+        assert!(error.contains("--external-address"));
+        assert_eq!(protocol.metrics.get(BOOTSTRAP_STALLED), 1);
+
+        let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+        let rpc_handler = JsonRpcHandler {
+            discovery,
+            store: protocol.store.clone(),
+            canonical_index: protocol.canonical_index.clone(),
+            transfers: protocol.transfers.clone(),
+            protocol: Some(protocol.clone()),
+            max_concurrent_requests: 32,
+            jsonrpc_rx,
+        };
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+        let (resp_tx, mut resp_rx) = mpsc::unbounded_channel::<Result<Value, String>>();
+        jsonrpc_tx
+            .send(PortalEndpoint {
+                kind: PortalEndpointKind::Health,
+                resp: resp_tx,
+            })
+            .unwrap();
+        assert_eq!(
+            resp_rx.recv().await.unwrap(),
+            Ok(json!({"healthy": false, "problems": ["bootstrap_stalled"]}))
+        );
+    }
+
+    #[test]
+    fn test_sqlite_storage_evicts_down_to_the_configured_capacity() {
+        let data_dir = std::env::temp_dir().join(format!(
+            "trin-capacity-{}",
+            hex::encode(NodeId::random().raw())
+        ));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let (store, _, _) = open_storage_at(
+            &data_dir.to_string_lossy(),
+            NodeId::random().raw(),
+            StorageBackend::Sqlite,
+            Some(25),
+            8,
+            0,
+            false,
+        )
+        .unwrap();
+        for i in 0..4u8 {
+            store.put(&[i], &[i; 10]).unwrap();
+        }
+        let held = store.content_keys().unwrap().len();
+        drop(store);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+
+        assert_eq!(held, 2);
+    }
+}
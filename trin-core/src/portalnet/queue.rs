@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+use super::metrics::Metrics;
+
+/// What to do with a new item when the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued item to make room for the new one.
+    DropOldest,
+    /// Discard the new item.
+    Reject,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+struct Inner<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+    metrics: Arc<Metrics>,
+    drop_metric: &'static str,
+}
+
+/// A bounded single-consumer queue that never blocks the producer. When full, items are dropped
+/// according to the overflow policy and counted in `drop_metric`.
+pub struct BoundedQueue<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for BoundedQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(
+        capacity: usize,
+        policy: OverflowPolicy,
+        metrics: Arc<Metrics>,
+        drop_metric: &'static str,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    items: VecDeque::with_capacity(capacity),
+                    closed: false,
+                }),
+                notify: Notify::new(),
+                capacity,
+                policy,
+                metrics,
+                drop_metric,
+            }),
+        }
+    }
+
+    pub fn push(&self, item: T) {
+        {
+            let mut state = self.inner.state.lock();
+            if state.items.len() < self.inner.capacity {
+                state.items.push_back(item);
+            } else {
+                if let OverflowPolicy::DropOldest = self.inner.policy {
+                    state.items.pop_front();
+                    state.items.push_back(item);
+                }
+                self.inner.metrics.increment(self.inner.drop_metric);
+            }
+        }
+        self.inner.notify.notify_one();
+    }
+
+    /// Waits for the next item. Returns `None` once the queue is closed and drained.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.inner.state.lock();
+                if let Some(item) = state.items.pop_front() {
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.inner.notify.notified().await;
+        }
+    }
+
+    /// Stop accepting new items. Already queued items can still be popped.
+    pub fn close(&self) {
+        self.inner.state.lock().closed = true;
+        self.inner.notify.notify_one();
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DROPPED: &str = "dropped";
+
+    async fn drain(queue: &BoundedQueue<u32>) -> Vec<u32> {
+        queue.close();
+        let mut items = vec![];
+        while let Some(item) = queue.pop().await {
+            items.push(item);
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn test_flood_drops_oldest() {
+        let metrics = Arc::new(Metrics::default());
+        let queue = BoundedQueue::new(4, OverflowPolicy::DropOldest, metrics.clone(), DROPPED);
+        for i in 0..10 {
+            queue.push(i);
+        }
+
+        assert_eq!(queue.len(), 4);
+        assert_eq!(metrics.get(DROPPED), 6);
+        assert_eq!(drain(&queue).await, vec![6, 7, 8, 9]);
+    }
+
+    #[tokio::test]
+    async fn test_flood_rejects_newest() {
+        let metrics = Arc::new(Metrics::default());
+        let queue = BoundedQueue::new(4, OverflowPolicy::Reject, metrics.clone(), DROPPED);
+        for i in 0..10 {
+            queue.push(i);
+        }
+
+        assert_eq!(queue.len(), 4);
+        assert_eq!(metrics.get(DROPPED), 6);
+        assert_eq!(drain(&queue).await, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_pop_waits_for_push() {
+        let metrics = Arc::new(Metrics::default());
+        let queue = BoundedQueue::new(4, OverflowPolicy::Reject, metrics.clone(), DROPPED);
+        let consumer = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.pop().await })
+        };
+        queue.push(1);
+
+        assert_eq!(consumer.await.unwrap(), Some(1));
+        assert_eq!(metrics.get(DROPPED), 0);
+    }
+}
@@ -0,0 +1,192 @@
+//! Records overlay requests and responses, so that a problematic session can be inspected or
+//! replayed offline. Each exchange is one line of JSON holding the raw message bytes as hex.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use discv5::enr::NodeId;
+use log::warn;
+use parking_lot::Mutex;
+use serde_json::json;
+
+/// Which way a recorded request went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// A peer's request, answered by this node
+    Inbound,
+    /// This node's request, answered by a peer
+    Outbound,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+}
+
+/// Appends exchanges to a newline-delimited JSON file. Once the file would grow past its size
+/// cap, it's rotated to `<path>.1`, replacing the previous rotation, and a fresh file is started.
+pub struct RequestRecorder {
+    path: PathBuf,
+    max_size: u64,
+    /// The open log and how many bytes it holds
+    file: Mutex<(File, u64)>,
+}
+
+impl RequestRecorder {
+    pub fn new(path: PathBuf, max_size: u64) -> Result<Self, String> {
+        let file = open_log(&path)?;
+        let size = file
+            .metadata()
+            .map_err(|e| format!("Failed to read request log size: {}", e))?
+            .len();
+        Ok(Self {
+            path,
+            max_size,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    /// Returns the file the log is rotated to.
+    pub fn rotated_path(&self) -> PathBuf {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        rotated.into()
+    }
+
+    /// Record a request and its response, or why there wasn't one. Failing to write is only
+    /// logged, so that recording never gets in the way of the request.
+    pub fn record<E: fmt::Display>(
+        &self,
+        direction: Direction,
+        peer: &NodeId,
+        request: &[u8],
+        response: &Result<Vec<u8>, E>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        let (response, error) = match response {
+            Ok(response) => (Some(format!("0x{}", hex::encode(response))), None),
+            Err(error) => (None, Some(error.to_string())),
+        };
+        let line = format!(
+            "{}\n",
+            json!({
+                "timestamp": timestamp,
+                "direction": direction.as_str(),
+                "peer": format!("0x{}", hex::encode(peer.raw())),
+                "request": format!("0x{}", hex::encode(request)),
+                "response": response,
+                "error": error,
+            })
+        );
+        if let Err(e) = self.append(line.as_bytes()) {
+            warn!("Failed to record request: {}", e);
+        }
+    }
+
+    fn append(&self, line: &[u8]) -> Result<(), String> {
+        let mut file = self.file.lock();
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_size {
+            fs::rename(&self.path, self.rotated_path())
+                .map_err(|e| format!("Failed to rotate request log: {}", e))?;
+            *file = (open_log(&self.path)?, 0);
+        }
+        file.0
+            .write_all(line)
+            .map_err(|e| format!("Failed to write request log: {}", e))?;
+        file.1 += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open request log {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::Value;
+
+    fn log_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "trin-requests-{}.jsonl",
+            hex::encode(NodeId::random().raw())
+        ))
+    }
+
+    fn read_entries(path: &Path) -> Vec<Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_exchanges_are_recorded_as_json_lines() {
+        let path = log_path();
+        let recorder = RequestRecorder::new(path.clone(), 1 << 20).unwrap();
+        let peer = NodeId::new(&[7; 32]);
+
+        recorder.record(
+            Direction::Inbound,
+            &peer,
+            &[1, 2],
+            &Ok::<_, String>(vec![3]),
+        );
+        recorder.record(
+            Direction::Outbound,
+            &peer,
+            &[4],
+            &Err::<Vec<u8>, _>("no response"),
+        );
+
+        let entries = read_entries(&path);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["direction"], "inbound");
+        assert_eq!(entries[0]["peer"], format!("0x{}", hex::encode([7; 32])));
+        assert_eq!(entries[0]["request"], "0x0102");
+        assert_eq!(entries[0]["response"], "0x03");
+        assert_eq!(entries[0]["error"], Value::Null);
+        assert!(entries[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(entries[1]["direction"], "outbound");
+        assert_eq!(entries[1]["response"], Value::Null);
+        assert_eq!(entries[1]["error"], "no response");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_log_is_rotated_at_its_size_cap() {
+        let path = log_path();
+        let peer = NodeId::new(&[7; 32]);
+        let recorder = RequestRecorder::new(path.clone(), 1).unwrap();
+
+        // A lone entry bigger than the cap is still written, to a fresh file.
+        recorder.record(Direction::Inbound, &peer, &[1], &Ok::<_, String>(vec![]));
+        recorder.record(Direction::Inbound, &peer, &[2], &Ok::<_, String>(vec![]));
+        recorder.record(Direction::Inbound, &peer, &[3], &Ok::<_, String>(vec![]));
+
+        let current = read_entries(&path);
+        let rotated = read_entries(&recorder.rotated_path());
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0]["request"], "0x03");
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(rotated[0]["request"], "0x02");
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&recorder.rotated_path()).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::lookup::content_id;
+use super::storage::{ContentStore, KeyValueBackend, WriteOp};
+use super::U256;
+use crate::utils::xor_two_values;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS content (
+        content_key BLOB PRIMARY KEY,
+        content_id BLOB NOT NULL,
+        distance BLOB NOT NULL,
+        value BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS content_distance ON content (distance);
+    CREATE TABLE IF NOT EXISTS kv (
+        key BLOB PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+";
+
+fn sql_error(e: rusqlite::Error) -> String {
+    format!("Sqlite error: {}", e)
+}
+
+/// Open a single-file database, creating its tables if needed.
+pub fn open_connection(path: &Path) -> Result<Arc<Mutex<Connection>>, String> {
+    init_connection(Connection::open(path).map_err(sql_error)?)
+}
+
+fn init_connection(conn: Connection) -> Result<Arc<Mutex<Connection>>, String> {
+    conn.execute_batch(SCHEMA).map_err(sql_error)?;
+    Ok(Arc::new(Mutex::new(conn)))
+}
+
+/// Content store in a SQLite table. Each row records the content's distance from the local node,
+/// so that once the store is over capacity the farthest content can be evicted with one query.
+pub struct SqliteContentStore {
+    conn: Arc<Mutex<Connection>>,
+    local_node_id: [u8; 32],
+    /// Maximum total size of stored values in bytes, or `None` for no limit.
+    capacity: Option<u64>,
+    /// The farthest distance still stored after an eviction. `None` until the store filled up.
+    radius: Mutex<Option<U256>>,
+}
+
+impl SqliteContentStore {
+    pub fn new(
+        conn: Arc<Mutex<Connection>>,
+        local_node_id: [u8; 32],
+        capacity: Option<u64>,
+    ) -> Self {
+        Self {
+            conn,
+            local_node_id,
+            capacity,
+            radius: Mutex::new(None),
+        }
+    }
+
+    /// A store in a private in-memory database, useful for tests and ephemeral nodes.
+    pub fn in_memory(local_node_id: [u8; 32], capacity: Option<u64>) -> Result<Self, String> {
+        let conn = init_connection(Connection::open_in_memory().map_err(sql_error)?)?;
+        Ok(Self::new(conn, local_node_id, capacity))
+    }
+
+    /// Returns the distance within which the node stores content. It covers the whole id space
+    /// until the store fills up, and then shrinks to the farthest content kept.
+    pub fn radius(&self) -> U256 {
+        let radius = *self.radius.lock();
+        radius.unwrap_or_else(U256::max_value)
+    }
+
+    /// Returns the total size of stored values in bytes.
+    pub fn total_size(&self) -> Result<u64, String> {
+        let size: i64 = self
+            .conn
+            .lock()
+            .query_row(
+                "SELECT COALESCE(SUM(LENGTH(value)), 0) FROM content",
+                params![],
+                |row| row.get(0),
+            )
+            .map_err(sql_error)?;
+        Ok(size as u64)
+    }
+
+    /// Delete the farthest content until the store fits its capacity.
+    fn evict(&self) -> Result<(), String> {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return Ok(()),
+        };
+        while self.total_size()? > capacity {
+            let conn = self.conn.lock();
+            let farthest: Option<Vec<u8>> = conn
+                .query_row(
+                    "SELECT content_key FROM content ORDER BY distance DESC LIMIT 1",
+                    params![],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(sql_error)?;
+            let farthest = match farthest {
+                Some(content_key) => content_key,
+                None => break,
+            };
+            conn.execute(
+                "DELETE FROM content WHERE content_key = ?1",
+                params![farthest],
+            )
+            .map_err(sql_error)?;
+            let remaining: Option<Vec<u8>> = conn
+                .query_row(
+                    "SELECT distance FROM content ORDER BY distance DESC LIMIT 1",
+                    params![],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(sql_error)?;
+            *self.radius.lock() = Some(
+                remaining.map_or_else(U256::zero, |distance| U256::from_big_endian(&distance)),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl ContentStore for SqliteContentStore {
+    fn get(&self, content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT value FROM content WHERE content_key = ?1",
+                params![content_key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sql_error)
+    }
+
+    fn put(&self, content_key: &[u8], value: &[u8]) -> Result<(), String> {
+        let id = content_id(content_key);
+        let distance = xor_two_values(&id, &self.local_node_id);
+        self.conn
+            .lock()
+            .execute(
+                "INSERT OR REPLACE INTO content (content_key, content_id, distance, value)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![content_key, &id[..], distance, value],
+            )
+            .map_err(sql_error)?;
+        self.evict()
+    }
+
+    fn delete(&self, content_key: &[u8]) -> Result<bool, String> {
+        let deleted = self
+            .conn
+            .lock()
+            .execute(
+                "DELETE FROM content WHERE content_key = ?1",
+                params![content_key],
+            )
+            .map_err(sql_error)?;
+        Ok(deleted > 0)
+    }
+
+    fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        let conn = self.conn.lock();
+        let mut statement = conn
+            .prepare("SELECT content_key FROM content ORDER BY content_key")
+            .map_err(sql_error)?;
+        let rows = statement
+            .query_map(params![], |row| row.get(0))
+            .map_err(sql_error)?;
+        rows.collect::<Result<Vec<Vec<u8>>, _>>().map_err(sql_error)
+    }
+}
+
+/// Key/value table in the same SQLite database, for the indexes layered on `KeyValueBackend`.
+pub struct SqliteBackend {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    pub fn new(conn: Arc<Mutex<Connection>>) -> Self {
+        Self { conn }
+    }
+}
+
+impl KeyValueBackend for SqliteBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.conn
+            .lock()
+            .query_row("SELECT value FROM kv WHERE key = ?1", params![key], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sql_error)
+    }
+
+    fn write(&self, batch: Vec<WriteOp>) -> Result<(), String> {
+        let mut conn = self.conn.lock();
+        let transaction = conn.transaction().map_err(sql_error)?;
+        for op in batch {
+            match op {
+                WriteOp::Put(key, value) => transaction.execute(
+                    "INSERT OR REPLACE INTO kv (key, value) VALUES (?1, ?2)",
+                    params![key, value],
+                ),
+                WriteOp::Delete(key) => {
+                    transaction.execute("DELETE FROM kv WHERE key = ?1", params![key])
+                }
+            }
+            .map_err(sql_error)?;
+        }
+        transaction.commit().map_err(sql_error)
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let conn = self.conn.lock();
+        let mut statement = conn
+            .prepare("SELECT key FROM kv WHERE key >= ?1 ORDER BY key")
+            .map_err(sql_error)?;
+        let rows = statement
+            .query_map(params![prefix], |row| row.get(0))
+            .map_err(sql_error)?;
+        let mut keys = vec![];
+        for key in rows {
+            let key: Vec<u8> = key.map_err(sql_error)?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::storage::{test_suite, CanonicalIndex};
+    use crate::utils::content_id_to_u256;
+    use ethereum_types::H256;
+
+    #[test]
+    fn test_sqlite_store_passes_store_suite() {
+        test_suite::run(&SqliteContentStore::in_memory([0; 32], None).unwrap());
+    }
+
+    #[test]
+    fn test_farthest_content_is_evicted_over_capacity() {
+        let store = SqliteContentStore::in_memory([0; 32], Some(20)).unwrap();
+        assert_eq!(store.radius(), U256::max_value());
+
+        let mut keys: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i]).collect();
+        keys.sort_by_key(|key| content_id(key));
+        for key in keys.iter() {
+            store.put(key, &[0xab; 10]).unwrap();
+        }
+
+        assert_eq!(store.total_size().unwrap(), 20);
+        let mut expected = keys[..2].to_vec();
+        expected.sort();
+        assert_eq!(store.content_keys().unwrap(), expected);
+        assert_eq!(store.get(&keys[2]).unwrap(), None);
+        assert_eq!(store.radius(), content_id_to_u256(&content_id(&keys[1])));
+    }
+
+    #[test]
+    fn test_canonical_index_on_sqlite_backend() {
+        let conn = init_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let index = CanonicalIndex::new(Arc::new(SqliteBackend::new(conn)));
+        index.insert(5, H256::repeat_byte(5)).unwrap();
+        index.insert(3, H256::repeat_byte(3)).unwrap();
+
+        assert_eq!(index.highest_block_number().unwrap(), Some(5));
+        assert_eq!(index.block_hash(3).unwrap(), Some(H256::repeat_byte(3)));
+    }
+}
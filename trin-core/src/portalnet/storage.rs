@@ -0,0 +1,1620 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use discv5::enr::NodeId;
+use ethereum_types::H256;
+use log::{error, info, warn};
+use lru::LruCache;
+use parking_lot::{Mutex, RwLock};
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use sha2::{Digest, Sha256};
+
+use super::lookup::content_id;
+use super::metrics::{
+    self, Metrics, CONTENT_STORED, STORE_COMPACTIONS, STORE_COMPACTION_DURATION_MS,
+    STORE_COMPACTION_RECLAIMED_BYTES,
+};
+use super::types::ContentType;
+use super::U256;
+use crate::types::header::Header;
+use crate::utils::content_distance;
+
+/// Content key -> blob hash
+const KEY_PREFIX: &[u8] = b"k";
+/// Blob hash -> content value
+const BLOB_PREFIX: &[u8] = b"b";
+/// Blob hash -> number of content keys that reference the blob (u64, big-endian)
+const REFCOUNT_PREFIX: &[u8] = b"r";
+/// Canonical block number (u64, big-endian) -> block hash
+const CANONICAL_PREFIX: &[u8] = b"n";
+/// Highest block number in the canonical index (u64, big-endian)
+const CANONICAL_HEAD_KEY: &[u8] = b"h";
+/// Version of the layout the database is in (u64, big-endian)
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+/// Version of the database layout this code reads and writes. Version 1 stored each content
+/// value directly under its content key; version 2 added the content-addressed blobs and the
+/// canonical index.
+pub const SCHEMA_VERSION: u64 = 2;
+
+/// Which database the content store is kept in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageBackend {
+    Rocksdb,
+    /// A single SQLite file, for portability.
+    Sqlite,
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        StorageBackend::Rocksdb
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rocksdb" => Ok(StorageBackend::Rocksdb),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            _ => Err(format!("Unknown storage backend: {}", s)),
+        }
+    }
+}
+
+/// A single write, applied atomically with the rest of its batch.
+pub enum WriteOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// Raw key/value storage that the content store is layered on.
+pub trait KeyValueBackend: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    fn write(&self, batch: Vec<WriteOp>) -> Result<(), String>;
+
+    /// Returns all keys starting with `prefix`, in ascending order.
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String>;
+
+    /// Reorganize the data on disk, reclaiming the space of deleted and overwritten values.
+    /// Backends that don't need it do nothing.
+    fn compact(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Returns how many bytes the data takes up on disk, or 0 if the backend can't tell.
+    fn size_on_disk(&self) -> Result<u64, String> {
+        Ok(0)
+    }
+}
+
+impl KeyValueBackend for DB {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        DB::get(self, key).map_err(|e| format!("Unable to read from db: {}", e))
+    }
+
+    fn write(&self, batch: Vec<WriteOp>) -> Result<(), String> {
+        let mut write_batch = WriteBatch::default();
+        for op in batch {
+            match op {
+                WriteOp::Put(key, value) => write_batch.put(key, value),
+                WriteOp::Delete(key) => write_batch.delete(key),
+            }
+        }
+        DB::write(self, write_batch).map_err(|e| format!("Unable to write to db: {}", e))
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        Ok(self
+            .iterator(IteratorMode::From(prefix, Direction::Forward))
+            .map(|(key, _)| key.to_vec())
+            .take_while(|key| key.starts_with(prefix))
+            .collect())
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        self.compact_range::<&[u8], &[u8]>(None, None);
+        Ok(())
+    }
+
+    fn size_on_disk(&self) -> Result<u64, String> {
+        self.property_int_value("rocksdb.total-sst-files-size")
+            .map(|size| size.unwrap_or(0))
+            .map_err(|e| format!("Unable to read db size: {}", e))
+    }
+}
+
+impl<B: KeyValueBackend + ?Sized> KeyValueBackend for Arc<B> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        (**self).get(key)
+    }
+
+    fn write(&self, batch: Vec<WriteOp>) -> Result<(), String> {
+        (**self).write(batch)
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        (**self).keys_with_prefix(prefix)
+    }
+
+    fn compact(&self) -> Result<(), String> {
+        (**self).compact()
+    }
+
+    fn size_on_disk(&self) -> Result<u64, String> {
+        (**self).size_on_disk()
+    }
+}
+
+/// In-memory backend, useful for tests and ephemeral nodes.
+#[derive(Default)]
+pub struct MemoryBackend {
+    entries: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KeyValueBackend for MemoryBackend {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.entries.read().get(key).cloned())
+    }
+
+    fn write(&self, batch: Vec<WriteOp>) -> Result<(), String> {
+        let mut entries = self.entries.write();
+        for op in batch {
+            match op {
+                WriteOp::Put(key, value) => entries.insert(key, value),
+                WriteOp::Delete(key) => entries.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        Ok(self
+            .entries
+            .read()
+            .range(prefix.to_vec()..)
+            .map(|(key, _)| key.clone())
+            .take_while(|key| key.starts_with(prefix))
+            .collect())
+    }
+}
+
+/// Storage for portal network content, keyed by content key.
+pub trait ContentStore: Send + Sync {
+    fn get(&self, content_key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    fn put(&self, content_key: &[u8], value: &[u8]) -> Result<(), String>;
+
+    /// Removes the content key, returning whether it was stored.
+    fn delete(&self, content_key: &[u8]) -> Result<bool, String>;
+
+    /// Returns all stored content keys.
+    fn content_keys(&self) -> Result<Vec<Vec<u8>>, String>;
+
+    /// Writes any buffered content through to the database. Stores that don't buffer writes have
+    /// nothing to flush.
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Returns up to `count` held content keys, ordered by the distance of their content ids to the
+    /// node, closest first.
+    fn content_closest_to(&self, node_id: &NodeId, count: usize) -> Result<Vec<Vec<u8>>, String> {
+        let mut by_distance: Vec<(U256, Vec<u8>)> = self
+            .content_keys()?
+            .into_iter()
+            .map(|content_key| {
+                (
+                    content_distance(node_id, &content_id(&content_key)),
+                    content_key,
+                )
+            })
+            .collect();
+        by_distance.sort();
+        Ok(by_distance
+            .into_iter()
+            .take(count)
+            .map(|(_, content_key)| content_key)
+            .collect())
+    }
+
+    /// Returns the stored value decoded as an RLP block header.
+    fn get_header(&self, content_key: &[u8]) -> Result<Option<Header>, String> {
+        match self.get(content_key)? {
+            Some(value) => Ok(Some(decode_header(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn decode_header(value: &[u8]) -> Result<Header, String> {
+    rlp::decode(value).map_err(|e| format!("Invalid stored header: {}", e))
+}
+
+/// Keeps the most recently read values, and decoded headers, in memory in front of another store.
+/// A read that misses reads the store without holding a cache lock, so misses run concurrently.
+/// Writes bump the generation of keys being read, and a read only caches what it read if the
+/// key's generation is unchanged, so a value overwritten or deleted mid-read is never cached.
+pub struct CachedStore<S: ContentStore> {
+    store: S,
+    values: Mutex<LruCache<Vec<u8>, Vec<u8>>>,
+    headers: Mutex<LruCache<Vec<u8>, Header>>,
+    /// Content key -> (generation, reads in progress), for keys with missed reads in progress
+    fills: Mutex<HashMap<Vec<u8>, (u64, usize)>>,
+    hits: AtomicU64,
+}
+
+impl<S: ContentStore> CachedStore<S> {
+    /// Cache up to `capacity` values, and as many decoded headers. At least one of each is cached.
+    pub fn new(store: S, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            store,
+            values: Mutex::new(LruCache::new(capacity)),
+            headers: Mutex::new(LruCache::new(capacity)),
+            fills: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of reads served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Change the store under both cache locks, then drop the key's cached value and header, and
+    /// bump its generation so that reads in progress don't cache what they read.
+    fn write_through<T>(
+        &self,
+        content_key: &[u8],
+        write: impl FnOnce(&S) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let mut values = self.values.lock();
+        let mut headers = self.headers.lock();
+        let result = write(&self.store);
+        let content_key = content_key.to_vec();
+        values.pop(&content_key);
+        headers.pop(&content_key);
+        if let Some((generation, _)) = self.fills.lock().get_mut(&content_key) {
+            *generation += 1;
+        }
+        result
+    }
+
+    /// Start a missed read of the key, returning its generation. Called under the lock of the
+    /// cache the read fills, so that no write lands between the miss and the generation.
+    fn start_fill(&self, content_key: &[u8]) -> u64 {
+        let mut fills = self.fills.lock();
+        let (generation, reads) = fills.entry(content_key.to_vec()).or_insert((0, 0));
+        *reads += 1;
+        *generation
+    }
+
+    /// Finish a read started with `start_fill`. Returns whether the key is unwritten since, so
+    /// that what was read can be cached. Called under the lock of the cache the read fills.
+    fn finish_fill(&self, content_key: &[u8], started_generation: u64) -> bool {
+        let mut fills = self.fills.lock();
+        let (generation, reads) = fills
+            .get_mut(content_key)
+            .expect("every finished read was started");
+        let unwritten = *generation == started_generation;
+        *reads -= 1;
+        if *reads == 0 {
+            fills.remove(content_key);
+        }
+        unwritten
+    }
+}
+
+impl<S: ContentStore> ContentStore for CachedStore<S> {
+    fn get(&self, content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let generation = {
+            let mut values = self.values.lock();
+            if let Some(value) = values.get(&content_key.to_vec()) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(value.clone()));
+            }
+            self.start_fill(content_key)
+        };
+        let value = self.store.get(content_key);
+        let mut values = self.values.lock();
+        if self.finish_fill(content_key, generation) {
+            if let Ok(Some(value)) = &value {
+                values.put(content_key.to_vec(), value.clone());
+            }
+        }
+        value
+    }
+
+    fn put(&self, content_key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.write_through(content_key, |store| store.put(content_key, value))
+    }
+
+    fn delete(&self, content_key: &[u8]) -> Result<bool, String> {
+        self.write_through(content_key, |store| store.delete(content_key))
+    }
+
+    fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.store.content_keys()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.store.flush()
+    }
+
+    fn get_header(&self, content_key: &[u8]) -> Result<Option<Header>, String> {
+        let generation = {
+            let mut headers = self.headers.lock();
+            if let Some(header) = headers.get(&content_key.to_vec()) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(header.clone()));
+            }
+            self.start_fill(content_key)
+        };
+        let header = self
+            .store
+            .get(content_key)
+            .and_then(|value| value.map(|value| decode_header(&value)).transpose());
+        let mut headers = self.headers.lock();
+        if self.finish_fill(content_key, generation) {
+            if let Ok(Some(header)) = &header {
+                headers.put(content_key.to_vec(), header.clone());
+            }
+        }
+        header
+    }
+}
+
+/// Buffers writes in memory in front of another store, and writes them through in batches: once
+/// `capacity` writes are pending, on `flush`, and when the store is dropped. Reads see buffered
+/// writes before they're flushed.
+pub struct BufferedStore<S: ContentStore> {
+    store: S,
+    capacity: usize,
+    /// Content key -> value, for writes not yet flushed
+    pending: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    // Serializes flushes, so that a write is never flushed twice at once.
+    flush_lock: Mutex<()>,
+}
+
+impl<S: ContentStore> BufferedStore<S> {
+    /// Buffer up to `capacity` writes before flushing. At least one write is buffered.
+    pub fn new(store: S, capacity: usize) -> Self {
+        Self {
+            store,
+            capacity: capacity.max(1),
+            pending: Mutex::new(BTreeMap::new()),
+            flush_lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the number of writes not yet flushed.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len()
+    }
+}
+
+impl<S: ContentStore> ContentStore for BufferedStore<S> {
+    fn get(&self, content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if let Some(value) = self.pending.lock().get(content_key) {
+            return Ok(Some(value.clone()));
+        }
+        self.store.get(content_key)
+    }
+
+    fn put(&self, content_key: &[u8], value: &[u8]) -> Result<(), String> {
+        let full = {
+            let mut pending = self.pending.lock();
+            pending.insert(content_key.to_vec(), value.to_vec());
+            pending.len() >= self.capacity
+        };
+        match full {
+            true => self.flush(),
+            false => Ok(()),
+        }
+    }
+
+    fn delete(&self, content_key: &[u8]) -> Result<bool, String> {
+        // A flush in progress mustn't write the content back after it's deleted.
+        let _guard = self.flush_lock.lock();
+        let was_pending = self.pending.lock().remove(content_key).is_some();
+        Ok(self.store.delete(content_key)? || was_pending)
+    }
+
+    fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        let mut content_keys = self.store.content_keys()?;
+        content_keys.extend(self.pending.lock().keys().cloned());
+        content_keys.sort();
+        content_keys.dedup();
+        Ok(content_keys)
+    }
+
+    /// Write the pending content through. Each write stays readable from the buffer until the
+    /// store below has it, and a write overwritten during the flush stays pending.
+    fn flush(&self) -> Result<(), String> {
+        let _guard = self.flush_lock.lock();
+        let batch = self.pending.lock().clone();
+        for (content_key, value) in batch {
+            self.store.put(&content_key, &value)?;
+            let mut pending = self.pending.lock();
+            if pending.get(&content_key) == Some(&value) {
+                pending.remove(&content_key);
+            }
+        }
+        self.store.flush()
+    }
+}
+
+/// Counts the content written to another store, by content type.
+pub struct MeteredStore {
+    store: Arc<dyn ContentStore>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredStore {
+    pub fn new(store: Arc<dyn ContentStore>, metrics: Arc<Metrics>) -> Self {
+        Self { store, metrics }
+    }
+}
+
+impl ContentStore for MeteredStore {
+    fn get(&self, content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.store.get(content_key)
+    }
+
+    fn put(&self, content_key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.store.put(content_key, value)?;
+        self.metrics
+            .increment(&metrics::for_content(CONTENT_STORED, content_key));
+        Ok(())
+    }
+
+    fn delete(&self, content_key: &[u8]) -> Result<bool, String> {
+        self.store.delete(content_key)
+    }
+
+    fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.store.content_keys()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.store.flush()
+    }
+
+    fn get_header(&self, content_key: &[u8]) -> Result<Option<Header>, String> {
+        self.store.get_header(content_key)
+    }
+}
+
+/// Leading bytes of every compressed value: the stream identifier of the snappy framing format.
+/// Values stored uncompressed, like those written before compression was enabled, don't start
+/// with it, so both kinds can coexist in one store.
+const COMPRESSED_VALUE_MARKER: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+
+/// Snappy-compresses values written to another store, with `compress`, and decompresses
+/// compressed values on read whether or not it compresses writes, so that compression can be
+/// turned on and off over the life of a store.
+pub struct CompressedStore<S: ContentStore> {
+    store: S,
+    compress: bool,
+}
+
+impl<S: ContentStore> CompressedStore<S> {
+    pub fn new(store: S, compress: bool) -> Self {
+        Self { store, compress }
+    }
+}
+
+impl<S: ContentStore> ContentStore for CompressedStore<S> {
+    fn get(&self, content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self.store.get(content_key)? {
+            Some(value) if value.starts_with(COMPRESSED_VALUE_MARKER) => {
+                let mut decompressed = vec![];
+                snap::read::FrameDecoder::new(value.as_slice())
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| format!("Invalid compressed content: {}", e))?;
+                Ok(Some(decompressed))
+            }
+            value => Ok(value),
+        }
+    }
+
+    fn put(&self, content_key: &[u8], value: &[u8]) -> Result<(), String> {
+        if !self.compress {
+            return self.store.put(content_key, value);
+        }
+        let mut encoder = snap::write::FrameEncoder::new(vec![]);
+        encoder
+            .write_all(value)
+            .map_err(|e| format!("Unable to compress content: {}", e))?;
+        let compressed = encoder
+            .into_inner()
+            .map_err(|e| format!("Unable to compress content: {}", e.error()))?;
+        self.store.put(content_key, &compressed)
+    }
+
+    fn delete(&self, content_key: &[u8]) -> Result<bool, String> {
+        self.store.delete(content_key)
+    }
+
+    fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.store.content_keys()
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.store.flush()
+    }
+}
+
+impl<S: ContentStore> Drop for BufferedStore<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Failed to flush buffered content: {}", e);
+        }
+    }
+}
+
+/// Deleting at least this many content keys at once compacts the database straight away, rather
+/// than waiting for the next scheduled compaction.
+pub const LARGE_EVICTION_BATCH: usize = 1000;
+
+/// Compacts the database behind the content store, counting how long it takes and the space it
+/// reclaims.
+pub struct Compactor {
+    backend: Arc<dyn KeyValueBackend>,
+    metrics: Arc<Metrics>,
+}
+
+impl Compactor {
+    pub fn new(backend: Arc<dyn KeyValueBackend>, metrics: Arc<Metrics>) -> Self {
+        Self { backend, metrics }
+    }
+
+    /// Compact the whole database. Returns the bytes reclaimed.
+    pub fn compact(&self) -> Result<u64, String> {
+        let size_before = self.backend.size_on_disk()?;
+        let started = Instant::now();
+        self.backend.compact()?;
+        let duration = started.elapsed();
+        let reclaimed = size_before.saturating_sub(self.backend.size_on_disk()?);
+
+        self.metrics.increment(STORE_COMPACTIONS);
+        self.metrics
+            .set(STORE_COMPACTION_DURATION_MS, duration.as_millis() as u64);
+        self.metrics
+            .increment_by(STORE_COMPACTION_RECLAIMED_BYTES, reclaimed);
+        info!(
+            "Compacted the store in {}ms, reclaiming {} bytes",
+            duration.as_millis(),
+            reclaimed
+        );
+        Ok(reclaimed)
+    }
+
+    /// Compact after `deleted` content keys were removed at once, if that's a large batch.
+    pub fn after_deletes(&self, deleted: usize) -> Result<(), String> {
+        if deleted >= LARGE_EVICTION_BATCH {
+            self.compact()?;
+        }
+        Ok(())
+    }
+}
+
+/// Content-addressed store: each value is stored once, keyed by its sha256, and content keys map
+/// to that hash. A blob is reference counted, so it is only deleted when no key references it.
+pub struct ContentAddressedStore<B: KeyValueBackend> {
+    backend: B,
+    // Serializes the read-modify-write of reference counts.
+    write_lock: Mutex<()>,
+}
+
+impl<B: KeyValueBackend> ContentAddressedStore<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Check every stored value against the hash it's stored under, reporting the content keys
+    /// whose value is missing or no longer matches, as after silent disk corruption. With
+    /// `repair`, those keys are deleted so the content can be fetched again.
+    pub fn verify(&self, repair: bool) -> Result<StoreVerification, String> {
+        let content_keys = self.content_keys()?;
+        let mut corrupted = vec![];
+        for content_key in content_keys.iter() {
+            let blob_hash = match self.backend.get(&prefixed(KEY_PREFIX, content_key))? {
+                Some(blob_hash) => blob_hash,
+                None => continue,
+            };
+            let intact = match self.backend.get(&prefixed(BLOB_PREFIX, &blob_hash))? {
+                Some(value) => Sha256::digest(&value).as_slice() == blob_hash.as_slice(),
+                None => false,
+            };
+            if !intact {
+                warn!(
+                    "Stored value of content key 0x{} doesn't match its hash",
+                    hex::encode(content_key)
+                );
+                corrupted.push(content_key.clone());
+            }
+        }
+        if repair {
+            for content_key in corrupted.iter() {
+                self.delete(content_key)?;
+            }
+        }
+        Ok(StoreVerification {
+            scanned: content_keys.len(),
+            corrupted,
+            repaired: repair,
+        })
+    }
+
+    fn refcount(&self, blob_hash: &[u8]) -> Result<u64, String> {
+        match self.backend.get(&prefixed(REFCOUNT_PREFIX, blob_hash))? {
+            Some(bytes) => decode_u64(&bytes),
+            None => Ok(0),
+        }
+    }
+
+    /// Adds the writes that drop one reference to `blob_hash`, deleting the blob at zero.
+    fn release_blob(&self, blob_hash: &[u8], batch: &mut Vec<WriteOp>) -> Result<(), String> {
+        match self.refcount(blob_hash)? {
+            0 | 1 => {
+                batch.push(WriteOp::Delete(prefixed(BLOB_PREFIX, blob_hash)));
+                batch.push(WriteOp::Delete(prefixed(REFCOUNT_PREFIX, blob_hash)));
+            }
+            count => batch.push(WriteOp::Put(
+                prefixed(REFCOUNT_PREFIX, blob_hash),
+                (count - 1).to_be_bytes().to_vec(),
+            )),
+        }
+        Ok(())
+    }
+}
+
+impl<B: KeyValueBackend> ContentStore for ContentAddressedStore<B> {
+    fn get(&self, content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self.backend.get(&prefixed(KEY_PREFIX, content_key))? {
+            Some(blob_hash) => self.backend.get(&prefixed(BLOB_PREFIX, &blob_hash)),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, content_key: &[u8], value: &[u8]) -> Result<(), String> {
+        let _guard = self.write_lock.lock();
+        let blob_hash = Sha256::digest(value).to_vec();
+        let mut batch = vec![];
+
+        match self.backend.get(&prefixed(KEY_PREFIX, content_key))? {
+            Some(existing) if existing == blob_hash => return Ok(()),
+            Some(existing) => self.release_blob(&existing, &mut batch)?,
+            None => {}
+        }
+
+        let count = self.refcount(&blob_hash)?;
+        if count == 0 {
+            batch.push(WriteOp::Put(
+                prefixed(BLOB_PREFIX, &blob_hash),
+                value.to_vec(),
+            ));
+        }
+        batch.push(WriteOp::Put(
+            prefixed(REFCOUNT_PREFIX, &blob_hash),
+            (count + 1).to_be_bytes().to_vec(),
+        ));
+        batch.push(WriteOp::Put(prefixed(KEY_PREFIX, content_key), blob_hash));
+        self.backend.write(batch)
+    }
+
+    fn delete(&self, content_key: &[u8]) -> Result<bool, String> {
+        let _guard = self.write_lock.lock();
+        let blob_hash = match self.backend.get(&prefixed(KEY_PREFIX, content_key))? {
+            Some(blob_hash) => blob_hash,
+            None => return Ok(false),
+        };
+
+        let mut batch = vec![WriteOp::Delete(prefixed(KEY_PREFIX, content_key))];
+        self.release_blob(&blob_hash, &mut batch)?;
+        self.backend.write(batch)?;
+        Ok(true)
+    }
+
+    fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        Ok(self
+            .backend
+            .keys_with_prefix(KEY_PREFIX)?
+            .into_iter()
+            .map(|key| key[KEY_PREFIX.len()..].to_vec())
+            .collect())
+    }
+}
+
+/// What an integrity scan of the store found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoreVerification {
+    /// Content keys checked
+    pub scanned: usize,
+    /// Content keys whose stored value doesn't match
+    pub corrupted: Vec<Vec<u8>>,
+    /// Whether the corrupted keys were deleted
+    pub repaired: bool,
+}
+
+impl StoreVerification {
+    pub fn print(&self) {
+        for content_key in self.corrupted.iter() {
+            println!("CORRUPT 0x{}", hex::encode(content_key));
+        }
+        println!(
+            "{} content keys scanned, {} corrupted{}",
+            self.scanned,
+            self.corrupted.len(),
+            match self.repaired {
+                true => ", deleted",
+                false => "",
+            }
+        );
+    }
+}
+
+/// Which content a node is willing to store.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoragePolicy {
+    /// Content types to store, or `None` to store every type.
+    pub allowed_content_types: Option<Vec<ContentType>>,
+}
+
+impl StoragePolicy {
+    /// Store only the given content types. An empty allowlist stores every type.
+    pub fn from_allowlist(content_types: Vec<ContentType>) -> Self {
+        Self {
+            allowed_content_types: match content_types.is_empty() {
+                true => None,
+                false => Some(content_types),
+            },
+        }
+    }
+
+    /// Store block headers, and drop bodies, receipts and accumulators.
+    pub fn headers_only() -> Self {
+        Self {
+            allowed_content_types: Some(vec![ContentType::BlockHeader]),
+        }
+    }
+
+    /// Returns whether the content key is of an allowed type. Keys of unknown type are only
+    /// stored when every type is allowed.
+    pub fn should_store(&self, content_key: &[u8]) -> bool {
+        match &self.allowed_content_types {
+            Some(allowed) => ContentType::from_content_key(content_key)
+                .map_or(false, |content_type| allowed.contains(&content_type)),
+            None => true,
+        }
+    }
+
+    /// Returns, for each offered content key, whether it's allowed and not stored yet.
+    pub fn wanted_content(
+        &self,
+        store: &dyn ContentStore,
+        content_keys: &[Vec<u8>],
+    ) -> Result<Vec<bool>, String> {
+        content_keys
+            .iter()
+            .map(|content_key| {
+                Ok(self.should_store(content_key) && store.get(content_key)?.is_none())
+            })
+            .collect()
+    }
+
+    /// Delete all stored content that isn't allowed. Returns the number of deleted keys. When every
+    /// type is allowed there's nothing to delete, and the store isn't scanned.
+    pub fn prune(&self, store: &dyn ContentStore) -> Result<usize, String> {
+        if self.allowed_content_types.is_none() {
+            return Ok(0);
+        }
+        let mut pruned = 0;
+        for content_key in store.content_keys()? {
+            if !self.should_store(&content_key) && store.delete(&content_key)? {
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+/// Whose offers of content a node accepts. Content from an accepted peer is still only taken if
+/// the storage policy wants it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OfferAcceptancePolicy {
+    /// Accept offers from any peer.
+    All,
+    /// Accept offers only from these peers, like bridges that inject new content.
+    TrustedPeers(Vec<NodeId>),
+    /// Accept no offers.
+    None,
+}
+
+impl Default for OfferAcceptancePolicy {
+    fn default() -> Self {
+        OfferAcceptancePolicy::All
+    }
+}
+
+impl OfferAcceptancePolicy {
+    pub fn accepts_from(&self, peer: &NodeId) -> bool {
+        match self {
+            OfferAcceptancePolicy::All => true,
+            OfferAcceptancePolicy::TrustedPeers(trusted) => trusted.contains(peer),
+            OfferAcceptancePolicy::None => false,
+        }
+    }
+}
+
+impl FromStr for OfferAcceptancePolicy {
+    type Err = String;
+
+    /// Parse `all`, `none`, or `trusted:<node id>,<node id>...` with the node ids in hex.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => return Ok(OfferAcceptancePolicy::All),
+            "none" => return Ok(OfferAcceptancePolicy::None),
+            _ => {}
+        }
+        let node_ids = match s.strip_prefix("trusted:") {
+            Some(node_ids) => node_ids,
+            None => {
+                return Err(format!(
+                    "Expected all, none or trusted:<node ids>, got {}",
+                    s
+                ))
+            }
+        };
+        node_ids
+            .split(',')
+            .map(|node_id| {
+                let raw = hex::decode(node_id.trim_start_matches("0x"))
+                    .map_err(|e| format!("Invalid trusted node id {}: {}", node_id, e))?;
+                NodeId::parse(&raw)
+                    .map_err(|e| format!("Invalid trusted node id {}: {}", node_id, e))
+            })
+            .collect::<Result<Vec<NodeId>, String>>()
+            .map(OfferAcceptancePolicy::TrustedPeers)
+    }
+}
+
+/// Index of canonical block hashes by block number.
+pub struct CanonicalIndex {
+    backend: Arc<dyn KeyValueBackend>,
+    write_lock: Mutex<()>,
+}
+
+impl CanonicalIndex {
+    pub fn new(backend: Arc<dyn KeyValueBackend>) -> Self {
+        Self {
+            backend,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn insert(&self, block_number: u64, block_hash: H256) -> Result<(), String> {
+        let _guard = self.write_lock.lock();
+        let mut batch = vec![WriteOp::Put(
+            prefixed(CANONICAL_PREFIX, &block_number.to_be_bytes()),
+            block_hash.as_bytes().to_vec(),
+        )];
+        match self.highest_block_number()? {
+            Some(head) if head >= block_number => {}
+            _ => batch.push(WriteOp::Put(
+                CANONICAL_HEAD_KEY.to_vec(),
+                block_number.to_be_bytes().to_vec(),
+            )),
+        }
+        self.backend.write(batch)
+    }
+
+    pub fn block_hash(&self, block_number: u64) -> Result<Option<H256>, String> {
+        Ok(self
+            .backend
+            .get(&prefixed(CANONICAL_PREFIX, &block_number.to_be_bytes()))?
+            .map(|hash| H256::from_slice(&hash)))
+    }
+
+    /// Returns the highest indexed block number, or `None` if nothing is indexed yet.
+    pub fn highest_block_number(&self) -> Result<Option<u64>, String> {
+        match self.backend.get(CANONICAL_HEAD_KEY)? {
+            Some(bytes) => Ok(Some(decode_u64(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// What a schema migration changed, or would change in a dry run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Migration {
+    pub from_version: u64,
+    pub to_version: u64,
+    /// Content keys moved into the new layout
+    pub migrated_keys: usize,
+}
+
+/// Upgrade the database to the current layout, and mark it with the schema version. A database
+/// without a marker is taken to be version 1 if it holds any content outside the current layout,
+/// and current otherwise. A database marked current is left alone without scanning its keys. In a
+/// dry run, nothing is written. Each key is moved on its own, so an interrupted migration picks up
+/// where it left off when run again.
+pub fn migrate(backend: Arc<dyn KeyValueBackend>, dry_run: bool) -> Result<Migration, String> {
+    let marked_version = match backend.get(SCHEMA_VERSION_KEY)? {
+        Some(bytes) => Some(decode_u64(&bytes)?),
+        None => None,
+    };
+    match marked_version {
+        Some(version) if version > SCHEMA_VERSION => {
+            return Err(format!(
+                "Database schema version {} is newer than this version of trin supports ({})",
+                version, SCHEMA_VERSION
+            ));
+        }
+        Some(SCHEMA_VERSION) => {
+            return Ok(Migration {
+                from_version: SCHEMA_VERSION,
+                to_version: SCHEMA_VERSION,
+                migrated_keys: 0,
+            });
+        }
+        _ => {}
+    }
+
+    let legacy_keys = backend
+        .keys_with_prefix(&[])?
+        .into_iter()
+        .filter(|key| is_legacy_key(key))
+        .collect::<Vec<_>>();
+    let from_version = match marked_version {
+        Some(version) => version,
+        None if legacy_keys.is_empty() => SCHEMA_VERSION,
+        None => 1,
+    };
+    let migration = Migration {
+        from_version,
+        to_version: SCHEMA_VERSION,
+        migrated_keys: match from_version {
+            1 => legacy_keys.len(),
+            _ => 0,
+        },
+    };
+    if from_version == SCHEMA_VERSION {
+        if !dry_run {
+            backend.write(vec![WriteOp::Put(
+                SCHEMA_VERSION_KEY.to_vec(),
+                SCHEMA_VERSION.to_be_bytes().to_vec(),
+            )])?;
+        }
+        return Ok(migration);
+    }
+    if dry_run {
+        info!(
+            "Dry run: would migrate the store from schema v{} to v{}, moving {} content keys",
+            from_version, SCHEMA_VERSION, migration.migrated_keys
+        );
+        return Ok(migration);
+    }
+
+    info!(
+        "Migrating the store from schema v{} to v{}, moving {} content keys",
+        from_version, SCHEMA_VERSION, migration.migrated_keys
+    );
+    let store = ContentAddressedStore::new(backend.clone());
+    for key in legacy_keys {
+        if let Some(value) = backend.get(&key)? {
+            store.put(&key, &value)?;
+        }
+        backend.write(vec![WriteOp::Delete(key)])?;
+    }
+    backend.write(vec![WriteOp::Put(
+        SCHEMA_VERSION_KEY.to_vec(),
+        SCHEMA_VERSION.to_be_bytes().to_vec(),
+    )])?;
+    info!("Migrated the store to schema v{}", SCHEMA_VERSION);
+    Ok(migration)
+}
+
+/// Returns whether the key is outside the current layout: a version 1 content key, which starts
+/// with a content type selector rather than one of the layout's prefixes.
+fn is_legacy_key(key: &[u8]) -> bool {
+    key != SCHEMA_VERSION_KEY
+        && key != CANONICAL_HEAD_KEY
+        && ![KEY_PREFIX, BLOB_PREFIX, REFCOUNT_PREFIX, CANONICAL_PREFIX]
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+}
+
+fn decode_u64(bytes: &[u8]) -> Result<u64, String> {
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| format!("Invalid u64 length: {}", bytes.len()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn prefixed(prefix: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut prefixed_key = prefix.to_vec();
+    prefixed_key.extend_from_slice(key);
+    prefixed_key
+}
+
+/// Behaviour every `ContentStore` must share, run against each implementation.
+#[cfg(test)]
+pub(crate) mod test_suite {
+    use super::ContentStore;
+
+    pub fn run(store: &dyn ContentStore) {
+        get_missing_key(store);
+        put_then_get(store);
+        overwrite(store);
+        delete(store);
+        content_keys(store);
+    }
+
+    fn get_missing_key(store: &dyn ContentStore) {
+        assert_eq!(store.get(b"missing").unwrap(), None);
+        assert!(!store.delete(b"missing").unwrap());
+    }
+
+    fn put_then_get(store: &dyn ContentStore) {
+        store.put(b"put", b"value").unwrap();
+        assert_eq!(store.get(b"put").unwrap(), Some(b"value".to_vec()));
+        store.delete(b"put").unwrap();
+    }
+
+    fn overwrite(store: &dyn ContentStore) {
+        store.put(b"overwrite", b"old").unwrap();
+        store.put(b"overwrite", b"new").unwrap();
+        assert_eq!(store.get(b"overwrite").unwrap(), Some(b"new".to_vec()));
+        store.delete(b"overwrite").unwrap();
+    }
+
+    fn delete(store: &dyn ContentStore) {
+        store.put(b"delete", b"value").unwrap();
+        assert!(store.delete(b"delete").unwrap());
+        assert_eq!(store.get(b"delete").unwrap(), None);
+        assert!(!store.delete(b"delete").unwrap());
+    }
+
+    fn content_keys(store: &dyn ContentStore) {
+        assert!(store.content_keys().unwrap().is_empty());
+        store.put(b"two", b"value").unwrap();
+        store.put(b"one", b"value").unwrap();
+        assert_eq!(
+            store.content_keys().unwrap(),
+            vec![b"one".to_vec(), b"two".to_vec()]
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_content_addressed_store_passes_store_suite() {
+        test_suite::run(&ContentAddressedStore::new(MemoryBackend::default()));
+    }
+
+    #[test]
+    fn test_parse_storage_backend() {
+        assert_eq!("sqlite".parse(), Ok(StorageBackend::Sqlite));
+        assert_eq!("rocksdb".parse(), Ok(StorageBackend::Rocksdb));
+        assert!("leveldb".parse::<StorageBackend>().is_err());
+    }
+
+    #[test]
+    fn test_parse_offer_acceptance_policy() {
+        let bridge = NodeId::new(&[7; 32]);
+        assert_eq!("all".parse(), Ok(OfferAcceptancePolicy::All));
+        assert_eq!("none".parse(), Ok(OfferAcceptancePolicy::None));
+        assert_eq!(
+            format!("trusted:0x{}", hex::encode(bridge.raw())).parse(),
+            Ok(OfferAcceptancePolicy::TrustedPeers(vec![bridge]))
+        );
+        assert!("trusted:0x0102".parse::<OfferAcceptancePolicy>().is_err());
+        assert!("some".parse::<OfferAcceptancePolicy>().is_err());
+    }
+
+    fn blob_count(store: &ContentAddressedStore<MemoryBackend>) -> usize {
+        store.backend().keys_with_prefix(BLOB_PREFIX).unwrap().len()
+    }
+
+    #[test]
+    fn test_shared_blob_is_stored_once() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        store.put(b"header-by-hash", b"header").unwrap();
+        store.put(b"header-by-number", b"header").unwrap();
+
+        assert_eq!(blob_count(&store), 1);
+        assert_eq!(
+            store.get(b"header-by-hash").unwrap(),
+            Some(b"header".to_vec())
+        );
+        assert_eq!(
+            store.get(b"header-by-number").unwrap(),
+            Some(b"header".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_deleting_one_key_retains_shared_blob() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        store.put(b"header-by-hash", b"header").unwrap();
+        store.put(b"header-by-number", b"header").unwrap();
+
+        assert!(store.delete(b"header-by-hash").unwrap());
+        assert_eq!(store.get(b"header-by-hash").unwrap(), None);
+        assert_eq!(
+            store.get(b"header-by-number").unwrap(),
+            Some(b"header".to_vec())
+        );
+        assert_eq!(blob_count(&store), 1);
+
+        assert!(store.delete(b"header-by-number").unwrap());
+        assert_eq!(blob_count(&store), 0);
+    }
+
+    #[test]
+    fn test_overwriting_key_releases_old_blob() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        store.put(b"key", b"old").unwrap();
+        store.put(b"key", b"new").unwrap();
+
+        assert_eq!(store.get(b"key").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(blob_count(&store), 1);
+    }
+
+    #[test]
+    fn test_repeated_put_does_not_leak_references() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        store.put(b"key", b"value").unwrap();
+        store.put(b"key", b"value").unwrap();
+
+        assert!(store.delete(b"key").unwrap());
+        assert_eq!(blob_count(&store), 0);
+    }
+
+    #[test]
+    fn test_delete_missing_key() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        assert!(!store.delete(b"missing").unwrap());
+    }
+
+    #[test]
+    fn test_second_get_is_served_from_cache() {
+        let store = CachedStore::new(ContentAddressedStore::new(MemoryBackend::default()), 8);
+        store.put(&[1], &[2, 3]).unwrap();
+
+        assert_eq!(store.get(&[1]).unwrap(), Some(vec![2, 3]));
+        assert_eq!(store.hits(), 0);
+        assert_eq!(store.get(&[1]).unwrap(), Some(vec![2, 3]));
+        assert_eq!(store.hits(), 1);
+
+        store.put(&[1], &[4]).unwrap();
+        assert_eq!(store.get(&[1]).unwrap(), Some(vec![4]));
+        assert_eq!(store.hits(), 1);
+    }
+
+    /// Pauses a read after it has read the value, until the test resumes it.
+    struct PausingStore {
+        store: ContentAddressedStore<MemoryBackend>,
+        paused: std::sync::Barrier,
+        resumed: std::sync::Barrier,
+    }
+
+    impl ContentStore for PausingStore {
+        fn get(&self, content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            let value = self.store.get(content_key)?;
+            self.paused.wait();
+            self.resumed.wait();
+            Ok(value)
+        }
+
+        fn put(&self, content_key: &[u8], value: &[u8]) -> Result<(), String> {
+            self.store.put(content_key, value)
+        }
+
+        fn delete(&self, content_key: &[u8]) -> Result<bool, String> {
+            self.store.delete(content_key)
+        }
+
+        fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+            self.store.content_keys()
+        }
+    }
+
+    #[test]
+    fn test_delete_during_a_missed_get_leaves_nothing_cached() {
+        let store = Arc::new(CachedStore::new(
+            PausingStore {
+                store: ContentAddressedStore::new(MemoryBackend::default()),
+                paused: std::sync::Barrier::new(2),
+                resumed: std::sync::Barrier::new(2),
+            },
+            8,
+        ));
+        store.put(&[1], &[2]).unwrap();
+
+        let reader = {
+            let store = store.clone();
+            std::thread::spawn(move || store.get(&[1]).unwrap())
+        };
+        // The reader has read the value and not cached it yet. It holds no cache lock meanwhile,
+        // or the delete would wait for it forever.
+        store.store.paused.wait();
+        assert!(store.delete(&[1]).unwrap());
+        store.store.resumed.wait();
+        assert_eq!(reader.join().unwrap(), Some(vec![2]));
+
+        assert_eq!(store.store.store.get(&[1]).unwrap(), None);
+        assert_eq!(store.values.lock().peek(&vec![1]), None);
+        assert!(store.fills.lock().is_empty());
+    }
+
+    #[test]
+    fn test_buffered_store_passes_store_suite() {
+        test_suite::run(&BufferedStore::new(
+            ContentAddressedStore::new(MemoryBackend::default()),
+            4,
+        ));
+    }
+
+    #[test]
+    fn test_buffered_writes_are_readable_and_flushed_on_shutdown() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = BufferedStore::new(ContentAddressedStore::new(backend.clone()), 8);
+        let database = ContentAddressedStore::new(backend.clone());
+        for i in 0..3u8 {
+            store.put(&[i], &[i; 4]).unwrap();
+        }
+
+        assert_eq!(store.pending(), 3);
+        assert_eq!(store.get(&[1]).unwrap(), Some(vec![1; 4]));
+        assert_eq!(store.content_keys().unwrap().len(), 3);
+        assert_eq!(database.get(&[1]).unwrap(), None);
+
+        drop(store);
+        for i in 0..3u8 {
+            assert_eq!(database.get(&[i]).unwrap(), Some(vec![i; 4]));
+        }
+    }
+
+    #[test]
+    fn test_full_write_buffer_is_flushed() {
+        let backend = Arc::new(MemoryBackend::default());
+        let store = BufferedStore::new(ContentAddressedStore::new(backend.clone()), 2);
+        let database = ContentAddressedStore::new(backend);
+
+        store.put(&[1], &[1]).unwrap();
+        assert_eq!(database.get(&[1]).unwrap(), None);
+        store.put(&[2], &[2]).unwrap();
+        assert_eq!(store.pending(), 0);
+        assert_eq!(database.get(&[1]).unwrap(), Some(vec![1]));
+
+        store.put(&[3], &[3]).unwrap();
+        assert!(store.delete(&[3]).unwrap());
+        store.flush().unwrap();
+        assert_eq!(database.get(&[3]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decoded_header_is_cached() {
+        let store = CachedStore::new(ContentAddressedStore::new(MemoryBackend::default()), 8);
+        let header = Header {
+            number: 7,
+            mix_hash: Some(H256::repeat_byte(1)),
+            nonce: Some(Default::default()),
+            ..Default::default()
+        };
+        store.put(&[0, 7], &rlp::encode(&header)).unwrap();
+
+        assert_eq!(store.get_header(&[0, 7]).unwrap(), Some(header.clone()));
+        assert_eq!(store.get_header(&[0, 7]).unwrap(), Some(header));
+        assert_eq!(store.hits(), 1);
+        assert_eq!(store.get_header(&[0, 8]).unwrap(), None);
+
+        store.delete(&[0, 7]).unwrap();
+        assert_eq!(store.get_header(&[0, 7]).unwrap(), None);
+    }
+
+    /// A store that can't list its keys, to show a scan doesn't happen.
+    struct UnscannableStore;
+
+    impl ContentStore for UnscannableStore {
+        fn get(&self, _content_key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            Ok(None)
+        }
+
+        fn put(&self, _content_key: &[u8], _value: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn delete(&self, _content_key: &[u8]) -> Result<bool, String> {
+            Ok(false)
+        }
+
+        fn content_keys(&self) -> Result<Vec<Vec<u8>>, String> {
+            Err("Scanned every key".to_string())
+        }
+    }
+
+    #[test]
+    fn test_policy_allowing_every_type_prunes_without_scanning() {
+        assert_eq!(StoragePolicy::default().prune(&UnscannableStore), Ok(0));
+        assert!(StoragePolicy::headers_only()
+            .prune(&UnscannableStore)
+            .is_err());
+    }
+
+    #[test]
+    fn test_headers_only_policy_rejects_and_prunes_bodies() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        let header_key = vec![ContentType::BlockHeader.selector(), 1];
+        let body_key = vec![ContentType::BlockBody.selector(), 1];
+        let receipts_key = vec![ContentType::Receipts.selector(), 1];
+        store.put(&header_key, b"header").unwrap();
+        store.put(&body_key, b"body").unwrap();
+        store.put(&receipts_key, b"receipts").unwrap();
+
+        let policy = StoragePolicy::headers_only();
+        assert_eq!(policy.prune(&store).unwrap(), 2);
+        assert_eq!(store.content_keys().unwrap(), vec![header_key.clone()]);
+        assert_eq!(store.get(&body_key).unwrap(), None);
+
+        let offered = vec![
+            vec![ContentType::BlockBody.selector(), 2],
+            vec![ContentType::BlockHeader.selector(), 2],
+            header_key,
+        ];
+        assert_eq!(
+            policy.wanted_content(&store, &offered).unwrap(),
+            vec![false, true, false]
+        );
+    }
+
+    #[test]
+    fn test_default_policy_stores_everything() {
+        let policy = StoragePolicy::default();
+        assert!(policy.should_store(&[ContentType::BlockBody.selector()]));
+        assert!(policy.should_store(&[0xff]));
+        assert!(!StoragePolicy::headers_only().should_store(&[0xff]));
+    }
+
+    #[test]
+    fn test_canonical_index_tracks_highest_block() {
+        let index = CanonicalIndex::new(Arc::new(MemoryBackend::default()));
+        assert_eq!(index.highest_block_number().unwrap(), None);
+
+        for block_number in (0..=10).rev() {
+            index
+                .insert(block_number, H256::from_low_u64_be(block_number))
+                .unwrap();
+        }
+        assert_eq!(index.highest_block_number().unwrap(), Some(10));
+        assert_eq!(index.block_hash(3).unwrap(), Some(H256::from_low_u64_be(3)));
+        assert_eq!(index.block_hash(11).unwrap(), None);
+    }
+
+    #[test]
+    fn test_content_keys() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        store.put(b"one", b"value").unwrap();
+        store.put(b"two", b"value").unwrap();
+        assert_eq!(
+            store.content_keys().unwrap(),
+            vec![b"one".to_vec(), b"two".to_vec()]
+        );
+    }
+
+    /// A version 1 database: content values stored straight under their content keys, two of
+    /// them sharing a value.
+    fn v1_fixture() -> (Arc<dyn KeyValueBackend>, Vec<(Vec<u8>, Vec<u8>)>) {
+        let content = vec![
+            (vec![0x00, 0x00, 0x01, 0xaa], b"header".to_vec()),
+            (vec![0x01, 0x00, 0x01, 0xaa], b"body".to_vec()),
+            (vec![0x01, 0x00, 0x01, 0xbb], b"body".to_vec()),
+        ];
+        let backend: Arc<dyn KeyValueBackend> = Arc::new(MemoryBackend::default());
+        backend
+            .write(
+                content
+                    .iter()
+                    .map(|(key, value)| WriteOp::Put(key.clone(), value.clone()))
+                    .collect(),
+            )
+            .unwrap();
+        (backend, content)
+    }
+
+    #[test]
+    fn test_v1_layout_is_migrated_to_current_schema() {
+        let (backend, content) = v1_fixture();
+        let expected = Migration {
+            from_version: 1,
+            to_version: SCHEMA_VERSION,
+            migrated_keys: 3,
+        };
+
+        // A dry run reports the migration without touching the data.
+        assert_eq!(migrate(backend.clone(), true), Ok(expected.clone()));
+        assert_eq!(backend.get(SCHEMA_VERSION_KEY), Ok(None));
+        assert_eq!(backend.get(&content[0].0), Ok(Some(content[0].1.clone())));
+
+        assert_eq!(migrate(backend.clone(), false), Ok(expected));
+        let store = ContentAddressedStore::new(backend.clone());
+        for (key, value) in &content {
+            assert_eq!(store.get(key), Ok(Some(value.clone())));
+            assert_eq!(backend.get(key), Ok(None));
+        }
+        assert_eq!(store.content_keys().unwrap().len(), 3);
+        assert_eq!(
+            backend.keys_with_prefix(BLOB_PREFIX).unwrap().len(),
+            2,
+            "the shared body is stored once"
+        );
+
+        // Once migrated, the database is marked current and left alone.
+        assert_eq!(
+            migrate(backend.clone(), false),
+            Ok(Migration {
+                from_version: SCHEMA_VERSION,
+                to_version: SCHEMA_VERSION,
+                migrated_keys: 0,
+            })
+        );
+        assert_eq!(store.content_keys().unwrap().len(), 3);
+    }
+
+    /// Refuses to list every key, to show a full scan doesn't happen.
+    struct UnscannableBackend(MemoryBackend);
+
+    impl KeyValueBackend for UnscannableBackend {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            self.0.get(key)
+        }
+
+        fn write(&self, batch: Vec<WriteOp>) -> Result<(), String> {
+            self.0.write(batch)
+        }
+
+        fn keys_with_prefix(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+            match prefix.is_empty() {
+                true => Err("Scanned every key".to_string()),
+                false => self.0.keys_with_prefix(prefix),
+            }
+        }
+    }
+
+    #[test]
+    fn test_current_schema_is_not_scanned() {
+        let backend: Arc<dyn KeyValueBackend> =
+            Arc::new(UnscannableBackend(MemoryBackend::default()));
+        assert!(migrate(backend.clone(), false).is_err());
+
+        backend
+            .write(vec![WriteOp::Put(
+                SCHEMA_VERSION_KEY.to_vec(),
+                SCHEMA_VERSION.to_be_bytes().to_vec(),
+            )])
+            .unwrap();
+        assert_eq!(
+            migrate(backend, false),
+            Ok(Migration {
+                from_version: SCHEMA_VERSION,
+                to_version: SCHEMA_VERSION,
+                migrated_keys: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_newer_schema_is_refused() {
+        let backend: Arc<dyn KeyValueBackend> = Arc::new(MemoryBackend::default());
+        backend
+            .write(vec![WriteOp::Put(
+                SCHEMA_VERSION_KEY.to_vec(),
+                (SCHEMA_VERSION + 1).to_be_bytes().to_vec(),
+            )])
+            .unwrap();
+        assert!(migrate(backend, false).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_value_is_detected_and_repaired() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        store.put(b"intact", b"header").unwrap();
+        store.put(b"rotten", b"body").unwrap();
+        let blob_hash = Sha256::digest(b"body").to_vec();
+        store
+            .backend()
+            .write(vec![WriteOp::Put(
+                prefixed(BLOB_PREFIX, &blob_hash),
+                b"bodz".to_vec(),
+            )])
+            .unwrap();
+
+        // Without repair, the corruption is only reported.
+        let expected = StoreVerification {
+            scanned: 2,
+            corrupted: vec![b"rotten".to_vec()],
+            repaired: false,
+        };
+        assert_eq!(store.verify(false), Ok(expected.clone()));
+        assert_eq!(store.get(b"rotten"), Ok(Some(b"bodz".to_vec())));
+
+        assert_eq!(
+            store.verify(true),
+            Ok(StoreVerification {
+                repaired: true,
+                ..expected
+            })
+        );
+        assert_eq!(store.get(b"rotten"), Ok(None));
+        assert_eq!(store.get(b"intact"), Ok(Some(b"header".to_vec())));
+        assert_eq!(blob_count(&store), 1);
+        assert_eq!(
+            store.verify(false).unwrap().corrupted,
+            Vec::<Vec<u8>>::new()
+        );
+    }
+
+    #[test]
+    fn test_compressed_and_uncompressed_values_read_back() {
+        let backend: Arc<dyn KeyValueBackend> = Arc::new(MemoryBackend::default());
+        let raw = ContentAddressedStore::new(backend.clone());
+        let legacy_value = b"written before compression was enabled".to_vec();
+        raw.put(b"legacy", &legacy_value).unwrap();
+
+        let store = CompressedStore::new(ContentAddressedStore::new(backend), true);
+        let body = vec![0xc0; 4096];
+        store.put(b"body", &body).unwrap();
+        assert_eq!(store.get(b"body"), Ok(Some(body.clone())));
+        assert_eq!(store.get(b"legacy"), Ok(Some(legacy_value)));
+        assert_eq!(store.get(b"missing"), Ok(None));
+
+        let stored = raw.get(b"body").unwrap().unwrap();
+        assert!(stored.starts_with(COMPRESSED_VALUE_MARKER));
+        assert!(stored.len() < body.len() / 10);
+
+        // With compression turned back off, compressed values still read.
+        let store = CompressedStore::new(raw, false);
+        assert_eq!(store.get(b"body"), Ok(Some(body)));
+    }
+
+    #[test]
+    fn test_content_closest_to_orders_held_content_by_distance() {
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        for i in 0..20u32 {
+            store.put(&i.to_be_bytes(), b"held").unwrap();
+        }
+        let node_id = NodeId::new(&[0x5a; 32]);
+
+        // By XOR distance of the keys' sha256 content ids; by plain difference the third closest
+        // would be key 9.
+        let expected: Vec<Vec<u8>> = vec![16u32, 2, 14, 4, 8]
+            .into_iter()
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        assert_eq!(store.content_closest_to(&node_id, 5).unwrap(), expected);
+        let all = store.content_closest_to(&node_id, 100).unwrap();
+        assert_eq!(all.len(), 20);
+        assert_eq!(all[..5].to_vec(), expected);
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use super::discovery::Subnetwork;
+use super::storage::StorageBackend;
+use super::{Enr, U256};
+use crate::utils::node_id_hex;
+
+/// Snapshot of the running node's configuration, logged once its services are up.
+pub struct StartupSummary {
+    pub enr: Enr,
+    pub data_dir: String,
+    /// Local sockets discv5 listens on
+    pub listen_addresses: Vec<SocketAddr>,
+    /// How json-rpc is served, like `http port 8545`
+    pub json_rpc: String,
+    pub subnetworks: Vec<Subnetwork>,
+    pub storage_backend: StorageBackend,
+    pub store_cache_capacity: usize,
+    pub write_buffer_size: usize,
+    pub compress_content: bool,
+    pub max_peers: Option<usize>,
+    pub data_radius: U256,
+}
+
+impl fmt::Display for StartupSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Node id:      {}", node_id_hex(&self.enr.node_id()))?;
+        writeln!(f, "ENR:          {}", self.enr.to_base64())?;
+        writeln!(f, "Data dir:     {}", self.data_dir)?;
+        for address in self.listen_addresses.iter() {
+            let family = match address {
+                SocketAddr::V4(_) => "IPv4",
+                SocketAddr::V6(_) => "IPv6",
+            };
+            writeln!(f, "Listening on: {} ({})", address, family)?;
+        }
+        writeln!(f, "JSON-RPC:     {}", self.json_rpc)?;
+        let subnetworks: Vec<String> = self
+            .subnetworks
+            .iter()
+            .map(|subnetwork| format!("{:?}", subnetwork).to_lowercase())
+            .collect();
+        writeln!(f, "Subnetworks:  {}", subnetworks.join(", "))?;
+        writeln!(
+            f,
+            "Storage:      {:?}, caching {} values, buffering {} writes, {}",
+            self.storage_backend,
+            self.store_cache_capacity,
+            self.write_buffer_size,
+            match self.compress_content {
+                true => "compressed",
+                false => "uncompressed",
+            }
+        )?;
+        match self.max_peers {
+            Some(max_peers) => writeln!(f, "Max peers:    {}", max_peers)?,
+            None => writeln!(f, "Max peers:    unlimited")?,
+        }
+        write!(f, "Data radius:  0x{:x}", self.data_radius)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use discv5::enr::{CombinedKey, EnrBuilder};
+
+    #[test]
+    fn test_summary_names_the_node_and_its_subnetworks() {
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![7; 32].as_mut_slice()).unwrap();
+        let mut builder = EnrBuilder::new("v4");
+        builder.ip("127.0.0.1".parse().unwrap());
+        builder.udp(9007);
+        let enr: Enr = builder.build(&enr_key).unwrap();
+        let summary = StartupSummary {
+            enr: enr.clone(),
+            data_dir: "/tmp/trin".to_string(),
+            listen_addresses: vec![
+                "0.0.0.0:9001".parse().unwrap(),
+                "[::]:9001".parse().unwrap(),
+            ],
+            json_rpc: "http port 8555".to_string(),
+            subnetworks: vec![Subnetwork::History, Subnetwork::State],
+            storage_backend: StorageBackend::Sqlite,
+            store_cache_capacity: 1024,
+            write_buffer_size: 0,
+            compress_content: true,
+            max_peers: None,
+            data_radius: U256::max_value(),
+        }
+        .to_string();
+
+        assert!(summary.contains(&node_id_hex(&enr.node_id())));
+        assert!(summary.contains(&enr.to_base64()));
+        assert!(summary.contains("Subnetworks:  history, state"));
+        assert!(summary.contains("Listening on: 0.0.0.0:9001 (IPv4)"));
+        assert!(summary.contains("Listening on: [::]:9001 (IPv6)"));
+        assert!(summary.contains("Sqlite"));
+        assert!(summary.ends_with(&format!("0x{}", "f".repeat(64))));
+    }
+}
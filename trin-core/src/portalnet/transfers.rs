@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use super::storage::ContentStore;
+use super::types::describe_content_key;
+use super::validation::ContentValidator;
+
+/// Snapshot of an in-flight content transfer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferProgress {
+    pub id: u64,
+    pub content_key: Vec<u8>,
+    pub transferred: u64,
+    pub total: u64,
+}
+
+impl TransferProgress {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "contentKey": format!("0x{}", hex::encode(&self.content_key)),
+            "content": describe_content_key(&self.content_key),
+            "transferred": self.transferred,
+            "total": self.total,
+        })
+    }
+}
+
+/// Emitted every time a transfer moves forward.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferEvent {
+    /// Bytes moved by this step of the transfer
+    pub bytes: u64,
+    pub progress: TransferProgress,
+}
+
+/// Why an inbound transfer was aborted. Partial content is never stored.
+#[derive(Debug, PartialEq)]
+pub enum TransferError {
+    /// No packet arrived within the stall timeout.
+    TimedOut,
+    /// The sender went away before the content was complete.
+    Closed,
+    /// The sender sent more bytes than announced.
+    Oversized,
+    /// The content failed validation for its content key.
+    Invalid(String),
+    /// As many transfers as allowed are already in flight. The transfer can be retried once one
+    /// of them finishes.
+    AtCapacity,
+    Store(String),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::TimedOut => write!(f, "transfer stalled"),
+            TransferError::Closed => write!(f, "transfer closed before completion"),
+            TransferError::Oversized => write!(f, "transfer exceeded its announced size"),
+            TransferError::Invalid(e) => write!(f, "transferred content is invalid: {}", e),
+            TransferError::AtCapacity => write!(f, "too many transfers in flight, retry later"),
+            TransferError::Store(e) => write!(f, "failed to store transfer: {}", e),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Most transfers in flight at once, inbound and outbound together
+    max_active: Option<usize>,
+    next_id: AtomicU64,
+    active: RwLock<BTreeMap<u64, TransferProgress>>,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<TransferEvent>>>,
+}
+
+/// Tracks in-flight content transfers and reports their progress to subscribers.
+#[derive(Clone, Default)]
+pub struct TransferRegistry {
+    inner: Arc<Inner>,
+}
+
+impl TransferRegistry {
+    /// A registry allowing at most `max_active` transfers in flight at once, or any number
+    /// without a cap.
+    pub fn new(max_active: Option<usize>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                max_active,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Register a new transfer. It stays active until the returned handle is dropped. Errs if
+    /// the registry is at its cap.
+    pub fn start(&self, content_key: Vec<u8>, total: u64) -> Result<Transfer, TransferError> {
+        let mut active = self.inner.active.write();
+        if let Some(max_active) = self.inner.max_active {
+            if active.len() >= max_active {
+                return Err(TransferError::AtCapacity);
+            }
+        }
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        active.insert(
+            id,
+            TransferProgress {
+                id,
+                content_key,
+                transferred: 0,
+                total,
+            },
+        );
+        Ok(Transfer {
+            id,
+            registry: self.clone(),
+        })
+    }
+
+    /// Receive an event for every step of every transfer started after subscribing.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<TransferEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.inner.subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Buffer the content arriving in `packets`, and commit it to the store once all `total`
+    /// bytes arrived and the content passed validation. The transfer is aborted, and its buffer
+    /// discarded, if no packet arrives within `stall_timeout`. It's refused outright if the
+    /// registry is at its cap.
+    pub async fn receive(
+        &self,
+        content_key: Vec<u8>,
+        total: u64,
+        packets: &mut mpsc::UnboundedReceiver<Vec<u8>>,
+        stall_timeout: Duration,
+        store: &dyn ContentStore,
+        validator: &dyn ContentValidator,
+    ) -> Result<(), TransferError> {
+        let transfer = self.start(content_key.clone(), total)?;
+        let mut buffer = Vec::new();
+        while (buffer.len() as u64) < total {
+            let packet = match timeout(stall_timeout, packets.recv()).await {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Err(TransferError::Closed),
+                Err(_) => return Err(TransferError::TimedOut),
+            };
+            if (buffer.len() + packet.len()) as u64 > total {
+                return Err(TransferError::Oversized);
+            }
+            transfer.advance(packet.len() as u64);
+            buffer.extend_from_slice(&packet);
+        }
+        validator
+            .validate(&content_key, &buffer)
+            .map_err(TransferError::Invalid)?;
+        store
+            .put(&content_key, &buffer)
+            .map_err(TransferError::Store)
+    }
+
+    pub fn active(&self) -> Vec<TransferProgress> {
+        self.inner.active.read().values().cloned().collect()
+    }
+
+    fn advance(&self, id: u64, bytes: u64) {
+        let progress = {
+            let mut active = self.inner.active.write();
+            let progress = match active.get_mut(&id) {
+                Some(progress) => progress,
+                None => return,
+            };
+            progress.transferred = progress.transferred.saturating_add(bytes);
+            progress.clone()
+        };
+        let event = TransferEvent { bytes, progress };
+        self.inner
+            .subscribers
+            .lock()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn finish(&self, id: u64) {
+        self.inner.active.write().remove(&id);
+    }
+}
+
+/// Handle to an in-flight transfer, used to report progress as packets are sent or received.
+pub struct Transfer {
+    id: u64,
+    registry: TransferRegistry,
+}
+
+impl Transfer {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        self.registry.advance(self.id, bytes);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.registry.finish(self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::portalnet::storage::{ContentAddressedStore, MemoryBackend};
+    use crate::portalnet::types::ContentType;
+    use crate::portalnet::validation::{ContentSizeLimits, ValidatorRegistry};
+    use crate::types::rlp_limits::RlpLimits;
+
+    #[tokio::test]
+    async fn test_stalled_transfer_is_discarded() {
+        let registry = TransferRegistry::default();
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        let (packets_tx, mut packets_rx) = mpsc::unbounded_channel();
+        packets_tx.send(vec![0xab; 1000]).unwrap();
+
+        let result = registry
+            .receive(
+                vec![1, 2],
+                2500,
+                &mut packets_rx,
+                Duration::from_millis(50),
+                &store,
+                &ValidatorRegistry::new(),
+            )
+            .await;
+
+        assert_eq!(result, Err(TransferError::TimedOut));
+        assert!(registry.active().is_empty());
+        assert_eq!(store.get(&[1, 2]).unwrap(), None);
+        assert!(store.content_keys().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_complete_transfer_is_stored() {
+        let registry = TransferRegistry::default();
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        let (packets_tx, mut packets_rx) = mpsc::unbounded_channel();
+        packets_tx.send(vec![1; 3]).unwrap();
+        packets_tx.send(vec![2; 2]).unwrap();
+
+        registry
+            .receive(
+                vec![1, 2],
+                5,
+                &mut packets_rx,
+                Duration::from_secs(5),
+                &store,
+                &ValidatorRegistry::new(),
+            )
+            .await
+            .unwrap();
+
+        assert!(registry.active().is_empty());
+        assert_eq!(store.get(&[1, 2]).unwrap(), Some(vec![1, 1, 1, 2, 2]));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_transfer_is_not_stored() {
+        let registry = TransferRegistry::default();
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        let (packets_tx, mut packets_rx) = mpsc::unbounded_channel();
+        packets_tx.send(vec![0xff]).unwrap();
+        let header_key = vec![ContentType::BlockHeader.selector(); 35];
+
+        let result = registry
+            .receive(
+                header_key.clone(),
+                1,
+                &mut packets_rx,
+                Duration::from_secs(5),
+                &store,
+                &ValidatorRegistry::history(RlpLimits::default(), ContentSizeLimits::default()),
+            )
+            .await;
+
+        assert!(matches!(result, Err(TransferError::Invalid(_))));
+        assert_eq!(store.get(&header_key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_multi_packet_transfer_progress_sums_to_total() {
+        let registry = TransferRegistry::default();
+        let mut events = registry.subscribe();
+        let payload = vec![0xab; 2500];
+
+        let transfer = registry.start(vec![1, 2], payload.len() as u64).unwrap();
+        for packet in payload.chunks(1000) {
+            transfer.advance(packet.len() as u64);
+        }
+        assert_eq!(registry.active()[0].transferred, 2500);
+        drop(transfer);
+        assert!(registry.active().is_empty());
+
+        let mut sum = 0;
+        let mut count = 0;
+        while let Ok(event) = events.try_recv() {
+            sum += event.bytes;
+            count += 1;
+            assert_eq!(event.progress.transferred, sum);
+            assert_eq!(event.progress.total, 2500);
+        }
+        assert_eq!(count, 3);
+        assert_eq!(sum, 2500);
+    }
+
+    #[tokio::test]
+    async fn test_transfers_beyond_cap_are_rejected_until_one_finishes() {
+        let registry = TransferRegistry::new(Some(2));
+        let store = ContentAddressedStore::new(MemoryBackend::default());
+        let first = registry.start(vec![1], 10).unwrap();
+        let _second = registry.start(vec![2], 10).unwrap();
+
+        assert_eq!(
+            registry.start(vec![3], 10).err(),
+            Some(TransferError::AtCapacity)
+        );
+        let (packets_tx, mut packets_rx) = mpsc::unbounded_channel();
+        packets_tx.send(vec![3; 5]).unwrap();
+        let result = registry
+            .receive(
+                vec![3],
+                5,
+                &mut packets_rx,
+                Duration::from_secs(5),
+                &store,
+                &ValidatorRegistry::new(),
+            )
+            .await;
+        assert_eq!(result, Err(TransferError::AtCapacity));
+        assert_eq!(registry.active().len(), 2);
+
+        drop(first);
+        registry
+            .receive(
+                vec![3],
+                5,
+                &mut packets_rx,
+                Duration::from_secs(5),
+                &store,
+                &ValidatorRegistry::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(store.get(&[3]).unwrap(), Some(vec![3; 5]));
+    }
+}
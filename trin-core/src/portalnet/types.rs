@@ -4,6 +4,7 @@ use std::str::FromStr;
 
 use base64;
 use rlp::Encodable;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use ssz;
 use ssz::{Decode, DecodeError, Encode, SszDecoderBuilder, SszEncoder};
 use ssz_derive::{Decode, Encode};
@@ -33,6 +34,7 @@ impl Message {
                     Request::Ping(p) => payload.append(&mut p.as_ssz_bytes()),
                     Request::FindNodes(p) => payload.append(&mut p.as_ssz_bytes()),
                     Request::FindContent(p) => payload.append(&mut p.as_ssz_bytes()),
+                    Request::Offer(p) => payload.append(&mut p.as_ssz_bytes()),
                 }
                 payload
             }
@@ -42,6 +44,7 @@ impl Message {
                     Response::Pong(p) => payload.append(&mut p.as_ssz_bytes()),
                     Response::Nodes(p) => payload.append(&mut p.as_ssz_bytes()),
                     Response::FoundContent(p) => payload.append(&mut p.as_ssz_bytes()),
+                    Response::Accept(p) => payload.append(&mut p.as_ssz_bytes()),
                 }
                 payload
             }
@@ -65,6 +68,10 @@ impl Message {
                     FindContent::from_ssz_bytes(&bytes[1..])
                         .map_err(|e| format!("Failed to decode ssz: {:?}", e))?,
                 ))),
+                7 => Ok(Message::Request(Request::Offer(
+                    Offer::from_ssz_bytes(&bytes[1..])
+                        .map_err(|e| format!("Failed to decode ssz: {:?}", e))?,
+                ))),
                 2 => Ok(Message::Response(Response::Pong(
                     Pong::from_ssz_bytes(&bytes[1..])
                         .map_err(|e| format!("Failed to decode ssz: {:?}", e))?,
@@ -77,6 +84,10 @@ impl Message {
                     FoundContent::from_ssz_bytes(&bytes[1..])
                         .map_err(|e| format!("Failed to decode ssz: {:?}", e))?,
                 ))),
+                8 => Ok(Message::Response(Response::Accept(
+                    Accept::from_ssz_bytes(&bytes[1..])
+                        .map_err(|e| format!("Failed to decode ssz: {:?}", e))?,
+                ))),
                 _ => Err("Unknown message id".to_string()),
             }
         } else {
@@ -85,11 +96,41 @@ impl Message {
     }
 }
 
+/// An overlay message tagged with the id of the request it belongs to, so responses can be
+/// matched to their requests.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Envelope {
+    pub request_id: u32,
+    pub message: Message,
+}
+
+impl Envelope {
+    /// Return the byte representation: the big-endian request id, then the message.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.request_id.to_be_bytes().to_vec();
+        bytes.append(&mut self.message.to_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 4 {
+            return Err("Missing request id".to_string());
+        }
+        let mut request_id = [0u8; 4];
+        request_id.copy_from_slice(&bytes[..4]);
+        Ok(Self {
+            request_id: u32::from_be_bytes(request_id),
+            message: Message::from_bytes(&bytes[4..])?,
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Request {
     Ping(Ping),
     FindNodes(FindNodes),
     FindContent(FindContent),
+    Offer(Offer),
 }
 
 impl Request {
@@ -98,6 +139,7 @@ impl Request {
             Request::Ping(_) => 1,
             Request::FindNodes(_) => 3,
             Request::FindContent(_) => 5,
+            Request::Offer(_) => 7,
         }
     }
 }
@@ -107,6 +149,7 @@ pub enum Response {
     Pong(Pong),
     Nodes(Nodes),
     FoundContent(FoundContent),
+    Accept(Accept),
 }
 
 impl Response {
@@ -115,6 +158,7 @@ impl Response {
             Response::Pong(_) => 2,
             Response::Nodes(_) => 4,
             Response::FoundContent(_) => 6,
+            Response::Accept(_) => 8,
         }
     }
 }
@@ -219,6 +263,46 @@ pub struct FoundContent {
     pub payload: Vec<u8>,
 }
 
+/// What a NODES response means for the requester.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FindNodesResult {
+    Enrs(Vec<Enr>),
+    /// The peer knows no nodes at the requested distances.
+    Empty,
+}
+
+impl From<Nodes> for FindNodesResult {
+    fn from(nodes: Nodes) -> Self {
+        match nodes.enrs.is_empty() {
+            true => FindNodesResult::Empty,
+            false => FindNodesResult::Enrs(nodes.enrs),
+        }
+    }
+}
+
+/// What a FOUNDCONTENT response means for the requester.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FindContentResult {
+    Content(Vec<u8>),
+    /// The peer doesn't have the content, and returned peers closer to it.
+    Enrs(Vec<Enr>),
+    /// The peer has neither the content nor any closer peers: a zero-length payload with no
+    /// ENRs.
+    NotFound,
+}
+
+impl From<FoundContent> for FindContentResult {
+    fn from(found: FoundContent) -> Self {
+        if !found.payload.is_empty() {
+            FindContentResult::Content(found.payload)
+        } else if !found.enrs.is_empty() {
+            FindContentResult::Enrs(found.enrs.into_iter().map(|enr| enr.0).collect())
+        } else {
+            FindContentResult::NotFound
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct SszEnr(Enr);
 
@@ -263,6 +347,49 @@ impl ssz::Encode for SszEnr {
     }
 }
 
+/// An ENR as carried in config and json-rpc: its base64 text, parsed and verified when it's
+/// deserialized, so a malformed record is refused up front with an error naming it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SerdeEnr(pub Enr);
+
+impl Deref for SerdeEnr {
+    type Target = Enr;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<SerdeEnr> for Enr {
+    fn from(enr: SerdeEnr) -> Self {
+        enr.0
+    }
+}
+
+impl FromStr for SerdeEnr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Enr::from_str(s)
+            .map(SerdeEnr)
+            .map_err(|e| format!("Invalid ENR {}: {}", s, e))
+    }
+}
+
+impl Serialize for SerdeEnr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_base64())
+    }
+}
+
+impl<'de> Deserialize<'de> for SerdeEnr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
 impl ssz::Encode for FoundContent {
     fn is_ssz_fixed_len() -> bool {
         false
@@ -297,6 +424,115 @@ impl ssz::Decode for FoundContent {
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Encode, Decode)]
+pub struct Offer {
+    pub content_keys: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Encode, Decode)]
+pub struct Accept {
+    pub connection_id: u16,
+    /// Whether each offered content key is wanted, in the order they were offered.
+    pub content_keys: Vec<bool>,
+}
+
+/// The kind of history content a content key refers to, given by its first byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentType {
+    BlockHeader,
+    BlockBody,
+    Receipts,
+    EpochAccumulator,
+}
+
+impl ContentType {
+    pub fn selector(&self) -> u8 {
+        match self {
+            ContentType::BlockHeader => 0x00,
+            ContentType::BlockBody => 0x01,
+            ContentType::Receipts => 0x02,
+            ContentType::EpochAccumulator => 0x03,
+        }
+    }
+
+    /// Returns the type of the content key, or `None` for an empty key or unknown selector.
+    pub fn from_content_key(content_key: &[u8]) -> Option<Self> {
+        match content_key.first()? {
+            0x00 => Some(ContentType::BlockHeader),
+            0x01 => Some(ContentType::BlockBody),
+            0x02 => Some(ContentType::Receipts),
+            0x03 => Some(ContentType::EpochAccumulator),
+            _ => None,
+        }
+    }
+
+    /// Returns the name the type is given on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentType::BlockHeader => "header",
+            ContentType::BlockBody => "body",
+            ContentType::Receipts => "receipts",
+            ContentType::EpochAccumulator => "accumulator",
+        }
+    }
+}
+
+/// Returns a short readable form of a content key for logs and RPC, like
+/// `BlockHeader(0xabcd...1234)` for the block hash that ends a header key. Keys of an unknown type
+/// are shown whole, as `Unknown(0x...)`.
+pub fn describe_content_key(content_key: &[u8]) -> String {
+    let content_type = match ContentType::from_content_key(content_key) {
+        Some(content_type) => content_type,
+        None => return format!("Unknown(0x{})", hex::encode(content_key)),
+    };
+    let id = match content_key.len() {
+        len if len >= 33 => {
+            let hash = hex::encode(&content_key[len - 32..]);
+            format!("0x{}...{}", &hash[..4], &hash[60..])
+        }
+        _ => format!("0x{}", hex::encode(&content_key[1..])),
+    };
+    format!("{:?}({})", content_type, id)
+}
+
+/// Check that a content key is of a known type and has the length of its type: a selector, chain
+/// id and block hash for block content, or a selector and epoch hash for an epoch accumulator.
+pub fn validate_content_key(content_key: &[u8]) -> Result<ContentType, String> {
+    let content_type = ContentType::from_content_key(content_key).ok_or_else(|| {
+        format!(
+            "Unknown content key type: 0x{}",
+            hex::encode(content_key.get(..1).unwrap_or_default())
+        )
+    })?;
+    let expected_len = match content_type {
+        ContentType::EpochAccumulator => 1 + 32,
+        _ => 1 + 2 + 32,
+    };
+    if content_key.len() != expected_len {
+        return Err(format!(
+            "{:?} content key has {} bytes, expected {}",
+            content_type,
+            content_key.len(),
+            expected_len
+        ));
+    }
+    Ok(content_type)
+}
+
+impl FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "header" => Ok(ContentType::BlockHeader),
+            "body" => Ok(ContentType::BlockBody),
+            "receipts" => Ok(ContentType::Receipts),
+            "accumulator" => Ok(ContentType::EpochAccumulator),
+            _ => Err(format!("Unknown content type: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct HexData(pub Vec<u8>);
 
@@ -312,8 +548,102 @@ impl FromStr for HexData {
 mod test {
     use super::*;
     use discv5::enr::{CombinedKey, EnrBuilder};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
     use std::net::Ipv4Addr;
 
+    fn arb_u256() -> impl Strategy<Value = U256> {
+        any::<[u64; 4]>().prop_map(U256)
+    }
+
+    fn arb_enr() -> impl Strategy<Value = Enr> {
+        (1u8..=255, any::<u16>()).prop_map(|(key_byte, port)| {
+            let enr_key =
+                CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+            EnrBuilder::new("v4")
+                .ip(Ipv4Addr::new(192, 168, 0, key_byte).into())
+                .udp(port)
+                .build(&enr_key)
+                .unwrap()
+        })
+    }
+
+    /// History content keys: a content type selector, a chain id, and a block hash.
+    fn arb_content_key() -> impl Strategy<Value = Vec<u8>> {
+        (0u8..4, any::<u16>(), any::<[u8; 32]>()).prop_map(|(selector, chain_id, block_hash)| {
+            let mut key = vec![selector];
+            key.extend_from_slice(&chain_id.to_be_bytes());
+            key.extend_from_slice(&block_hash);
+            key
+        })
+    }
+
+    fn arb_request() -> impl Strategy<Value = Request> {
+        prop_oneof![
+            (any::<u64>(), arb_u256()).prop_map(|(enr_seq, data_radius)| Request::Ping(Ping {
+                enr_seq,
+                data_radius
+            })),
+            vec(any::<u16>(), 0..8)
+                .prop_map(|distances| Request::FindNodes(FindNodes { distances })),
+            arb_content_key()
+                .prop_map(|content_key| Request::FindContent(FindContent { content_key })),
+            vec(arb_content_key(), 0..8)
+                .prop_map(|content_keys| Request::Offer(Offer { content_keys })),
+        ]
+    }
+
+    fn arb_response() -> impl Strategy<Value = Response> {
+        prop_oneof![
+            (any::<u64>(), arb_u256()).prop_map(|(enr_seq, data_radius)| Response::Pong(Pong {
+                enr_seq,
+                data_radius
+            })),
+            (any::<u8>(), vec(arb_enr(), 0..4))
+                .prop_map(|(total, enrs)| Response::Nodes(Nodes { total, enrs })),
+            (vec(arb_enr(), 0..4), vec(any::<u8>(), 0..64)).prop_map(|(enrs, payload)| {
+                Response::FoundContent(FoundContent {
+                    enrs: enrs.into_iter().map(SszEnr::new).collect(),
+                    payload,
+                })
+            }),
+            (any::<u16>(), vec(any::<bool>(), 0..8)).prop_map(|(connection_id, content_keys)| {
+                Response::Accept(Accept {
+                    connection_id,
+                    content_keys,
+                })
+            }),
+        ]
+    }
+
+    fn arb_message() -> impl Strategy<Value = Message> {
+        prop_oneof![
+            arb_request().prop_map(Message::Request),
+            arb_response().prop_map(Message::Response),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_arbitrary_message_round_trip(message in arb_message()) {
+            prop_assert_eq!(Message::from_bytes(&message.to_bytes()).unwrap(), message);
+        }
+
+        #[test]
+        fn test_arbitrary_envelope_round_trip(request_id in any::<u32>(), message in arb_message()) {
+            let envelope = Envelope { request_id, message };
+            prop_assert_eq!(Envelope::from_bytes(&envelope.to_bytes()).unwrap(), envelope);
+        }
+
+        #[test]
+        fn test_arbitrary_content_key_type_round_trip(content_key in arb_content_key()) {
+            let content_type = ContentType::from_content_key(&content_key).unwrap();
+            prop_assert_eq!(content_type.selector(), content_key[0]);
+        }
+    }
+
     #[test]
     fn test_found_content_encodes_empty() {
         let empty_enrs: Vec<SszEnr> = vec![];
@@ -390,4 +720,123 @@ mod test {
         assert!(SszEnr(enr_one).eq(decoded.enrs.first().unwrap()));
         assert!(SszEnr(enr_two).eq(&decoded.enrs.into_iter().nth(1).unwrap()));
     }
+
+    #[test]
+    fn test_empty_nodes_is_distinct_from_enrs() {
+        let empty = Nodes {
+            total: 1,
+            enrs: vec![],
+        };
+        assert_eq!(FindNodesResult::from(empty), FindNodesResult::Empty);
+
+        let enr_key = CombinedKey::secp256k1_from_bytes(vec![1; 32].as_mut_slice()).unwrap();
+        let enr = EnrBuilder::new("v4").build(&enr_key).unwrap();
+        let nodes = Nodes {
+            total: 1,
+            enrs: vec![enr.clone()],
+        };
+        assert_eq!(
+            FindNodesResult::from(nodes),
+            FindNodesResult::Enrs(vec![enr])
+        );
+    }
+
+    #[test]
+    fn test_zero_length_content_is_not_found() {
+        let found = FoundContent {
+            enrs: vec![],
+            payload: vec![],
+        };
+        let decoded = FoundContent::from_ssz_bytes(&found.as_ssz_bytes()).unwrap();
+        assert_eq!(
+            FindContentResult::from(decoded),
+            FindContentResult::NotFound
+        );
+
+        let found = FoundContent {
+            enrs: vec![],
+            payload: vec![0],
+        };
+        assert_eq!(
+            FindContentResult::from(found),
+            FindContentResult::Content(vec![0])
+        );
+    }
+
+    #[test]
+    fn test_describe_content_key() {
+        let mut block_hash = vec![0xab, 0xcd];
+        block_hash.extend_from_slice(&[0; 28]);
+        block_hash.extend_from_slice(&[0x12, 0x34]);
+        let key = |content_type: ContentType, chain_id: &[u8]| {
+            let mut key = vec![content_type.selector()];
+            key.extend_from_slice(chain_id);
+            key.extend_from_slice(&block_hash);
+            key
+        };
+
+        assert_eq!(
+            describe_content_key(&key(ContentType::BlockHeader, &[0x00, 0x01])),
+            "BlockHeader(0xabcd...1234)"
+        );
+        assert_eq!(
+            describe_content_key(&key(ContentType::BlockBody, &[0x00, 0x01])),
+            "BlockBody(0xabcd...1234)"
+        );
+        assert_eq!(
+            describe_content_key(&key(ContentType::Receipts, &[0x00, 0x01])),
+            "Receipts(0xabcd...1234)"
+        );
+        assert_eq!(
+            describe_content_key(&key(ContentType::EpochAccumulator, &[])),
+            "EpochAccumulator(0xabcd...1234)"
+        );
+        assert_eq!(
+            describe_content_key(&[ContentType::BlockHeader.selector(), 0x07]),
+            "BlockHeader(0x07)"
+        );
+        assert_eq!(describe_content_key(&[0x42, 0x01]), "Unknown(0x4201)");
+        assert_eq!(describe_content_key(&[]), "Unknown(0x)");
+    }
+
+    #[test]
+    fn test_enr_list_deserializes_into_typed_enrs() {
+        #[derive(Deserialize)]
+        struct Config {
+            bootnodes: Vec<SerdeEnr>,
+        }
+        let enrs: Vec<Enr> = (1..=2)
+            .map(|key_byte| {
+                let enr_key =
+                    CombinedKey::secp256k1_from_bytes(vec![key_byte; 32].as_mut_slice()).unwrap();
+                EnrBuilder::new("v4")
+                    .ip(Ipv4Addr::new(192, 168, 0, key_byte).into())
+                    .udp(9000)
+                    .build(&enr_key)
+                    .unwrap()
+            })
+            .collect();
+        let snippet = serde_json::json!({
+            "bootnodes": enrs.iter().map(|enr| enr.to_base64()).collect::<Vec<_>>(),
+        });
+
+        let config: Config = serde_json::from_value(snippet).unwrap();
+        let parsed: Vec<Enr> = config.bootnodes.into_iter().map(Enr::from).collect();
+        assert_eq!(parsed, enrs);
+        assert_eq!(
+            serde_json::to_value(SerdeEnr(enrs[0].clone())).unwrap(),
+            serde_json::json!(enrs[0].to_base64())
+        );
+
+        let error = serde_json::from_value::<Config>(serde_json::json!({
+            "bootnodes": [enrs[0].to_base64(), "enr:-aoeu"],
+        }))
+        .err()
+        .unwrap();
+        assert!(
+            error.to_string().contains("Invalid ENR enr:-aoeu"),
+            "{}",
+            error
+        );
+    }
 }
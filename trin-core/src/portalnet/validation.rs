@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ethereum_types::H256;
+
+use super::metrics::{self, Metrics, CONTENT_VALIDATION_FAILURES};
+use super::types::ContentType;
+use crate::types::header::Header;
+use crate::types::rlp_limits::RlpLimits;
+
+/// Checks that content returned by a peer is what its content key refers to.
+pub trait ContentValidator: Send + Sync {
+    fn validate(&self, content_key: &[u8], content: &[u8]) -> Result<(), String>;
+}
+
+/// Counts the content failing another validator, by content type.
+pub struct MeteredValidator<V: ContentValidator> {
+    validator: V,
+    metrics: Arc<Metrics>,
+}
+
+impl<V: ContentValidator> MeteredValidator<V> {
+    pub fn new(validator: V, metrics: Arc<Metrics>) -> Self {
+        Self { validator, metrics }
+    }
+}
+
+impl<V: ContentValidator> ContentValidator for MeteredValidator<V> {
+    fn validate(&self, content_key: &[u8], content: &[u8]) -> Result<(), String> {
+        let result = self.validator.validate(content_key, content);
+        if result.is_err() {
+            self.metrics.increment(&metrics::for_content(
+                CONTENT_VALIDATION_FAILURES,
+                content_key,
+            ));
+        }
+        result
+    }
+}
+
+/// Largest content of each type accepted from a peer, in bytes. These are tighter than the global
+/// RLP size limit, so that oversized content is rejected by its type before any hashing or
+/// decoding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ContentSizeLimits {
+    pub block_header: usize,
+    pub block_body: usize,
+    pub receipts: usize,
+    pub epoch_accumulator: usize,
+}
+
+impl Default for ContentSizeLimits {
+    fn default() -> Self {
+        Self {
+            // Mainnet headers are around 550 bytes.
+            block_header: 1024,
+            block_body: 4 * 1024 * 1024,
+            receipts: 4 * 1024 * 1024,
+            // 8192 header records of a hash and total difficulty each
+            epoch_accumulator: 1024 * 1024,
+        }
+    }
+}
+
+impl ContentSizeLimits {
+    pub fn max_size(&self, content_type: ContentType) -> usize {
+        match content_type {
+            ContentType::BlockHeader => self.block_header,
+            ContentType::BlockBody => self.block_body,
+            ContentType::Receipts => self.receipts,
+            ContentType::EpochAccumulator => self.epoch_accumulator,
+        }
+    }
+}
+
+/// Check content of one type against its size limit.
+fn check_size(content_type: ContentType, content: &[u8], max_size: usize) -> Result<(), String> {
+    if content.len() > max_size {
+        return Err(format!(
+            "{:?} content of {} bytes is over the limit of {} bytes",
+            content_type,
+            content.len(),
+            max_size
+        ));
+    }
+    Ok(())
+}
+
+/// Validates block headers against the block hash that ends their content key.
+#[derive(Clone, Debug)]
+pub struct HeaderValidator {
+    /// Bounds on the header payloads decoded from peers
+    pub rlp_limits: RlpLimits,
+    pub max_size: usize,
+}
+
+impl ContentValidator for HeaderValidator {
+    fn validate(&self, content_key: &[u8], content: &[u8]) -> Result<(), String> {
+        check_size(ContentType::BlockHeader, content, self.max_size)?;
+        if content_key.len() < 33 {
+            return Err(format!(
+                "Header content key is too short: {} bytes",
+                content_key.len()
+            ));
+        }
+        let block_hash = H256::from_slice(&content_key[content_key.len() - 32..]);
+        let header: Header = self
+            .rlp_limits
+            .decode(content)
+            .map_err(|e| format!("Invalid header content: {}", e))?;
+        if header.hash() != block_hash {
+            return Err(format!(
+                "Header hashes to {:?}, but the content key is for {:?}",
+                header.hash(),
+                block_hash
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Holds block bodies to their size limit. A body can't be checked against its header's roots
+/// without the header, so nothing else is checked yet.
+#[derive(Clone, Debug)]
+pub struct BodyValidator {
+    pub max_size: usize,
+}
+
+impl ContentValidator for BodyValidator {
+    fn validate(&self, _content_key: &[u8], content: &[u8]) -> Result<(), String> {
+        check_size(ContentType::BlockBody, content, self.max_size)
+    }
+}
+
+/// Holds receipts to their size limit, until they can be checked against the header's receipts
+/// root.
+#[derive(Clone, Debug)]
+pub struct ReceiptsValidator {
+    pub max_size: usize,
+}
+
+impl ContentValidator for ReceiptsValidator {
+    fn validate(&self, _content_key: &[u8], content: &[u8]) -> Result<(), String> {
+        check_size(ContentType::Receipts, content, self.max_size)
+    }
+}
+
+/// Holds epoch accumulators to their size limit, until they can be checked against the master
+/// accumulator.
+#[derive(Clone, Debug)]
+pub struct AccumulatorValidator {
+    pub max_size: usize,
+}
+
+impl ContentValidator for AccumulatorValidator {
+    fn validate(&self, _content_key: &[u8], content: &[u8]) -> Result<(), String> {
+        check_size(ContentType::EpochAccumulator, content, self.max_size)
+    }
+}
+
+/// Validators by the selector byte that starts the content keys they check, so that a content
+/// type brings its own validation. Content of a type without a validator is accepted.
+#[derive(Clone, Default)]
+pub struct ValidatorRegistry {
+    validators: HashMap<u8, Arc<dyn ContentValidator>>,
+}
+
+impl ValidatorRegistry {
+    /// A registry with no validators, accepting all content.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry validating each history content type, within the given limits.
+    pub fn history(rlp_limits: RlpLimits, size_limits: ContentSizeLimits) -> Self {
+        let mut registry = Self::new();
+        registry.register(
+            ContentType::BlockHeader.selector(),
+            HeaderValidator {
+                rlp_limits,
+                max_size: size_limits.block_header,
+            },
+        );
+        registry.register(
+            ContentType::BlockBody.selector(),
+            BodyValidator {
+                max_size: size_limits.block_body,
+            },
+        );
+        registry.register(
+            ContentType::Receipts.selector(),
+            ReceiptsValidator {
+                max_size: size_limits.receipts,
+            },
+        );
+        registry.register(
+            ContentType::EpochAccumulator.selector(),
+            AccumulatorValidator {
+                max_size: size_limits.epoch_accumulator,
+            },
+        );
+        registry
+    }
+
+    /// Validate content keys starting with `selector` with the validator, replacing any
+    /// validator registered for it before.
+    pub fn register(&mut self, selector: u8, validator: impl ContentValidator + 'static) {
+        self.validators.insert(selector, Arc::new(validator));
+    }
+}
+
+impl ContentValidator for ValidatorRegistry {
+    fn validate(&self, content_key: &[u8], content: &[u8]) -> Result<(), String> {
+        match content_key
+            .first()
+            .and_then(|selector| self.validators.get(selector))
+        {
+            Some(validator) => validator.validate(content_key, content),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header_key(block_hash: H256) -> Vec<u8> {
+        let mut key = vec![ContentType::BlockHeader.selector(), 0x00, 0x01];
+        key.extend_from_slice(block_hash.as_bytes());
+        key
+    }
+
+    fn history_validator() -> ValidatorRegistry {
+        ValidatorRegistry::history(RlpLimits::default(), ContentSizeLimits::default())
+    }
+
+    #[test]
+    fn test_header_must_match_its_hash() {
+        let header = Header {
+            number: 7,
+            ..Default::default()
+        };
+        let content = rlp::encode(&header);
+
+        let validator = history_validator();
+        assert!(validator
+            .validate(&header_key(header.hash()), &content)
+            .is_ok());
+        assert!(validator
+            .validate(&header_key(H256::repeat_byte(1)), &content)
+            .is_err());
+        assert!(validator
+            .validate(&header_key(header.hash()), &[0xff])
+            .is_err());
+    }
+
+    #[test]
+    fn test_oversized_header_is_rejected() {
+        let header = Header::default();
+        let validator = ValidatorRegistry::history(
+            RlpLimits {
+                max_size: 64,
+                ..Default::default()
+            },
+            ContentSizeLimits::default(),
+        );
+        assert!(validator
+            .validate(&header_key(header.hash()), &rlp::encode(&header))
+            .is_err());
+    }
+
+    #[test]
+    fn test_other_content_is_accepted() {
+        let key = vec![ContentType::BlockBody.selector(), 1, 2];
+        assert!(history_validator().validate(&key, b"body").is_ok());
+    }
+
+    #[test]
+    fn test_header_over_its_size_limit_is_rejected() {
+        let header = Header {
+            extra_data: vec![0; 64],
+            ..Default::default()
+        };
+        let content = rlp::encode(&header);
+        let validator = ValidatorRegistry::history(
+            RlpLimits::default(),
+            ContentSizeLimits {
+                block_header: content.len() - 1,
+                ..Default::default()
+            },
+        );
+
+        let error = validator
+            .validate(&header_key(header.hash()), &content)
+            .unwrap_err();
+        assert!(error.contains("BlockHeader content"), "{}", error);
+        // The same bytes are well within the limit for a body.
+        let body_key = vec![ContentType::BlockBody.selector(), 1, 2];
+        assert!(validator.validate(&body_key, &content).is_ok());
+    }
+
+    #[test]
+    fn test_validation_failures_are_counted_by_type() {
+        let metrics = Arc::new(Metrics::default());
+        let validator = MeteredValidator::new(history_validator(), metrics.clone());
+        assert!(validator
+            .validate(&header_key(H256::repeat_byte(1)), &[0xff])
+            .is_err());
+        let body_key = vec![ContentType::BlockBody.selector(), 1];
+        assert!(validator.validate(&body_key, b"body").is_ok());
+
+        assert_eq!(metrics.get("content_validation_failures_header"), 1);
+        assert_eq!(metrics.get("content_validation_failures_body"), 0);
+    }
+
+    /// Records the content keys it's asked to validate, and rejects them all.
+    #[derive(Default)]
+    struct RecordingValidator {
+        seen: Arc<parking_lot::Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl ContentValidator for RecordingValidator {
+        fn validate(&self, content_key: &[u8], _content: &[u8]) -> Result<(), String> {
+            self.seen.lock().push(content_key.to_vec());
+            Err("rejected".to_owned())
+        }
+    }
+
+    #[test]
+    fn test_registered_validator_is_invoked_for_its_content_type() {
+        let validator = RecordingValidator::default();
+        let seen = validator.seen.clone();
+        let mut registry = history_validator();
+        registry.register(ContentType::Receipts.selector(), validator);
+
+        let receipts_key = vec![ContentType::Receipts.selector(), 1];
+        let body_key = vec![ContentType::BlockBody.selector(), 1];
+        assert_eq!(
+            registry.validate(&receipts_key, b"receipts"),
+            Err("rejected".to_owned())
+        );
+        assert!(registry.validate(&body_key, b"body").is_ok());
+        assert_eq!(*seen.lock(), vec![receipts_key]);
+
+        // A type without a validator is accepted as is.
+        assert!(ValidatorRegistry::new()
+            .validate(&[0x7f], b"anything")
+            .is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use super::metrics::{Metrics, DISCV5_DEGRADED, DISCV5_STALLS};
+
+/// Notices when discv5 goes quiet. A dead socket doesn't fail loudly: requests just time out and
+/// no events arrive, so a long enough silence is reported as a degraded state.
+pub struct ActivityWatchdog {
+    last_activity: Mutex<Instant>,
+    /// How long discv5 may go without activity before it's considered degraded
+    stall_after: Duration,
+    degraded: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+impl ActivityWatchdog {
+    pub fn new(stall_after: Duration, metrics: Arc<Metrics>) -> Self {
+        Self {
+            last_activity: Mutex::new(Instant::now()),
+            stall_after,
+            degraded: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    /// Record inbound or outbound discv5 traffic, which clears a degraded state.
+    pub fn record_activity(&self) {
+        *self.last_activity.lock() = Instant::now();
+        if self.degraded.swap(false, Ordering::Relaxed) {
+            info!("discv5 activity resumed");
+            self.metrics.set(DISCV5_DEGRADED, 0);
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether discv5 is degraded, reporting it when it turns so.
+    pub fn check(&self) -> bool {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> bool {
+        let quiet_for = now.saturating_duration_since(*self.last_activity.lock());
+        if quiet_for > self.stall_after && !self.degraded.swap(true, Ordering::Relaxed) {
+            warn!(
+                "No discv5 activity for {}s, the UDP socket may be down",
+                quiet_for.as_secs()
+            );
+            self.metrics.set(DISCV5_DEGRADED, 1);
+            self.metrics.increment(DISCV5_STALLS);
+        }
+        self.is_degraded()
+    }
+}
+
+/// Check the watchdog for as long as the node runs, a few times per stall period.
+pub async fn watch_discv5_activity(watchdog: Arc<ActivityWatchdog>) {
+    let mut interval = tokio::time::interval(watchdog.stall_after / 4);
+    loop {
+        interval.tick().await;
+        watchdog.check();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_silence_is_reported_as_degraded() {
+        let metrics = Arc::new(Metrics::default());
+        let watchdog = ActivityWatchdog::new(Duration::from_secs(30), metrics.clone());
+        let started = *watchdog.last_activity.lock();
+
+        assert!(!watchdog.check_at(started + Duration::from_secs(10)));
+        assert_eq!(metrics.get(DISCV5_DEGRADED), 0);
+
+        assert!(watchdog.check_at(started + Duration::from_secs(31)));
+        assert!(watchdog.check_at(started + Duration::from_secs(60)));
+        assert_eq!(metrics.get(DISCV5_DEGRADED), 1);
+        assert_eq!(metrics.get(DISCV5_STALLS), 1);
+
+        watchdog.record_activity();
+        assert!(!watchdog.is_degraded());
+        assert!(!watchdog.check());
+        assert_eq!(metrics.get(DISCV5_DEGRADED), 0);
+    }
+}
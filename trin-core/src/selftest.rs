@@ -0,0 +1,235 @@
+use crate::portalnet::types::{
+    Accept, Envelope, FindContent, FindNodes, FoundContent, Message, Nodes, Offer, Ping, Pong,
+    Request, Response, SszEnr,
+};
+use crate::portalnet::{Enr, U256};
+use crate::types::header::{Header, LONDON_BLOCK_NUMBER};
+use discv5::enr::{CombinedKey, EnrBuilder};
+use ethereum_types::{H256, H64};
+use ssz::{Decode, Encode};
+use std::fmt::Debug;
+use std::net::Ipv4Addr;
+
+/// Outcome of a single self-test case.
+pub struct SelfTestCase {
+    pub name: &'static str,
+    pub result: Result<(), String>,
+}
+
+/// Results of running every built-in round-trip fixture.
+pub struct SelfTestReport {
+    pub cases: Vec<SelfTestCase>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.cases.iter().all(|case| case.result.is_ok())
+    }
+
+    pub fn print(&self) {
+        for case in self.cases.iter() {
+            match &case.result {
+                Ok(_) => println!("PASS {}", case.name),
+                Err(msg) => println!("FAIL {}: {}", case.name, msg),
+            }
+        }
+        let failed = self
+            .cases
+            .iter()
+            .filter(|case| case.result.is_err())
+            .count();
+        println!("{} passed, {} failed", self.cases.len() - failed, failed);
+    }
+}
+
+/// Run encode/decode round-trips against built-in fixtures, without touching the network.
+pub fn run_selftest() -> SelfTestReport {
+    let enr = fixture_enr();
+    let cases = vec![
+        SelfTestCase {
+            name: "ping ssz fixed bytes",
+            result: check_ping_bytes(),
+        },
+        SelfTestCase {
+            name: "u256 ssz little-endian",
+            result: check_u256_endianness(),
+        },
+        SelfTestCase {
+            name: "header rlp round-trip",
+            result: check_header_rlp(),
+        },
+        SelfTestCase {
+            name: "enr rlp round-trip",
+            result: check_enr_rlp(&enr),
+        },
+        SelfTestCase {
+            name: "enr base64 round-trip",
+            result: check_enr_base64(&enr),
+        },
+        SelfTestCase {
+            name: "ping message round-trip",
+            result: check_message(Message::Request(Request::Ping(Ping {
+                enr_seq: 1,
+                data_radius: U256::from(u64::MAX),
+            }))),
+        },
+        SelfTestCase {
+            name: "pong message round-trip",
+            result: check_message(Message::Response(Response::Pong(Pong {
+                enr_seq: 1,
+                data_radius: U256::max_value(),
+            }))),
+        },
+        SelfTestCase {
+            name: "find nodes message round-trip",
+            result: check_message(Message::Request(Request::FindNodes(FindNodes {
+                distances: vec![0, 255, 256],
+            }))),
+        },
+        SelfTestCase {
+            name: "nodes message round-trip",
+            result: check_message(Message::Response(Response::Nodes(Nodes {
+                total: 1,
+                enrs: vec![enr.clone()],
+            }))),
+        },
+        SelfTestCase {
+            name: "find content message round-trip",
+            result: check_message(Message::Request(Request::FindContent(FindContent {
+                content_key: vec![1; 32],
+            }))),
+        },
+        SelfTestCase {
+            name: "found content message round-trip",
+            result: check_message(Message::Response(Response::FoundContent(FoundContent {
+                enrs: vec![SszEnr::new(enr)],
+                payload: vec![],
+            }))),
+        },
+        SelfTestCase {
+            name: "found content payload round-trip",
+            result: check_message(Message::Response(Response::FoundContent(FoundContent {
+                enrs: vec![],
+                payload: vec![0xab; 64],
+            }))),
+        },
+        SelfTestCase {
+            name: "request envelope round-trip",
+            result: check_envelope(Envelope {
+                request_id: 0xdead_beef,
+                message: Message::Request(Request::Ping(Ping {
+                    enr_seq: 1,
+                    data_radius: U256::from(1),
+                })),
+            }),
+        },
+        SelfTestCase {
+            name: "offer message round-trip",
+            result: check_message(Message::Request(Request::Offer(Offer {
+                content_keys: vec![vec![0; 33], vec![1; 33]],
+            }))),
+        },
+        SelfTestCase {
+            name: "accept message round-trip",
+            result: check_message(Message::Response(Response::Accept(Accept {
+                connection_id: 0x1234,
+                content_keys: vec![true, false],
+            }))),
+        },
+    ];
+
+    SelfTestReport { cases }
+}
+
+fn fixture_enr() -> Enr {
+    let enr_key = CombinedKey::secp256k1_from_bytes(vec![1; 32].as_mut_slice()).unwrap();
+    EnrBuilder::new("v4")
+        .ip(Ipv4Addr::new(192, 168, 0, 1).into())
+        .udp(9000)
+        .build(&enr_key)
+        .unwrap()
+}
+
+fn check_equal<T: PartialEq + Debug>(expected: T, actual: T) -> Result<(), String> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(format!("expected {:?}, got {:?}", expected, actual))
+    }
+}
+
+fn check_message(msg: Message) -> Result<(), String> {
+    let decoded = Message::from_bytes(&msg.to_bytes())?;
+    check_equal(msg, decoded)
+}
+
+fn check_envelope(envelope: Envelope) -> Result<(), String> {
+    let decoded = Envelope::from_bytes(&envelope.to_bytes())?;
+    check_equal(envelope, decoded)
+}
+
+fn check_ping_bytes() -> Result<(), String> {
+    let msg = Message::Request(Request::Ping(Ping {
+        enr_seq: 1,
+        data_radius: U256::from(1),
+    }));
+    let mut expected = vec![1, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+    expected.extend_from_slice(&[0; 31]);
+    check_equal(hex::encode(expected), hex::encode(msg.to_bytes()))
+}
+
+fn check_u256_endianness() -> Result<(), String> {
+    let value = U256::from(0x0102);
+    let encoded = value.as_ssz_bytes();
+    check_equal(&[0x02, 0x01, 0x00][..], &encoded[..3])?;
+    let decoded = U256::from_ssz_bytes(&encoded).map_err(|e| format!("{:?}", e))?;
+    check_equal(value, decoded)
+}
+
+fn check_header_rlp() -> Result<(), String> {
+    let header = Header {
+        parent_hash: H256::repeat_byte(1),
+        number: LONDON_BLOCK_NUMBER,
+        gas_limit: 30_000_000.into(),
+        gas_used: 15_000_000.into(),
+        timestamp: 1_628_166_822,
+        extra_data: b"trin".to_vec(),
+        mix_hash: Some(H256::repeat_byte(2)),
+        nonce: Some(H64::repeat_byte(3)),
+        base_fee_per_gas: Some(1_000_000_000.into()),
+        ..Default::default()
+    };
+    let decoded: Header =
+        rlp::decode(&rlp::encode(&header)).map_err(|e| format!("rlp decoding failed: {}", e))?;
+    check_equal(header, decoded)
+}
+
+fn check_enr_rlp(enr: &Enr) -> Result<(), String> {
+    let decoded: Enr =
+        rlp::decode(&rlp::encode(enr)).map_err(|e| format!("rlp decoding failed: {}", e))?;
+    check_equal(enr.clone(), decoded)
+}
+
+fn check_enr_base64(enr: &Enr) -> Result<(), String> {
+    let decoded: Enr = enr.to_base64().parse()?;
+    check_equal(enr.clone(), decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        let report = run_selftest();
+        for case in report.cases.iter() {
+            assert!(
+                case.result.is_ok(),
+                "{} failed: {:?}",
+                case.name,
+                case.result
+            );
+        }
+        assert!(report.passed());
+    }
+}
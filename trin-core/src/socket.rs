@@ -1,5 +1,6 @@
 use log::debug;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
 
 #[cfg(unix)]
 use interfaces::{self, Interface};
@@ -9,6 +10,41 @@ use ipconfig;
 
 const STUN_SERVER: &str = "143.198.142.185:3478";
 
+/// Which local port discv5 sends from. discv5 sends and receives on one socket, so this is also
+/// the port it listens on, and the one the ENR advertises unless an external address is given.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourcePortStrategy {
+    /// Send from the configured listen port, so a NAT that maps ports symmetrically keeps it.
+    ListenPort,
+    /// Send from a random free port, picked when the node starts.
+    Ephemeral,
+}
+
+impl Default for SourcePortStrategy {
+    fn default() -> Self {
+        SourcePortStrategy::ListenPort
+    }
+}
+
+impl FromStr for SourcePortStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "listen-port" => Ok(SourcePortStrategy::ListenPort),
+            "ephemeral" => Ok(SourcePortStrategy::Ephemeral),
+            _ => Err(format!("Unknown source port strategy: {}", s)),
+        }
+    }
+}
+
+/// Returns a socket on the IP address with a port the OS picked as free.
+pub fn ephemeral_socket(ip: IpAddr) -> Result<SocketAddr, String> {
+    UdpSocket::bind(SocketAddr::new(ip, 0))
+        .and_then(|socket| socket.local_addr())
+        .map_err(|e| format!("Unable to pick an ephemeral port: {}", e))
+}
+
 /// Ping a STUN server on the public network. This does two things:
 /// - Creates an externally-addressable UDP port, if you are behind a NAT
 /// - Returns the public IP and port that corresponds to your local port
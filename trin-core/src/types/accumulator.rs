@@ -0,0 +1,178 @@
+use ethereum_types::H256;
+use sha2::{Digest, Sha256};
+
+use super::header::{Header, MERGE_BLOCK_NUMBER};
+
+/// Number of header records in each epoch accumulator.
+pub const EPOCH_SIZE: u64 = 8192;
+
+/// Most epochs the master accumulator holds, the SSZ list limit of its historical epochs.
+pub const MAX_HISTORICAL_EPOCHS: u64 = 131_072;
+
+/// Depth of the Merkle tree of header records in an epoch accumulator.
+const EPOCH_DEPTH: usize = 13;
+
+/// Depth of the Merkle tree of epoch roots in the master accumulator.
+const HISTORICAL_EPOCHS_DEPTH: usize = 17;
+
+/// Number of sibling hashes in a proof of a header against the master accumulator root: the
+/// header's total difficulty, its branch within the epoch, the epoch's length, the epoch's branch
+/// within the historical epochs, and their length.
+pub const HEADER_PROOF_LENGTH: usize = 1 + EPOCH_DEPTH + 1 + HISTORICAL_EPOCHS_DEPTH + 1;
+
+/// Verify that a pre-Merge header is in the master accumulator with the given root. The proof
+/// holds the sibling hashes of the Merkle branch from the header record to the root, starting at
+/// the leaf, as laid out in the accumulator's SSZ encoding: each epoch is a list of header records
+/// of a block hash and total difficulty, and the master accumulator is the list of epoch roots.
+pub fn verify_header_with_proof(
+    header: &Header,
+    proof: &[H256],
+    accumulator_root: H256,
+) -> Result<(), String> {
+    if header.number >= MERGE_BLOCK_NUMBER {
+        return Err(format!(
+            "Block {} is not covered by the pre-Merge accumulator",
+            header.number
+        ));
+    }
+    if proof.len() != HEADER_PROOF_LENGTH {
+        return Err(format!(
+            "Header proof has {} hashes, expected {}",
+            proof.len(),
+            HEADER_PROOF_LENGTH
+        ));
+    }
+    // The header record is a container of the block hash and total difficulty, so the total
+    // difficulty is the block hash's sibling.
+    let record = hash_pair(&header.hash(), &proof[0]);
+    let epoch_branch = &proof[1..=EPOCH_DEPTH];
+    let epoch_length = &proof[EPOCH_DEPTH + 1];
+    let epochs_branch = &proof[EPOCH_DEPTH + 2..HEADER_PROOF_LENGTH - 1];
+    let epochs_length = &proof[HEADER_PROOF_LENGTH - 1];
+
+    let epoch_root = hash_pair(
+        &branch_root(record, header.number % EPOCH_SIZE, epoch_branch),
+        epoch_length,
+    );
+    let root = hash_pair(
+        &branch_root(epoch_root, header.number / EPOCH_SIZE, epochs_branch),
+        epochs_length,
+    );
+    if root != accumulator_root {
+        return Err(format!(
+            "Header proof for block {} leads to root {:?}, expected {:?}",
+            header.number, root, accumulator_root
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the root of the tree with `leaf` at `index`, given its siblings from the bottom up.
+fn branch_root(leaf: H256, index: u64, branch: &[H256]) -> H256 {
+    branch
+        .iter()
+        .enumerate()
+        .fold(leaf, |node, (depth, sibling)| match (index >> depth) & 1 {
+            0 => hash_pair(&node, sibling),
+            _ => hash_pair(sibling, &node),
+        })
+}
+
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut hasher = Sha256::new();
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    H256::from_slice(&hasher.finalize())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BLOCK_1_RLP: &[u8] = include_bytes!("../../fuzz/corpus/header_rlp/block_1");
+
+    /// Proof of mainnet block 1 in a master accumulator holding a single epoch, of the first two
+    /// mainnet blocks with their total difficulties.
+    const BLOCK_1_PROOF: [&str; HEADER_PROOF_LENGTH] = [
+        "000080ff07000000000000000000000000000000000000000000000000000000",
+        "23d6398abe4eba641e97a075b30780c12ebe18b24e83a9a9c7bdd94a910cf749",
+        "f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb4b",
+        "db56114e00fdd4c1f85c892bf35ac9a89289aaecb1ebd0a96cde606a748b5d71",
+        "c78009fdf07fc56a11f122370658a353aaa542ed63e44c4bc15ff4cd105ab33c",
+        "536d98837f2dd165a55d5eeae91485954472d56f246df256bf3cae19352a123c",
+        "9efde052aa15429fae05bad4d0b1d7c64da64d03d7a1854a588c2cb8430c0d30",
+        "d88ddfeed400a8755596b21942c1497e114c302e6118290f91e6772976041fa1",
+        "87eb0ddba57e35f6d286673802a4af5975e22506c7cf4c64bb6be5ee11527f2c",
+        "26846476fd5fc54a5d43385167c95144f2643f533cc85bb9d16b782f8d7db193",
+        "506d86582d252405b840018792cad2bf1259f1ef5aa5f887e13cb2f0094f51e1",
+        "ffff0ad7e659772f9534c195c815efc4014ef1e1daed4404c06385d11192e92b",
+        "6cf04127db05441cd833107a52be852868890e4317e6a02ab47683aa75964220",
+        "b7d05f875f140027ef5118a2247bbb84ce8f2f0f1123623085daf7960c329f5f",
+        "0200000000000000000000000000000000000000000000000000000000000000",
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        "f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb4b",
+        "db56114e00fdd4c1f85c892bf35ac9a89289aaecb1ebd0a96cde606a748b5d71",
+        "c78009fdf07fc56a11f122370658a353aaa542ed63e44c4bc15ff4cd105ab33c",
+        "536d98837f2dd165a55d5eeae91485954472d56f246df256bf3cae19352a123c",
+        "9efde052aa15429fae05bad4d0b1d7c64da64d03d7a1854a588c2cb8430c0d30",
+        "d88ddfeed400a8755596b21942c1497e114c302e6118290f91e6772976041fa1",
+        "87eb0ddba57e35f6d286673802a4af5975e22506c7cf4c64bb6be5ee11527f2c",
+        "26846476fd5fc54a5d43385167c95144f2643f533cc85bb9d16b782f8d7db193",
+        "506d86582d252405b840018792cad2bf1259f1ef5aa5f887e13cb2f0094f51e1",
+        "ffff0ad7e659772f9534c195c815efc4014ef1e1daed4404c06385d11192e92b",
+        "6cf04127db05441cd833107a52be852868890e4317e6a02ab47683aa75964220",
+        "b7d05f875f140027ef5118a2247bbb84ce8f2f0f1123623085daf7960c329f5f",
+        "df6af5f5bbdb6be9ef8aa618e4bf8073960867171e29676f8b284dea6a08a85e",
+        "b58d900f5e182e3c50ef74969ea16c7726c549757cc23523c369587da7293784",
+        "d49a7502ffcfb0340b1d7885688500ca308161a7f96b62df9d083b71fcc8f2bb",
+        "8fe6b1689256c0d385f42f5bbe2027a22c1996e110ba97c171d3e5948de92beb",
+        "0100000000000000000000000000000000000000000000000000000000000000",
+    ];
+
+    const ACCUMULATOR_ROOT: &str =
+        "60afa4345f6a72e9e49ce5bf5dc187f5081041c733ee62fd2213cffdf53fb83c";
+
+    fn block_1() -> Header {
+        rlp::decode(BLOCK_1_RLP).unwrap()
+    }
+
+    fn block_1_proof() -> Vec<H256> {
+        BLOCK_1_PROOF
+            .iter()
+            .map(|hash| H256::from_slice(&hex::decode(hash).unwrap()))
+            .collect()
+    }
+
+    fn accumulator_root() -> H256 {
+        H256::from_slice(&hex::decode(ACCUMULATOR_ROOT).unwrap())
+    }
+
+    #[test]
+    fn test_header_verifies_against_fixture_proof() {
+        let root = accumulator_root();
+        assert_eq!(
+            verify_header_with_proof(&block_1(), &block_1_proof(), root),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_tampered_proof_is_rejected() {
+        let root = accumulator_root();
+        let header = block_1();
+        for tampered_at in vec![0, 1, EPOCH_DEPTH + 1, HEADER_PROOF_LENGTH - 1] {
+            let mut proof = block_1_proof();
+            proof[tampered_at] = H256::repeat_byte(0xab);
+            assert!(verify_header_with_proof(&header, &proof, root).is_err());
+        }
+
+        // A truncated proof fails too, as does any proof of a post-Merge header.
+        let proof = block_1_proof();
+        assert!(verify_header_with_proof(&header, &proof[1..], root).is_err());
+        let post_merge = Header {
+            number: MERGE_BLOCK_NUMBER,
+            ..header
+        };
+        assert!(verify_header_with_proof(&post_merge, &proof, root).is_err());
+    }
+}
@@ -0,0 +1,549 @@
+use ethereum_types::{Bloom, H160, H256, H64, U256};
+use keccak_hash::keccak;
+use rlp::{Encodable, RlpStream};
+use serde_json::Value;
+
+use super::header::Header;
+use super::trie::ordered_trie_root;
+
+/// An Ethereum block: its header and body.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Block {
+    pub header: Header,
+    /// Transactions in their canonical encoding: an RLP list for legacy transactions, and the
+    /// type byte followed by the RLP payload for typed (EIP-2718) transactions.
+    pub transactions: Vec<Vec<u8>>,
+    pub uncles: Vec<Header>,
+}
+
+impl Block {
+    /// Returns the length of the RLP-encoded block, as reported in the `size` field of rpc
+    /// responses.
+    pub fn size(&self) -> usize {
+        rlp::encode(self).len()
+    }
+
+    /// Returns the root of the trie of the block's transactions, which a valid block's header
+    /// commits to.
+    pub fn transactions_root(&self) -> H256 {
+        ordered_trie_root(&self.transactions)
+    }
+
+    /// Build a block from an `eth_getBlockByHash` or `eth_getBlockByNumber` response with full
+    /// transaction objects. The response only lists uncle hashes, so the uncle headers are passed
+    /// separately, in order. Each transaction is checked against its hash when the response has
+    /// one, and the transactions together against the header's transactions root.
+    pub fn from_infura_response(block: &Value, uncles: Vec<Header>) -> Result<Self, String> {
+        if !block.is_object() {
+            return Err(format!("Block is not an object: {}", block));
+        }
+        let uncle_hashes = field(block, "uncles")?
+            .as_array()
+            .ok_or_else(|| "Field uncles is not an array".to_string())?;
+        if uncle_hashes.len() != uncles.len() {
+            return Err(format!(
+                "Block has {} uncles, but {} uncle headers were given",
+                uncle_hashes.len(),
+                uncles.len()
+            ));
+        }
+
+        let transactions = field(block, "transactions")?
+            .as_array()
+            .ok_or_else(|| "Field transactions is not an array".to_string())?;
+        if transactions.iter().any(Value::is_string) {
+            return Err(
+                "Response lists only transaction hashes, full transaction objects are needed"
+                    .to_string(),
+            );
+        }
+        let transactions = transactions
+            .iter()
+            .map(encode_transaction)
+            .collect::<Result<Vec<Vec<u8>>, String>>()?;
+
+        let block = Self {
+            header: header_from_infura_response(block)?,
+            transactions,
+            uncles,
+        };
+        if block.transactions_root() != block.header.transactions_root {
+            return Err(format!(
+                "Transactions hash to root {:?}, but the header has {:?}",
+                block.transactions_root(),
+                block.header.transactions_root
+            ));
+        }
+        Ok(block)
+    }
+
+    /// Build a block from a whole json-rpc response to `eth_getBlockByHash` or
+    /// `eth_getBlockByNumber`, as `from_infura_response` does with its result. An error response,
+    /// like a rate limit, fails with the provider's code and message, and a null result, for a
+    /// block the provider doesn't know, fails too.
+    pub fn from_infura_rpc_response(response: &Value, uncles: Vec<Header>) -> Result<Self, String> {
+        Self::from_infura_response(rpc_result(response)?, uncles)
+    }
+}
+
+/// Returns the result of a json-rpc response, or Err describing the error response or missing
+/// result.
+fn rpc_result(response: &Value) -> Result<&Value, String> {
+    if let Some(error) = response.get("error") {
+        return Err(match (error.get("code"), error.get("message")) {
+            (Some(code), Some(message)) => format!(
+                "Provider returned error {}: {}",
+                code,
+                message.as_str().unwrap_or(&message.to_string())
+            ),
+            _ => format!("Provider returned error: {}", error),
+        });
+    }
+    match response.get("result") {
+        Some(Value::Null) => {
+            Err("Provider returned a null result, the block is unknown to it".to_string())
+        }
+        Some(result) => Ok(result),
+        None => Err("Provider response has neither a result nor an error".to_string()),
+    }
+}
+
+impl Encodable for Block {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(3);
+        s.append(&self.header);
+        s.begin_list(self.transactions.len());
+        for transaction in self.transactions.iter() {
+            if is_legacy_transaction(transaction) {
+                s.append_raw(transaction, 1);
+            } else {
+                s.append(transaction);
+            }
+        }
+        s.append_list(&self.uncles);
+    }
+}
+
+/// Legacy transactions are encoded as an RLP list, typed transactions start with their type byte.
+fn is_legacy_transaction(transaction: &[u8]) -> bool {
+    transaction.first().map_or(false, |byte| *byte >= 0xc0)
+}
+
+fn header_from_infura_response(block: &Value) -> Result<Header, String> {
+    let log_bloom = data(block, "logsBloom")?;
+    if log_bloom.len() != 256 {
+        return Err(format!("Invalid logsBloom length: {}", log_bloom.len()));
+    }
+    let base_fee_per_gas = match block.get("baseFeePerGas") {
+        Some(Value::Null) | None => None,
+        Some(_) => Some(quantity(block, "baseFeePerGas")?),
+    };
+
+    Ok(Header {
+        parent_hash: hash(block, "parentHash")?,
+        uncles_hash: hash(block, "sha3Uncles")?,
+        author: address(block, "miner")?,
+        state_root: hash(block, "stateRoot")?,
+        transactions_root: hash(block, "transactionsRoot")?,
+        receipts_root: hash(block, "receiptsRoot")?,
+        log_bloom: Bloom::from_slice(&log_bloom),
+        difficulty: quantity(block, "difficulty")?,
+        number: quantity_u64(block, "number")?,
+        gas_limit: quantity(block, "gasLimit")?,
+        gas_used: quantity(block, "gasUsed")?,
+        timestamp: quantity_u64(block, "timestamp")?,
+        extra_data: data(block, "extraData")?,
+        mix_hash: Some(hash(block, "mixHash")?),
+        nonce: Some(H64::from_slice(&fixed_data(block, "nonce", 8)?)),
+        base_fee_per_gas,
+    })
+}
+
+/// Returns the canonical encoding of a full transaction object.
+fn encode_transaction(transaction: &Value) -> Result<Vec<u8>, String> {
+    let transaction_type = match transaction.get("type") {
+        Some(Value::Null) | None => 0,
+        Some(_) => quantity_u64(transaction, "type")?,
+    };
+    let to = match transaction.get("to") {
+        Some(Value::Null) | None => vec![],
+        Some(_) => address(transaction, "to")?.as_bytes().to_vec(),
+    };
+
+    let mut s = RlpStream::new();
+    match transaction_type {
+        0 => {
+            s.begin_list(9);
+            s.append(&quantity(transaction, "nonce")?)
+                .append(&quantity(transaction, "gasPrice")?)
+                .append(&quantity(transaction, "gas")?)
+                .append(&to)
+                .append(&quantity(transaction, "value")?)
+                .append(&data(transaction, "input")?);
+        }
+        1 => {
+            s.begin_list(11);
+            s.append(&quantity(transaction, "chainId")?)
+                .append(&quantity(transaction, "nonce")?)
+                .append(&quantity(transaction, "gasPrice")?)
+                .append(&quantity(transaction, "gas")?)
+                .append(&to)
+                .append(&quantity(transaction, "value")?)
+                .append(&data(transaction, "input")?);
+            append_access_list(&mut s, transaction)?;
+        }
+        2 => {
+            s.begin_list(12);
+            s.append(&quantity(transaction, "chainId")?)
+                .append(&quantity(transaction, "nonce")?)
+                .append(&quantity(transaction, "maxPriorityFeePerGas")?)
+                .append(&quantity(transaction, "maxFeePerGas")?)
+                .append(&quantity(transaction, "gas")?)
+                .append(&to)
+                .append(&quantity(transaction, "value")?)
+                .append(&data(transaction, "input")?);
+            append_access_list(&mut s, transaction)?;
+        }
+        other => return Err(format!("Unsupported transaction type: {}", other)),
+    }
+    s.append(&quantity(transaction, "v")?)
+        .append(&quantity(transaction, "r")?)
+        .append(&quantity(transaction, "s")?);
+
+    let mut encoded = match transaction_type {
+        0 => vec![],
+        typed => vec![typed as u8],
+    };
+    encoded.extend_from_slice(&s.out());
+
+    match transaction.get("hash") {
+        Some(Value::Null) | None => {}
+        Some(_) => {
+            let expected = hash(transaction, "hash")?;
+            if keccak(&encoded) != expected {
+                return Err(format!(
+                    "Transaction {:?} doesn't match its encoding, which hashes to {:?}",
+                    expected,
+                    keccak(&encoded)
+                ));
+            }
+        }
+    }
+    Ok(encoded)
+}
+
+fn append_access_list(s: &mut RlpStream, transaction: &Value) -> Result<(), String> {
+    let access_list = field(transaction, "accessList")?
+        .as_array()
+        .ok_or_else(|| "Field accessList is not an array".to_string())?;
+    s.begin_list(access_list.len());
+    for item in access_list {
+        let storage_keys = field(item, "storageKeys")?
+            .as_array()
+            .ok_or_else(|| "Field storageKeys is not an array".to_string())?;
+        s.begin_list(2);
+        s.append(&address(item, "address")?);
+        s.begin_list(storage_keys.len());
+        for key in storage_keys {
+            s.append(&H256::from_slice(&decode_hex(key, 32)?));
+        }
+    }
+    Ok(())
+}
+
+fn field<'a>(object: &'a Value, name: &str) -> Result<&'a Value, String> {
+    object
+        .get(name)
+        .ok_or_else(|| format!("Missing field {}", name))
+}
+
+fn hex_str<'a>(object: &'a Value, name: &str) -> Result<&'a str, String> {
+    field(object, name)?
+        .as_str()
+        .map(|value| value.trim_start_matches("0x"))
+        .ok_or_else(|| format!("Field {} is not a string", name))
+}
+
+fn data(object: &Value, name: &str) -> Result<Vec<u8>, String> {
+    hex::decode(hex_str(object, name)?).map_err(|e| format!("Invalid hex in {}: {}", name, e))
+}
+
+fn fixed_data(object: &Value, name: &str, length: usize) -> Result<Vec<u8>, String> {
+    decode_hex(field(object, name)?, length).map_err(|e| format!("{}: {}", name, e))
+}
+
+fn decode_hex(value: &Value, length: usize) -> Result<Vec<u8>, String> {
+    let value = value
+        .as_str()
+        .ok_or_else(|| "Expected a hex string".to_string())?;
+    let bytes = hex::decode(value.trim_start_matches("0x")).map_err(|e| e.to_string())?;
+    if bytes.len() != length {
+        return Err(format!(
+            "Invalid length: {}, expected {} bytes",
+            bytes.len(),
+            length
+        ));
+    }
+    Ok(bytes)
+}
+
+fn hash(object: &Value, name: &str) -> Result<H256, String> {
+    Ok(H256::from_slice(&fixed_data(object, name, 32)?))
+}
+
+fn address(object: &Value, name: &str) -> Result<H160, String> {
+    Ok(H160::from_slice(&fixed_data(object, name, 20)?))
+}
+
+/// Parse a hex quantity, which has no leading zeros and may have an odd number of digits.
+fn quantity(object: &Value, name: &str) -> Result<U256, String> {
+    let digits = hex_str(object, name)?;
+    if digits.len() > 64 {
+        return Err(format!(
+            "Field {} is 0x{}, which overflows 256 bits",
+            name, digits
+        ));
+    }
+    let padded = format!("{:0>64}", digits);
+    let bytes =
+        hex::decode(padded).map_err(|e| format!("Invalid hex in {}, 0x{}: {}", name, digits, e))?;
+    Ok(U256::from_big_endian(&bytes))
+}
+
+/// Parse a hex quantity that must fit in 64 bits, like a block number or timestamp.
+fn quantity_u64(object: &Value, name: &str) -> Result<u64, String> {
+    let value = quantity(object, name)?;
+    if value > U256::from(u64::MAX) {
+        return Err(format!(
+            "Field {} is 0x{:x}, which overflows 64 bits",
+            name, value
+        ));
+    }
+    Ok(value.as_u64())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    /// The mainnet genesis block, as returned by Infura for `eth_getBlockByNumber("0x0", true)`.
+    fn genesis_response() -> Value {
+        json!({
+            "difficulty": "0x400000000",
+            "extraData": "0x11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa",
+            "gasLimit": "0x1388",
+            "gasUsed": "0x0",
+            "hash": "0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3",
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "miner": "0x0000000000000000000000000000000000000000",
+            "mixHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "nonce": "0x0000000000000042",
+            "number": "0x0",
+            "parentHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+            "receiptsRoot": "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+            "sha3Uncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+            "size": "0x21c",
+            "stateRoot": "0xd7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544",
+            "timestamp": "0x0",
+            "totalDifficulty": "0x400000000",
+            "transactions": [],
+            "transactionsRoot": "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+            "uncles": []
+        })
+    }
+
+    #[test]
+    fn test_size_matches_infura_fixture() {
+        let response = genesis_response();
+        let block = Block::from_infura_response(&response, vec![]).unwrap();
+
+        assert_eq!(
+            block.header.hash(),
+            hash(&response, "hash").unwrap(),
+            "fixture header was parsed incorrectly"
+        );
+        assert_eq!(
+            block.size() as u64,
+            quantity(&response, "size").unwrap().as_u64()
+        );
+    }
+
+    #[test]
+    fn test_size_counts_body() {
+        let empty = Block::default();
+        let block = Block {
+            transactions: vec![vec![0xc1, 0x80], vec![0x02, 0xc1, 0x80]],
+            uncles: vec![Header::default()],
+            ..Default::default()
+        };
+        let header_size = rlp::encode(&Header::default()).len();
+
+        // The transactions take 2 and 1+3 bytes. The uncle list holds a header, and its prefix
+        // grows to 3 bytes for a payload that long.
+        assert_eq!(block.size(), empty.size() + 2 + 4 + header_size + 2);
+    }
+
+    /// A London block with a legacy, an access list and a fee market transaction. The legacy one
+    /// is the first transaction on mainnet.
+    const FULL_BLOCK_RESPONSE: &str = r#"{
+    "baseFeePerGas": "0x7",
+    "difficulty": "0x1bc0b2b0f5a5ba",
+    "extraData": "0x7472696e",
+    "gasLimit": "0x1c9c380",
+    "gasUsed": "0x75b08",
+    "logsBloom": "0x00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+    "miner": "0x3333333333333333333333333333333333333333",
+    "mixHash": "0x4444444444444444444444444444444444444444444444444444444444444444",
+    "nonce": "0x0000000000000042",
+    "number": "0xc5d488",
+    "parentHash": "0x5555555555555555555555555555555555555555555555555555555555555555",
+    "receiptsRoot": "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+    "sha3Uncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+    "stateRoot": "0x6666666666666666666666666666666666666666666666666666666666666666",
+    "timestamp": "0x610bdaa6",
+    "transactions": [
+        {
+            "type": "0x0",
+            "nonce": "0x0",
+            "gasPrice": "0x2d79883d2000",
+            "gas": "0x5208",
+            "to": "0x5df9b87991262f6ba471f09758cde1c0fc1de734",
+            "value": "0x7a69",
+            "input": "0x",
+            "v": "0x1c",
+            "r": "0x88ff6cf0fefd94db46111149ae4bfc179e9b94721fffd821d38d16464b3f71d0",
+            "s": "0x45e0aff800961cfce805daef7016b9b675c137a6a41a548f7b60a3484c06a33a",
+            "hash": "0x5c504ed432cb51138bcf09aa5e8a410dd4a1e204ef84bfed1be16dfba1b22060",
+            "transactionIndex": "0x0"
+        },
+        {
+            "type": "0x1",
+            "chainId": "0x1",
+            "nonce": "0x5",
+            "gasPrice": "0x2540be400",
+            "gas": "0x186a0",
+            "to": "0x1111111111111111111111111111111111111111",
+            "value": "0x0",
+            "input": "0xdeadbeef",
+            "accessList": [
+                {
+                    "address": "0x2222222222222222222222222222222222222222",
+                    "storageKeys": [
+                        "0x0000000000000000000000000000000000000000000000000000000000000001",
+                        "0x0000000000000000000000000000000000000000000000000000000000000002"
+                    ]
+                }
+            ],
+            "v": "0x1",
+            "r": "0xabababababababababababababababababababababababababababababababab",
+            "s": "0xcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd0e",
+            "hash": "0x92e2e1e81a84b138bdccc8efcc64d0ace4e681efc76d31cbcbb35dbd9f623708",
+            "transactionIndex": "0x1"
+        },
+        {
+            "type": "0x2",
+            "chainId": "0x1",
+            "nonce": "0x6",
+            "maxPriorityFeePerGas": "0x3b9aca00",
+            "maxFeePerGas": "0x4a817c800",
+            "gas": "0x30d40",
+            "to": null,
+            "value": "0x1",
+            "input": "0x6080604052",
+            "accessList": [],
+            "v": "0x0",
+            "r": "0x1212121212121212121212121212121212121212121212121212121212121212",
+            "s": "0x3434343434343434343434343434343434343434343434343434343434343434",
+            "hash": "0x523f2c008a3a79f889209a5319f16a167c51c55c9a3f6121f9d9dcb6bb4f03c1",
+            "transactionIndex": "0x2"
+        }
+    ],
+    "transactionsRoot": "0x217ddfea8954913833f4016c2df1e4bb285d518b925bae5038c898b32d923759",
+    "uncles": []
+}"#;
+
+    #[test]
+    fn test_full_transactions_match_transactions_root() {
+        let response: Value = serde_json::from_str(FULL_BLOCK_RESPONSE).unwrap();
+        let block = Block::from_infura_response(&response, vec![]).unwrap();
+
+        assert_eq!(block.transactions.len(), 3);
+        assert_eq!(block.transactions[1][0], 0x01);
+        assert_eq!(block.transactions[2][0], 0x02);
+        assert_eq!(
+            block.transactions_root(),
+            hash(&response, "transactionsRoot").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tampered_transaction_is_rejected() {
+        let mut response: Value = serde_json::from_str(FULL_BLOCK_RESPONSE).unwrap();
+        response["transactions"][0]["value"] = json!("0x7a6a");
+        assert!(Block::from_infura_response(&response, vec![]).is_err());
+
+        // Without the transaction hash to catch it, the transactions root still does.
+        response["transactions"][0]["hash"] = Value::Null;
+        assert!(Block::from_infura_response(&response, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_transaction_hashes_only_are_rejected() {
+        let mut response: Value = serde_json::from_str(FULL_BLOCK_RESPONSE).unwrap();
+        response["transactions"] = json!([format!("0x{}", "11".repeat(32))]);
+        assert!(Block::from_infura_response(&response, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_missing_uncle_headers_are_rejected() {
+        let mut response = genesis_response();
+        response["uncles"] = json!([format!("0x{}", "11".repeat(32))]);
+        assert!(Block::from_infura_response(&response, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_overflowing_quantity_names_the_field_and_value() {
+        let mut response = genesis_response();
+        response["number"] = json!("0x10000000000000000");
+        assert_eq!(
+            Block::from_infura_response(&response, vec![]),
+            Err("Field number is 0x10000000000000000, which overflows 64 bits".to_string())
+        );
+
+        response["number"] = json!(format!("0x1{}", "0".repeat(64)));
+        let error = Block::from_infura_response(&response, vec![]).unwrap_err();
+        assert!(error.starts_with("Field number is 0x1000"));
+        assert!(error.ends_with("which overflows 256 bits"));
+    }
+
+    #[test]
+    fn test_provider_error_response_is_reported() {
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": -32005, "message": "daily request count exceeded, request rate limited"}
+        });
+        assert_eq!(
+            Block::from_infura_rpc_response(&response, vec![]),
+            Err(
+                "Provider returned error -32005: daily request count exceeded, request rate limited"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_null_result_is_reported() {
+        let response = json!({"jsonrpc": "2.0", "id": 1, "result": null});
+        assert_eq!(
+            Block::from_infura_rpc_response(&response, vec![]),
+            Err("Provider returned a null result, the block is unknown to it".to_string())
+        );
+        assert!(Block::from_infura_response(&Value::Null, vec![]).is_err());
+
+        let response = json!({"jsonrpc": "2.0", "id": 1, "result": genesis_response()});
+        let block = Block::from_infura_rpc_response(&response, vec![]).unwrap();
+        assert_eq!(block.header.number, 0);
+    }
+}
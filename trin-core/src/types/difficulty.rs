@@ -0,0 +1,161 @@
+use ethereum_types::U256;
+
+use super::header::{Header, EMPTY_UNCLES_HASH};
+
+const HOMESTEAD_BLOCK_NUMBER: u64 = 1_150_000;
+const BYZANTIUM_BLOCK_NUMBER: u64 = 4_370_000;
+const MINIMUM_DIFFICULTY: u64 = 131_072;
+const DIFFICULTY_BOUND_DIVISOR: u64 = 2048;
+const EXP_DIFFICULTY_PERIOD: u64 = 100_000;
+
+/// Difficulty bomb delays, as (first block number, delay), newest first.
+const BOMB_DELAYS: [(u64, u64); 6] = [
+    (15_050_000, 11_400_000), // Gray Glacier
+    (13_773_000, 10_700_000), // Arrow Glacier
+    (12_965_000, 9_700_000),  // London
+    (9_200_000, 9_000_000),   // Muir Glacier
+    (7_280_000, 5_000_000),   // Constantinople
+    (BYZANTIUM_BLOCK_NUMBER, 3_000_000),
+];
+
+fn bomb_delay(block_number: u64) -> u64 {
+    BOMB_DELAYS
+        .iter()
+        .find(|(fork_block, _)| block_number >= *fork_block)
+        .map_or(0, |(_, delay)| *delay)
+}
+
+/// Returns the difficulty expected for the child of `parent`, mined at `timestamp`, following
+/// the Frontier, Homestead and Byzantium (and later) adjustment formulas, including the bomb delay
+/// that applies at the child's block number.
+pub fn expected_difficulty(parent: &Header, timestamp: u64) -> U256 {
+    let block_number = parent.number + 1;
+    let time_delta = timestamp.saturating_sub(parent.timestamp);
+    let bound = parent.difficulty / U256::from(DIFFICULTY_BOUND_DIVISOR);
+
+    // The adjustment is bound/DIFFICULTY_BOUND_DIVISOR times a signed factor.
+    let factor: i64 = if block_number < HOMESTEAD_BLOCK_NUMBER {
+        if time_delta < 13 {
+            1
+        } else {
+            -1
+        }
+    } else if block_number < BYZANTIUM_BLOCK_NUMBER {
+        std::cmp::max(1 - (time_delta / 10) as i64, -99)
+    } else {
+        let parent_has_uncles = parent.uncles_hash != EMPTY_UNCLES_HASH;
+        let uncle_factor = if parent_has_uncles { 2 } else { 1 };
+        std::cmp::max(uncle_factor - (time_delta / 9) as i64, -99)
+    };
+
+    let adjustment = bound * U256::from(factor.unsigned_abs());
+    let mut difficulty = if factor >= 0 {
+        parent.difficulty.saturating_add(adjustment)
+    } else {
+        parent.difficulty.saturating_sub(adjustment)
+    };
+    difficulty = std::cmp::max(difficulty, U256::from(MINIMUM_DIFFICULTY));
+
+    let fake_block_number = block_number.saturating_sub(bomb_delay(block_number));
+    let period_count = fake_block_number / EXP_DIFFICULTY_PERIOD;
+    if period_count > 1 {
+        difficulty = difficulty.saturating_add(U256::one() << (period_count - 2) as usize);
+    }
+
+    difficulty
+}
+
+/// Checks that the header's difficulty matches the difficulty expected from its parent.
+pub fn verify_difficulty(header: &Header, parent: &Header) -> Result<(), String> {
+    if header.number != parent.number + 1 {
+        return Err(format!(
+            "Header {} is not a child of header {}",
+            header.number, parent.number
+        ));
+    }
+
+    let expected = expected_difficulty(parent, header.timestamp);
+    if header.difficulty != expected {
+        return Err(format!(
+            "Invalid difficulty for header {}: expected {}, got {}",
+            header.number, expected, header.difficulty
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ethereum_types::H256;
+
+    fn header(number: u64, timestamp: u64, difficulty: u64) -> Header {
+        Header {
+            number,
+            timestamp,
+            difficulty: U256::from(difficulty),
+            uncles_hash: EMPTY_UNCLES_HASH,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_frontier_mainnet_blocks() {
+        let genesis = header(0, 0, 17_179_869_184);
+        let block_one = header(1, 1_438_269_988, 17_171_480_576);
+        let block_two = header(2, 1_438_270_017, 17_163_096_064);
+
+        assert_eq!(verify_difficulty(&block_one, &genesis), Ok(()));
+        assert_eq!(verify_difficulty(&block_two, &block_one), Ok(()));
+    }
+
+    // The following parent/child pairs are synthetic: parent difficulty and timestamps are
+    // chosen so each case exercises a different part of the formula.
+
+    #[test]
+    fn test_byzantium_bomb_delay() {
+        let parent = header(4_369_999, 1_508_131_300, 2_950_000_000_000_000);
+        assert_eq!(
+            expected_difficulty(&parent, 1_508_131_310),
+            U256::from(2_950_000_000_002_048u64)
+        );
+    }
+
+    #[test]
+    fn test_last_block_before_constantinople_bomb_delay() {
+        let parent = Header {
+            uncles_hash: H256::repeat_byte(1),
+            ..header(7_279_998, 1_551_383_500, 2_950_000_000_000_000)
+        };
+        assert_eq!(
+            expected_difficulty(&parent, 1_551_383_505),
+            U256::from(2_953_980_371_002_776u64)
+        );
+    }
+
+    #[test]
+    fn test_constantinople_bomb_delay() {
+        let parent = Header {
+            uncles_hash: H256::repeat_byte(1),
+            ..header(7_279_999, 1_551_383_500, 2_950_000_000_000_000)
+        };
+        assert_eq!(
+            expected_difficulty(&parent, 1_551_383_505),
+            U256::from(2_952_880_860_423_576u64)
+        );
+    }
+
+    #[test]
+    fn test_london_bomb_delay() {
+        let parent = header(12_964_999, 1_628_166_800, 2_950_000_000_000_000);
+        let child = header(12_965_000, 1_628_166_820, 2_948_560_644_054_324);
+        assert_eq!(verify_difficulty(&child, &parent), Ok(()));
+    }
+
+    #[test]
+    fn test_wrong_difficulty_is_rejected() {
+        let parent = header(12_964_999, 1_628_166_800, 2_950_000_000_000_000);
+        let child = header(12_965_000, 1_628_166_820, 2_950_000_000_000_000);
+        assert!(verify_difficulty(&child, &parent).is_err());
+    }
+}
@@ -0,0 +1,536 @@
+use ethereum_types::{Bloom, H160, H256, H64, U256};
+use keccak_hash::keccak;
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
+
+/// First block which includes the `base_fee_per_gas` field.
+pub const LONDON_BLOCK_NUMBER: u64 = 12_965_000;
+
+/// First proof-of-stake block, whose nonce must be zero and whose mix hash carries prevRandao.
+pub const MERGE_BLOCK_NUMBER: u64 = 15_537_394;
+
+/// Keccak-256 hash of the RLP empty list, the `uncles_hash` of every block without uncles. Blocks
+/// from the Merge onwards have no uncles, so they all carry it.
+pub const EMPTY_UNCLES_HASH: H256 = H256([
+    0x1d, 0xcc, 0x4d, 0xe8, 0xde, 0xc7, 0x5d, 0x7a, 0xab, 0x85, 0xb5, 0x67, 0xb6, 0xcc, 0xd4, 0x1a,
+    0xd3, 0x12, 0x45, 0x1b, 0x94, 0x8a, 0x74, 0x13, 0xf0, 0xa1, 0x42, 0xfd, 0x40, 0xd4, 0x93, 0x47,
+]);
+
+/// Maximum size of `extra_data` allowed on mainnet.
+pub const MAX_EXTRA_DATA_SIZE: usize = 32;
+
+/// How closely a decoded header must follow the mainnet field rules.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodingMode {
+    /// Mainnet rules: `extra_data` is at most 32 bytes, `gas_used` is at most `gas_limit`, headers
+    /// from London onwards carry a base fee, and headers from the Merge onwards have a zero nonce
+    /// and no uncles.
+    Strict,
+    /// Accept `extra_data` of any length and a base fee whenever one is present, as used by
+    /// clique proof-of-authority testnets.
+    Lenient,
+}
+
+impl Default for DecodingMode {
+    fn default() -> Self {
+        DecodingMode::Strict
+    }
+}
+
+/// An Ethereum block header.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Header {
+    pub parent_hash: H256,
+    pub uncles_hash: H256,
+    pub author: H160,
+    pub state_root: H256,
+    pub transactions_root: H256,
+    pub receipts_root: H256,
+    pub log_bloom: Bloom,
+    pub difficulty: U256,
+    pub number: u64,
+    pub gas_limit: U256,
+    pub gas_used: U256,
+    pub timestamp: u64,
+    pub extra_data: Vec<u8>,
+    pub mix_hash: Option<H256>,
+    pub nonce: Option<H64>,
+    pub base_fee_per_gas: Option<U256>,
+}
+
+impl Header {
+    /// Returns the Keccak-256 hash of the header.
+    pub fn hash(&self) -> H256 {
+        keccak(rlp::encode(self))
+    }
+
+    /// Returns the Keccak-256 hash of the header without its mix hash and nonce, which is what
+    /// proof-of-work seals: the mining hash that ethash verification takes as input.
+    pub fn hash_without_seal(&self) -> H256 {
+        keccak(self.rlp(false).out())
+    }
+
+    /// Returns whether both headers are of the same block, judged by their hashes. Unlike `==`,
+    /// which compares every field, this ignores anything that isn't hashed, like a mix hash set
+    /// without a nonce.
+    pub fn same_block(&self, other: &Header) -> bool {
+        self.hash() == other.hash()
+    }
+
+    /// Err, naming both values, if the header uses more gas than its gas limit.
+    pub fn check_gas_used(&self) -> Result<(), String> {
+        if self.gas_used > self.gas_limit {
+            return Err(format!(
+                "Header {} uses {} gas, over its gas limit of {}",
+                self.number, self.gas_used, self.gas_limit
+            ));
+        }
+        Ok(())
+    }
+
+    /// Decode a header, applying the field rules of the given mode. Fields beyond the ones the
+    /// mode knows about are rejected rather than silently dropped.
+    pub fn decode_with_mode(rlp: &Rlp, mode: DecodingMode) -> Result<Self, DecoderError> {
+        let item_count = rlp.item_count()?;
+        let mut header = Header {
+            parent_hash: rlp.val_at(0)?,
+            uncles_hash: rlp.val_at(1)?,
+            author: rlp.val_at(2)?,
+            state_root: rlp.val_at(3)?,
+            transactions_root: rlp.val_at(4)?,
+            receipts_root: rlp.val_at(5)?,
+            log_bloom: rlp.val_at(6)?,
+            difficulty: rlp.val_at(7)?,
+            number: rlp.val_at(8)?,
+            gas_limit: rlp.val_at(9)?,
+            gas_used: rlp.val_at(10)?,
+            timestamp: rlp.val_at(11)?,
+            extra_data: rlp.val_at(12)?,
+            mix_hash: Some(rlp.val_at(13)?),
+            nonce: Some(rlp.val_at(14)?),
+            base_fee_per_gas: None,
+        };
+
+        match mode {
+            DecodingMode::Strict => {
+                if header.extra_data.len() > MAX_EXTRA_DATA_SIZE {
+                    return Err(DecoderError::Custom("extra_data exceeds 32 bytes"));
+                }
+                if header.check_gas_used().is_err() {
+                    return Err(DecoderError::Custom("gas_used exceeds gas_limit"));
+                }
+                let expected_count = match header.number >= LONDON_BLOCK_NUMBER {
+                    true => 16,
+                    false => 15,
+                };
+                if item_count != expected_count {
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                if header.number >= LONDON_BLOCK_NUMBER {
+                    header.base_fee_per_gas = Some(rlp.val_at(15)?);
+                }
+                if header.number >= MERGE_BLOCK_NUMBER && header.nonce != Some(H64::zero()) {
+                    return Err(DecoderError::Custom(
+                        "post-merge header has a nonzero nonce",
+                    ));
+                }
+                if header.number >= MERGE_BLOCK_NUMBER && header.uncles_hash != EMPTY_UNCLES_HASH {
+                    return Err(DecoderError::Custom("post-merge header has uncles"));
+                }
+            }
+            DecodingMode::Lenient => {
+                if item_count > 16 {
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                if item_count > 15 {
+                    header.base_fee_per_gas = Some(rlp.val_at(15)?);
+                }
+            }
+        }
+
+        Ok(header)
+    }
+
+    /// Returns the RLP representation of the header, optionally without the seal fields.
+    pub fn rlp(&self, with_seal: bool) -> RlpStream {
+        let mut s = RlpStream::new();
+        self.stream_rlp(&mut s, with_seal);
+        s
+    }
+
+    fn stream_rlp(&self, s: &mut RlpStream, with_seal: bool) {
+        let stream_length_without_seal = if self.base_fee_per_gas.is_some() {
+            14
+        } else {
+            13
+        };
+
+        if with_seal && self.mix_hash.is_some() && self.nonce.is_some() {
+            s.begin_list(stream_length_without_seal + 2);
+        } else {
+            s.begin_list(stream_length_without_seal);
+        }
+
+        s.append(&self.parent_hash)
+            .append(&self.uncles_hash)
+            .append(&self.author)
+            .append(&self.state_root)
+            .append(&self.transactions_root)
+            .append(&self.receipts_root)
+            .append(&self.log_bloom)
+            .append(&self.difficulty)
+            .append(&self.number)
+            .append(&self.gas_limit)
+            .append(&self.gas_used)
+            .append(&self.timestamp)
+            .append(&self.extra_data);
+
+        if with_seal {
+            if let (Some(mix_hash), Some(nonce)) = (self.mix_hash, self.nonce) {
+                s.append(&mix_hash).append(&nonce);
+            }
+        }
+
+        if let Some(base_fee_per_gas) = self.base_fee_per_gas {
+            s.append(&base_fee_per_gas);
+        }
+    }
+}
+
+impl Encodable for Header {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        self.stream_rlp(s, true);
+    }
+}
+
+impl Decodable for Header {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        Header::decode_with_mode(rlp, DecodingMode::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    fn arb_h256() -> impl Strategy<Value = H256> {
+        any::<[u8; 32]>().prop_map(H256::from)
+    }
+
+    fn arb_u256() -> impl Strategy<Value = U256> {
+        any::<[u8; 32]>().prop_map(|bytes| U256::from_big_endian(&bytes))
+    }
+
+    /// Headers that follow the mainnet field rules for their block number, from before London to
+    /// after the Merge.
+    fn arb_header() -> impl Strategy<Value = Header> {
+        let hashes = (
+            arb_h256(),
+            arb_h256(),
+            any::<[u8; 20]>(),
+            arb_h256(),
+            arb_h256(),
+            arb_h256(),
+            arb_h256(),
+        );
+        let values = (
+            vec(any::<u8>(), 256),
+            arb_u256(),
+            0..MERGE_BLOCK_NUMBER * 2,
+            arb_u256(),
+            arb_u256(),
+            any::<u64>(),
+            vec(any::<u8>(), 0..=MAX_EXTRA_DATA_SIZE),
+            any::<[u8; 8]>(),
+            arb_u256(),
+        );
+        (hashes, values).prop_map(
+            |(
+                (
+                    parent_hash,
+                    uncles_hash,
+                    author,
+                    state_root,
+                    transactions_root,
+                    receipts_root,
+                    mix_hash,
+                ),
+                (
+                    log_bloom,
+                    difficulty,
+                    number,
+                    gas_limit,
+                    gas_used,
+                    timestamp,
+                    extra_data,
+                    nonce,
+                    base_fee,
+                ),
+            )| Header {
+                parent_hash,
+                uncles_hash: match number >= MERGE_BLOCK_NUMBER {
+                    true => EMPTY_UNCLES_HASH,
+                    false => uncles_hash,
+                },
+                author: H160::from(author),
+                state_root,
+                transactions_root,
+                receipts_root,
+                log_bloom: Bloom::from_slice(&log_bloom),
+                difficulty,
+                number,
+                gas_limit: gas_limit.max(gas_used),
+                gas_used: gas_used.min(gas_limit),
+                timestamp,
+                extra_data,
+                mix_hash: Some(mix_hash),
+                nonce: Some(match number >= MERGE_BLOCK_NUMBER {
+                    true => H64::zero(),
+                    false => H64::from(nonce),
+                }),
+                base_fee_per_gas: match number >= LONDON_BLOCK_NUMBER {
+                    true => Some(base_fee),
+                    false => None,
+                },
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_header_round_trip(header in arb_header()) {
+            let decoded: Header = rlp::decode(&rlp::encode(&header)).unwrap();
+            prop_assert_eq!(decoded, header);
+        }
+    }
+
+    fn london_header() -> Header {
+        Header {
+            parent_hash: H256::repeat_byte(1),
+            uncles_hash: H256::repeat_byte(2),
+            author: H160::repeat_byte(3),
+            state_root: H256::repeat_byte(4),
+            transactions_root: H256::repeat_byte(5),
+            receipts_root: H256::repeat_byte(6),
+            log_bloom: Bloom::repeat_byte(7),
+            difficulty: U256::from(8_000_000_000_000_000u64),
+            number: LONDON_BLOCK_NUMBER,
+            gas_limit: U256::from(30_000_000),
+            gas_used: U256::from(15_000_000),
+            timestamp: 1_628_166_822,
+            extra_data: b"trin".to_vec(),
+            mix_hash: Some(H256::repeat_byte(9)),
+            nonce: Some(H64::repeat_byte(10)),
+            base_fee_per_gas: Some(U256::from(1_000_000_000)),
+        }
+    }
+
+    #[test]
+    fn test_london_header_round_trip() {
+        let header = london_header();
+        let decoded: Header = rlp::decode(&rlp::encode(&header)).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.hash(), header.hash());
+    }
+
+    #[test]
+    fn test_pre_london_header_round_trip() {
+        let header = Header {
+            number: LONDON_BLOCK_NUMBER - 1,
+            base_fee_per_gas: None,
+            ..london_header()
+        };
+        let decoded: Header = rlp::decode(&rlp::encode(&header)).unwrap();
+        assert_eq!(decoded, header);
+    }
+
+    #[test]
+    fn test_rlp_without_seal_omits_seal_fields() {
+        let header = london_header();
+        let with_seal = header.rlp(true).out();
+        let without_seal = header.rlp(false).out();
+        assert_eq!(Rlp::new(&with_seal).item_count().unwrap(), 16);
+        assert_eq!(Rlp::new(&without_seal).item_count().unwrap(), 14);
+    }
+
+    #[test]
+    fn test_post_merge_header_with_nonzero_nonce_is_rejected() {
+        let header = Header {
+            number: MERGE_BLOCK_NUMBER,
+            difficulty: U256::zero(),
+            uncles_hash: EMPTY_UNCLES_HASH,
+            nonce: Some(H64::from_low_u64_be(0x42)),
+            ..london_header()
+        };
+        let encoded = rlp::encode(&header);
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Strict).is_err());
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Lenient).is_ok());
+
+        let header = Header {
+            nonce: Some(H64::zero()),
+            ..header
+        };
+        let decoded: Header = rlp::decode(&rlp::encode(&header)).unwrap();
+        assert_eq!(decoded, header);
+    }
+
+    #[test]
+    fn test_gas_used_over_gas_limit_is_rejected() {
+        let header = Header {
+            gas_used: U256::from(30_000_001),
+            gas_limit: U256::from(30_000_000),
+            ..london_header()
+        };
+        let error = header.check_gas_used().unwrap_err();
+        assert!(
+            error.contains("30000001") && error.contains("30000000"),
+            "{}",
+            error
+        );
+        let encoded = rlp::encode(&header);
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Strict).is_err());
+
+        let header = Header {
+            gas_used: header.gas_limit,
+            ..header
+        };
+        assert_eq!(header.check_gas_used(), Ok(()));
+        let decoded: Header = rlp::decode(&rlp::encode(&header)).unwrap();
+        assert_eq!(decoded, header);
+    }
+
+    #[test]
+    fn test_empty_uncles_hash_is_hash_of_empty_list() {
+        assert_eq!(EMPTY_UNCLES_HASH, keccak(rlp::EMPTY_LIST_RLP));
+    }
+
+    #[test]
+    fn test_post_merge_header_with_uncles_is_rejected() {
+        let header = Header {
+            number: MERGE_BLOCK_NUMBER + 1,
+            difficulty: U256::zero(),
+            nonce: Some(H64::zero()),
+            ..london_header()
+        };
+        assert_ne!(header.uncles_hash, EMPTY_UNCLES_HASH);
+        let encoded = rlp::encode(&header);
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Strict).is_err());
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Lenient).is_ok());
+
+        let header = Header {
+            uncles_hash: EMPTY_UNCLES_HASH,
+            ..header
+        };
+        let decoded: Header = rlp::decode(&rlp::encode(&header)).unwrap();
+        assert_eq!(decoded, header);
+    }
+
+    #[test]
+    fn test_clique_header_requires_lenient_mode() {
+        // Clique headers carry 32 bytes of vanity plus a 65 byte signature in extra_data, and
+        // testnets past the mainnet London height may not include a base fee.
+        let header = Header {
+            number: LONDON_BLOCK_NUMBER + 1,
+            extra_data: vec![0xcc; 97],
+            base_fee_per_gas: None,
+            ..london_header()
+        };
+        let encoded = rlp::encode(&header);
+
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Strict).is_err());
+        assert!(rlp::decode::<Header>(&encoded).is_err());
+        let decoded = Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Lenient).unwrap();
+        assert_eq!(decoded, header);
+    }
+
+    #[test]
+    fn test_same_block_ignores_unhashed_fields() {
+        let header = Header {
+            mix_hash: None,
+            nonce: None,
+            ..london_header()
+        };
+        // The seal is only hashed when both of its fields are set.
+        let with_partial_seal = Header {
+            mix_hash: Some(H256::repeat_byte(9)),
+            ..header.clone()
+        };
+        assert_ne!(header, with_partial_seal);
+        assert!(header.same_block(&with_partial_seal));
+
+        let other = Header {
+            number: header.number + 1,
+            ..header.clone()
+        };
+        assert!(!header.same_block(&other));
+    }
+
+    /// Mainnet block 1, as kept in the fuzz corpus.
+    const BLOCK_1_RLP: &[u8] = include_bytes!("../../fuzz/corpus/header_rlp/block_1");
+
+    #[test]
+    fn test_mainnet_block_1_decodes_to_its_hash() {
+        let header: Header = rlp::decode(BLOCK_1_RLP).unwrap();
+        assert_eq!(header.number, 1);
+        assert_eq!(
+            format!("{:x}", header.hash()),
+            "88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6"
+        );
+        assert_eq!(rlp::encode(&header).to_vec(), BLOCK_1_RLP);
+    }
+
+    #[test]
+    fn test_mainnet_block_1_hash_without_seal_is_its_mining_hash() {
+        let header: Header = rlp::decode(BLOCK_1_RLP).unwrap();
+        assert_eq!(
+            format!("{:x}", header.hash_without_seal()),
+            "85913a3057ea8bec78cd916871ca73802e77724e014dda65add3405d02240eb7"
+        );
+        // The seal fields don't affect it.
+        let unsealed = Header {
+            mix_hash: None,
+            nonce: None,
+            ..header.clone()
+        };
+        assert_eq!(unsealed.hash_without_seal(), header.hash_without_seal());
+        assert_ne!(header.hash_without_seal(), header.hash());
+    }
+
+    #[test]
+    fn test_truncated_or_corrupted_header_does_not_panic() {
+        let encoded = rlp::encode(&london_header()).to_vec();
+        for &mode in &[DecodingMode::Strict, DecodingMode::Lenient] {
+            for len in 0..encoded.len() {
+                assert!(Header::decode_with_mode(&Rlp::new(&encoded[..len]), mode).is_err());
+            }
+            for i in 0..encoded.len() {
+                for &byte in &[0x00, 0x7f, 0x80, 0xb8, 0xbf, 0xc0, 0xf8, 0xff] {
+                    let mut corrupted = encoded.clone();
+                    corrupted[i] = byte;
+                    let _ = Header::decode_with_mode(&Rlp::new(&corrupted), mode);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_with_extra_fields_is_rejected() {
+        let header = london_header();
+        let mut s = RlpStream::new_list(17);
+        for item in Rlp::new(&rlp::encode(&header)).iter() {
+            s.append_raw(item.as_raw(), 1);
+        }
+        s.append(&0u8);
+        let encoded = s.out();
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Strict).is_err());
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Lenient).is_err());
+
+        let pre_london = Header {
+            number: LONDON_BLOCK_NUMBER - 1,
+            ..header
+        };
+        let encoded = rlp::encode(&pre_london);
+        assert!(Header::decode_with_mode(&Rlp::new(&encoded), DecodingMode::Strict).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+pub mod accumulator;
+pub mod block;
+pub mod difficulty;
+pub mod header;
+pub mod rlp_limits;
+pub mod trie;
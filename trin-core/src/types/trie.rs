@@ -0,0 +1,140 @@
+use ethereum_types::H256;
+use keccak_hash::keccak;
+use rlp::RlpStream;
+
+/// Returns the root of the Merkle Patricia trie holding the values under their RLP-encoded
+/// index, as used for a block's transactions and receipts roots.
+pub fn ordered_trie_root(values: &[Vec<u8>]) -> H256 {
+    trie_root(
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (rlp::encode(&index).to_vec(), value.clone()))
+            .collect(),
+    )
+}
+
+/// Returns the root of the Merkle Patricia trie holding the entries. Keys must be unique.
+pub fn trie_root(entries: Vec<(Vec<u8>, Vec<u8>)>) -> H256 {
+    let mut entries: Vec<(Vec<u8>, Vec<u8>)> = entries
+        .into_iter()
+        .map(|(key, value)| (nibbles(&key), value))
+        .collect();
+    entries.sort();
+    keccak(encode_node(&entries, 0))
+}
+
+fn nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter()
+        .flat_map(|byte| vec![byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+/// Hex-prefix encoding of a node path, which flags whether the node is a leaf and whether the
+/// path has an odd number of nibbles.
+fn hex_prefix(path: &[u8], is_leaf: bool) -> Vec<u8> {
+    let mut flag = (path.len() % 2) as u8;
+    if is_leaf {
+        flag += 2;
+    }
+    let mut encoded = vec![];
+    let rest = if path.len() % 2 == 1 {
+        encoded.push((flag << 4) | path[0]);
+        &path[1..]
+    } else {
+        encoded.push(flag << 4);
+        path
+    };
+    encoded.extend(rest.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+    encoded
+}
+
+/// Returns the RLP encoding of the node holding the sorted entries, whose keys all share their
+/// first `depth` nibbles.
+fn encode_node(entries: &[(Vec<u8>, Vec<u8>)], depth: usize) -> Vec<u8> {
+    let mut s = RlpStream::new();
+    let (first, last) = match entries {
+        [] => {
+            s.append_empty_data();
+            return s.out().to_vec();
+        }
+        [(key, value)] => {
+            s.begin_list(2);
+            s.append(&hex_prefix(&key[depth..], true));
+            s.append(value);
+            return s.out().to_vec();
+        }
+        [first, .., last] => (&first.0, &last.0),
+    };
+
+    // The entries are sorted, so the first and last keys share the shortest common prefix.
+    let shared = first[depth..]
+        .iter()
+        .zip(last[depth..].iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if shared > 0 {
+        s.begin_list(2);
+        s.append(&hex_prefix(&first[depth..depth + shared], false));
+        append_child(&mut s, encode_node(entries, depth + shared));
+        return s.out().to_vec();
+    }
+
+    s.begin_list(17);
+    for nibble in 0..16 {
+        let children: Vec<(Vec<u8>, Vec<u8>)> = entries
+            .iter()
+            .filter(|(key, _)| key.get(depth) == Some(&nibble))
+            .cloned()
+            .collect();
+        if children.is_empty() {
+            s.append_empty_data();
+        } else {
+            append_child(&mut s, encode_node(&children, depth + 1));
+        }
+    }
+    match entries.iter().find(|(key, _)| key.len() == depth) {
+        Some((_, value)) => s.append(value),
+        None => s.append_empty_data(),
+    };
+    s.out().to_vec()
+}
+
+/// Nodes shorter than a hash are embedded in their parent, longer ones are referenced by hash.
+fn append_child(s: &mut RlpStream, node: Vec<u8>) {
+    if node.len() < 32 {
+        s.append_raw(&node, 1);
+    } else {
+        s.append(&keccak(node));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_empty_trie_root() {
+        assert_eq!(
+            ordered_trie_root(&[]),
+            H256::from_str("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trie_root_with_extension_and_branch() {
+        // From the ethereum/tests trie fixtures.
+        let entries = vec![
+            (b"doe".to_vec(), b"reindeer".to_vec()),
+            (b"dog".to_vec(), b"puppy".to_vec()),
+            (b"dogglesworth".to_vec(), b"cat".to_vec()),
+        ];
+        assert_eq!(
+            trie_root(entries),
+            H256::from_str("8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3")
+                .unwrap()
+        );
+    }
+}
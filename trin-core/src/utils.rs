@@ -1,6 +1,8 @@
-use crate::portalnet::Enr;
+use crate::portalnet::{Enr, U256};
 use directories::ProjectDirs;
+use discv5::enr::NodeId;
 use std::{env, fs};
+use tracing_subscriber::EnvFilter;
 
 const TRIN_DATA_ENV_VAR: &str = "TRIN_DATA_PATH";
 
@@ -16,9 +18,66 @@ pub fn xor_two_values(first: &[u8], second: &[u8]) -> Vec<u8> {
         .collect()
 }
 
+/// Returns the full, 0x-prefixed hex encoding of a node id.
+pub fn node_id_hex(node_id: &NodeId) -> String {
+    format!("0x{}", hex::encode(node_id.raw()))
+}
+
+/// Returns the node id as an integer, reading its bytes as big-endian, the order the XOR metric
+/// and data radii are defined in.
+pub fn node_id_to_u256(node_id: &NodeId) -> U256 {
+    U256::from_big_endian(&node_id.raw())
+}
+
+/// Returns the content id as an integer, reading its bytes as big-endian.
+pub fn content_id_to_u256(content_id: &[u8; 32]) -> U256 {
+    U256::from_big_endian(content_id)
+}
+
+/// Returns the big-endian bytes of the integer, the inverse of `content_id_to_u256`.
+pub fn u256_to_content_id(value: U256) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    bytes
+}
+
+/// Returns the XOR distance between a node and a content id, comparable with a data radius.
+pub fn content_distance(node_id: &NodeId, content_id: &[u8; 32]) -> U256 {
+    node_id_to_u256(node_id) ^ content_id_to_u256(content_id)
+}
+
+/// Returns the log2 distance encoded by an XOR distance: the index of its highest set bit, plus one.
+pub fn log2_distance(xor_distance: &[u8]) -> u64 {
+    for (i, byte) in xor_distance.iter().enumerate() {
+        if *byte != 0 {
+            return ((xor_distance.len() - i) * 8) as u64 - byte.leading_zeros() as u64;
+        }
+    }
+    0
+}
+
+/// Build the log filter from an env-filter-style directive, like `trin_core=debug,discv5=info`.
+/// Without a directive, fall back to the `RUST_LOG` environment variable.
+pub fn build_log_filter(directive: Option<&str>) -> Result<EnvFilter, String> {
+    match directive {
+        Some(directive) => EnvFilter::try_new(directive)
+            .map_err(|e| format!("Invalid log directive '{}': {}", directive, e)),
+        None => Ok(EnvFilter::from_default_env()),
+    }
+}
+
+pub fn init_tracing_logger(directive: Option<&str>) -> Result<(), String> {
+    tracing_subscriber::fmt()
+        .with_env_filter(build_log_filter(directive)?)
+        .try_init()
+        .map_err(|e| format!("Unable to initialize logger: {}", e))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use tracing::Subscriber;
+    use tracing_subscriber::filter::LevelFilter;
 
     #[test]
     fn test_xor_two_zeros() {
@@ -41,15 +100,80 @@ mod test {
         let two = vec![0, 0, 1];
         xor_two_values(&one, &two);
     }
+
+    #[test]
+    fn test_log2_distance() {
+        assert_eq!(log2_distance(&[0, 0]), 0);
+        assert_eq!(log2_distance(&[0, 1]), 1);
+        assert_eq!(log2_distance(&[0, 0xff]), 8);
+        assert_eq!(log2_distance(&[0x10, 0]), 13);
+    }
+
+    #[test]
+    fn test_id_conversions_are_big_endian() {
+        let mut low = [0u8; 32];
+        low[31] = 1;
+        let mut high = [0u8; 32];
+        high[0] = 1;
+        assert_eq!(content_id_to_u256(&low), U256::one());
+        assert_eq!(content_id_to_u256(&high), U256::one() << 248);
+        assert_eq!(node_id_to_u256(&NodeId::new(&high)), U256::one() << 248);
+
+        let mut id = [0u8; 32];
+        id.copy_from_slice(
+            &hex::decode("0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20")
+                .unwrap(),
+        );
+        let value = content_id_to_u256(&id);
+        assert_eq!(value.low_u64(), 0x191a1b1c1d1e1f20);
+        assert_eq!(u256_to_content_id(value), id);
+        assert_eq!(u256_to_content_id(U256::max_value()), [0xff; 32]);
+    }
+
+    #[test]
+    fn test_content_distance_matches_byte_xor() {
+        let node_id = NodeId::new(&[0xf0; 32]);
+        let content_id = [0x0f; 32];
+        assert_eq!(content_distance(&node_id, &content_id), U256::max_value());
+        assert_eq!(
+            u256_to_content_id(content_distance(&node_id, &[0xf1; 32])),
+            xor_two_values(&node_id.raw(), &[0xf1; 32])[..]
+        );
+    }
+
+    #[test]
+    fn test_log_directive_is_parsed() {
+        let filter = build_log_filter(Some("trin_core=debug,discv5=info")).unwrap();
+        let directives = filter.to_string();
+        assert!(directives.contains("trin_core=debug"));
+        assert!(directives.contains("discv5=info"));
+    }
+
+    #[test]
+    fn test_log_directive_is_applied_to_subscriber() {
+        let filter = build_log_filter(Some("trin_core=debug,discv5=info")).unwrap();
+        let subscriber = tracing_subscriber::fmt().with_env_filter(filter).finish();
+        assert_eq!(subscriber.max_level_hint(), Some(LevelFilter::DEBUG));
+    }
+
+    #[test]
+    fn test_invalid_log_directive() {
+        assert!(build_log_filter(Some("trin_core=loudest")).is_err());
+    }
 }
 
 pub fn get_data_dir(local_enr: Enr) -> String {
-    let path = env::var(TRIN_DATA_ENV_VAR).unwrap_or_else(|_| get_default_data_dir(local_enr));
+    let path = data_dir_override().unwrap_or_else(|| get_default_data_dir(local_enr));
 
     fs::create_dir_all(&path).expect("Unable to create data directory folder");
     path
 }
 
+/// Returns the data directory set with `TRIN_DATA_PATH`, in place of the default one.
+pub fn data_dir_override() -> Option<String> {
+    env::var(TRIN_DATA_ENV_VAR).ok()
+}
+
 pub fn get_default_data_dir(local_enr: Enr) -> String {
     // Windows: C:\Users\Username\AppData\Roaming\Trin\data
     // macOS: ~/Library/Application Support/Trin
@@ -1,10 +1,11 @@
 use std::env;
 
-use log::info;
+use log::{info, warn};
 use tokio::sync::mpsc;
 
 use trin_core::cli::TrinConfig;
 use trin_core::jsonrpc::launch_trin;
+use trin_core::portalnet::discovery::Subnetwork;
 use trin_core::portalnet::protocol::{
     JsonRpcHandler, PortalEndpoint, PortalnetConfig, PortalnetProtocol,
 };
@@ -22,58 +23,81 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
         trin_config.web3_http_port = 8555;
     }
 
-    trin_config.display_config();
-
     let infura_project_id = match env::var("TRIN_INFURA_PROJECT_ID") {
-        Ok(val) => val,
+        _ if trin_config.offline => None,
+        Ok(val) => Some(val),
         Err(_) => panic!(
             "Must supply Infura key as environment variable, like:\n\
             TRIN_INFURA_PROJECT_ID=\"your-key-here\" trin"
         ),
     };
 
-    let bootnode_enrs = trin_config
-        .bootnodes
-        .iter()
-        .map(|nodestr| nodestr.parse().unwrap())
-        .collect();
-
     let portalnet_config = PortalnetConfig {
-        external_addr: trin_config.external_addr,
-        private_key: trin_config.private_key.clone(),
-        listen_port: trin_config.discovery_port,
-        bootnode_enrs,
-        ..Default::default()
+        subnetworks: vec![Subnetwork::History],
+        ..PortalnetConfig::from(&trin_config)
     };
 
     let (jsonrpc_tx, jsonrpc_rx) = mpsc::unbounded_channel::<PortalEndpoint>();
+    let offline = trin_config.offline;
+    let json_rpc = trin_config.json_rpc_endpoint();
 
     let web3_server_task = tokio::task::spawn_blocking(|| {
         launch_trin(trin_config, infura_project_id, jsonrpc_tx);
     });
 
+    if offline {
+        let rpc_handler = JsonRpcHandler::new_offline(portalnet_config, jsonrpc_rx)?;
+        tokio::spawn(rpc_handler.process_jsonrpc_requests());
+        info!(
+            "Offline mode: networking disabled, serving json-rpc over {}",
+            json_rpc
+        );
+
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to pause until ctrl-c");
+
+        web3_server_task.await.unwrap();
+        return Ok(());
+    }
+
     info!(
         "About to spawn portal p2p with boot nodes: {:?}",
         portalnet_config.bootnode_enrs
     );
 
+    let max_concurrent_requests = portalnet_config.max_concurrent_requests;
+    let bootstrap_timeout = portalnet_config.bootstrap_timeout;
+    let summary_config = portalnet_config.clone();
     tokio::spawn(async move {
-        let (mut p2p, events) = PortalnetProtocol::new(portalnet_config).await.unwrap();
+        let (p2p, events) = PortalnetProtocol::new(portalnet_config).await.unwrap();
 
         let rpc_handler = JsonRpcHandler {
             discovery: p2p.discovery.clone(),
+            store: p2p.store.clone(),
+            canonical_index: p2p.canonical_index.clone(),
+            transfers: p2p.transfers.clone(),
+            protocol: Some(p2p.clone()),
+            max_concurrent_requests,
             jsonrpc_rx,
         };
 
         tokio::spawn(events.process_discv5_requests());
         tokio::spawn(rpc_handler.process_jsonrpc_requests());
+        info!(
+            "trin-history started\n{}",
+            p2p.startup_summary(&summary_config, json_rpc)
+        );
 
-        // hacky test: make sure we establish a session with the boot node
-        p2p.ping_bootnodes().await.unwrap();
+        // Bond with the bootnodes, and report a node that fails to join the network.
+        let _ = p2p.bootstrap(bootstrap_timeout).await;
 
         tokio::signal::ctrl_c()
             .await
             .expect("failed to pause until ctrl-c");
+        if let Err(e) = p2p.store.flush() {
+            warn!("Failed to flush buffered content on shutdown: {}", e);
+        }
     })
     .await
     .unwrap();
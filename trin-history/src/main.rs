@@ -1,6 +1,10 @@
+use trin_core::cli::TrinConfig;
+use trin_core::utils::init_tracing_logger;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    let trin_config = TrinConfig::new();
+    init_tracing_logger(trin_config.log.as_deref())?;
 
     trin_history::main().await
 }